@@ -0,0 +1,73 @@
+use crate::{KvsClient, Result, Scan, WriteBatch};
+
+/// Operations on a key-value store, whether it is reached over the network or embedded.
+///
+/// Code written against `KvsApi` runs on a `KvsClient` as well as on a `LocalClient`,
+/// so an application can move between both deployments without changing it, and tests
+/// can run it without a server.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KvStore, KvsApi, KvsEngine, LocalClient};
+/// use tempfile::TempDir;
+///
+/// fn rename(client: &mut impl KvsApi, from: &str, to: &str) -> kvs::Result<()> {
+///     if let Some(value) = client.get(from.to_owned())? {
+///         client.set(to.to_owned(), value)?;
+///         client.remove(from.to_owned())?;
+///     }
+///     Ok(())
+/// }
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut client = LocalClient::new(KvStore::open(temp_dir.path()).unwrap());
+/// client.set("old".to_owned(), "value".to_owned()).unwrap();
+/// rename(&mut client, "old", "new").unwrap();
+/// assert_eq!(client.get("new".to_owned()).unwrap(), Some("value".to_owned()));
+/// ```
+pub trait KvsApi {
+    /// Set the value of a string key to a string.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Get the string value of a given string key, `None` if it doesn't exist.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Remove a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::KeyNotFound` if the given key does not exist.
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Go through the keys in steps, see `Scan`.
+    ///
+    /// Returns the cursor of the next step, "0" once every key was gone through,
+    /// and the keys of this step matching the pattern.
+    fn scan(&mut self, scan: Scan) -> Result<(String, Vec<String>)>;
+
+    /// Apply the writes of `batch` in order and atomically.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()>;
+}
+
+impl KvsApi for KvsClient {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvsClient::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvsClient::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvsClient::remove(self, key)
+    }
+
+    fn scan(&mut self, scan: Scan) -> Result<(String, Vec<String>)> {
+        KvsClient::scan(self, scan)
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        KvsClient::write_batch(self, batch)
+    }
+}
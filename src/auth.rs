@@ -0,0 +1,231 @@
+use crate::random::secret_bytes;
+use crate::{Error, Result};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// User of the AUTH requests that only give a password.
+pub const DEFAULT_USER: &str = "default";
+
+/// Checks the credentials connections authenticate with, see `KvsServer::set_auth_provider`.
+///
+/// The server only knows whether AUTH succeeded, so a deployment can check credentials
+/// however it wants by implementing this, e.g. against a company-wide auth service.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{AuthProvider, Result};
+///
+/// struct Unlocked;
+///
+/// impl AuthProvider for Unlocked {
+///     fn authenticate(&self, _username: &str, password: &str) -> Result<bool> {
+///         Ok(password == "open sesame")
+///     }
+/// }
+///
+/// assert!(Unlocked.authenticate("default", "open sesame").unwrap());
+/// ```
+pub trait AuthProvider: Send + Sync {
+    /// Whether `password` is that of `username`.
+    ///
+    /// It is called from the thread of the connection sending AUTH, other connections
+    /// aren't held up while it runs.
+    ///
+    /// # Errors
+    ///
+    /// An error means the credentials couldn't be checked, e.g. the service checking them
+    /// is unreachable. The AUTH fails, without counting as a wrong password.
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool>;
+}
+
+/// A single password for the default user, like Redis' requirepass.
+pub struct StaticPassword {
+    password: String,
+}
+
+impl StaticPassword {
+    pub fn new(password: impl Into<String>) -> Self {
+        StaticPassword {
+            password: password.into(),
+        }
+    }
+}
+
+impl AuthProvider for StaticPassword {
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool> {
+        Ok(username == DEFAULT_USER && constant_time_eq(password, &self.password))
+    }
+}
+
+/// Users and their passwords, read from a file with a "USERNAME PASSWORD" line each.
+///
+/// Blank lines and lines starting with '#' are skipped. Passwords are stored as they are,
+/// so only the server should be able to read the file. It is read once, by `load`.
+#[derive(Debug, Clone, Default)]
+pub struct UserFile {
+    users: HashMap<String, String>,
+}
+
+impl UserFile {
+    /// # Errors
+    ///
+    /// It returns `Error::InvalidUserFile` with the number of the first line that isn't a
+    /// username and a password.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut users = HashMap::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(char::is_whitespace) {
+                Some((username, password)) if !password.trim().is_empty() => {
+                    users.insert(username.to_owned(), password.trim().to_owned());
+                }
+                _ => return Err(Error::InvalidUserFile(path.to_owned(), i + 1)),
+            }
+        }
+        Ok(UserFile { users })
+    }
+}
+
+impl AuthProvider for UserFile {
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool> {
+        Ok(self
+            .users
+            .get(username)
+            .is_some_and(|expected| constant_time_eq(password, expected)))
+    }
+}
+
+/// Credentials checked by an external program, e.g. one asking a company's auth service.
+///
+/// The program is run for every AUTH with the username as its last argument and the
+/// password on its standard input, so that the password doesn't show in the list of
+/// processes. It accepts the credentials by exiting with status 0. The connection waits
+/// for it, it should time out by itself.
+#[derive(Debug, Clone)]
+pub struct ExternalVerifier {
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+impl ExternalVerifier {
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        ExternalVerifier {
+            program: program.into(),
+            args: vec![],
+        }
+    }
+
+    /// Pass `arg` to the program before the username.
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+}
+
+impl AuthProvider for ExternalVerifier {
+    /// # Errors
+    ///
+    /// It returns `Error::IO` if the program can't be run.
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .arg(username)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // A program deciding without reading the password closes its end early
+        match writeln!(stdin, "{}", password) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+        drop(stdin);
+        Ok(child.wait()?.success())
+    }
+}
+
+/// Tokens issued by AUTH ROTATE, which connections authenticate with instead of a password.
+///
+/// They live in the server's memory only: a restart revokes them all, clients then
+/// authenticate with the credentials of the auth provider again.
+#[derive(Default)]
+pub(crate) struct TokenStore {
+    tokens: Mutex<HashMap<String, Token>>,
+}
+
+struct Token {
+    user: String,
+    expires_at: Instant,
+}
+
+impl TokenStore {
+    /// A new token of `user`, valid for `ttl`.
+    pub(crate) fn issue(&self, user: &str, ttl: Duration) -> io::Result<String> {
+        let mut token = "kvs-".to_owned();
+        for byte in secret_bytes::<16>()? {
+            write!(token, "{:02x}", byte).expect("writing to a String never fails");
+        }
+        let mut tokens = self.tokens.lock().unwrap();
+        // Expired tokens are only dropped here, so they don't pile up
+        let now = Instant::now();
+        tokens.retain(|_, token| token.expires_at > now);
+        tokens.insert(
+            token.clone(),
+            Token {
+                user: user.to_owned(),
+                expires_at: now + ttl,
+            },
+        );
+        Ok(token)
+    }
+
+    /// The user of `token`, `None` if it expired or was revoked.
+    pub(crate) fn user_of(&self, token: &str) -> Option<String> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens
+            .get(token)
+            .filter(|token| token.expires_at > Instant::now())
+            .map(|token| token.user.clone())
+    }
+
+    /// Make `token` expire within `grace`, if it doesn't already.
+    pub(crate) fn expire_within(&self, token: &str, grace: Duration) {
+        if let Some(token) = self.tokens.lock().unwrap().get_mut(token) {
+            token.expires_at = token.expires_at.min(Instant::now() + grace);
+        }
+    }
+
+    /// Revoke every token of `user`, returns how many were still valid.
+    pub(crate) fn revoke(&self, user: &str) -> usize {
+        let now = Instant::now();
+        let mut revoked = 0;
+        self.tokens.lock().unwrap().retain(|_, token| {
+            let keep = token.user != user;
+            if !keep && token.expires_at > now {
+                revoked += 1;
+            }
+            keep
+        });
+        revoked
+    }
+}
+
+/// Compare passwords in a time that doesn't depend on where they differ.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
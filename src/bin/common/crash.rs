@@ -0,0 +1,89 @@
+use kvs::RecentRequests;
+use log::error;
+use serde_json::json;
+use std::backtrace::Backtrace;
+use std::fs;
+use std::panic::{self, PanicHookInfo};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::SystemTime;
+
+/// Write a crash report to `dir` on a panic, then abort the process.
+///
+/// A panic in any thread ends the process: a server thread dying in the middle of a
+/// request may leave locks poisoned or data half written, it is safer to restart. The
+/// report is the JSON file "crash-<unix millis>-<pid>.json", with the build, `config`,
+/// the panic and its backtrace, and the requests kept in `recent`.
+pub fn install_crash_handler(
+    program: &'static str,
+    dir: PathBuf,
+    config: String,
+    recent: Option<RecentRequests>,
+) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        match write_crash_report(program, &dir, &config, recent.as_ref(), info) {
+            Ok(path) => error!("Crash report written to {:?}", path),
+            Err(e) => error!("Failed to write a crash report to {:?}: {}", dir, e),
+        }
+        process::abort();
+    }));
+}
+
+fn write_crash_report(
+    program: &str,
+    dir: &Path,
+    config: &str,
+    recent: Option<&RecentRequests>,
+    info: &PanicHookInfo,
+) -> anyhow::Result<PathBuf> {
+    let now = unix_millis(SystemTime::now());
+    let message = info
+        .payload()
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "<non-string panic payload>".to_owned());
+    let requests: Vec<_> = recent
+        .map(RecentRequests::requests)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|request| {
+            json!({
+                "at": unix_millis(request.at),
+                "connection": request.connection,
+                "command": request.command,
+                "key": request.key,
+            })
+        })
+        .collect();
+    let report = json!({
+        "program": program,
+        "version": env!("CARGO_PKG_VERSION"),
+        "build": {
+            "profile": if cfg!(debug_assertions) { "debug" } else { "release" },
+            "os": std::env::consts::OS,
+            "arch": std::env::consts::ARCH,
+        },
+        "pid": process::id(),
+        "time": now,
+        "thread": thread::current().name().unwrap_or("<unnamed>"),
+        "message": message,
+        "location": info.location().map(ToString::to_string),
+        "config": config,
+        "recent_requests": requests,
+        "backtrace": Backtrace::force_capture().to_string(),
+    });
+
+    let path = dir.join(format!("crash-{}-{}.json", now, process::id()));
+    fs::write(&path, serde_json::to_string_pretty(&report)?)?;
+    Ok(path)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
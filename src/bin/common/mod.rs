@@ -1,120 +1,103 @@
-use bytes::Bytes;
-use clap::{Args, Subcommand};
-use redis_protocol::resp2::prelude::*;
-use std::str::from_utf8;
-use thiserror::Error;
+use anyhow::bail;
+use clap::ValueEnum;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::env::current_dir;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
 
-#[derive(Debug)]
-pub enum Response {
-    Ok,
-    Value(String),
-    Err(String),
-}
+mod crash;
 
-impl From<Response> for Frame {
-    fn from(response: Response) -> Self {
-        match response {
-            Response::Ok => Frame::SimpleString("OK".into()),
-            Response::Value(value) => Frame::SimpleString(value.into()),
-            // TODO:
-            Response::Err(err) => Frame::Error(err.into()),
-        }
-    }
-}
+pub use crash::install_crash_handler;
 
-#[derive(Subcommand, Debug)]
-pub enum Request {
-    /// Set the value of a string key to a string
-    Set(Set),
-    /// Get the string value of a given string key
-    Get(Get),
-    /// Remove a given key
-    Rm(Remove),
-}
+// Start of every redb file
+const REDB_MAGIC: &[u8; 9] = b"redb\x1a\n\xa9\r\n";
 
-#[derive(Args, Debug)]
-pub struct Set {
-    pub key: String,
-    pub value: String,
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
+pub enum Engine {
+    #[value(alias = "kvs")]
+    KvStore,
+    Redb,
+    Sled,
 }
 
-#[derive(Args, Debug)]
-pub struct Get {
-    pub key: String,
-}
-
-#[derive(Args, Debug)]
-pub struct Remove {
-    pub key: String,
-}
-
-impl From<Request> for Frame {
-    fn from(request: Request) -> Self {
-        let mut frame_vec = vec![];
-        match request {
-            Request::Set(Set { key, value }) => {
-                frame_vec.push(Frame::BulkString("set".into()));
-                frame_vec.push(Frame::BulkString(key.into()));
-                frame_vec.push(Frame::BulkString(value.into()));
-            }
-            Request::Get(Get { key }) => {
-                frame_vec.push(Frame::BulkString("get".into()));
-                frame_vec.push(Frame::BulkString(key.into()));
-            }
-            Request::Rm(Remove { key }) => {
-                frame_vec.push(Frame::BulkString("remove".into()));
-                frame_vec.push(Frame::BulkString(key.into()));
-            }
+/// Get current engine from engine file
+///
+/// If the engine file is missing but data of an engine is found, the engine file is
+/// written again for that engine. If there is no engine exists, return Ok(None).
+pub fn current_engine() -> anyhow::Result<Option<Engine>> {
+    let engine_path = current_dir()?.join("engine");
+    if !engine_path.exists() {
+        let engine = detect_engine(&current_dir()?)?;
+        if let Some(engine) = engine {
+            warn!(
+                "The engine file is missing, recreating it for the {:?} data found",
+                engine
+            );
+            fs::write(engine_path, serde_json::to_string(&engine)?)?;
+        }
+        anyhow::Ok(engine)
+    } else {
+        let str_from_engine_file = fs::read_to_string(engine_path)?;
+        // if let Ok(engine) = Engine::from_str(&str_from_engine_file, true) {
+        if let Some(engine) = serde_json::from_str(&str_from_engine_file)? {
+            debug!("current engine type: {:?}", engine);
+            anyhow::Ok(Some(engine))
+        } else {
+            error!("Unexpected engine type: {:?}", str_from_engine_file);
+            anyhow::Ok(None)
         }
-        Frame::Array(frame_vec)
     }
 }
 
-impl TryFrom<Frame> for Request {
-    type Error = RequestError;
-
-    // 一坨答辩
-    // need refactor
-    fn try_from(frame: Frame) -> std::result::Result<Self, Self::Error> {
-        if let Frame::Array(bulk_string_vec) = frame {
-            let mut v: Vec<Bytes> = vec![];
-            for bulk_string in bulk_string_vec {
-                if let Frame::BulkString(s) = bulk_string {
-                    v.push(s);
-                }
-            }
-            // println!("{:?}", &v);
+/// The engine whose data is in `dir`, told by the files it leaves there.
+///
+/// It fails if data of several engines is found, the engine to use is then unclear.
+fn detect_engine(dir: &Path) -> anyhow::Result<Option<Engine>> {
+    let mut found = vec![];
+    if has_logs(&dir.join("kvstore"))? {
+        found.push(Engine::KvStore);
+    }
+    if has_redb_magic(&dir.join("redb"))? {
+        found.push(Engine::Redb);
+    }
+    let sled = dir.join("sled");
+    if sled.join("conf").is_file() && sled.join("db").is_file() {
+        found.push(Engine::Sled);
+    }
+    match found[..] {
+        [] => anyhow::Ok(None),
+        [engine] => anyhow::Ok(Some(engine)),
+        _ => bail!(
+            "Data of several engines found in {:?}: {:?}, write the engine file by hand",
+            dir,
+            found
+        ),
+    }
+}
 
-            if let Some(a) = v.get(0) {
-                if a == &Bytes::from(&b"set"[..]) && v.len() == 3 {
-                    Ok(Request::Set(Set {
-                        key: from_utf8(&v[1])?.to_string(),
-                        value: from_utf8(&v[2])?.to_string(),
-                    }))
-                } else if a == &Bytes::from(&b"get"[..]) && v.len() == 2 {
-                    Ok(Request::Get(Get {
-                        key: from_utf8(&v[1])?.to_string(),
-                    }))
-                } else if a == &Bytes::from(&b"remove"[..]) && v.len() == 2 {
-                    Ok(Request::Rm(Remove {
-                        key: from_utf8(&v[1])?.to_string(),
-                    }))
-                } else {
-                    Err(RequestError::ParseFrameErr)
-                }
-            } else {
-                Err(RequestError::ParseFrameErr)
-            }
-        } else {
-            Err(RequestError::ParseFrameErr)
+/// Whether `dir` holds data files of a `KvStore`.
+fn has_logs(dir: &Path) -> io::Result<bool> {
+    if !dir.is_dir() {
+        return Ok(false);
+    }
+    for entry in fs::read_dir(dir)? {
+        if entry?.path().extension().is_some_and(|ext| ext == "log") {
+            return Ok(true);
         }
     }
+    Ok(false)
 }
 
-#[derive(Error, Debug)]
-pub enum RequestError {
-    #[error("Cannot parse Frame into Request")]
-    ParseFrameErr,
-    #[error("Utf8Error")]
-    Utf8Error(#[from] std::str::Utf8Error),
+fn has_redb_magic(path: &Path) -> io::Result<bool> {
+    if !path.is_file() {
+        return Ok(false);
+    }
+    let mut magic = [0; REDB_MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == REDB_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
 }
@@ -0,0 +1,388 @@
+use anyhow::bail;
+use clap::{Args, Parser, Subcommand};
+use common::*;
+use kvs::*;
+use redis_protocol::resp2::prelude::*;
+use std::collections::hash_map::{Entry, HashMap};
+use std::env::current_dir;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+#[derive(Parser, Debug)]
+#[command(name = "kvs-admin", author, version, about, long_about = None)]
+struct Options {
+    #[command(subcommand)]
+    command: AdminCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Erase all current and historical versions of matching keys from disk
+    ///
+    /// Run it while kvs-server is stopped, in the directory the server runs in.
+    Scrub {
+        /// Glob-style pattern of the keys to erase, e.g. "user:42:*"
+        #[arg(long)]
+        key: String,
+    },
+    /// Print the on-disk format of the kvs engine as JSON
+    FormatSpec,
+    /// Show the state of a running server's background jobs
+    Tasks {
+        #[arg(
+            short,
+            long,
+            default_value = "127.0.0.1:7878",
+            help = "IP:PORT",
+            env = "KVS_ADDR"
+        )]
+        addr: String,
+    },
+    /// Show what a running server is doing: the requests it is serving, and for how long
+    CurrentOps {
+        #[arg(
+            short,
+            long,
+            default_value = "127.0.0.1:7878",
+            help = "IP:PORT",
+            env = "KVS_ADDR"
+        )]
+        addr: String,
+    },
+    /// Make a running server reclaim the disk space of a database now
+    Compact {
+        #[arg(
+            short,
+            long,
+            default_value = "127.0.0.1:7878",
+            help = "IP:PORT",
+            env = "KVS_ADDR"
+        )]
+        addr: String,
+        /// Number of the database
+        #[arg(long, default_value_t = 0)]
+        db: usize,
+    },
+    /// Copy keys from the local data to a running server
+    ///
+    /// Run it while kvs-server is stopped, in the directory the server runs in.
+    /// Keys are copied in sorted order, so an interrupted copy can be resumed.
+    CopyTo(CopyTo),
+    /// Send the requests recorded by kvs-server --capture to a running server
+    ///
+    /// Requests are sent on as many connections as they were received on, at the pace
+    /// they were received. Requests switching the protocol or subscribing are skipped.
+    Replay(Replay),
+}
+
+#[derive(Args, Debug)]
+struct CopyTo {
+    /// Address of the destination server
+    #[arg(long, help = "IP:PORT")]
+    dest: String,
+    /// Only copy keys starting with this prefix
+    #[arg(long)]
+    prefix: Option<String>,
+    /// Only copy keys sorting after this one, e.g. the last key copied by an interrupted run
+    #[arg(long, value_name = "KEY")]
+    resume_after: Option<String>,
+    /// Number of keys sent in one MSET
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: u32,
+    /// Number of MSETs sent before waiting for their replies
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..))]
+    pipeline: u32,
+    /// Copy at most this many keys per second
+    #[arg(long, value_name = "KEYS", value_parser = clap::value_parser!(u32).range(1..))]
+    rate: Option<u32>,
+    /// Only report which keys would be copied, without writing to the destination
+    #[arg(long)]
+    dry_run: bool,
+    /// Read every round of keys back from the destination and compare them with the source
+    #[arg(long, conflicts_with = "dry_run")]
+    verify: bool,
+}
+
+#[derive(Args, Debug)]
+struct Replay {
+    /// Capture file written by kvs-server --capture
+    file: PathBuf,
+    /// Address of the destination server
+    #[arg(long, help = "IP:PORT")]
+    dest: String,
+    /// Replay this many times faster than recorded, e.g. "2x" or "0.5x", or "max" not to wait
+    #[arg(long, default_value = "1x", value_parser = parse_speed)]
+    speed: f64,
+}
+
+fn parse_speed(s: &str) -> std::result::Result<f64, String> {
+    if s == "max" {
+        return Ok(f64::INFINITY);
+    }
+    match s.strip_suffix('x').unwrap_or(s).parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!("expected a speed like 2x or max, got {:?}", s)),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let options = Options::parse();
+    install_crash_handler("kvs-admin", current_dir()?, format!("{:?}", options), None);
+
+    match options.command {
+        AdminCommand::Scrub { key } => scrub(&key)?,
+        AdminCommand::FormatSpec => {
+            println!("{}", serde_json::to_string_pretty(&KvStore::format_spec())?)
+        }
+        AdminCommand::Tasks { addr } => {
+            let tasks = KvsClient::connect(&addr)?.tasks()?;
+            if tasks.is_empty() {
+                println!("No background jobs");
+            } else {
+                print!("{}", tasks);
+            }
+        }
+        AdminCommand::CurrentOps { addr } => {
+            print!("{}", KvsClient::connect(&addr)?.current_ops()?)
+        }
+        AdminCommand::Compact { addr, db } => {
+            let mut client = KvsClient::connect(&addr)?;
+            client.select(db)?;
+            client.compact()?;
+        }
+        AdminCommand::CopyTo(options) => copy_to(&options)?,
+        AdminCommand::Replay(options) => replay(&options)?,
+    }
+
+    anyhow::Ok(())
+}
+
+fn scrub(pattern: &str) -> anyhow::Result<()> {
+    match current_engine()? {
+        Some(Engine::KvStore) => {
+            let mut store = KvStore::open(current_dir()?.join("kvstore"))?;
+            let count = store.scrub(pattern)?;
+            println!("Scrubbed {} key(s) matching {:?}", count, pattern);
+        }
+        Some(engine) => bail!("scrub is not supported by the {:?} engine", engine),
+        None => bail!("No data found in {:?}", current_dir()?),
+    }
+
+    anyhow::Ok(())
+}
+
+fn copy_to(options: &CopyTo) -> anyhow::Result<()> {
+    let mut store = match current_engine()? {
+        Some(Engine::KvStore) => KvStore::open(current_dir()?.join("kvstore"))?,
+        Some(engine) => bail!("copy-to is not supported by the {:?} engine", engine),
+        None => bail!("No data found in {:?}", current_dir()?),
+    };
+
+    let prefix = options.prefix.as_deref().unwrap_or("");
+    // The protocol only carries strings, keys and values that aren't UTF-8 are left out
+    let mut skipped = 0;
+    let mut keys = vec![];
+    for key in store
+        .keys()
+        .filter(|key| key.starts_with(prefix.as_bytes()))
+    {
+        match std::str::from_utf8(key) {
+            Ok(key)
+                if options
+                    .resume_after
+                    .as_deref()
+                    .is_none_or(|after| key > after) =>
+            {
+                keys.push(key.to_owned())
+            }
+            Ok(_) => {}
+            Err(_) => skipped += 1,
+        }
+    }
+    keys.sort_unstable();
+
+    let mut client = KvsClient::connect(&options.dest)?;
+    if options.dry_run {
+        let mut bytes = 0;
+        for key in &keys {
+            bytes += store
+                .get_bytes(key.clone().into_bytes())?
+                .map_or(0, |value| value.len());
+        }
+        match (keys.first(), keys.last()) {
+            (Some(first), Some(last)) => println!(
+                "Would copy {} key(s) ({} byte(s) of values) to {}, from {:?} to {:?}",
+                keys.len(),
+                bytes,
+                options.dest,
+                first,
+                last
+            ),
+            _ => println!("No keys to copy"),
+        }
+        report_skipped(skipped);
+        return anyhow::Ok(());
+    }
+
+    let batch_size = options.batch_size as usize;
+    let start = Instant::now();
+    let mut copied = 0;
+    // Every round sends up to `pipeline` MSETs, then waits for all their replies.
+    // With a rate limit, a round holds at most a second worth of keys.
+    let round_size = batch_size * options.pipeline as usize;
+    let round_size = options
+        .rate
+        .map_or(round_size, |rate| round_size.min(rate as usize));
+    for round in keys.chunks(round_size) {
+        let mut requests = vec![];
+        let mut copies = vec![];
+        for batch in round.chunks(batch_size) {
+            let mut pairs = vec![];
+            for key in batch {
+                match store.get(key.clone()) {
+                    Ok(Some(value)) => pairs.push((key.clone(), value)),
+                    Ok(None) => {}
+                    Err(Error::NotUtf8) => skipped += 1,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if !pairs.is_empty() {
+                copies.extend(pairs.iter().cloned());
+                requests.push(Request::Mset(Mset::new(pairs)));
+            }
+        }
+
+        let mut result = send_round(&mut client, requests).map(|_| ());
+        if options.verify && result.is_ok() {
+            result = verify_round(&mut client, copies);
+        }
+        if let Err(e) = result {
+            match keys[..copied].last() {
+                Some(key) => eprintln!("Copy interrupted, resume with --resume-after {:?}", key),
+                None => eprintln!("Copy interrupted, no key was copied"),
+            }
+            return Err(e);
+        }
+        copied += round.len();
+
+        if let Some(rate) = options.rate {
+            let target = Duration::from_secs_f64(copied as f64 / rate as f64);
+            if let Some(wait) = target.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+    }
+
+    match keys.last() {
+        Some(key) => println!(
+            "Copied {} key(s) to {}, last key {:?}",
+            copied, options.dest, key
+        ),
+        None => println!("No keys to copy"),
+    }
+    report_skipped(skipped);
+    anyhow::Ok(())
+}
+
+// Tell about the keys left out of a copy.
+fn report_skipped(skipped: usize) {
+    if skipped > 0 {
+        eprintln!("Skipped {} key(s) whose key or value isn't UTF-8", skipped);
+    }
+}
+
+// Pipeline the requests of a round, failing on the first error reply.
+fn send_round(client: &mut KvsClient, requests: Vec<Request>) -> anyhow::Result<Vec<Response>> {
+    let responses = client.pipeline(requests)?;
+    if let Some(Response::Error { code, msg }) = responses
+        .iter()
+        .find(|response| matches!(response, Response::Error { .. }))
+    {
+        bail!("{} {}", code, msg);
+    }
+    anyhow::Ok(responses)
+}
+
+// Read the keys of a round back from the destination and compare them with the copies.
+fn verify_round(client: &mut KvsClient, copies: Vec<(String, String)>) -> anyhow::Result<()> {
+    let requests = copies
+        .iter()
+        .map(|(key, _)| Request::Get(Get { key: key.clone() }))
+        .collect();
+    let responses = send_round(client, requests)?;
+    for ((key, value), response) in copies.into_iter().zip(responses) {
+        match response {
+            Response::Bulk(copied) if copied == value.as_bytes() => {}
+            _ => bail!("Verification failed, {:?} differs on the destination", key),
+        }
+    }
+    anyhow::Ok(())
+}
+
+fn replay(options: &Replay) -> anyhow::Result<()> {
+    let capture = CaptureReader::open(&options.file)?;
+    let mut connections = HashMap::new();
+    let mut first = None;
+    let start = Instant::now();
+    let (mut sent, mut skipped, mut errors) = (0, 0, 0);
+
+    for request in capture {
+        let request = request?;
+        let frame = request.frame()?;
+        if skip_on_replay(&frame) {
+            skipped += 1;
+            continue;
+        }
+
+        // Wait until the request is as far from the first one as it was when recorded
+        let first = *first.get_or_insert(request.at);
+        let offset = request.at.duration_since(first).unwrap_or_default();
+        let target = offset.div_f64(options.speed);
+        if let Some(wait) = target.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+
+        let codec = match connections.entry(request.connection) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(Codec::from_stream(TcpStream::connect(&options.dest)?)?)
+            }
+        };
+        codec.write_frame(&frame)?;
+        match codec.read_response()? {
+            Some(Response::Error { .. }) => errors += 1,
+            Some(_) => {}
+            None => bail!("{} closed the connection", options.dest),
+        }
+        sent += 1;
+    }
+
+    println!(
+        "Replayed {} request(s) on {} connection(s) in {:?}, {} error reply(ies), {} skipped",
+        sent,
+        connections.len(),
+        start.elapsed(),
+        errors,
+        skipped
+    );
+    anyhow::Ok(())
+}
+
+/// Whether the replies to a request couldn't be read one for one in RESP2.
+fn skip_on_replay(frame: &Frame) -> bool {
+    let name = match frame {
+        Frame::Array(args) => match args.first() {
+            Some(Frame::BulkString(name)) => String::from_utf8_lossy(name).to_lowercase(),
+            _ => return false,
+        },
+        _ => return false,
+    };
+    matches!(
+        name.as_str(),
+        "hello" | "subscribe" | "psubscribe" | "unsubscribe" | "punsubscribe"
+    )
+}
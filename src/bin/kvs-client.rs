@@ -1,42 +1,300 @@
-use anyhow;
-use bytes::BytesMut;
-use clap::Parser;
-use common::*;
-use redis_protocol::resp2::prelude::*;
-use std::io::Write;
-use std::net::TcpStream;
-
-mod common;
-
-#[derive(Parser, Debug)]
-#[command(name = "kvs-client", author, version, about, long_about = None)]
-struct Options {
-    #[command(subcommand)]
-    command: Request,
-    #[arg(short, long, default_value = "127.0.0.1:7878", help = "IP:PORT")]
-    addr: String,
-}
-
-fn main() -> anyhow::Result<()> {
-    // TODO: transfer single request per connect(parse args) -> many requests per connect(parse input to command)
-    // TODO: Imitate deet
-    let options = Options::parse();
-    println!("{:?}", options);
-
-    if let Ok(mut stream) = TcpStream::connect(&options.addr) {
-        let frame = Frame::from(options.command);
-
-        let mut buf = BytesMut::new();
-        let len = match encode_bytes(&mut buf, &frame) {
-            Ok(l) => l,
-            Err(e) => panic!("Error encoding frame: {:?}", e),
-        };
-        println!("Encoded {} bytes into buffer with contents {:?}", len, buf);
-
-        stream.write(&buf).unwrap();
-    } else {
-        println!("Couldn't connect to server...");
-    }
-
-    anyhow::Ok(())
-}
+use clap::Parser;
+use kvs::*;
+use std::path::PathBuf;
+
+/// The options before the command can also be set with the environment variable named after
+/// them, e.g. KVS_ADDR for --addr, a flag given on the command line wins.
+#[derive(Parser, Debug)]
+#[command(name = "kvs-client", author, version, about, long_about = None)]
+struct Options {
+    #[command(subcommand)]
+    command: Request,
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value = "127.0.0.1:7878",
+        help = "IP:PORT",
+        env = "KVS_ADDR"
+    )]
+    addr: String,
+    /// User to authenticate as, the default user if only --password is given
+    #[arg(long, global = true, requires = "password", env = "KVS_USER")]
+    user: Option<String>,
+    /// Password to authenticate with, if the server requires it
+    #[arg(long, global = true, env = "KVS_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+    /// Database to run the command on
+    #[arg(long, global = true, default_value_t = 0, env = "KVS_DB")]
+    db: usize,
+    /// Priority of the command when it waits for the engine
+    #[arg(long, global = true, value_enum, env = "KVS_PRIORITY")]
+    priority: Option<Priority>,
+    /// Journal set and rm in DIR while the server is unreachable, and send them once it is back
+    #[arg(long, global = true, value_name = "DIR", env = "KVS_JOURNAL")]
+    journal: Option<PathBuf>,
+    /// Keys, values and members are typed and shown in hex, e.g. "006b6579" for "\0key"
+    #[arg(long, global = true, conflicts_with = "base64")]
+    hex: bool,
+    /// Keys, values and members are typed and shown in base64
+    #[arg(long, global = true)]
+    base64: bool,
+}
+
+impl Options {
+    fn encoding(&self) -> Option<Encoding> {
+        if self.hex {
+            Some(Encoding::Hex)
+        } else if self.base64 {
+            Some(Encoding::Base64)
+        } else {
+            None
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    // TODO: transfer single request per connect(parse args) -> many requests per connect(parse input to command)
+    // TODO: Imitate deet
+    let mut options = Options::parse();
+    let encoding = options.encoding();
+    if let Some(encoding) = encoding {
+        decode_request(&mut options.command, encoding)?;
+    }
+    let show = |s: String| match encoding {
+        Some(encoding) => encoding.encode(&s),
+        None => s,
+    };
+
+    if let Request::Flushdb(Flushdb { yes: false }) = options.command {
+        anyhow::bail!("FLUSHDB removes every key on the server, pass --yes to confirm");
+    }
+
+    let mut client = match &options.journal {
+        Some(dir) => KvsClient::connect_journaled(&options.addr, RetryPolicy::default(), dir)?,
+        None => KvsClient::connect(&options.addr)?,
+    };
+    if let Some(password) = options.password.take() {
+        client.auth(options.user.take(), password)?;
+    }
+    if options.db != 0 {
+        client.select(options.db)?;
+    }
+    if let Some(priority) = options.priority {
+        client.set_priority(priority)?;
+    }
+    match options.command {
+        // Messages are printed until kvs-client is interrupted
+        Request::Subscribe(Subscribe { channels }) => print_messages(client.subscribe(channels)?)?,
+        Request::Psubscribe(Psubscribe { patterns }) => {
+            print_messages(client.psubscribe(patterns)?)?
+        }
+        Request::Unsubscribe(_) | Request::Punsubscribe(_) => {
+            anyhow::bail!("Subscriptions end with the run of kvs-client that made them")
+        }
+        Request::Auth(Auth::Login { .. }) => {
+            anyhow::bail!("Authentication lasts for a single run of kvs-client, pass --password")
+        }
+        Request::Auth(Auth::Rotate) => println!("{}", client.rotate_token()?),
+        Request::Auth(Auth::Revoke { username }) => {
+            println!("{}", client.revoke_tokens(username)?)
+        }
+        Request::Select(_) => {
+            anyhow::bail!("The database is selected for a single run of kvs-client, pass --db")
+        }
+        Request::Priority(_) => {
+            anyhow::bail!("The priority is set for a single run of kvs-client, pass --priority")
+        }
+        Request::Publish(Publish { channel, message }) => {
+            println!("{}", client.publish(channel, message)?)
+        }
+        Request::Set(Set { key, value }) => client.set(key, value)?,
+        Request::Get(Get { key }) => match client.get(key)? {
+            Some(value) => println!("{}", show(value)),
+            None => println!("Key not found"),
+        },
+        Request::Setnx(Setnx { key, value }) => {
+            println!("{}", client.set_nx(key, value)? as i64)
+        }
+        Request::Getset(Getset { key, value }) => match client.get_set(key, value)? {
+            Some(value) => println!("{}", show(value)),
+            None => println!("Key not found"),
+        },
+        Request::Rm(Remove { mut keys }) => {
+            if keys.len() == 1 {
+                client.remove(keys.remove(0))?
+            } else {
+                println!("{}", client.remove_keys(keys)?)
+            }
+        }
+        Request::Exists(Exists { keys }) => println!("{}", client.count_existing(keys)?),
+        Request::Keys(Keys { pattern }) => {
+            for key in client.keys(pattern)? {
+                println!("{}", show(key));
+            }
+        }
+        Request::Scan(scan) => {
+            let (cursor, keys) = client.scan(scan)?;
+            println!("{}", cursor);
+            for key in keys {
+                println!("{}", show(key));
+            }
+        }
+        Request::Mset(Mset { pairs }) => {
+            if pairs.len() % 2 != 0 {
+                anyhow::bail!("Missing value of key {:?}", pairs[pairs.len() - 1]);
+            }
+            let mut pairs = pairs.into_iter();
+            let pairs = std::iter::from_fn(|| Some((pairs.next()?, pairs.next()?))).collect();
+            client.mset(pairs)?
+        }
+        Request::Append(Append { key, value }) => println!("{}", client.append(key, value)?),
+        Request::Expire(Expire { key, seconds }) => {
+            if !client.expire(key, seconds)? {
+                println!("Key not found");
+            }
+        }
+        Request::Ttl(Ttl { key }) => println!("{}", client.ttl(key)?),
+        Request::Persist(Persist { key }) => println!("{}", client.persist(key)? as i64),
+        Request::Rename(Rename { key, newkey }) => client.rename(key, newkey)?,
+        Request::Renamenx(Renamenx { key, newkey }) => {
+            println!("{}", client.rename_nx(key, newkey)? as i64)
+        }
+        Request::Sadd(Sadd { key, members }) => println!("{}", client.sadd(key, members)?),
+        Request::Srem(Srem { key, members }) => println!("{}", client.srem(key, members)?),
+        Request::Smembers(Smembers { key }) => {
+            for member in client.smembers(key)? {
+                println!("{}", show(member));
+            }
+        }
+        Request::Sismember(Sismember { key, member }) => {
+            println!("{}", client.sismember(key, member)? as i64)
+        }
+        Request::Scard(Scard { key }) => println!("{}", client.scard(key)?),
+        Request::Sunion(Sunion { keys }) => {
+            for member in client.sunion(keys)? {
+                println!("{}", show(member));
+            }
+        }
+        Request::Sinter(Sinter { keys }) => {
+            for member in client.sinter(keys)? {
+                println!("{}", show(member));
+            }
+        }
+        Request::Migrate(migrate) => match client.migrate_report(migrate)? {
+            None => println!("Key not found"),
+            Some(report) if !report.is_empty() => println!("{}", report),
+            Some(_) => {}
+        },
+        Request::Dump(Dump { key }) => match client.dump(key)? {
+            Some(blob) => println!("{}", blob),
+            None => println!("Key not found"),
+        },
+        Request::Restore(restore) => client.restore(restore)?,
+        Request::Dbsize => println!("{}", client.db_size()?),
+        Request::Flushdb(_) => client.flush_db()?,
+        Request::Info(Info { section }) => println!("{}", client.info(section)?),
+        Request::Config(Config::Get { pattern }) => {
+            for (name, value) in client.config_get(pattern)? {
+                println!("{} {}", name, value);
+            }
+        }
+        Request::Config(Config::Set { name, value }) => client.config_set(name, value)?,
+        Request::Tasks => print!("{}", client.tasks()?),
+        Request::Currentops => print!("{}", client.current_ops()?),
+        Request::Compact => client.compact()?,
+        Request::Shutdown => client.shutdown()?,
+        Request::Upgrade => client.upgrade()?,
+        // Each run of kvs-client is a connection of its own
+        Request::Multi
+        | Request::Exec
+        | Request::Discard
+        | Request::Watch(_)
+        | Request::Unwatch => {
+            anyhow::bail!("Transactions can't span several runs of kvs-client")
+        }
+        Request::Lockkey(_) | Request::Unlockkey(_) => {
+            anyhow::bail!("Key locks are released when kvs-client exits")
+        }
+        Request::Command(CommandArgs { query }) => match query {
+            None => {
+                for doc in client.commands()? {
+                    print_command(&doc);
+                }
+            }
+            Some(CommandQuery::Count) => println!("{}", client.command_count()?),
+            Some(CommandQuery::Info { names }) => {
+                for doc in client.command_info(names)? {
+                    match doc {
+                        Some(doc) => print_command(&doc),
+                        None => println!("Unknown command"),
+                    }
+                }
+            }
+        },
+        Request::Client(request) => match request {
+            Client::Setname { name } => client.set_name(name)?,
+            Client::Getname => match client.client_name()? {
+                Some(name) => println!("{}", name),
+                None => println!("No name"),
+            },
+            Client::Setinfo { attr, value } => client.set_client_info(attr, value)?,
+            Client::List => print!("{}", client.client_list()?),
+        },
+        Request::Hello(Hello { protover, setname }) => {
+            if protover.is_some_and(|protover| protover != 2) {
+                anyhow::bail!("kvs-client only speaks RESP2");
+            }
+            if let Some(name) = setname {
+                client.set_name(name)?;
+            }
+            for (name, value) in client.hello()? {
+                println!("{} {}", name, value);
+            }
+        }
+    }
+
+    anyhow::Ok(())
+}
+
+/// Decode the keys, values and members of `request` typed in `encoding`.
+fn decode_request(request: &mut Request, encoding: Encoding) -> kvs::Result<()> {
+    for key in request.keys_mut() {
+        *key = encoding.decode(key)?;
+    }
+    let values: Vec<&mut String> = match request {
+        Request::Set(Set { value, .. })
+        | Request::Setnx(Setnx { value, .. })
+        | Request::Getset(Getset { value, .. })
+        | Request::Append(Append { value, .. })
+        | Request::Sismember(Sismember { member: value, .. }) => vec![value],
+        Request::Mset(Mset { pairs }) => pairs.iter_mut().skip(1).step_by(2).collect(),
+        Request::Sadd(Sadd { members, .. }) | Request::Srem(Srem { members, .. }) => {
+            members.iter_mut().collect()
+        }
+        _ => vec![],
+    };
+    for value in values {
+        *value = encoding.decode(value)?;
+    }
+    Ok(())
+}
+
+fn print_messages(mut subscriber: Subscriber) -> anyhow::Result<()> {
+    loop {
+        let message = subscriber.next_message()?;
+        println!("{} {}", message.channel, message.payload);
+    }
+}
+
+fn print_command(doc: &CommandDoc) {
+    println!(
+        "{} arity={} flags={} keys={},{},{}",
+        doc.name,
+        doc.arity,
+        doc.flags.join(","),
+        doc.first_key,
+        doc.last_key,
+        doc.step
+    );
+}
@@ -1,27 +1,218 @@
-use anyhow;
-use bytes::{Bytes, BytesMut};
 use chrono::Local;
-use clap::{Parser, ValueEnum};
+use clap::Parser;
 use common::*;
 use env_logger::Env;
 use kvs::*;
-use log::{debug, error, info, warn};
-use redis_protocol::resp2::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::env::current_dir;
+use log::{debug, error, info};
+use std::convert::Infallible;
+use std::env::{self, current_dir};
+use std::fmt;
 use std::fs;
-use std::io::{BufReader, Read, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
 mod common;
 
+// Time on top of --drain-timeout an upgraded server waits for the old one to save its data
+const UPGRADE_SAVE_TIME: Duration = Duration::from_secs(30);
+// Requests a crash report lists, the last ones the server started serving
+const CRASH_REPORT_REQUESTS: usize = 100;
+
+/// Every option can also be set with the environment variable named after it, e.g. KVS_ADDR
+/// for --addr, a flag given on the command line wins.
 #[derive(Parser, Debug)]
 #[command(name = "kvs-server", author, version, about, long_about = None)]
 struct Options {
-    #[arg(short, long, default_value = "127.0.0.1:7878", help = "IP:PORT")]
+    #[arg(
+        short,
+        long,
+        default_value = "127.0.0.1:7878",
+        help = "IP:PORT",
+        env = "KVS_ADDR"
+    )]
     addr: String,
-    #[arg(short, long, help = "ENGINE-TYPE")]
+    #[arg(short, long, help = "ENGINE-TYPE", env = "KVS_ENGINE")]
     engine: Option<Engine>,
+    /// Accept command NAME as NEW_NAME only, or disable it if NEW_NAME is empty (repeatable)
+    #[arg(long, value_name = "NAME=NEW_NAME", value_parser = parse_rename, env = "KVS_RENAME_COMMAND")]
+    rename_command: Vec<(String, String)>,
+    /// Maximum size of a request in bytes
+    #[arg(long, default_value_t = FrameLimits::default().max_frame_size, env = "KVS_MAX_REQUEST_SIZE")]
+    max_request_size: usize,
+    /// Maximum number of arguments of a request, including the command name
+    #[arg(long, default_value_t = FrameLimits::default().max_array_len, env = "KVS_MAX_REQUEST_ARGS")]
+    max_request_args: usize,
+    /// Maximum length of a request argument in bytes
+    #[arg(long, default_value_t = FrameLimits::default().max_bulk_len, env = "KVS_MAX_ARG_LEN")]
+    max_arg_len: usize,
+    /// Evict a client when a response blocks on writing for longer than this, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = SlowClientPolicy::default().write_timeout.as_millis() as u64, env = "KVS_CLIENT_WRITE_TIMEOUT")]
+    client_write_timeout: u64,
+    /// Evict a client blocking writes for longer than this per minute, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = SlowClientPolicy::default().max_blocked_time.as_millis() as u64, env = "KVS_CLIENT_MAX_BLOCKED")]
+    client_max_blocked: u64,
+    /// Lengthen the timeouts set by EXPIRE by a random amount of up to PERCENT percent
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 0,
+        env = "KVS_TTL_JITTER"
+    )]
+    ttl_jitter: u32,
+    /// Largest value accepted by writes in bytes, 0 for no limit
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 0,
+        env = "KVS_MAX_VALUE_SIZE"
+    )]
+    max_value_size: usize,
+    /// Longest key accepted by writes in bytes, 0 for no limit
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 0,
+        env = "KVS_MAX_KEY_LEN"
+    )]
+    max_key_len: usize,
+    /// Bytes keys written may contain, as decimal values and ranges, e.g. "48-57,97-122,58"
+    #[arg(long, value_name = "RANGES", env = "KVS_KEY_ALLOWED_BYTES")]
+    key_allowed_bytes: Option<ByteRanges>,
+    /// Fold keys to lower case, so keys differing only in case are the same key
+    #[arg(long, env = "KVS_CASE_INSENSITIVE_KEYS")]
+    case_insensitive_keys: bool,
+    /// Batch writes arriving within this many microseconds and sync each batch to disk, e.g. 200
+    #[arg(long, value_name = "MICROS", env = "KVS_WRITE_BATCH_WINDOW")]
+    write_batch_window: Option<u64>,
+    /// Publish changes to keys on their "__keyspace__:KEY" channel
+    #[arg(long, env = "KVS_NOTIFY_KEYSPACE_EVENTS")]
+    notify_keyspace_events: bool,
+    /// Purge expired keys every this many milliseconds, 0 to leave them until compaction
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 1000,
+        env = "KVS_EXPIRE_SWEEP_INTERVAL"
+    )]
+    expire_sweep_interval: u64,
+    /// Check the data files for corruption in the background at this many bytes a second,
+    /// 0 not to
+    #[arg(long, value_name = "BYTES", default_value_t = 1 << 20, env = "KVS_VERIFY_RATE")]
+    verify_rate: u64,
+    /// Save what makes the next start faster, e.g. the kvs index, every this many
+    /// milliseconds, 0 to only save it on shutdown
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 60_000,
+        env = "KVS_CHECKPOINT_INTERVAL"
+    )]
+    checkpoint_interval: u64,
+    /// On SHUTDOWN, wait up to this many milliseconds for the requests being served to be
+    /// answered and their connections closed
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 5000,
+        env = "KVS_DRAIN_TIMEOUT"
+    )]
+    drain_timeout: u64,
+    /// Number of databases clients SELECT from, database N > 0 is kept next to the
+    /// engine directory in "<engine>-dbN"
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..), env = "KVS_DATABASES")]
+    databases: u64,
+    /// Record every request received to FILE, for kvs-admin replay
+    #[arg(long, value_name = "FILE", env = "KVS_CAPTURE")]
+    capture: Option<PathBuf>,
+    /// Spread the data files of the kvs engine across DIR too, e.g. on another disk (repeatable)
+    #[arg(long, value_name = "DIR", env = "KVS_DATA_DIR")]
+    data_dir: Vec<PathBuf>,
+    /// When the kvs engine fsyncs writes: "always", "never", or every this many milliseconds
+    #[arg(long, value_name = "POLICY", default_value = "never", env = "KVS_SYNC")]
+    sync: SyncPolicy,
+    /// How the kvs engine compresses records: "none" or "lz4"
+    #[arg(
+        long,
+        value_name = "ALGORITHM",
+        default_value = "none",
+        env = "KVS_COMPRESSION"
+    )]
+    compression: Compression,
+    /// Records shorter than this many bytes aren't compressed
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 64,
+        env = "KVS_COMPRESSION_MIN_SIZE"
+    )]
+    compression_min_size: usize,
+    /// Require clients to authenticate with PASSWORD, as the default user
+    #[arg(
+        long,
+        value_name = "PASSWORD",
+        env = "KVS_REQUIREPASS",
+        hide_env_values = true,
+        conflicts_with_all = ["users_file", "auth_command"]
+    )]
+    requirepass: Option<Password>,
+    /// Require clients to authenticate as one of the users of FILE, a "USERNAME PASSWORD"
+    /// line each
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "KVS_USERS_FILE",
+        conflicts_with = "auth_command"
+    )]
+    users_file: Option<PathBuf>,
+    /// Require clients to authenticate, PROGRAM checks their credentials: it gets the
+    /// username as argument and the password on stdin, and exits with 0 to accept them
+    #[arg(long, value_name = "PROGRAM", env = "KVS_AUTH_COMMAND")]
+    auth_command: Option<PathBuf>,
+    /// Tokens of AUTH ROTATE are valid for this many milliseconds
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = ServerConfig::default().token_ttl.as_millis() as u64,
+        value_parser = clap::value_parser!(u64).range(1..),
+        env = "KVS_TOKEN_TTL"
+    )]
+    token_ttl: u64,
+    /// Take over the listening socket of the server being upgraded, passed on standard
+    /// input, rather than binding IP:PORT. The server runs itself so on UPGRADE.
+    #[arg(long)]
+    upgrade: bool,
+    /// Write a report to DIR when the server crashes, the current directory by default
+    #[arg(long, value_name = "DIR", env = "KVS_CRASH_DIR")]
+    crash_dir: Option<PathBuf>,
+}
+
+/// A password, hidden from the options logged on start.
+#[derive(Clone)]
+struct Password(String);
+
+impl FromStr for Password {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Infallible> {
+        Ok(Password(s.to_owned()))
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<hidden>")
+    }
+}
+
+fn parse_rename(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, new_name)) if !name.is_empty() => Ok((name.to_owned(), new_name.to_owned())),
+        _ => Err(format!("expected NAME=NEW_NAME, got {:?}", s)),
+    }
 }
 
 impl Options {
@@ -46,7 +237,7 @@ impl Options {
     //    sled |  sled |  Err  |  sled  |
     // ==================================
     fn set_engine(&mut self) -> anyhow::Result<()> {
-        let cur_engine = Self::current_engine()?;
+        let cur_engine = current_engine()?;
         if cur_engine.is_none() {
             if self.engine.is_none() {
                 self.engine = Some(Engine::KvStore)
@@ -54,7 +245,7 @@ impl Options {
             // write engine type to engine file，e.g. kvs
             fs::write(
                 current_dir()?.join("engine"),
-                format!("{}", serde_json::to_string(&self.engine)?),
+                serde_json::to_string(&self.engine)?,
             )?;
         } else {
             if self.engine.is_none() {
@@ -70,33 +261,6 @@ impl Options {
         }
         anyhow::Ok(())
     }
-
-    /// Get current engine from engine file
-    ///
-    /// If there is no engine exists, return Ok(None).
-    fn current_engine() -> anyhow::Result<Option<Engine>> {
-        let engine_path = current_dir()?.join("engine");
-        if !engine_path.exists() {
-            anyhow::Ok(None)
-        } else {
-            let str_from_engine_file = fs::read_to_string(engine_path)?;
-            // if let Ok(engine) = Engine::from_str(&str_from_engine_file, true) {
-            if let Some(engine) = serde_json::from_str(&str_from_engine_file)? {
-                debug!("current engine type: {:?}", engine);
-                anyhow::Ok(Some(engine))
-            } else {
-                error!("Unexpected engine type: {:?}", str_from_engine_file);
-                anyhow::Ok(None)
-            }
-        }
-    }
-}
-
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
-enum Engine {
-    KvStore,
-    Redb,
-    Sled,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -121,25 +285,53 @@ fn main() -> anyhow::Result<()> {
     options.set_engine()?;
     debug!("After setting engine, {:?}", options);
 
-    run(options)?;
+    let recent = RecentRequests::new(CRASH_REPORT_REQUESTS);
+    let crash_dir = match &options.crash_dir {
+        Some(dir) => dir.clone(),
+        None => current_dir()?,
+    };
+    install_crash_handler(
+        "kvs-server",
+        crash_dir,
+        format!("{:?}", options),
+        Some(recent.clone()),
+    );
+
+    run(options, recent)?;
 
     anyhow::Ok(())
 }
 
-fn run(options: Options) -> anyhow::Result<()> {
+fn run(options: Options, recent: RecentRequests) -> anyhow::Result<()> {
     if let Some(engine) = options.engine {
         match engine {
             Engine::KvStore => {
                 let path = current_dir()?.join("kvstore");
-                let mut server = KvsServer::new(KvStore::open(path)?);
                 debug!("kvsServer - kvStore");
-                server.start_server(&options.addr)?;
+                // Each database has a directory of its own in every data directory
+                let open = |path: &Path| {
+                    let name = path.file_name().unwrap_or_default();
+                    let options = KvStoreOptions {
+                        data_dirs: options.data_dir.iter().map(|dir| dir.join(name)).collect(),
+                        sync: options.sync,
+                        compression: options.compression,
+                        compression_min_size: options.compression_min_size,
+                        ..KvStoreOptions::default()
+                    };
+                    KvStore::open_with(path, options)
+                };
+                serve(&path, &options, recent, open)?;
             }
             Engine::Redb => {
+                if !options.data_dir.is_empty() {
+                    anyhow::bail!("--data-dir is only supported by the kvs engine");
+                }
+                if options.compression != Compression::None {
+                    anyhow::bail!("--compression is only supported by the kvs engine");
+                }
                 let path = current_dir()?.join("redb");
-                let mut server = KvsServer::new(Redb::open(path)?);
                 debug!("kvsServer - redb");
-                server.start_server(&options.addr)?;
+                serve(&path, &options, recent, |path: &Path| Redb::open(path))?;
             }
             Engine::Sled => todo!(),
         }
@@ -148,74 +340,121 @@ fn run(options: Options) -> anyhow::Result<()> {
     anyhow::Ok(())
 }
 
-// Trait Object or Generic Type
-// A generic type parameter can work with one concrete type at a time,
-// whereas trait objects allow for multiple concrete types to fill in for the trait object at run-time.
-// We don't need multiple concrete types
-pub struct KvsServer<E: KvsEngine> {
-    engine: E,
-}
+fn serve<E: KvsEngine + Send + 'static>(
+    path: &Path,
+    options: &Options,
+    recent: RecentRequests,
+    open: impl Fn(&Path) -> Result<E>,
+) -> anyhow::Result<()> {
+    // The server being upgraded releases the databases once it has shut down
+    let listener = options.upgrade.then(inherited_listener).transpose()?;
+    let deadline = options
+        .upgrade
+        .then(|| Instant::now() + Duration::from_millis(options.drain_timeout) + UPGRADE_SAVE_TIME);
+    let open = |path: &Path| loop {
+        match open(path) {
+            Err(Error::AlreadyLocked { .. } | Error::Redb(redb::Error::DatabaseAlreadyOpen))
+                if deadline.is_some_and(|deadline| Instant::now() < deadline) =>
+            {
+                thread::sleep(Duration::from_millis(50))
+            }
+            result => return result,
+        }
+    };
 
-impl<E: KvsEngine> KvsServer<E> {
-    pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+    let mut server = KvsServer::new(open(path)?);
+    for db in 1..options.databases {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!("-db{}", db));
+        server.add_database(open(&PathBuf::from(name))?);
+    }
+    server.set_frame_limits(FrameLimits {
+        max_frame_size: options.max_request_size,
+        max_array_len: options.max_request_args,
+        max_bulk_len: options.max_arg_len,
+    });
+    server.set_slow_client_policy(SlowClientPolicy {
+        write_timeout: Duration::from_millis(options.client_write_timeout),
+        max_blocked_time: Duration::from_millis(options.client_max_blocked),
+        window: Duration::from_secs(60),
+    });
+    server.set_ttl_jitter(options.ttl_jitter);
+    server.set_max_value_size(Some(options.max_value_size).filter(|&size| size > 0));
+    server.set_key_rules(KeyRules {
+        max_len: Some(options.max_key_len).filter(|&len| len > 0),
+        allowed_bytes: options.key_allowed_bytes.clone(),
+        case_insensitive: options.case_insensitive_keys,
+    });
+    server.set_write_batch_window(options.write_batch_window.map(Duration::from_micros));
+    for (name, new_name) in &options.rename_command {
+        server.rename_command(name, new_name);
+    }
+    server.set_notify_keyspace_events(options.notify_keyspace_events);
+    if options.expire_sweep_interval > 0 {
+        server.add_expire_sweep(Duration::from_millis(options.expire_sweep_interval));
+    }
+    if options.verify_rate > 0 {
+        server.add_verify_job(options.verify_rate);
+    }
+    if options.checkpoint_interval > 0 {
+        server.add_checkpoint_job(Duration::from_millis(options.checkpoint_interval));
+    }
+    server.set_drain_timeout(Duration::from_millis(options.drain_timeout));
+    if let Some(capture) = &options.capture {
+        server.capture_to(capture)?;
+    }
+    if let Some(Password(password)) = &options.requirepass {
+        server.set_auth_provider(StaticPassword::new(password.as_str()));
+    }
+    if let Some(path) = &options.users_file {
+        server.set_auth_provider(UserFile::load(path)?);
+    }
+    if let Some(program) = &options.auth_command {
+        server.set_auth_provider(ExternalVerifier::new(program));
+    }
+    server.set_token_ttl(Duration::from_millis(options.token_ttl));
+    server.keep_recent_requests(recent);
+    #[cfg(unix)]
+    server.set_upgrade_handler(start_upgraded);
+    match listener {
+        Some(listener) => server.start_server_on(listener)?,
+        None => server.start_server(&options.addr)?,
     }
 
-    pub fn start_server<A: ToSocketAddrs>(&mut self, addr: &A) -> anyhow::Result<()> {
-        debug!("start server");
-        // set up the networking, the server is synchronous and single-threaded.
-        let listener = TcpListener::bind(addr).unwrap();
-
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
-
-            self.handle_connection(stream)?;
-        }
+    anyhow::Ok(())
+}
 
-        debug!("end server");
-        anyhow::Ok(())
-    }
+/// Run this program again with the same arguments and `--upgrade`, handing it `listener`.
+#[cfg(unix)]
+fn start_upgraded(listener: &TcpListener) -> Result<()> {
+    use std::os::fd::OwnedFd;
 
-    // TODO: handle single request per connect -> many requests per connect
-    fn handle_connection(&mut self, stream: TcpStream) -> anyhow::Result<()> {
-        let mut reader = BufReader::new(stream);
-        let mut buf = [0; 1024];
-        reader.read(&mut buf)?;
-        debug!(
-            "{:?}",
-            std::str::from_utf8(&buf[..])?.trim_matches(char::from(0))
-        );
-
-        // parse the contents of buf
-        let (frame, frame_size) = match decode(&Bytes::copy_from_slice(&buf)) {
-            Ok(Some((f, c))) => (f, c),
-            Ok(None) => panic!("Incomplete frame."),
-            Err(e) => panic!("Error parsing bytes: {:?}", e),
-        };
-        debug!("Parsed frame {:?} and consumed {} bytes", frame, frame_size);
-
-        let request = Request::try_from(frame)?;
-        info!("Request: {:?}", request);
-
-        // cmd excutor
-        match request {
-            Request::Set(Set { key, value }) => {
-                debug!("set key:{:?} value:{:?}", key, value);
-                self.engine.set(key, value)?;
-            }
-            Request::Get(Get { key }) => {
-                debug!("get key:{:?}", key);
-                let a = self.engine.get(key)?;
-                info!("Get {:?}", a);
-            }
-            Request::Rm(Remove { key }) => {
-                debug!("remove key:{:?}", key);
-                self.engine.remove(key)?;
-            }
-        }
+    // The path it was run by, which leads to the new binary once it replaced this one
+    let mut args = env::args_os();
+    let program = args.next().expect("the program is the first argument");
+    let child = std::process::Command::new(program)
+        .args(args.filter(|arg| arg != "--upgrade"))
+        .arg("--upgrade")
+        .stdin(OwnedFd::from(listener.try_clone()?))
+        .spawn()?;
+    info!("Upgrading to server process {}", child.id());
+    Ok(())
+}
 
-        // TODO: transfer cmd execute result
+/// The listening socket handed over on standard input by `start_upgraded`.
+fn inherited_listener() -> io::Result<TcpListener> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
 
-        anyhow::Ok(())
+        let listener = TcpListener::from(io::stdin().as_fd().try_clone_to_owned()?);
+        // Fails unless standard input is a socket
+        info!("Taking over the connections to {}", listener.local_addr()?);
+        Ok(listener)
     }
+    #[cfg(not(unix))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--upgrade is only supported on Unix",
+    ))
 }
@@ -0,0 +1,27 @@
+use serde::{Deserialize, Deserializer, Serializer};
+
+// Keys and values are bytes, which JSON has no type for: they are written as a string if they
+// are UTF-8, as an array of bytes otherwise. JSON written when they were strings reads the same.
+
+/// Serialize bytes, for `#[serde(with = "crate::binary")]`.
+pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => serializer.serialize_str(s),
+        Err(_) => serializer.serialize_bytes(bytes),
+    }
+}
+
+/// Deserialize bytes written by `serialize`.
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Bytes {
+        Utf8(String),
+        Other(Vec<u8>),
+    }
+
+    Ok(match Bytes::deserialize(deserializer)? {
+        Bytes::Utf8(s) => s.into_bytes(),
+        Bytes::Other(bytes) => bytes,
+    })
+}
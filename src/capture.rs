@@ -0,0 +1,137 @@
+use crate::{Error, Result};
+use bytes::{Bytes, BytesMut};
+use log::warn;
+use redis_protocol::resp2::prelude::*;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+// Start of a capture file, followed by its records
+const MAGIC: &[u8; 8] = b"KVSCAP1\n";
+// Microseconds since the UNIX epoch, connection number and length of the request, little-endian
+const HEADER_LEN: usize = 8 + 8 + 4;
+
+/// A request received by a server, as recorded in a capture file, see `KvsServer::capture_to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedRequest {
+    /// When the server read it
+    pub at: SystemTime,
+    /// Number of the connection it was received on, connections are numbered from 0
+    pub connection: u64,
+    /// The request in RESP, before renamed commands are resolved
+    pub resp: Vec<u8>,
+}
+
+impl CapturedRequest {
+    /// Decode the request into a frame, e.g. to send it with `Codec::write_frame`.
+    pub fn frame(&self) -> Result<Frame> {
+        match decode(&Bytes::copy_from_slice(&self.resp))? {
+            Some((frame, _)) => Ok(frame),
+            None => Err(Error::InvalidCapture),
+        }
+    }
+}
+
+/// Where a server records the requests it receives.
+pub(crate) struct CaptureWriter {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl CaptureWriter {
+    /// Create a capture file at `path`, replacing any file there.
+    pub(crate) fn create(path: impl AsRef<Path>) -> Result<CaptureWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+        Ok(CaptureWriter {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Append `frame`, received on connection `connection` just now.
+    ///
+    /// Records are flushed one by one, so a capture can be read while the server runs.
+    pub(crate) fn record(&self, connection: u64, frame: &Frame) -> Result<()> {
+        let mut resp = BytesMut::new();
+        encode_bytes(&mut resp, frame)?;
+        let at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&at.to_le_bytes())?;
+        writer.write_all(&connection.to_le_bytes())?;
+        writer.write_all(&(resp.len() as u32).to_le_bytes())?;
+        writer.write_all(&resp)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The requests of a capture file, in the order they were received.
+///
+/// A record torn by the server stopping in the middle of writing it ends the capture.
+pub struct CaptureReader<R: Read> {
+    reader: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// # Errors
+    ///
+    /// It returns `Error::InvalidCapture` if the file isn't a capture.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        CaptureReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        match reader.read_exact(&mut magic) {
+            Ok(()) if &magic == MAGIC => Ok(CaptureReader { reader }),
+            Ok(()) => Err(Error::InvalidCapture),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(Error::InvalidCapture),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<CapturedRequest>> {
+        let mut header = [0; HEADER_LEN];
+        if !self.read_full(&mut header)? {
+            return Ok(None);
+        }
+        let at = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let connection = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let len = u32::from_le_bytes(header[16..20].try_into().unwrap());
+        let mut resp = vec![0; len as usize];
+        if !self.read_full(&mut resp)? {
+            warn!("Dropped a request torn at the end of the capture");
+            return Ok(None);
+        }
+        Ok(Some(CapturedRequest {
+            at: SystemTime::UNIX_EPOCH + Duration::from_micros(at),
+            connection,
+            resp,
+        }))
+    }
+
+    /// Fill `buf`, returns `false` at the end of the capture.
+    fn read_full(&mut self, buf: &mut [u8]) -> Result<bool> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedRequest>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
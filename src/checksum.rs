@@ -0,0 +1,35 @@
+/// CRC-32 (IEEE 802.3) of `bytes`.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    reflected_crc(0xEDB8_8320, bytes)
+}
+
+/// CRC-32C (Castagnoli) of `bytes`, it detects more errors than CRC-32 in short messages.
+pub(crate) fn crc32c(bytes: &[u8]) -> u32 {
+    reflected_crc(0x82F6_3B78, bytes)
+}
+
+/// CRC-32 of `bytes` with the reversed form of `polynomial`.
+fn reflected_crc(polynomial: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (polynomial & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+}
@@ -1 +1,1180 @@
-pub struct KvsClient {}
+use crate::engines::utf8;
+use crate::journal::{self, Journal};
+use crate::random::random_fraction;
+use crate::{
+    Append, Auth, BatchOp, Client, Codec, CommandArgs, CommandDoc, CommandQuery, Config, Dump,
+    DumpPayload, Error, Exists, Expire, Flushdb, Get, Getset, Hello, Info, Keys, Lockkey, Message,
+    Migrate, Mset, Persist, Priority, PriorityArgs, Psubscribe, Publish, Remove, Rename, Renamenx,
+    Request, Response, Restore, Result, Sadd, Scan, Scard, Select, Set, Setnx, Sinter, Sismember,
+    Smembers, Srem, Subscribe, Sunion, Ttl, Unlockkey, Watch, WriteBatch,
+};
+use bytes::Bytes;
+use log::warn;
+use redis_protocol::resp2::prelude::*;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How a `KvsClient` connects and retries after failures.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Timeout of a single connection attempt
+    pub connect_timeout: Duration,
+    /// How many times a failed attempt is retried before the error is returned
+    pub max_retries: u32,
+    /// Backoff before the first retry, it doubles on every further retry
+    pub base_backoff: Duration,
+    /// Upper bound of the backoff
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            connect_timeout: Duration::from_secs(1),
+            max_retries: 3,
+            base_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter before the `attempt`-th retry (counting from 0).
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        ceiling.mul_f64(random_fraction())
+    }
+}
+
+/// A client of `KvsServer`.
+///
+/// The connection is kept open across requests. When it breaks, the client reconnects on the
+/// next request; idempotent requests (GET) are also retried right away with backoff.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use kvs::KvsClient;
+///
+/// let mut client = KvsClient::connect("127.0.0.1:7878").unwrap();
+/// client.set("key".to_string(), "value".to_string()).unwrap();
+/// assert_eq!(client.get("key".to_string()).unwrap(), Some("value".to_string()));
+/// ```
+pub struct KvsClient {
+    addrs: Vec<SocketAddr>,
+    policy: RetryPolicy,
+    io_timeout: Option<Duration>,
+    /// Database selected with `select`, selected again on every new connection
+    db: usize,
+    /// Set with `set_priority`, set again on every new connection
+    priority: Priority,
+    /// Set with `set_name`, set again on every new connection
+    name: Option<String>,
+    /// Given to `auth`, authenticated with again on every new connection
+    credentials: Option<Auth>,
+    connection: Option<Connection>,
+    /// Writes made while the server was unreachable, see `connect_journaled`
+    journal: Option<Journal>,
+}
+
+type Connection = Codec<TcpStream, TcpStream>;
+
+impl KvsClient {
+    /// Connect to the server at `addr` with the default `RetryPolicy`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Self::connect_with(addr, RetryPolicy::default())
+    }
+
+    /// Connect to the server at `addr` with the given `RetryPolicy`.
+    ///
+    /// # Errors
+    ///
+    /// It returns the last I/O error if no connection could be established within
+    /// `policy.max_retries` retries.
+    pub fn connect_with<A: ToSocketAddrs>(addr: A, policy: RetryPolicy) -> Result<Self> {
+        let addrs = addr.to_socket_addrs()?.collect();
+        let mut client = KvsClient {
+            addrs,
+            policy,
+            io_timeout: None,
+            db: 0,
+            priority: Priority::Normal,
+            name: None,
+            credentials: None,
+            connection: None,
+            journal: None,
+        };
+        client.connection = Some(client.open_connection()?);
+        Ok(client)
+    }
+
+    /// Connect to the server at `addr`, journaling `set` and `remove` in `dir` while it
+    /// is unreachable.
+    ///
+    /// Unlike `connect_with` it succeeds without reaching the server. Journaled writes are
+    /// kept on disk, including across restarts of the client, and replayed in order before
+    /// the next write that reaches the server, or by `replay_journal`. They are replayed on
+    /// the database selected at the time, and only once thanks to a marker key the server
+    /// keeps during the replay. Reads aren't served from the journal.
+    ///
+    /// A write whose connection broke after sending it is journaled too, so it may be
+    /// applied twice.
+    pub fn connect_journaled<A: ToSocketAddrs>(
+        addr: A,
+        policy: RetryPolicy,
+        dir: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let addrs = addr.to_socket_addrs()?.collect();
+        let mut client = KvsClient {
+            addrs,
+            policy,
+            io_timeout: None,
+            db: 0,
+            priority: Priority::Normal,
+            name: None,
+            credentials: None,
+            connection: None,
+            journal: Some(Journal::open(dir)?),
+        };
+        match client.open_connection() {
+            Ok(connection) => client.connection = Some(connection),
+            Err(Error::IO(e)) => warn!("Couldn't connect to server: {}, journaling writes", e),
+            Err(e) => return Err(e),
+        }
+        Ok(client)
+    }
+
+    /// Number of writes journaled and not replayed yet, see `connect_journaled`.
+    pub fn pending_writes(&self) -> usize {
+        self.journal.as_ref().map_or(0, Journal::len)
+    }
+
+    /// Send the writes journaled while the server was unreachable, returns how many were sent.
+    ///
+    /// Writes a previous replay sent before it was cut short are skipped. A write failing on
+    /// the server, e.g. because the value is too large, is dropped with a warning.
+    pub fn replay_journal(&mut self) -> Result<usize> {
+        let mut journal = match self.journal.take() {
+            Some(journal) => journal,
+            None => return Ok(0),
+        };
+        let result = self.replay(&mut journal);
+        self.journal = Some(journal);
+        result
+    }
+
+    /// Set the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.write(Request::Set(Set { key, value }))? {
+            Some(Response::Ok) | None => Ok(()),
+            Some(_) => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Set a key only if it doesn't exist.
+    ///
+    /// Returns `false` if the key exists, its value is left untouched.
+    pub fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        match self.request(Request::Setnx(Setnx { key, value }))? {
+            Response::Integer(n) => Ok(n > 0),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Set a key and return its previous value.
+    ///
+    /// Returns `Ok(None)` if the key did not exist.
+    pub fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        match self.request(Request::Getset(Getset { key, value }))? {
+            Response::Bulk(value) => string(value).map(Some),
+            Response::Nil => Ok(None),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Append `value` to the value of a key, creating it if it doesn't exist.
+    ///
+    /// Returns the length of the new value in bytes.
+    pub fn append(&mut self, key: String, value: String) -> Result<u64> {
+        match self.request(Request::Append(Append { key, value }))? {
+            Response::Integer(n) => u64::try_from(n).map_err(|_| Error::UnexpectedResponse),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Get the string value of a given string key.
+    ///
+    /// Returns `Ok(None)` if the given key does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(Request::Get(Get { key }))? {
+            Response::Bulk(value) => string(value).map(Some),
+            Response::Nil => Ok(None),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Whether a given key exists.
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        Ok(self.count_existing(vec![key])? > 0)
+    }
+
+    /// Count how many of the given keys exist, a key given twice is counted twice.
+    pub fn count_existing(&mut self, keys: Vec<String>) -> Result<u64> {
+        match self.request(Request::Exists(Exists { keys }))? {
+            Response::Integer(n) => u64::try_from(n).map_err(|_| Error::UnexpectedResponse),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// List all keys matching a glob-style pattern.
+    pub fn keys(&mut self, pattern: String) -> Result<Vec<String>> {
+        match self.request(Request::Keys(Keys { pattern }))? {
+            Response::Array(keys) => values(keys),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Go through the next keys of an incremental scan, see `Scan`.
+    ///
+    /// Returns the cursor to pass to the next call, `"0"` once the scan is done,
+    /// and the keys found.
+    pub fn scan(&mut self, scan: Scan) -> Result<(String, Vec<String>)> {
+        match self.request(Request::Scan(scan))? {
+            Response::Array(reply) => match <[Response; 2]>::try_from(reply) {
+                Ok([Response::Bulk(cursor), Response::Array(keys)]) => {
+                    Ok((string(cursor)?, values(keys)?))
+                }
+                _ => Err(Error::UnexpectedResponse),
+            },
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Remove a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::KeyNotFound` if the given key does not exist.
+    /// A journaled remove succeeds either way, see `connect_journaled`.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.write(Request::Rm(Remove { keys: vec![key] }))? {
+            Some(Response::Integer(0)) => Err(Error::KeyNotFound),
+            Some(Response::Integer(_)) | None => Ok(()),
+            Some(_) => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Remove the given keys, missing keys are skipped.
+    ///
+    /// Returns the number of keys removed.
+    pub fn remove_keys(&mut self, keys: Vec<String>) -> Result<u64> {
+        match self.request(Request::Rm(Remove { keys }))? {
+            Response::Integer(n) => u64::try_from(n).map_err(|_| Error::UnexpectedResponse),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Set a key to expire after `seconds`, it is removed right away if `seconds` isn't positive.
+    ///
+    /// Returns `false` if the key doesn't exist.
+    pub fn expire(&mut self, key: String, seconds: i64) -> Result<bool> {
+        match self.request(Request::Expire(Expire { key, seconds }))? {
+            Response::Integer(n) => Ok(n > 0),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Remaining time to live of a key in seconds, -1 if it doesn't expire, -2 if it doesn't exist.
+    pub fn ttl(&mut self, key: String) -> Result<i64> {
+        match self.request(Request::Ttl(Ttl { key }))? {
+            Response::Integer(n) => Ok(n),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Make a key persistent.
+    ///
+    /// Returns `false` if the key doesn't exist or doesn't expire.
+    pub fn persist(&mut self, key: String) -> Result<bool> {
+        match self.request(Request::Persist(Persist { key }))? {
+            Response::Integer(n) => Ok(n > 0),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Rename `key` to `newkey`, overwriting `newkey` if it exists.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::KeyNotFound` if `key` does not exist.
+    pub fn rename(&mut self, key: String, newkey: String) -> Result<()> {
+        match self.request(Request::Rename(Rename { key, newkey })) {
+            Ok(Response::Ok) => Ok(()),
+            Ok(_) => Err(Error::UnexpectedResponse),
+            Err(e) => Err(no_such_key(e)),
+        }
+    }
+
+    /// Rename `key` to `newkey` unless `newkey` exists.
+    ///
+    /// Returns `false` if `newkey` exists, nothing is renamed then.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::KeyNotFound` if `key` does not exist.
+    pub fn rename_nx(&mut self, key: String, newkey: String) -> Result<bool> {
+        match self.request(Request::Renamenx(Renamenx { key, newkey })) {
+            Ok(Response::Integer(n)) => Ok(n > 0),
+            Ok(_) => Err(Error::UnexpectedResponse),
+            Err(e) => Err(no_such_key(e)),
+        }
+    }
+
+    /// Add members to the set of a key, creating it if it doesn't exist.
+    ///
+    /// Returns the number of members that weren't in the set yet.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::WrongType` if the key holds a string, so do the other set methods.
+    pub fn sadd(&mut self, key: String, members: Vec<String>) -> Result<u64> {
+        match self.request(Request::Sadd(Sadd { key, members }))? {
+            Response::Integer(n) => u64::try_from(n).map_err(|_| Error::UnexpectedResponse),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Remove members from the set of a key, the key is removed with its last member.
+    ///
+    /// Returns the number of members that were in the set.
+    pub fn srem(&mut self, key: String, members: Vec<String>) -> Result<u64> {
+        match self.request(Request::Srem(Srem { key, members }))? {
+            Response::Integer(n) => u64::try_from(n).map_err(|_| Error::UnexpectedResponse),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// The members of the set of a key, sorted, empty if the key doesn't exist.
+    pub fn smembers(&mut self, key: String) -> Result<Vec<String>> {
+        match self.request(Request::Smembers(Smembers { key }))? {
+            Response::Array(members) => values(members),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Whether `member` is in the set of a key.
+    pub fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        match self.request(Request::Sismember(Sismember { key, member }))? {
+            Response::Integer(n) => Ok(n > 0),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Number of members of the set of a key, 0 if the key doesn't exist.
+    pub fn scard(&mut self, key: String) -> Result<u64> {
+        match self.request(Request::Scard(Scard { key }))? {
+            Response::Integer(n) => u64::try_from(n).map_err(|_| Error::UnexpectedResponse),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// The members of any of the sets of the given keys, sorted.
+    pub fn sunion(&mut self, keys: Vec<String>) -> Result<Vec<String>> {
+        match self.request(Request::Sunion(Sunion { keys }))? {
+            Response::Array(members) => values(members),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// The members of all the sets of the given keys, sorted.
+    pub fn sinter(&mut self, keys: Vec<String>) -> Result<Vec<String>> {
+        match self.request(Request::Sinter(Sinter { keys }))? {
+            Response::Array(members) => values(members),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Move `key` to the server at `host:port`, see `Migrate`.
+    ///
+    /// Returns `false` if the key doesn't exist.
+    pub fn migrate(&mut self, migrate: Migrate) -> Result<bool> {
+        Ok(self.migrate_report(migrate)?.is_some())
+    }
+
+    /// Like `migrate`, but returns what a dry run reports would move.
+    ///
+    /// Returns `Ok(None)` if the key doesn't exist, and an empty report unless `dry_run`.
+    pub fn migrate_report(&mut self, migrate: Migrate) -> Result<Option<String>> {
+        match self.request(Request::Migrate(migrate))? {
+            Response::Ok => Ok(Some(String::new())),
+            Response::Bulk(s) if s == "NOKEY" => Ok(None),
+            Response::Bulk(report) => string(report).map(Some),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Serialize the entry of a given key, see `DumpPayload`.
+    ///
+    /// Returns `Ok(None)` if the given key does not exist.
+    pub fn dump(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(Request::Dump(Dump { key }))? {
+            Response::Bulk(blob) => string(blob).map(Some),
+            Response::Nil => Ok(None),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Create a key from the output of `dump`, see `Restore`.
+    pub fn restore(&mut self, restore: Restore) -> Result<()> {
+        match self.request(Request::Restore(restore))? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Set the read and write timeout of the connection, `None` waits forever.
+    ///
+    /// It also applies to connections opened later.
+    pub fn set_io_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.io_timeout = timeout;
+        if let Some(connection) = &self.connection {
+            let stream = connection.stream();
+            stream.set_read_timeout(timeout)?;
+            stream.set_write_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the client holds a connection that is still open.
+    ///
+    /// It doesn't send anything, it only checks that the server hasn't closed the connection
+    /// and that no unsolicited data is waiting.
+    pub fn is_healthy(&self) -> bool {
+        let connection = match &self.connection {
+            Some(connection) => connection,
+            None => return false,
+        };
+        if connection.has_buffered() {
+            return false;
+        }
+
+        let stream = connection.stream();
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let healthy = match stream.peek(&mut [0; 1]) {
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+            // Ok(0) means the server closed the connection, Ok(n) is unsolicited data
+            Ok(_) => false,
+        };
+        healthy && stream.set_nonblocking(false).is_ok()
+    }
+
+    /// Set the values of several string keys in one request.
+    pub fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self.request(Request::Mset(Mset::new(pairs)))? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Send all `requests` before waiting for any response, and return the responses in order.
+    ///
+    /// Error responses are returned as `Response::Error` instead of failing the whole pipeline.
+    /// Nothing is retried, since the server may have executed part of the pipeline.
+    pub fn pipeline(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        if self.connection.is_none() {
+            self.connection = Some(self.open_connection()?);
+        }
+
+        let result = self.send_all(requests);
+        if let Err(Error::IO(_)) = result {
+            // The connection is unusable after an I/O error
+            self.connection = None;
+        }
+        result
+    }
+
+    /// Number of keys in the selected database.
+    pub fn db_size(&mut self) -> Result<u64> {
+        match self.request(Request::Dbsize)? {
+            Response::Integer(n) => u64::try_from(n).map_err(|_| Error::UnexpectedResponse),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Remove every key on the server.
+    pub fn flush_db(&mut self) -> Result<()> {
+        match self.request(Request::Flushdb(Flushdb::default()))? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Statistics of the server and its engine, as `# Section` headers
+    /// followed by `name:value` lines, only of `section` if given.
+    pub fn info(&mut self, section: Option<String>) -> Result<String> {
+        match self.request(Request::Info(Info { section }))? {
+            Response::Bulk(info) => string(info),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Settings of the server and its engine whose name matches a glob-style pattern,
+    /// as name/value pairs.
+    pub fn config_get(&mut self, pattern: String) -> Result<Vec<(String, String)>> {
+        match self.request(Request::Config(Config::Get { pattern }))? {
+            Response::Array(reply) => {
+                let mut reply = values(reply)?.into_iter();
+                Ok(std::iter::from_fn(|| Some((reply.next()?, reply.next()?))).collect())
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Change a setting of the server or its engine while it runs.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::Server` if the setting doesn't exist or `value` is invalid.
+    pub fn config_set(&mut self, name: String, value: String) -> Result<()> {
+        match self.request(Request::Config(Config::Set { name, value }))? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Get the state of the server's background jobs, as formatted by `JobStatus`.
+    pub fn tasks(&mut self) -> Result<String> {
+        match self.request(Request::Tasks)? {
+            Response::Bulk(tasks) => string(tasks),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Get the requests the server is serving, a line per request as described by
+    /// `Currentops`: connection, command, first key and milliseconds elapsed.
+    pub fn current_ops(&mut self) -> Result<String> {
+        match self.request(Request::Currentops)? {
+            Response::Bulk(ops) => string(ops),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Make the server reclaim the disk space of the selected database now, see
+    /// `KvsEngine::compact`.
+    pub fn compact(&mut self) -> Result<()> {
+        match self.request(Request::Compact)? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Stop the server: it closes its connections once their current request is answered,
+    /// requests they sent after it are refused with `Error::ShuttingDown`.
+    pub fn shutdown(&mut self) -> Result<()> {
+        match self.request(Request::Shutdown)? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Replace the server process by a new one taking over its listening socket, see
+    /// `KvsServer::set_upgrade_handler`. The server then shuts down like on `shutdown`.
+    pub fn upgrade(&mut self) -> Result<()> {
+        match self.request(Request::Upgrade)? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Name/value pairs describing the server, e.g. its version.
+    ///
+    /// The connection stays on RESP2, the only protocol `KvsClient` speaks.
+    pub fn hello(&mut self) -> Result<Vec<(String, String)>> {
+        match self.request(Request::Hello(Hello::default()))? {
+            Response::Array(reply) => {
+                let reply = reply
+                    .into_iter()
+                    .map(|response| match response {
+                        Response::Integer(n) => Ok(n.to_string()),
+                        Response::Bulk(value) => string(value),
+                        _ => Err(Error::UnexpectedResponse),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let mut reply = reply.into_iter();
+                Ok(std::iter::from_fn(|| Some((reply.next()?, reply.next()?))).collect())
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Make the next `transaction` fail if one of `keys` is written before it.
+    ///
+    /// Keys are watched until the next transaction or `unwatch`, and are forgotten
+    /// if the connection is lost, which isn't retried for that reason.
+    pub fn watch(&mut self, keys: Vec<String>) -> Result<()> {
+        match self.request(Request::Watch(Watch { keys }))? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub fn unwatch(&mut self) -> Result<()> {
+        match self.request(Request::Unwatch)? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Lock `key` for `timeout`, or extend the lock if this connection holds it already.
+    ///
+    /// Until the key is unlocked, the timeout passes or the connection is lost, writes to
+    /// the key from other connections fail with `Error::KeyLocked`, as do their MIGRATE of
+    /// it and their FLUSHDB of its database. Returns `false` if another connection holds
+    /// the lock.
+    pub fn lock_key(&mut self, key: String, timeout: Duration) -> Result<bool> {
+        let timeout = timeout.as_millis() as u64;
+        match self.request(Request::Lockkey(Lockkey { key, timeout }))? {
+            Response::Integer(n) => Ok(n > 0),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Release the lock of `key`, returns `false` if this connection didn't hold it.
+    pub fn unlock_key(&mut self, key: String) -> Result<bool> {
+        match self.request(Request::Unlockkey(Unlockkey { key }))? {
+            Response::Integer(n) => Ok(n > 0),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Every command the server accepts.
+    pub fn commands(&mut self) -> Result<Vec<CommandDoc>> {
+        match self.request(Request::Command(CommandArgs { query: None }))? {
+            Response::Array(docs) => docs.into_iter().map(command_doc).collect(),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub fn command_count(&mut self) -> Result<u64> {
+        let query = Some(CommandQuery::Count);
+        match self.request(Request::Command(CommandArgs { query }))? {
+            Response::Integer(n) => u64::try_from(n).map_err(|_| Error::UnexpectedResponse),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Describe the given commands, `None` for those the server doesn't accept.
+    pub fn command_info(&mut self, names: Vec<String>) -> Result<Vec<Option<CommandDoc>>> {
+        let query = Some(CommandQuery::Info { names });
+        match self.request(Request::Command(CommandArgs { query }))? {
+            Response::Array(docs) => docs
+                .into_iter()
+                .map(|doc| match doc {
+                    Response::Nil => Ok(None),
+                    doc => command_doc(doc).map(Some),
+                })
+                .collect(),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Authenticate as `username`, the default user if `None`, including after reconnecting.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::WrongPass` if the server rejects the credentials.
+    pub fn auth(&mut self, username: Option<String>, password: String) -> Result<()> {
+        let auth = Auth::Login { username, password };
+        match self.request(Request::Auth(auth.clone()))? {
+            Response::Ok => {
+                self.credentials = Some(auth);
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Get a new token of the user the connection authenticated as, which the client
+    /// authenticates with from now on, including after reconnecting.
+    ///
+    /// Give the token to `auth` in place of a password. It expires after the server's
+    /// "token-ttl", so rotate it again before. The token the client used so far stays
+    /// valid for a minute at most, for other clients to move to the new one.
+    pub fn rotate_token(&mut self) -> Result<String> {
+        match self.request(Request::Auth(Auth::Rotate))? {
+            Response::Bulk(token) => {
+                let token = string(token)?;
+                self.credentials = Some(Auth::Login {
+                    username: None,
+                    password: token.clone(),
+                });
+                Ok(token)
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Revoke every token of `username`, returns how many were still valid.
+    ///
+    /// Connections authenticated with one of them have to authenticate again.
+    pub fn revoke_tokens(&mut self, username: String) -> Result<u64> {
+        match self.request(Request::Auth(Auth::Revoke { username }))? {
+            Response::Integer(count) => Ok(count as u64),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Work on the database `index` from now on, including after reconnecting.
+    pub fn select(&mut self, index: usize) -> Result<()> {
+        match self.request(Request::Select(Select { index }))? {
+            Response::Ok => {
+                self.db = index;
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Have the requests from now on get the engine with `priority`, including after
+    /// reconnecting, see `Priority`.
+    pub fn set_priority(&mut self, priority: Priority) -> Result<()> {
+        match self.request(Request::Priority(PriorityArgs { class: priority }))? {
+            Response::Ok => {
+                self.priority = priority;
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Name the connection in the server's CLIENT LIST, including after reconnecting, so
+    /// its requests can be told from those of other services. An empty name removes it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::Server` if `name` has spaces, newlines or other special characters.
+    pub fn set_name(&mut self, name: String) -> Result<()> {
+        let request = Request::Client(Client::Setname { name: name.clone() });
+        match self.request(request)? {
+            Response::Ok => {
+                self.name = Some(name).filter(|name| !name.is_empty());
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// The name of the connection, as the server knows it.
+    pub fn client_name(&mut self) -> Result<Option<String>> {
+        match self.request(Request::Client(Client::Getname))? {
+            Response::Bulk(name) => string(name).map(Some),
+            Response::Nil => Ok(None),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Tell the server the name ("lib-name") or version ("lib-ver") of the client library
+    /// in use, shown by CLIENT LIST.
+    pub fn set_client_info(&mut self, attr: String, value: String) -> Result<()> {
+        match self.request(Request::Client(Client::Setinfo { attr, value }))? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// The connections of the server, a line of "field=value" pairs each, e.g.
+    /// "id=3 addr=127.0.0.1:50432 name=billing age=12 idle=0 db=0 cmd=get lib-name= lib-ver=".
+    pub fn client_list(&mut self) -> Result<String> {
+        match self.request(Request::Client(Client::List))? {
+            Response::Bulk(list) => string(list),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Send `message` to the subscribers of `channel`, returns how many received it.
+    pub fn publish(&mut self, channel: String, message: String) -> Result<u64> {
+        match self.request(Request::Publish(Publish { channel, message }))? {
+            Response::Integer(n) => u64::try_from(n).map_err(|_| Error::UnexpectedResponse),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Turn the connection into a `Subscriber` receiving the messages published on `channels`.
+    ///
+    /// Changes to a key are published on its "__keyspace__:KEY" channel if the server is
+    /// configured to.
+    pub fn subscribe(self, channels: Vec<String>) -> Result<Subscriber> {
+        let count = channels.len();
+        self.into_subscriber(Request::Subscribe(Subscribe { channels }), count)
+    }
+
+    /// Like `subscribe`, with glob-style patterns matching the channels.
+    pub fn psubscribe(self, patterns: Vec<String>) -> Result<Subscriber> {
+        let count = patterns.len();
+        self.into_subscriber(Request::Psubscribe(Psubscribe { patterns }), count)
+    }
+
+    /// Send a subscription request and take its `count` confirmations.
+    fn into_subscriber(mut self, request: Request, count: usize) -> Result<Subscriber> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.open_connection()?,
+        };
+        connection.write_request(request)?;
+        for _ in 0..count {
+            match read_response(&mut connection)? {
+                Response::Array(confirmation) if confirmation.len() == 3 => {}
+                Response::Error { code, msg } => return Err(server_error(code, msg)),
+                _ => return Err(Error::UnexpectedResponse),
+            }
+        }
+        Ok(Subscriber { connection })
+    }
+
+    /// Apply the writes of `batch` in order, atomically, in a transaction.
+    ///
+    /// Keys set to expire are sent with RESTORE, which takes a TTL in milliseconds.
+    ///
+    /// # Errors
+    ///
+    /// It returns the first error of a write as `Error::Server`, the other writes are
+    /// applied all the same. Keys and values are sent as strings, nothing is sent if one
+    /// of them isn't UTF-8: it returns `Error::NotUtf8`.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut requests = vec![];
+        for op in batch {
+            requests.push(match op {
+                BatchOp::Set {
+                    key,
+                    value,
+                    expires_at: None,
+                } => Request::Set(Set {
+                    key: utf8(key)?,
+                    value: utf8(value)?,
+                }),
+                BatchOp::Set {
+                    key,
+                    value,
+                    expires_at: Some(expires_at),
+                } => {
+                    // A key about to expire still gets 1ms, 0 would make it persistent
+                    let ttl = expires_at
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default();
+                    Request::Restore(Restore {
+                        key: utf8(key)?,
+                        ttl: (ttl.as_millis() as u64).max(1),
+                        blob: DumpPayload {
+                            value: utf8(value)?,
+                        }
+                        .to_blob()?,
+                        replace: true,
+                    })
+                }
+                BatchOp::Remove { key } => Request::Rm(Remove {
+                    keys: vec![utf8(key)?],
+                }),
+            });
+        }
+        if requests.is_empty() {
+            return Ok(());
+        }
+        for response in self.transaction(requests)? {
+            if let Response::Error { code, msg } = response {
+                return Err(server_error(code, msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute `requests` atomically with MULTI and EXEC, and return their responses in order.
+    ///
+    /// Like in `pipeline`, error responses of single requests are returned as `Response::Error`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::Server` if the server didn't execute the transaction, e.g. because
+    /// one of the requests couldn't be queued, and `Error::TransactionAborted` if a key
+    /// watched with `watch` was written since.
+    pub fn transaction(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let mut pipeline = vec![Request::Multi];
+        pipeline.extend(requests);
+        pipeline.push(Request::Exec);
+        let mut responses = self.pipeline(pipeline)?;
+        let exec = responses.pop();
+        match (responses.first(), exec) {
+            (Some(Response::Ok), Some(Response::Array(responses))) => Ok(responses),
+            (_, Some(Response::Nil)) => Err(Error::TransactionAborted),
+            (_, Some(Response::Error { code, msg })) => Err(server_error(code, msg)),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Send a write, or journal it if the client has a journal and the server is unreachable.
+    ///
+    /// Returns `None` if the write was journaled. Journaled writes are replayed first, so that
+    /// the server receives writes in the order they were made.
+    fn write(&mut self, request: Request) -> Result<Option<Response>> {
+        let command = match (&self.journal, journal::command(&request)) {
+            (Some(_), Some(command)) => command,
+            _ => return self.request(request).map(Some),
+        };
+        let result = match self.replay_journal() {
+            Ok(_) => self.request(request).map(Some),
+            Err(e) => Err(e),
+        };
+        match result {
+            Err(Error::IO(e)) => {
+                warn!("Server unreachable: {}, journaling the write", e);
+                let journal = self.journal.as_mut().expect("checked above");
+                journal.append(command)?;
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    fn replay(&mut self, journal: &mut Journal) -> Result<usize> {
+        if journal.is_empty() {
+            return Ok(0);
+        }
+        let marker = journal.marker_key();
+        let replayed = match self.get(marker.clone())? {
+            Some(seq) => seq.parse().map_err(|_| Error::UnexpectedResponse)?,
+            None => 0,
+        };
+        let mut count = 0;
+        for (seq, request) in journal.requests().skip(replayed) {
+            let name = request.name();
+            let mark = Request::Set(Set {
+                key: marker.clone(),
+                value: seq.to_string(),
+            });
+            if let Some(Response::Error { code, msg }) =
+                self.transaction(vec![request, mark])?.into_iter().next()
+            {
+                warn!("Dropped journaled {}: {} {}", name, code, msg);
+            }
+            count += 1;
+        }
+        journal.clear()?;
+        self.remove_keys(vec![marker])?;
+        Ok(count)
+    }
+
+    /// Send `request` and wait for its response, reconnecting and retrying as the policy allows.
+    ///
+    /// Error responses are returned as `Error::Server`.
+    fn request(&mut self, request: Request) -> Result<Response> {
+        let idempotent = request.is_idempotent();
+        let frame = Frame::from(request);
+
+        let mut attempt = 0;
+        loop {
+            if self.connection.is_none() {
+                self.connection = Some(self.open_connection()?);
+            }
+
+            match self.send(&frame) {
+                Ok(Response::Error { code, msg }) => return Err(server_error(code, msg)),
+                Ok(response) => return Ok(response),
+                Err(Error::IO(e)) => {
+                    // The connection is unusable after an I/O error
+                    self.connection = None;
+                    if !idempotent || attempt >= self.policy.max_retries {
+                        return Err(Error::IO(e));
+                    }
+                    warn!("Request failed: {}, retrying", e);
+                    thread::sleep(self.policy.backoff(attempt));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send(&mut self, frame: &Frame) -> Result<Response> {
+        let connection = self.connection.as_mut().expect("connected before sending");
+        connection.write_frame(frame)?;
+        read_response(connection)
+    }
+
+    fn send_all(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let connection = self.connection.as_mut().expect("connected before sending");
+        let count = requests.len();
+        for request in requests {
+            connection.write_request(request)?;
+        }
+
+        let mut responses = Vec::with_capacity(count);
+        for _ in 0..count {
+            responses.push(read_response(connection)?);
+        }
+        Ok(responses)
+    }
+
+    fn open_connection(&self) -> Result<Connection> {
+        let mut attempt = 0;
+        loop {
+            let mut last_err =
+                io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to");
+            for addr in &self.addrs {
+                match TcpStream::connect_timeout(addr, self.policy.connect_timeout) {
+                    Ok(stream) => {
+                        stream.set_read_timeout(self.io_timeout)?;
+                        stream.set_write_timeout(self.io_timeout)?;
+                        let mut connection = Codec::from_stream(stream)?;
+                        // Nothing else is accepted before
+                        if let Some(auth) = &self.credentials {
+                            connection.write_request(Request::Auth(auth.clone()))?;
+                            if let Response::Error { code, msg } = read_response(&mut connection)? {
+                                return Err(server_error(code, msg));
+                            }
+                        }
+                        if self.db != 0 {
+                            let index = self.db;
+                            connection.write_request(Request::Select(Select { index }))?;
+                            if let Response::Error { code, msg } = read_response(&mut connection)? {
+                                return Err(server_error(code, msg));
+                            }
+                        }
+                        if self.priority != Priority::Normal {
+                            let class = self.priority;
+                            connection.write_request(Request::Priority(PriorityArgs { class }))?;
+                            if let Response::Error { code, msg } = read_response(&mut connection)? {
+                                return Err(server_error(code, msg));
+                            }
+                        }
+                        if let Some(name) = &self.name {
+                            let name = name.clone();
+                            connection.write_request(Request::Client(Client::Setname { name }))?;
+                            if let Response::Error { code, msg } = read_response(&mut connection)? {
+                                return Err(server_error(code, msg));
+                            }
+                        }
+                        return Ok(connection);
+                    }
+                    Err(e) => last_err = e,
+                }
+            }
+
+            if attempt >= self.policy.max_retries {
+                return Err(last_err.into());
+            }
+            warn!("Couldn't connect to server: {}, retrying", last_err);
+            thread::sleep(self.policy.backoff(attempt));
+            attempt += 1;
+        }
+    }
+}
+
+/// A connection receiving the messages of the channels it subscribed, see `KvsClient::subscribe`.
+pub struct Subscriber {
+    connection: Connection,
+}
+
+impl Subscriber {
+    /// Wait for the next message, for up to the I/O timeout of the client it came from.
+    pub fn next_message(&mut self) -> Result<Message> {
+        loop {
+            let fields = match read_response(&mut self.connection)? {
+                Response::Array(fields) => fields,
+                Response::Error { code, msg } => return Err(server_error(code, msg)),
+                _ => return Err(Error::UnexpectedResponse),
+            };
+            let mut fields = fields.into_iter().map(|field| match field {
+                Response::Bulk(value) => string(value).map(Some),
+                // Counts of (un)subscribe confirmations
+                _ => Ok(None),
+            });
+            let mut next = || fields.next().transpose().map(Option::flatten);
+            let message = match next()?.as_deref() {
+                Some("message") => Message {
+                    channel: next()?.ok_or(Error::UnexpectedResponse)?,
+                    pattern: None,
+                    payload: next()?.ok_or(Error::UnexpectedResponse)?,
+                },
+                Some("pmessage") => Message {
+                    pattern: next()?,
+                    channel: next()?.ok_or(Error::UnexpectedResponse)?,
+                    payload: next()?.ok_or(Error::UnexpectedResponse)?,
+                },
+                Some("subscribe" | "psubscribe" | "unsubscribe" | "punsubscribe") => continue,
+                _ => return Err(Error::UnexpectedResponse),
+            };
+            return Ok(message);
+        }
+    }
+}
+
+fn read_response(connection: &mut Connection) -> Result<Response> {
+    match connection.read_response()? {
+        Some(response) => Ok(response),
+        None => {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by server").into())
+        }
+    }
+}
+
+fn values(responses: Vec<Response>) -> Result<Vec<String>> {
+    responses
+        .into_iter()
+        .map(|response| match response {
+            Response::Bulk(value) => string(value),
+            _ => Err(Error::UnexpectedResponse),
+        })
+        .collect()
+}
+
+fn command_doc(response: Response) -> Result<CommandDoc> {
+    let fields = match response {
+        Response::Array(fields) => <[Response; 6]>::try_from(fields),
+        _ => return Err(Error::UnexpectedResponse),
+    };
+    match fields {
+        Ok(
+            [Response::Bulk(name), Response::Integer(arity), Response::Array(flags), Response::Integer(first_key), Response::Integer(last_key), Response::Integer(step)],
+        ) => Ok(CommandDoc {
+            name: string(name)?,
+            arity,
+            flags: values(flags)?,
+            first_key,
+            last_key,
+            step,
+        }),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn string(bulk: Bytes) -> Result<String> {
+    String::from_utf8(bulk.into()).map_err(|_| Error::UnexpectedResponse)
+}
+
+/// Generic `ERR` errors are shown without their code.
+/// `Error::KeyNotFound` for the "no such key" error of the server, other errors as they are.
+fn no_such_key(e: Error) -> Error {
+    match e {
+        Error::Server(msg) if msg == "no such key" => Error::KeyNotFound,
+        e => e,
+    }
+}
+
+fn server_error(code: String, msg: String) -> Error {
+    match code.as_str() {
+        "ERR" => Error::Server(msg),
+        "WRONGTYPE" => Error::WrongType,
+        "LOCKED" => Error::KeyLocked,
+        "SHUTDOWN" => Error::ShuttingDown,
+        "NOAUTH" => Error::AuthRequired,
+        "WRONGPASS" => Error::WrongPass,
+        _ => Error::Server(format!("{} {}", code, msg)),
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The open connections of a server, listed by CLIENT LIST.
+#[derive(Default)]
+pub(crate) struct ClientRegistry {
+    clients: Mutex<BTreeMap<u64, ClientInfo>>,
+}
+
+/// What a connection tells about itself, and what it did last.
+struct ClientInfo {
+    addr: SocketAddr,
+    connected_at: Instant,
+    last_request_at: Instant,
+    /// Set with CLIENT SETNAME or HELLO SETNAME
+    name: Option<String>,
+    /// Set with CLIENT SETINFO
+    lib_name: Option<String>,
+    lib_ver: Option<String>,
+    db: usize,
+    /// Name of the last command
+    cmd: &'static str,
+    /// Start, command and first key of the request being served
+    running: Option<(Instant, &'static str, Option<String>)>,
+}
+
+/// Attributes of a connection set with CLIENT SETINFO.
+pub(crate) const CLIENT_INFO_ATTRS: [&str; 2] = ["lib-name", "lib-ver"];
+
+impl ClientRegistry {
+    /// Add connection `connection` from `addr`, until it is `remove`d.
+    pub(crate) fn add(&self, connection: u64, addr: SocketAddr) {
+        let now = Instant::now();
+        self.clients.lock().unwrap().insert(
+            connection,
+            ClientInfo {
+                addr,
+                connected_at: now,
+                last_request_at: now,
+                name: None,
+                lib_name: None,
+                lib_ver: None,
+                db: 0,
+                cmd: "NULL",
+                running: None,
+            },
+        );
+    }
+
+    pub(crate) fn remove(&self, connection: u64) {
+        self.clients.lock().unwrap().remove(&connection);
+    }
+
+    /// Record the start of a request of command `cmd` on `connection`, about `key` if any.
+    pub(crate) fn start_request(&self, connection: u64, cmd: &'static str, key: Option<&str>) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&connection) {
+            client.running = Some((Instant::now(), cmd, key.map(str::to_owned)));
+        }
+    }
+
+    /// Record a request of command `cmd` on `connection`, working on database `db` after it.
+    pub(crate) fn record_request(&self, connection: u64, cmd: &'static str, db: usize) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&connection) {
+            client.last_request_at = Instant::now();
+            client.cmd = cmd;
+            client.db = db;
+            client.running = None;
+        }
+    }
+
+    /// Name `connection`, an empty name removes it.
+    pub(crate) fn set_name(&self, connection: u64, name: String) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&connection) {
+            client.name = Some(name).filter(|name| !name.is_empty());
+        }
+    }
+
+    pub(crate) fn name(&self, connection: u64) -> Option<String> {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&connection)?.name.clone()
+    }
+
+    /// Set attribute `attr` of `connection`, one of `CLIENT_INFO_ATTRS`.
+    pub(crate) fn set_info(&self, connection: u64, attr: &str, value: String) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&connection) {
+            let value = Some(value).filter(|value| !value.is_empty());
+            match attr {
+                "lib-name" => client.lib_name = value,
+                "lib-ver" => client.lib_ver = value,
+                _ => {}
+            }
+        }
+    }
+
+    /// A line of space-separated `field=value` pairs per connection, oldest first.
+    ///
+    /// Ages are in seconds, "idle" is the time since the last request.
+    pub(crate) fn list(&self) -> String {
+        let now = Instant::now();
+        let mut list = String::new();
+        for (connection, client) in self.clients.lock().unwrap().iter() {
+            writeln!(
+                list,
+                "id={} addr={} name={} age={} idle={} db={} cmd={} lib-name={} lib-ver={}",
+                connection,
+                client.addr,
+                client.name.as_deref().unwrap_or(""),
+                (now - client.connected_at).as_secs(),
+                (now - client.last_request_at).as_secs(),
+                client.db,
+                client.cmd,
+                client.lib_name.as_deref().unwrap_or(""),
+                client.lib_ver.as_deref().unwrap_or(""),
+            )
+            .unwrap();
+        }
+        list
+    }
+
+    /// A line of space-separated `field=value` pairs per request being served, the longest
+    /// running first.
+    ///
+    /// "elapsed" is in milliseconds. The key is quoted, as it may hold spaces; it is empty
+    /// for commands without keys.
+    pub(crate) fn current_ops(&self) -> String {
+        let now = Instant::now();
+        let clients = self.clients.lock().unwrap();
+        let mut ops: Vec<_> = clients
+            .iter()
+            .filter_map(|(connection, client)| {
+                let (started_at, cmd, key) = client.running.as_ref()?;
+                Some((*started_at, connection, client, cmd, key))
+            })
+            .collect();
+        ops.sort_by_key(|&(started_at, ..)| started_at);
+
+        let mut list = String::new();
+        for (started_at, connection, client, cmd, key) in ops {
+            writeln!(
+                list,
+                "id={} addr={} name={} db={} cmd={} key={} elapsed={}",
+                connection,
+                client.addr,
+                client.name.as_deref().unwrap_or(""),
+                client.db,
+                cmd,
+                key.as_ref()
+                    .map_or(String::new(), |key| format!("{:?}", key)),
+                (now - started_at).as_millis(),
+            )
+            .unwrap();
+        }
+        list
+    }
+}
+
+/// Whether `value` can be a name or attribute of a connection: CLIENT LIST separates
+/// them with spaces and lines.
+pub(crate) fn is_valid_client_value(value: &str) -> bool {
+    value.bytes().all(|b| b.is_ascii_graphic())
+}
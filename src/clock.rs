@@ -0,0 +1,74 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Where engines and the server read the current time from, for expiration times and
+/// timestamps, see `KvsEngine::set_clock`.
+///
+/// Intervals of background jobs and timeouts aren't affected, they are measured on the
+/// monotonic clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, e.g. to expire keys in tests without waiting.
+///
+/// Clones share the same time.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine, ManualClock};
+/// use std::sync::Arc;
+/// use std::time::{Duration, SystemTime};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut store = KvStore::open(temp_dir.path()).unwrap();
+/// let clock = ManualClock::new(SystemTime::now());
+/// store.set_clock(Arc::new(clock.clone()));
+///
+/// store.set("key".to_string(), "value".to_string()).unwrap();
+/// store.set_expiration("key".to_string(), Some(clock.now() + Duration::from_secs(60))).unwrap();
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!(store.get("key".to_string()).unwrap(), None);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        ManualClock::now(self)
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{Error, FrameLimits, Protocol, Request, Response, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use redis_protocol::resp2::prelude::*;
+use redis_protocol::resp3::encode::complete as resp3_encode;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+
+/// Both ends of a connection speaking RESP: the client writes requests and reads responses,
+/// the server reads requests and writes responses.
+///
+/// Requests are always RESP2 arrays, responses are encoded in the protocol of the codec.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{Codec, Get, Request, Response};
+///
+/// let mut wire = vec![];
+/// let mut client = Codec::new(&[][..], &mut wire);
+/// client.write_request(Request::Get(Get { key: "key".to_owned() })).unwrap();
+/// drop(client);
+///
+/// let mut server = Codec::new(&wire[..], vec![]);
+/// let request = server.read_request().unwrap().unwrap();
+/// assert_eq!(request.keys(), ["key"]);
+/// server.write_response(Response::Nil).unwrap();
+/// ```
+pub struct Codec<R: Read, W: Write> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+    // Bytes read past the end of the last frame
+    buf: BytesMut,
+    limits: Option<FrameLimits>,
+    protocol: Protocol,
+}
+
+impl Codec<TcpStream, TcpStream> {
+    pub fn from_stream(stream: TcpStream) -> Result<Self> {
+        Ok(Codec::new(stream.try_clone()?, stream))
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        self.writer.get_ref()
+    }
+}
+
+impl<R: Read, W: Write> Codec<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Codec {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            buf: BytesMut::new(),
+            limits: None,
+            protocol: Protocol::default(),
+        }
+    }
+
+    /// Reject frames read from now on that exceed `limits`, with `Error::FrameRejected`.
+    pub fn set_limits(&mut self, limits: Option<FrameLimits>) {
+        self.limits = limits;
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Encode the responses written from now on in `protocol`.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Whether bytes were received that haven't been decoded yet.
+    pub fn has_buffered(&self) -> bool {
+        !self.buf.is_empty() || !self.reader.buffer().is_empty()
+    }
+
+    /// Read the next frame.
+    ///
+    /// Returns `Ok(None)` if the peer closed the connection between two frames.
+    pub fn read_frame(&mut self) -> Result<Option<Frame>> {
+        let mut chunk = [0; 1024];
+        loop {
+            if let Some(frame) = decode_frame(&mut self.buf, self.limits.as_ref())? {
+                return Ok(Some(frame));
+            }
+            let n = self.reader.read(&mut chunk)?;
+            if n == 0 {
+                return closed(&self.buf);
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    pub fn read_request(&mut self) -> Result<Option<Request>> {
+        match self.read_frame()? {
+            Some(frame) => Ok(Some(Request::try_from(frame)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the next response, only RESP2 responses can be decoded.
+    pub fn read_response(&mut self) -> Result<Option<Response>> {
+        self.read_frame()?.map(Response::try_from).transpose()
+    }
+
+    /// Encode `frame` as is and write it, e.g. to send the same request again.
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        let mut buf = BytesMut::new();
+        encode_bytes(&mut buf, frame)?;
+        self.write(&buf)
+    }
+
+    pub fn write_request(&mut self, request: Request) -> Result<()> {
+        self.write_frame(&Frame::from(request))
+    }
+
+    pub fn write_response(&mut self, response: Response) -> Result<()> {
+        let buf = encode_response(response, self.protocol)?;
+        self.write(&buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer.write_all(buf)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The async flavor of `Codec`, on `futures` I/O traits.
+#[cfg(feature = "async")]
+pub struct AsyncCodec<R, W> {
+    reader: futures::io::BufReader<R>,
+    writer: futures::io::BufWriter<W>,
+    buf: BytesMut,
+    limits: Option<FrameLimits>,
+    protocol: Protocol,
+}
+
+#[cfg(feature = "async")]
+impl<R, W> AsyncCodec<R, W>
+where
+    R: futures::AsyncRead + Unpin,
+    W: futures::AsyncWrite + Unpin,
+{
+    pub fn new(reader: R, writer: W) -> Self {
+        AsyncCodec {
+            reader: futures::io::BufReader::new(reader),
+            writer: futures::io::BufWriter::new(writer),
+            buf: BytesMut::new(),
+            limits: None,
+            protocol: Protocol::default(),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Option<FrameLimits>) {
+        self.limits = limits;
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        use futures::AsyncReadExt;
+
+        let mut chunk = [0; 1024];
+        loop {
+            if let Some(frame) = decode_frame(&mut self.buf, self.limits.as_ref())? {
+                return Ok(Some(frame));
+            }
+            let n = self.reader.read(&mut chunk).await?;
+            if n == 0 {
+                return closed(&self.buf);
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    pub async fn read_request(&mut self) -> Result<Option<Request>> {
+        match self.read_frame().await? {
+            Some(frame) => Ok(Some(Request::try_from(frame)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn read_response(&mut self) -> Result<Option<Response>> {
+        self.read_frame().await?.map(Response::try_from).transpose()
+    }
+
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        let mut buf = BytesMut::new();
+        encode_bytes(&mut buf, frame)?;
+        self.write(&buf).await
+    }
+
+    pub async fn write_request(&mut self, request: Request) -> Result<()> {
+        self.write_frame(&Frame::from(request)).await
+    }
+
+    pub async fn write_response(&mut self, response: Response) -> Result<()> {
+        let buf = encode_response(response, self.protocol)?;
+        self.write(&buf).await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<()> {
+        use futures::AsyncWriteExt;
+
+        self.writer.write_all(buf).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Take the first frame out of `buf` if it is complete, checking it against `limits`.
+fn decode_frame(buf: &mut BytesMut, limits: Option<&FrameLimits>) -> Result<Option<Frame>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if let Some(limits) = limits {
+        limits.check(buf)?;
+    }
+    match decode(&Bytes::copy_from_slice(buf))? {
+        Some((frame, frame_size)) => {
+            buf.advance(frame_size);
+            Ok(Some(frame))
+        }
+        None => Ok(None),
+    }
+}
+
+fn encode_response(response: Response, protocol: Protocol) -> Result<BytesMut> {
+    let mut buf = BytesMut::new();
+    match protocol {
+        Protocol::Resp2 => encode_bytes(&mut buf, &Frame::from(response))?,
+        Protocol::Resp3 => resp3_encode::encode_bytes(&mut buf, &response.into_resp3())?,
+    };
+    Ok(buf)
+}
+
+/// The peer closed the connection, in the middle of a frame if `buf` isn't empty.
+fn closed<T>(buf: &BytesMut) -> Result<Option<T>> {
+    if buf.is_empty() {
+        Ok(None)
+    } else {
+        Err(Error::IO(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Incomplete frame",
+        )))
+    }
+}
@@ -0,0 +1,173 @@
+use crate::Response;
+
+/// A property of a command, as listed by COMMAND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandFlag {
+    /// Changes keys
+    Write,
+    /// Only reads keys
+    Readonly,
+    /// Looks into or changes the server itself
+    Admin,
+    /// Can't be queued after MULTI
+    NoMulti,
+    /// Works on channels rather than keys
+    Pubsub,
+}
+
+impl CommandFlag {
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandFlag::Write => "write",
+            CommandFlag::Readonly => "readonly",
+            CommandFlag::Admin => "admin",
+            CommandFlag::NoMulti => "no_multi",
+            CommandFlag::Pubsub => "pubsub",
+        }
+    }
+}
+
+/// How a command is called, the server checks requests against it before parsing them.
+#[derive(Debug)]
+pub struct CommandSpec {
+    /// Wire name, in lower case
+    pub name: &'static str,
+    /// Number of arguments including the name, or minus the minimum number if it varies
+    pub arity: i64,
+    pub flags: &'static [CommandFlag],
+    /// Position of the first key argument, 0 if there are no keys
+    pub first_key: i64,
+    /// Position of the last key argument, negative to count from the end
+    pub last_key: i64,
+    /// Distance between key arguments
+    pub step: i64,
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: CommandFlag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// Whether a request with `argc` arguments, including the name, has the right arity.
+    pub fn accepts(&self, argc: usize) -> bool {
+        let argc = argc as i64;
+        if self.arity < 0 {
+            argc >= -self.arity
+        } else {
+            argc == self.arity
+        }
+    }
+
+    /// The entry of the command in a COMMAND reply, under `name`.
+    pub(crate) fn to_response(&self, name: &str) -> Response {
+        Response::Array(vec![
+            Response::bulk(name.to_owned()),
+            Response::Integer(self.arity),
+            Response::Array(
+                self.flags
+                    .iter()
+                    .map(|flag| Response::bulk(flag.name()))
+                    .collect(),
+            ),
+            Response::Integer(self.first_key),
+            Response::Integer(self.last_key),
+            Response::Integer(self.step),
+        ])
+    }
+}
+
+/// A command as described by the server, see `KvsClient::command_info`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandDoc {
+    pub name: String,
+    pub arity: i64,
+    pub flags: Vec<String>,
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+}
+
+use CommandFlag::*;
+
+const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [CommandFlag],
+    keys: (i64, i64, i64),
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key: keys.0,
+        last_key: keys.1,
+        step: keys.2,
+    }
+}
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+
+/// Every command the server accepts.
+pub const COMMANDS: &[CommandSpec] = &[
+    spec("set", 3, &[Write], ONE_KEY),
+    spec("get", 2, &[Readonly], ONE_KEY),
+    spec("setnx", 3, &[Write], ONE_KEY),
+    spec("getset", 3, &[Write], ONE_KEY),
+    spec("remove", -2, &[Write], (1, -1, 1)),
+    spec("mset", -3, &[Write], (1, -1, 2)),
+    spec("append", 3, &[Write], ONE_KEY),
+    spec("exists", -2, &[Readonly], (1, -1, 1)),
+    spec("keys", 2, &[Readonly], NO_KEYS),
+    spec("scan", -2, &[Readonly], NO_KEYS),
+    spec("expire", 3, &[Write], ONE_KEY),
+    spec("ttl", 2, &[Readonly], ONE_KEY),
+    spec("persist", 2, &[Write], ONE_KEY),
+    spec("rename", 3, &[Write], (1, 2, 1)),
+    spec("renamenx", 3, &[Write], (1, 2, 1)),
+    spec("sadd", -3, &[Write], ONE_KEY),
+    spec("srem", -3, &[Write], ONE_KEY),
+    spec("smembers", 2, &[Readonly], ONE_KEY),
+    spec("sismember", 3, &[Readonly], ONE_KEY),
+    spec("scard", 2, &[Readonly], ONE_KEY),
+    spec("sunion", -2, &[Readonly], (1, -1, 1)),
+    spec("sinter", -2, &[Readonly], (1, -1, 1)),
+    // MIGRATE talks to another server, it would hold up a transaction
+    spec("migrate", -5, &[Write, NoMulti], (3, 3, 1)),
+    spec("dump", 2, &[Readonly], ONE_KEY),
+    spec("restore", -4, &[Write], ONE_KEY),
+    spec("dbsize", 1, &[Readonly], NO_KEYS),
+    spec("flushdb", 1, &[Write], NO_KEYS),
+    spec("info", -1, &[NoMulti], NO_KEYS),
+    spec("config", -3, &[Admin, NoMulti], NO_KEYS),
+    spec("tasks", 1, &[Admin, NoMulti], NO_KEYS),
+    spec("currentops", 1, &[Admin, NoMulti], NO_KEYS),
+    spec("compact", 1, &[Admin, NoMulti], NO_KEYS),
+    spec("shutdown", 1, &[Admin, NoMulti], NO_KEYS),
+    spec("upgrade", 1, &[Admin, NoMulti], NO_KEYS),
+    spec("multi", 1, &[NoMulti], NO_KEYS),
+    spec("exec", 1, &[NoMulti], NO_KEYS),
+    spec("discard", 1, &[NoMulti], NO_KEYS),
+    spec("watch", -2, &[NoMulti], (1, -1, 1)),
+    spec("unwatch", 1, &[NoMulti], NO_KEYS),
+    spec("lockkey", 3, &[NoMulti], ONE_KEY),
+    spec("unlockkey", 2, &[NoMulti], ONE_KEY),
+    spec("hello", -1, &[NoMulti], NO_KEYS),
+    spec("auth", -2, &[NoMulti], NO_KEYS),
+    spec("command", -1, &[NoMulti], NO_KEYS),
+    spec("select", 2, &[NoMulti], NO_KEYS),
+    spec("priority", 2, &[NoMulti], NO_KEYS),
+    spec("client", -2, &[NoMulti], NO_KEYS),
+    spec("subscribe", -2, &[Pubsub, NoMulti], NO_KEYS),
+    spec("psubscribe", -2, &[Pubsub, NoMulti], NO_KEYS),
+    spec("unsubscribe", -1, &[Pubsub, NoMulti], NO_KEYS),
+    spec("punsubscribe", -1, &[Pubsub, NoMulti], NO_KEYS),
+    spec("publish", 3, &[Pubsub, NoMulti], NO_KEYS),
+];
+
+/// The spec of a command by wire name, in any case.
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
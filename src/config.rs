@@ -0,0 +1,202 @@
+use crate::commands;
+use crate::{ByteRanges, Error, FrameLimits, KeyRules, Priority, Result, SlowClientPolicy};
+use log::LevelFilter;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Settings of a server that CONFIG SET can change while it runs.
+///
+/// The server shares them with its connections, changes apply from their next request.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub limits: FrameLimits,
+    pub slow_clients: SlowClientPolicy,
+    /// Percentage of random extra time added to the timeouts set by EXPIRE
+    pub ttl_jitter: u32,
+    /// Largest value accepted by writes in bytes, `None` for no limit
+    pub max_value_size: Option<usize>,
+    /// Constraints on the keys of requests
+    pub key_rules: KeyRules,
+    /// Whether changes to keys are published on their "__keyspace__:KEY" channel
+    pub notify_keyspace_events: bool,
+    /// Commands executed with high priority whatever the connection's, see `Priority`
+    pub high_priority_commands: Vec<String>,
+    /// Commands executed with low priority, unless also listed as high priority
+    pub low_priority_commands: Vec<String>,
+    /// How long the tokens of AUTH ROTATE are valid, an hour by default
+    pub token_ttl: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            limits: FrameLimits::default(),
+            slow_clients: SlowClientPolicy::default(),
+            ttl_jitter: 0,
+            max_value_size: None,
+            key_rules: KeyRules::default(),
+            notify_keyspace_events: false,
+            high_priority_commands: vec![],
+            low_priority_commands: vec![],
+            token_ttl: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Every setting and its value, by the name used by CONFIG GET and CONFIG SET.
+    ///
+    /// Durations are in milliseconds, switches are "yes" or "no", lists of commands are
+    /// separated by spaces. "loglevel" is the level of the `log` crate, it is the same for
+    /// all servers of the process.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("max-request-size", self.limits.max_frame_size.to_string()),
+            ("max-request-args", self.limits.max_array_len.to_string()),
+            ("max-arg-len", self.limits.max_bulk_len.to_string()),
+            (
+                "client-write-timeout",
+                self.slow_clients.write_timeout.as_millis().to_string(),
+            ),
+            (
+                "client-max-blocked",
+                self.slow_clients.max_blocked_time.as_millis().to_string(),
+            ),
+            ("ttl-jitter", self.ttl_jitter.to_string()),
+            (
+                "max-value-size",
+                self.max_value_size.unwrap_or(0).to_string(),
+            ),
+            (
+                "max-key-len",
+                self.key_rules.max_len.unwrap_or(0).to_string(),
+            ),
+            (
+                "key-allowed-bytes",
+                self.key_rules
+                    .allowed_bytes
+                    .as_ref()
+                    .map_or_else(String::new, ByteRanges::to_string),
+            ),
+            (
+                "case-insensitive-keys",
+                yes_no(self.key_rules.case_insensitive),
+            ),
+            (
+                "notify-keyspace-events",
+                yes_no(self.notify_keyspace_events),
+            ),
+            (
+                "high-priority-commands",
+                self.high_priority_commands.join(" "),
+            ),
+            (
+                "low-priority-commands",
+                self.low_priority_commands.join(" "),
+            ),
+            ("token-ttl", self.token_ttl.as_millis().to_string()),
+            (
+                "loglevel",
+                log::max_level().to_string().to_ascii_lowercase(),
+            ),
+        ]
+    }
+
+    /// Change the setting `name`, see `entries`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::UnknownConfig` if there is no such setting, and
+    /// `Error::InvalidConfig` if `value` can't be parsed.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "max-request-size" => self.limits.max_frame_size = parse(name, value)?,
+            "max-request-args" => self.limits.max_array_len = parse(name, value)?,
+            "max-arg-len" => self.limits.max_bulk_len = parse(name, value)?,
+            "client-write-timeout" => {
+                let millis = parse(name, value)?;
+                // A zero timeout would block writes forever
+                if millis == 0 {
+                    return Err(Error::InvalidConfig(name.to_owned(), value.to_owned()));
+                }
+                self.slow_clients.write_timeout = Duration::from_millis(millis);
+            }
+            "client-max-blocked" => {
+                self.slow_clients.max_blocked_time = Duration::from_millis(parse(name, value)?)
+            }
+            "ttl-jitter" => self.ttl_jitter = parse(name, value)?,
+            "max-value-size" => {
+                self.max_value_size = Some(parse(name, value)?).filter(|&size| size > 0)
+            }
+            "max-key-len" => {
+                self.key_rules.max_len = Some(parse(name, value)?).filter(|&len| len > 0)
+            }
+            // Empty allows any byte
+            "key-allowed-bytes" => {
+                self.key_rules.allowed_bytes = match value {
+                    "" => None,
+                    value => Some(parse(name, value)?),
+                }
+            }
+            "case-insensitive-keys" => self.key_rules.case_insensitive = parse_yes_no(name, value)?,
+            "notify-keyspace-events" => self.notify_keyspace_events = parse_yes_no(name, value)?,
+            "high-priority-commands" => self.high_priority_commands = parse_commands(name, value)?,
+            "low-priority-commands" => self.low_priority_commands = parse_commands(name, value)?,
+            "token-ttl" => {
+                let millis = parse(name, value)?;
+                // Tokens would be expired as soon as issued
+                if millis == 0 {
+                    return Err(Error::InvalidConfig(name.to_owned(), value.to_owned()));
+                }
+                self.token_ttl = Duration::from_millis(millis);
+            }
+            "loglevel" => log::set_max_level(parse::<LevelFilter>(name, value)?),
+            _ => return Err(Error::UnknownConfig(name.to_owned())),
+        }
+        Ok(())
+    }
+
+    /// The priority requests of command `name` are executed with, `None` for that of
+    /// their connection.
+    pub fn command_priority(&self, name: &str) -> Option<Priority> {
+        if self.high_priority_commands.iter().any(|c| c == name) {
+            Some(Priority::High)
+        } else if self.low_priority_commands.iter().any(|c| c == name) {
+            Some(Priority::Low)
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) fn yes_no(enabled: bool) -> String {
+    if enabled { "yes" } else { "no" }.to_owned()
+}
+
+pub(crate) fn parse_yes_no(name: &str, value: &str) -> Result<bool> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(Error::InvalidConfig(name.to_owned(), value.to_owned())),
+    }
+}
+
+fn parse_commands(name: &str, value: &str) -> Result<Vec<String>> {
+    value
+        .split_whitespace()
+        .map(|command| {
+            let command = command.to_lowercase();
+            match commands::lookup(&command) {
+                Some(_) => Ok(command),
+                None => Err(Error::InvalidConfig(name.to_owned(), value.to_owned())),
+            }
+        })
+        .collect()
+}
+
+/// Parse the value of a setting.
+pub(crate) fn parse<T: FromStr>(name: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidConfig(name.to_owned(), value.to_owned()))
+}
@@ -0,0 +1,44 @@
+use crate::checksum::crc32;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+const DUMP_VERSION: u32 = 1;
+
+/// A key's entry as serialized by DUMP and read back by RESTORE.
+///
+/// The blob is `<version>:<crc32 of payload, hex>:<JSON payload>`, so it is plain text
+/// and can be stored or sent anywhere a string value can.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct DumpPayload {
+    pub value: String,
+}
+
+impl DumpPayload {
+    pub fn to_blob(&self) -> Result<String> {
+        let payload = serde_json::to_string(self)?;
+        Ok(format!(
+            "{}:{:08x}:{}",
+            DUMP_VERSION,
+            crc32(payload.as_bytes()),
+            payload
+        ))
+    }
+
+    /// # Errors
+    ///
+    /// It returns `Error::InvalidDump` if the blob is malformed, of another version,
+    /// or doesn't match its checksum.
+    pub fn from_blob(blob: &str) -> Result<Self> {
+        let mut parts = blob.splitn(3, ':');
+        let (version, checksum, payload) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(version), Some(checksum), Some(payload)) => (version, checksum, payload),
+            _ => return Err(Error::InvalidDump),
+        };
+        if version.parse() != Ok(DUMP_VERSION)
+            || u32::from_str_radix(checksum, 16) != Ok(crc32(payload.as_bytes()))
+        {
+            return Err(Error::InvalidDump);
+        }
+        serde_json::from_str(payload).map_err(|_| Error::InvalidDump)
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{Error, Result};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// A text encoding of the bytes of keys and values, to show and type those holding
+/// control or other unprintable characters, see kvs-client's `--hex` and `--base64`.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::Encoding;
+///
+/// assert_eq!(Encoding::Hex.encode("\0key"), "006b6579");
+/// assert_eq!(Encoding::Base64.decode("AGtleQ==").unwrap(), "\0key");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Two lower case hex digits per byte
+    Hex,
+    /// Standard base64, padded with '='
+    Base64,
+}
+
+impl Encoding {
+    pub fn encode(self, s: &str) -> String {
+        self.encode_bytes(s.as_bytes())
+    }
+
+    /// # Errors
+    ///
+    /// It returns `Error::InvalidEncoding` if `s` isn't valid in the encoding, or if the
+    /// decoded bytes aren't UTF-8: keys and values are strings.
+    pub fn decode(self, s: &str) -> Result<String> {
+        let bytes = self.decode_bytes(s)?;
+        String::from_utf8(bytes).map_err(|_| Error::InvalidEncoding)
+    }
+
+    pub fn encode_bytes(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Hex => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            Encoding::Base64 => base64_encode(bytes),
+        }
+    }
+
+    /// # Errors
+    ///
+    /// It returns `Error::InvalidEncoding` if `s` isn't valid in the encoding.
+    pub fn decode_bytes(self, s: &str) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Hex => hex_decode(s),
+            Encoding::Base64 => base64_decode(s),
+        }
+        .ok_or(Error::InvalidEncoding)
+    }
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let data = s.trim_end_matches('=');
+    if s.len() - data.len() > 2 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(data.len() * 3 / 4);
+    for chunk in data.as_bytes().chunks(4) {
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let sextet = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            n |= sextet << (18 - 6 * i);
+        }
+        // Each character past the first one completes a byte
+        for i in 0..chunk.len() - 1 {
+            bytes.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(bytes)
+}
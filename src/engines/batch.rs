@@ -0,0 +1,83 @@
+use std::time::SystemTime;
+
+/// Writes applied atomically by `KvsEngine::write_batch`, in order.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine, WriteBatch};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("from".to_string(), "10".to_string()).unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch.set("to".to_string(), "10".to_string());
+/// batch.remove("from".to_string());
+/// store.write_batch(batch).unwrap();
+/// assert_eq!(store.get("to".to_string()).unwrap(), Some("10".to_string()));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A write of a batch, keys and values are bytes as for `KvsEngine::set_bytes`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    /// Set a key, expiring at `expires_at` if given
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+    },
+    /// Remove a key, nothing happens if it doesn't exist
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Set a key and make it persistent.
+    pub fn set(&mut self, key: String, value: String) {
+        self.push(BatchOp::Set {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+            expires_at: None,
+        });
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.push(BatchOp::Remove {
+            key: key.into_bytes(),
+        });
+    }
+
+    pub fn push(&mut self, op: BatchOp) {
+        self.ops.push(op);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
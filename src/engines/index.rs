@@ -0,0 +1,129 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+/// How a `KvStore` indexes its keys in memory, see `KvStoreOptions`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexKind {
+    /// A hash map: the fastest lookups, but listing keys in order means sorting them all
+    #[default]
+    Hash,
+    /// A B-tree: keys are kept sorted, so `scan`, `range`, `first_key` and `last_key`
+    /// only go through the keys they return
+    Ordered,
+}
+
+/// The in-memory map of keys to the position of their value.
+pub(crate) enum Index<V> {
+    Hash(HashMap<Vec<u8>, V>),
+    Ordered(BTreeMap<Vec<u8>, V>),
+}
+
+impl<V> Index<V> {
+    pub(crate) fn new(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Hash => Index::Hash(HashMap::new()),
+            IndexKind::Ordered => Index::Ordered(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn kind(&self) -> IndexKind {
+        match self {
+            Index::Hash(_) => IndexKind::Hash,
+            Index::Ordered(_) => IndexKind::Ordered,
+        }
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&V> {
+        match self {
+            Index::Hash(map) => map.get(key),
+            Index::Ordered(map) => map.get(key),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: V) -> Option<V> {
+        match self {
+            Index::Hash(map) => map.insert(key, value),
+            Index::Ordered(map) => map.insert(key, value),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<V> {
+        match self {
+            Index::Hash(map) => map.remove(key),
+            Index::Ordered(map) => map.remove(key),
+        }
+    }
+
+    pub(crate) fn retain(&mut self, f: impl FnMut(&Vec<u8>, &mut V) -> bool) {
+        match self {
+            Index::Hash(map) => map.retain(f),
+            Index::Ordered(map) => map.retain(f),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        match self {
+            Index::Hash(map) => map.clear(),
+            Index::Ordered(map) => map.clear(),
+        }
+    }
+
+    /// Entries in key order for an ordered index, in arbitrary order otherwise.
+    pub(crate) fn iter(&self) -> Box<dyn Iterator<Item = (&Vec<u8>, &V)> + '_> {
+        match self {
+            Index::Hash(map) => Box::new(map.iter()),
+            Index::Ordered(map) => Box::new(map.iter()),
+        }
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub(crate) fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut V> + '_> {
+        match self {
+            Index::Hash(map) => Box::new(map.values_mut()),
+            Index::Ordered(map) => Box::new(map.values_mut()),
+        }
+    }
+
+    /// Entries of keys sorting after `after`, in key order.
+    ///
+    /// `None` for a hash index, which can't list keys in order.
+    pub(crate) fn range_after(
+        &self,
+        after: Option<&[u8]>,
+    ) -> Option<impl Iterator<Item = (&Vec<u8>, &V)>> {
+        match self {
+            Index::Hash(_) => None,
+            Index::Ordered(map) => {
+                let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+                Some(map.range::<[u8], _>((start, Bound::Unbounded)))
+            }
+        }
+    }
+
+    /// The smallest key whose entry `live` accepts.
+    pub(crate) fn first_key(&self, mut live: impl FnMut(&V) -> bool) -> Option<&Vec<u8>> {
+        match self {
+            Index::Hash(map) => map
+                .iter()
+                .filter(|(_, v)| live(v))
+                .map(|(key, _)| key)
+                .min(),
+            Index::Ordered(map) => map.iter().find(|(_, v)| live(v)).map(|(key, _)| key),
+        }
+    }
+
+    /// The largest key whose entry `live` accepts.
+    pub(crate) fn last_key(&self, mut live: impl FnMut(&V) -> bool) -> Option<&Vec<u8>> {
+        match self {
+            Index::Hash(map) => map
+                .iter()
+                .filter(|(_, v)| live(v))
+                .map(|(key, _)| key)
+                .max(),
+            Index::Ordered(map) => map.iter().rev().find(|(_, v)| live(v)).map(|(key, _)| key),
+        }
+    }
+}
@@ -1,16 +1,49 @@
-use crate::engines::KvsEngine;
-use crate::{Error, Result};
+use crate::checksum::crc32c;
+use crate::config;
+use crate::engines::index::{Index, IndexKind};
+use crate::engines::{from_unix_millis, unix_millis, utf8, KvsEngine};
+use crate::glob::glob_match;
+use crate::lz4;
+use crate::mmap::Mmap;
+use crate::random::random_fraction;
+use crate::{BatchOp, Clock, Error, Result, SystemClock, WriteBatch};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::{self, create_dir_all, File};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::fmt;
+use std::fs::{self, create_dir_all, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::process;
 use std::result;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
-const COMPACT_THRESHOLD: u64 = 1_000_000; // Compact when reaching the threshold
+const SEGMENT_SIZE: u64 = 1 << 20; // Default of "segment-size"
+const COMPACTION_RATIO: f64 = 0.5; // Default of "compaction-ratio"
+const READ_SAMPLE: u64 = 1000; // Gets per measurement of the read dispersion
+const READ_DISPERSION_THRESHOLD: f64 = 0.5; // Compact when more of the gets hit older data files
+const FORMAT_VERSION: u32 = 7; // Version of the on-disk format described by `KvStore::format_spec`
+const RECORD_HEADER_LEN: usize = 8; // Length and CRC32C of the payload preceding each record
+const COMPRESSED_FLAG: u32 = 1 << 31; // In the length of a record whose payload is compressed
+const COMPRESSION_MIN_SIZE: usize = 64; // Default of "compression-min-size"
+const LOG_EXTENSION: &str = "log"; // Data files are named `<file_id>.log`
+const COMPACT_TMP_EXTENSION: &str = "compact.tmp"; // Data file being written by compaction
+const RECOVERY_REPORT_FILE: &str = "recovery.json"; // Report of the last open that had to repair data
+const INDEX_SNAPSHOT_FILE: &str = "index.json"; // Index as of the last checkpoint, see `IndexSnapshot`
+const LOCK_FILE: &str = "LOCK"; // Locked by the process writing the store, holds its PID
+const FORMAT_FILE: &str = "FORMAT"; // `FORMAT_MAGIC` then the format version of the store
+const FORMAT_MAGIC: &[u8; 8] = b"kvstore\n";
+const COMPACTED_FILE: &str = "COMPACTED"; // File_id written by the last compaction, older data files are stale
 
-/// The `KvStore` stores string key/value pairs on disk.
+// Canonical paths of the stores open in this process
+static OPEN_STORES: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// The `KvStore` stores key/value pairs on disk, keys and values being bytes.
 ///
 /// Key/value pairs are persisted to disk in data files.
 ///
@@ -19,143 +52,548 @@ const COMPACT_THRESHOLD: u64 = 1_000_000; // Compact when reaching the threshold
 /// # Example
 ///
 /// ```rust
-/// use kvs::KvStore;
-/// use std::env::current_dir;
+/// use kvs::{KvStore, KvsEngine};
+/// use tempfile::TempDir;
 ///
-/// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut store = KvStore::open(temp_dir.path()).unwrap();
 /// store.set("key".to_string(), "value".to_string()).unwrap();
 /// let val = store.get("key".to_string()).unwrap();
 /// assert_eq!(val, Some("value".to_string()));
 /// store.remove("key".to_string()).unwrap();
 /// ```
 pub struct KvStore {
-    path: PathBuf,
-    index: HashMap<String, CommandPos>, // A map of keys to log pointers
+    files: DataFiles,
+    index: Index<CommandPos>, // A map of keys to log pointers
     readers: HashMap<u64, BufReaderWithPos<File>>, // A map of file_id to reader
-    writer: BufWriterWithPos<File>,     // Writer of active data file
-    active_file_id: u64,                // Active data file
-    uncompacted_size: u64,
+    maps: HashMap<u64, Mmap>, // Sealed data files mapped into memory, by file_id
+    mmap: bool,               // Whether sealed data files are read through `maps`
+    writer: BufWriterWithPos<File>, // Writer of active data file
+    active_file_id: u64,      // Active data file
+    segments: HashMap<u64, SegmentUsage>, // Usage of every data file, by file_id
+    segment_size: u64,        // The active data file is sealed once it is this large
+    compressor: Compressor,
+    compaction_ratio: f64, // A sealed data file is compacted once this share of it is stale
+    io_stats: IoStats,
+    recovery_report: Option<RecoveryReport>,
+    last_compaction: Option<SystemTime>,
+    clock: Arc<dyn Clock>,
+    _open_path: OpenPath,
+    _dir_lock: Option<DirLock>, // Unless read-only
+    reads: u64,                 // Gets in the current sample
+    old_segment_reads: u64, // Gets in the current sample served from older data files than the active one
+    read_verify_percent: f64, // Percentage of gets whose record is verified, see `read_verified`
+    corrupt_reads: u64,     // Gets that found their record corrupt
+    verify_cursor: (u64, u64), // Data file and offset `verify_data` continues from
+    verified_bytes: u64,
+    corrupt_records: HashSet<(u64, u64)>, // Data file and offset of the corruption found by `verify_data`
+    opened_from_snapshot: bool,
+    sync_policy: SyncPolicy,
+    background_sync: Option<BackgroundSync>, // With `SyncPolicy::EveryNMillis`
+    commits: u64,                            // Writes to the active data files so far
+    group_commit: bool, // With `SyncPolicy::Always`, writes are fsynced by `SharedKvStore`
+    read_only: bool,
 }
 
-impl KvStore {
-    fn compact(&mut self) -> Result<()> {
-        // Collect set command in index into new data file
-        let compaction_file_id = self.active_file_id + 1;
-        let mut compaction_writer =
-            new_data_file(&self.path, compaction_file_id, &mut self.readers)?;
-
-        let mut new_pos = 0;
-        for cmd_pos in self.index.values_mut() {
-            let reader = self.readers.get_mut(&cmd_pos.file_id).unwrap();
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            }
-            let mut entry_reader = reader.take(cmd_pos.size);
-            let n = io::copy(&mut entry_reader, &mut compaction_writer)?;
+/// How a `KvStore` is opened, see `KvStore::open_with` and `KvStore::builder`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// How keys are indexed in memory, a hash map by default
+    pub index: IndexKind,
+    /// More directories to spread the data files across, e.g. on other disks
+    ///
+    /// New data files go to the store's directory and these in turn. Data files are
+    /// found in any of them on open, so directories can be added between runs, but one
+    /// holding data files must stay listed.
+    pub data_dirs: Vec<PathBuf>,
+    /// When writes are fsynced, `SyncPolicy::Never` by default
+    pub sync: SyncPolicy,
+    /// Bytes the active data file is sealed at, 1 MiB by default, see "segment-size"
+    pub segment_size: u64,
+    /// Share of a sealed data file that has to be stale for compaction to rewrite it,
+    /// 0.5 by default, see "compaction-ratio"
+    pub compaction_ratio: f64,
+    /// Open the store without changing anything on disk, every write fails with
+    /// `Error::ReadOnly`
+    ///
+    /// Legacy data files aren't upgraded and torn writes aren't truncated, they are
+    /// skipped like damaged records. A store without data files can't be opened so.
+    pub read_only: bool,
+    /// How records are compressed, not at all by default, see "compression"
+    ///
+    /// Records of either kind are read whatever it is, so it can change between runs.
+    pub compression: Compression,
+    /// Records whose JSON is shorter than this many bytes aren't compressed, 64 by
+    /// default, see "compression-min-size"
+    pub compression_min_size: usize,
+    /// Serve gets from sealed data files through memory maps instead of a seek and a read,
+    /// on Unix by default
+    ///
+    /// The active data file is always read through a buffered reader, as are all data
+    /// files where memory maps aren't supported.
+    pub mmap: bool,
+}
 
-            // Update index map
-            *cmd_pos = CommandPos {
-                file_id: compaction_file_id,
-                pos: new_pos,
-                size: n,
-            };
-            new_pos += n;
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            index: IndexKind::default(),
+            data_dirs: vec![],
+            sync: SyncPolicy::default(),
+            segment_size: SEGMENT_SIZE,
+            compaction_ratio: COMPACTION_RATIO,
+            read_only: false,
+            compression: Compression::default(),
+            compression_min_size: COMPRESSION_MIN_SIZE,
+            mmap: cfg!(unix),
         }
-        compaction_writer.flush()?;
+    }
+}
 
-        // remove stale data files.
-        let stale_files: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&file_id| file_id < compaction_file_id)
-            .cloned()
-            .collect();
-        for stale_file_id in stale_files {
-            self.readers.remove(&stale_file_id);
-            fs::remove_file(log_path(&self.path, stale_file_id))?;
+/// Options of a `KvStore` set one by one, see `KvStore::builder`.
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreBuilder {
+    options: KvStoreOptions,
+}
+
+impl KvStoreBuilder {
+    /// See `KvStoreOptions::index`.
+    pub fn index(mut self, index: IndexKind) -> Self {
+        self.options.index = index;
+        self
+    }
+
+    /// Add a directory to spread the data files across, see `KvStoreOptions::data_dirs`.
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.data_dirs.push(dir.into());
+        self
+    }
+
+    /// See `KvStoreOptions::sync`.
+    pub fn sync_policy(mut self, sync: SyncPolicy) -> Self {
+        self.options.sync = sync;
+        self
+    }
+
+    /// See `KvStoreOptions::segment_size`.
+    pub fn segment_size(mut self, bytes: u64) -> Self {
+        self.options.segment_size = bytes;
+        self
+    }
+
+    /// See `KvStoreOptions::compaction_ratio`.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.options.compaction_ratio = ratio;
+        self
+    }
+
+    /// See `KvStoreOptions::read_only`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// See `KvStoreOptions::compression`.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    /// See `KvStoreOptions::compression_min_size`.
+    pub fn compression_min_size(mut self, bytes: usize) -> Self {
+        self.options.compression_min_size = bytes;
+        self
+    }
+
+    /// See `KvStoreOptions::mmap`.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.options.mmap = mmap;
+        self
+    }
+
+    /// Open the store at `path` with these options, see `KvStore::open_with`.
+    pub fn open(self, path: impl AsRef<Path>) -> Result<KvStore> {
+        KvStore::open_with(path, self.options)
+    }
+}
+
+/// When a `KvStore` fsyncs the writes to its data files, see `KvStoreOptions::sync`.
+///
+/// Writes are always flushed to the OS before they return, so they survive a crash of
+/// the process whatever the policy; the policy is about a crash of the machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Fsync every write before it returns, nothing acknowledged can be lost
+    ///
+    /// Threads writing to a `SharedKvStore` at the same time share fsyncs.
+    Always,
+    /// Fsync in the background every this many milliseconds, the writes of about that
+    /// long can be lost
+    EveryNMillis(u64),
+    /// Leave it to the OS, unless `KvsEngine::sync` is called
+    #[default]
+    Never,
+}
+
+/// "always", "never", or a number of milliseconds for `SyncPolicy::EveryNMillis`.
+impl FromStr for SyncPolicy {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            millis => Ok(SyncPolicy::EveryNMillis(millis.parse()?)),
         }
+    }
+}
 
-        self.active_file_id += 2;
-        self.writer = new_data_file(&self.path, self.active_file_id, &mut self.readers)?;
-        self.uncompacted_size = 0;
+/// How a `KvStore` compresses the JSON of its records, see `KvStoreOptions::compression`.
+///
+/// Values of a few dozen bytes and more, JSON documents in particular, typically shrink
+/// by half. A record is only written compressed if that makes it shorter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// The LZ4 block format, fast enough not to slow writes down
+    Lz4,
+}
 
-        Ok(())
+/// "none" or "lz4".
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(Error::InvalidConfig("compression".to_owned(), s.to_owned())),
+        }
     }
 }
 
-impl KvsEngine for KvStore {
-    /// Open the KvStore at a given path.
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+        })
+    }
+}
+
+/// I/O accounting of a `KvStore` since it was opened.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoStats {
+    /// Bytes of log records written by set and remove
+    pub user_bytes_written: u64,
+    /// Bytes rewritten by compaction
+    pub compaction_bytes_written: u64,
+    /// Number of fsyncs
+    pub fsyncs: u64,
+    /// Total time spent in fsyncs
+    pub fsync_time: Duration,
+}
+
+impl IoStats {
+    /// Write amplification factor: bytes written to disk per byte written by users.
     ///
-    /// # Errors
+    /// It is 1.0 before anything has been written.
+    pub fn write_amplification(&self) -> f64 {
+        if self.user_bytes_written == 0 {
+            1.0
+        } else {
+            (self.user_bytes_written + self.compaction_bytes_written) as f64
+                / self.user_bytes_written as f64
+        }
+    }
+}
+
+/// What `KvStore::open` had to skip to open a store that wasn't in a clean state.
+///
+/// When there is anything to report, it is also logged and written to `recovery.json`
+/// in the store's directory, where it stays until the next such open.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryReport {
+    /// When the store was opened, in milliseconds since the UNIX epoch
+    pub opened_at: u64,
+    /// Data files whose end couldn't be read, and was ignored
+    pub skipped_tails: Vec<SkippedTail>,
+    /// Remove records of keys without an earlier Set record, they are ignored
     ///
-    /// It propagates I/O or serialization errors during writing the log.
+    /// Compacting a data file drops the records that remove records in newer ones are for,
+    /// so these alone aren't a sign of lost data.
+    pub orphan_removes: u64,
+}
+
+/// The unreadable end of a data file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SkippedTail {
+    pub file_id: u64,
+    /// Offset of the first record that couldn't be read
+    pub offset: u64,
+    pub bytes: u64,
+    pub error: String,
+    /// Whether the data file was truncated at `offset`, as done for a torn write at the end
+    /// of the newest data file
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+impl RecoveryReport {
+    /// Whether anything was skipped.
+    pub fn is_clean(&self) -> bool {
+        self.skipped_tails.is_empty()
+    }
+}
+
+/// Machine-readable description of the on-disk format of `KvStore`.
+///
+/// It is generated from the encoder itself, see `KvStore::format_spec`.
+#[derive(Debug, Serialize)]
+pub struct FormatSpec {
+    pub version: u32,
+    pub header: HeaderSpec,
+    pub segment: SegmentSpec,
+    pub records: Vec<RecordSpec>,
+}
+
+/// Layout of the file recording the format version of a store.
+#[derive(Debug, Serialize)]
+pub struct HeaderSpec {
+    pub file_name: &'static str,
+    /// The magic number the file starts with, as an escaped string
+    pub magic: String,
+    pub layout: &'static str,
+}
+
+/// Layout of a data file (segment).
+#[derive(Debug, Serialize)]
+pub struct SegmentSpec {
+    /// File name of a segment, `{file_id}` stands for its decimal id
+    pub file_name: String,
+    pub ordering: &'static str,
+    pub encoding: &'static str,
+    pub framing: &'static str,
+    pub compression: &'static str,
+}
+
+/// Layout of one kind of log record.
+#[derive(Debug, Serialize)]
+pub struct RecordSpec {
+    pub kind: &'static str,
+    /// First byte of the payload of a record of this kind
+    pub tag: u8,
+    /// In the order they are encoded in
+    pub fields: Vec<FieldSpec>,
+    /// The payload of a record of this kind, as written by the encoder, in hexadecimal
+    pub example: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldSpec {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub ty: &'static str,
+}
+
+impl KvStore {
+    /// Options to open a store with, one by one.
     ///
     /// # Example
     ///
     /// ```rust
-    /// use kvs::KvStore;
-    /// use std::env::current_dir;
+    /// use kvs::{KvStore, KvsEngine, SyncPolicy};
+    /// use tempfile::TempDir;
     ///
-    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::builder()
+    ///     .segment_size(64 * 1024)
+    ///     .compaction_ratio(0.25)
+    ///     .sync_policy(SyncPolicy::Always)
+    ///     .open(temp_dir.path())
+    ///     .unwrap();
+    /// store.set("key".to_string(), "value".to_string()).unwrap();
     /// ```
-    fn open(path: impl AsRef<Path>) -> Result<Self> {
-        // let path: PathBuf = path.into();
-        create_dir_all(&path)?;
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
 
-        let mut readers = HashMap::new();
-        let mut index = HashMap::new();
+    /// Describe the current on-disk format.
+    ///
+    /// Record layouts are derived from the fields sample commands are encoded from when
+    /// writing the log, so the description can't drift from the code.
+    pub fn format_spec() -> FormatSpec {
+        let records = Command::samples()
+            .iter()
+            .map(|command| RecordSpec {
+                kind: command.kind(),
+                tag: command.tag(),
+                fields: command
+                    .fields()
+                    .iter()
+                    .map(|(name, field)| FieldSpec {
+                        name,
+                        ty: field.type_name(),
+                    })
+                    .collect(),
+                example: command
+                    .encode()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect(),
+            })
+            .collect();
 
-        let file_list = sorted_file_list(&path)?;
+        FormatSpec {
+            version: FORMAT_VERSION,
+            header: HeaderSpec {
+                file_name: FORMAT_FILE,
+                magic: FORMAT_MAGIC.escape_ascii().to_string(),
+                layout: "the magic then the format version as a little-endian u32; stores without it are from before version 6 and are migrated when opened for writing, those of version 6 are read as they are, stores of a newer version are refused",
+            },
+            segment: SegmentSpec {
+                file_name: format!("{{file_id}}.{}", LOG_EXTENSION),
+                ordering: "segments are replayed in ascending file_id order, the last record of a key wins",
+                encoding: "a payload is the tag of its kind of record then its fields: a varint is an unsigned LEB128 integer, bytes their length as a varint then the bytes, an optional varint 0 if absent or 1 then the varint; a payload starting with '{' is a JSON record {\"<kind>\": {<fields>}}, as written before version 6, whose bytes are strings",
+                framing: "each record is its payload preceded by the length of the payload and its CRC32C, as little-endian u32s",
+                compression: "with the high bit of the length set, the payload is replaced by its length as a little-endian u32 then its LZ4 block, which the length and CRC32C are of",
+            },
+            records,
+        }
+    }
 
-        let mut uncompacted_size = 0;
+    /// When writes are fsynced.
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
 
-        for &file_id in &file_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, file_id))?);
-            // rebuild index
-            uncompacted_size += load_index(file_id, &mut reader, &mut index)?;
+    /// How keys are indexed in memory.
+    pub fn index_kind(&self) -> IndexKind {
+        self.index.kind()
+    }
 
-            readers.insert(file_id, reader);
-        }
+    /// Returns an iterator over all keys, in order with an ordered index,
+    /// in arbitrary order otherwise.
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        let now = unix_millis(self.clock.now());
+        self.index
+            .iter()
+            .filter(move |(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .map(|(key, _)| key.as_slice())
+    }
 
-        // Create new log file(active data file) and its writer
-        let active_file_id = (file_list.len() + 1) as u64;
-        let writer = new_data_file(&path, active_file_id, &mut readers)?;
+    /// The smallest key, `None` if the store is empty.
+    ///
+    /// It goes through all keys unless the index is ordered.
+    pub fn first_key(&self) -> Option<&[u8]> {
+        let now = unix_millis(self.clock.now());
+        self.index
+            .first_key(|cmd_pos| !cmd_pos.is_expired(now))
+            .map(Vec::as_slice)
+    }
 
-        let path = path.as_ref().to_path_buf();
-        Ok(KvStore {
-            path,
-            index,
-            readers,
-            writer,
-            active_file_id,
-            uncompacted_size,
-        })
+    /// The largest key, `None` if the store is empty.
+    ///
+    /// It goes through all keys unless the index is ordered.
+    pub fn last_key(&self) -> Option<&[u8]> {
+        let now = unix_millis(self.clock.now());
+        self.index
+            .last_key(|cmd_pos| !cmd_pos.is_expired(now))
+            .map(Vec::as_slice)
     }
 
-    /// Inserts a key-value pair into the kvstore.
+    /// Returns the I/O accounting since the store was opened.
+    pub fn io_stats(&self) -> IoStats {
+        let mut io_stats = self.io_stats.clone();
+        if let Some(background) = &self.background_sync {
+            io_stats.fsyncs += background.state.fsyncs.load(Ordering::Relaxed);
+            io_stats.fsync_time +=
+                Duration::from_nanos(background.state.fsync_nanos.load(Ordering::Relaxed));
+        }
+        io_stats
+    }
+
+    /// What had to be skipped to open the store, `None` if it was opened cleanly.
+    pub fn recovery_report(&self) -> Option<&RecoveryReport> {
+        self.recovery_report.as_ref()
+    }
+
+    /// Erase every version of the keys matching a glob-style `pattern` from disk.
+    /// Keys that aren't UTF-8 are matched with their invalid bytes replaced by U+FFFD.
     ///
-    /// If the map did have this key present, the value will be updated.
+    /// Unlike `remove`, this doesn't leave a tombstone behind: matching keys are dropped
+    /// from the index and all data files are rewritten, so neither the current value nor
+    /// any historical value of those keys remains in the log. Should the process die before
+    /// every older data file is deleted, the next open deletes the rest.
+    ///
+    /// Returns the number of live keys that were erased.
     ///
     /// # Errors
     ///
-    /// It propagates I/O or serialization errors during writing the log.
+    /// It propagates I/O errors during rewriting the data files.
     ///
     /// # Example
     ///
     /// ```rust
-    /// use kvs::KvStore;
-    /// use std::env::current_dir;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
     ///
-    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
-    /// store.set("key".to_string(), "value".to_string()).unwrap();
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("user:1".to_string(), "alice".to_string()).unwrap();
+    /// store.set("user:2".to_string(), "bob".to_string()).unwrap();
+    /// assert_eq!(store.scrub("user:*").unwrap(), 2);
+    /// assert_eq!(store.get("user:1".to_string()).unwrap(), None);
     /// ```
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    pub fn scrub(&mut self, pattern: &str) -> Result<usize> {
+        self.check_writable()?;
+        let matched_keys: Vec<Vec<u8>> = self
+            .index
+            .keys()
+            .filter(|key| glob_match(pattern, &String::from_utf8_lossy(key)))
+            .cloned()
+            .collect();
+        for key in &matched_keys {
+            self.index.remove(key);
+        }
+
+        // Compaction only copies what is still in the index and deletes every older data file,
+        // which drops both the live and the historical records of the erased keys.
+        self.compact()?;
+
+        Ok(matched_keys.len())
+    }
+
+    /// Index entry of a key, unless the key has expired.
+    fn live_entry(&self, key: &[u8]) -> Option<&CommandPos> {
+        let now = unix_millis(self.clock.now());
+        self.index
+            .get(key)
+            .filter(|cmd_pos| !cmd_pos.is_expired(now))
+    }
+
+    /// Writes fail on a store opened with `KvStoreOptions::read_only`.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn write_set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.check_writable()?;
         let pos = self.writer.pos;
 
         // Write log to file, store key/command position pair in index
-        let command = Command::Set { key, value };
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
+        let command = Command::Set {
+            key,
+            value,
+            expires_at,
+        };
+        write_record(&mut self.writer, &command, self.compressor)?;
+        self.commit()?;
+        self.io_stats.user_bytes_written += self.writer.pos - pos;
 
         // Insert new entry in index
         if let Command::Set { key, .. } = command {
@@ -165,108 +603,1802 @@ impl KvsEngine for KvStore {
                     file_id: self.active_file_id,
                     pos,
                     size: self.writer.pos - pos,
+                    expires_at,
+                    renamed: false,
                 },
             ) {
-                self.uncompacted_size += old_cmd.size;
+                self.add_stale(old_cmd.file_id, old_cmd.size);
             }
         }
 
-        // If uncompacted_size > COMPACT_THRESHOLD, then compact
-        if self.uncompacted_size > COMPACT_THRESHOLD {
-            self.compact()?;
+        self.maintain_segments()
+    }
+
+    /// Write `commands` as a batch to the active data file.
+    ///
+    /// Returns the position and size of every record.
+    fn write_records(&mut self, commands: &[Command]) -> Result<Vec<(u64, u64)>> {
+        let count = commands.len() as u64;
+        write_record(&mut self.writer, &Command::Batch { count }, self.compressor)?;
+        let mut positions = vec![];
+        for command in commands {
+            let pos = self.writer.pos;
+            write_record(&mut self.writer, command, self.compressor)?;
+            positions.push((pos, self.writer.pos - pos));
         }
+        self.commit()?;
+        Ok(positions)
+    }
 
+    /// Flush the writes to the active data file, and fsync them as the sync policy says.
+    fn commit(&mut self) -> Result<()> {
+        self.commits += 1;
+        if self.sync_policy == SyncPolicy::Always && !self.group_commit {
+            self.writer.sync(&mut self.io_stats)?;
+        } else {
+            self.writer.flush()?;
+        }
+        if let Some(background) = &self.background_sync {
+            background.state.dirty.store(true, Ordering::Release);
+        }
         Ok(())
     }
 
-    /// Get the string value of a given string key.
-    ///
-    /// Returns `OK(None)` if the given key does not exist.
+    /// Seal the active data file, and start writing to a new one `file_id`.
+    fn new_active_file(&mut self, file_id: u64) -> Result<()> {
+        // Group commits only fsync the active data file
+        if self.group_commit {
+            self.writer.sync(&mut self.io_stats)?;
+        }
+        if let Some(usage) = self.segments.get_mut(&self.active_file_id) {
+            usage.len = self.writer.pos;
+        }
+        // Unless compaction just deleted it
+        if self.readers.contains_key(&self.active_file_id) {
+            self.writer.flush()?;
+            self.map_segment(self.active_file_id)?;
+        }
+        self.active_file_id = file_id;
+        self.writer = new_data_file(&mut self.files, file_id, &mut self.readers)?;
+        self.segments.insert(file_id, SegmentUsage::default());
+        if let Some(background) = &self.background_sync {
+            background.replace_file(self.writer.file().try_clone()?)?;
+        }
+        Ok(())
+    }
+
+    /// Account `bytes` of data file `file_id` as stale.
+    fn add_stale(&mut self, file_id: u64, bytes: u64) {
+        self.segments.entry(file_id).or_default().stale += bytes;
+    }
+
+    /// Seal the active data file once it reaches the segment size, and compact the sealed
+    /// ones whose share of stale bytes exceeds the compaction ratio.
+    fn maintain_segments(&mut self) -> Result<()> {
+        if self.writer.pos >= self.segment_size {
+            self.new_active_file(self.active_file_id + 1)?;
+        }
+        if self.compaction_ratio >= 1.0 {
+            return Ok(());
+        }
+        let mut stale_files: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|&(&file_id, usage)| {
+                file_id != self.active_file_id
+                    && usage.stale as f64 > usage.len as f64 * self.compaction_ratio
+            })
+            .map(|(&file_id, _)| file_id)
+            .collect();
+        stale_files.sort_unstable();
+        for file_id in stale_files {
+            self.compact_segment(file_id)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite sealed data file `file_id` without its stale records. It keeps its file_id,
+    /// so the data files are still replayed in the order they were written.
     ///
-    /// # Errors
+    /// While older data files may hold records of a removed or expired key, a remove record
+    /// of it is kept. A data file left with nothing is deleted.
+    fn compact_segment(&mut self, file_id: u64) -> Result<()> {
+        let now = unix_millis(self.clock.now());
+        let oldest = self.readers.keys().all(|&id| id >= file_id);
+        let path = self.files.path(file_id).to_owned();
+        let tmp_path = path.with_extension(COMPACT_TMP_EXTENSION);
+        let mut writer = BufWriterWithPos::new(File::create(&tmp_path)?);
+
+        let reader = self.readers.get_mut(&file_id).unwrap();
+        let len = reader.seek(SeekFrom::End(0))?;
+        let mut pos = reader.seek(SeekFrom::Start(0))?;
+        // New position and size of the live records, by their old position
+        let mut moved = HashMap::new();
+        while pos < len {
+            let (cmd, size) = match read_record(reader, &path, pos) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                // The rest of the file was skipped on open, unless it was damaged since
+                Err(e @ Error::Corruption { .. }) => {
+                    let damaged_since = self
+                        .index
+                        .iter()
+                        .any(|(_, cmd_pos)| cmd_pos.file_id == file_id && cmd_pos.pos >= pos);
+                    if !damaged_since {
+                        break;
+                    }
+                    // Left as it is, its live records are still readable
+                    error!("Data file {:?} can't be compacted: {}", path, e);
+                    drop(writer);
+                    fs::remove_file(&tmp_path)?;
+                    self.segments.entry(file_id).or_default().stale = 0;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+
+            // Keys whose older records have to stay removed
+            let mut removes = vec![];
+            match cmd {
+                Command::Set { ref key, .. } | Command::Rename { to: ref key, .. }
+                    if self.index.get(key).is_some_and(|cmd_pos| {
+                        cmd_pos.file_id == file_id && cmd_pos.pos == pos
+                    }) =>
+                {
+                    if self.index.get(key).unwrap().is_expired(now) {
+                        let key = key.clone();
+                        self.index.remove(&key);
+                        removes.push(key);
+                        if let Command::Rename { from, .. } = cmd {
+                            removes.push(from);
+                        }
+                    } else {
+                        let new_pos = writer.pos;
+                        write_record(&mut writer, &cmd, self.compressor)?;
+                        moved.insert(pos, (new_pos, writer.pos - new_pos));
+                    }
+                }
+                // A stale rename still removed `from`
+                Command::Rename { from, .. } => removes.push(from),
+                Command::Remove { key } => removes.push(key),
+                // Batch records only matter until their batch is complete
+                Command::Set { .. } | Command::Batch { .. } => {}
+            }
+            if !oldest {
+                for key in removes {
+                    if self.index.get(&key).is_none() {
+                        write_record(&mut writer, &Command::Remove { key }, self.compressor)?;
+                    }
+                }
+            }
+            pos += size;
+        }
+
+        IndexSnapshot::remove(self.files.store_dir())?;
+        if writer.pos == 0 {
+            drop(writer);
+            fs::remove_file(&tmp_path)?;
+            self.readers.remove(&file_id);
+            self.maps.remove(&file_id);
+            self.segments.remove(&file_id);
+            self.files.remove(file_id)?;
+        } else {
+            // The compacted data must be durable before it replaces the only other copy
+            writer.sync(&mut self.io_stats)?;
+            let new_len = writer.pos;
+            drop(writer);
+            fs::rename(&tmp_path, &path)?;
+            self.readers
+                .insert(file_id, BufReaderWithPos::new(File::open(&path)?));
+            self.map_segment(file_id)?;
+            self.io_stats.compaction_bytes_written += new_len;
+            self.segments.insert(
+                file_id,
+                SegmentUsage {
+                    len: new_len,
+                    stale: 0,
+                },
+            );
+        }
+        sync_dir(path.parent().unwrap())?;
+
+        for cmd_pos in self.index.values_mut() {
+            if cmd_pos.file_id == file_id {
+                (cmd_pos.pos, cmd_pos.size) = moved[&cmd_pos.pos];
+            }
+        }
+        if self.verify_cursor.0 == file_id {
+            self.verify_cursor.1 = 0;
+        }
+        self.last_compaction = Some(self.clock.now());
+        Ok(())
+    }
+
+    /// Map sealed data file `file_id` into memory for gets, with `KvStoreOptions::mmap`.
     ///
-    /// It returns `KvsError::UnexpectedCommand` if the given command type unexpected.
+    /// It replaces the map of an older version of the file, e.g. before compaction.
+    fn map_segment(&mut self, file_id: u64) -> Result<()> {
+        self.maps.remove(&file_id);
+        if self.mmap {
+            if let Some(map) = Mmap::map(&File::open(self.files.path(file_id))?)? {
+                self.maps.insert(file_id, map);
+            }
+        }
+        Ok(())
+    }
+
+    /// Account a get served from data file `file_id`.
     ///
-    /// It propagates I/O or serialization errors during writing the log.
+    /// Compacts when most gets of a sample had to go to older data files, even if there
+    /// isn't much dead data: update-heavy workloads spread live keys over many data files.
+    fn record_read(&mut self, file_id: u64) -> Result<()> {
+        self.reads += 1;
+        if file_id != self.active_file_id {
+            self.old_segment_reads += 1;
+        }
+        if self.reads < READ_SAMPLE || self.read_only {
+            return Ok(());
+        }
+
+        let dispersion = self.old_segment_reads as f64 / self.reads as f64;
+        self.reads = 0;
+        self.old_segment_reads = 0;
+        // Compaction merges the older data files into one, which only helps if there are more
+        // than the live records fill
+        let live_bytes: u64 = self
+            .segments
+            .values()
+            .map(|usage| usage.len.saturating_sub(usage.stale))
+            .sum();
+        let needed_files = 2 + live_bytes / self.segment_size.max(1);
+        if dispersion > READ_DISPERSION_THRESHOLD && self.readers.len() as u64 > needed_files {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Read the record at `cmd_pos`, a damaged one is logged and counted in "corrupt_reads".
     ///
-    /// # Example
+    /// Returns the command and the size of the record.
+    fn read_at(&mut self, cmd_pos: &CommandPos) -> Result<(Command, u64)> {
+        let path = self.files.path(cmd_pos.file_id);
+        let record = if let Some(map) = self.maps.get(&cmd_pos.file_id) {
+            let mut record = map.get(cmd_pos.pos as usize..).unwrap_or_default();
+            read_record(&mut record, path, cmd_pos.pos)
+        } else {
+            let reader = self.readers.get_mut(&cmd_pos.file_id).unwrap();
+            if reader.pos != cmd_pos.pos {
+                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            }
+            read_record(reader, path, cmd_pos.pos)
+        };
+        let e = match record {
+            Ok(Some(record)) => return Ok(record),
+            Ok(None) => Error::Corruption {
+                file: path.to_owned(),
+                offset: cmd_pos.pos,
+            },
+            Err(e @ Error::Corruption { .. }) => e,
+            Err(e) => return Err(e),
+        };
+        self.corrupt_reads += 1;
+        error!("{}", e);
+        Err(e)
+    }
+
+    /// Read the record of `key` at `cmd_pos` and check it against the index: it must be
+    /// exactly `cmd_pos.size` long, holding the key and its expiration time.
     ///
-    /// ```rust
-    /// use kvs::KvStore;
-    /// use std::env::current_dir;
+    /// Checksums catch damaged records, this also catches a disk returning another intact
+    /// record than the one written there.
+    fn read_verified(&mut self, key: &[u8], cmd_pos: &CommandPos) -> Result<Command> {
+        let (cmd, size) = self.read_at(cmd_pos)?;
+        let intact = size == cmd_pos.size
+            && match &cmd {
+                Command::Set {
+                    key: cmd_key,
+                    expires_at,
+                    ..
+                }
+                | Command::Rename {
+                    to: cmd_key,
+                    expires_at,
+                    ..
+                } => cmd_key == key && *expires_at == cmd_pos.expires_at,
+                _ => false,
+            };
+        if intact {
+            return Ok(cmd);
+        }
+        self.corrupt_reads += 1;
+        let key = String::from_utf8_lossy(key).into_owned();
+        error!(
+            "Corrupt record of key {:?} in {:?} at offset {}",
+            key,
+            self.files.path(cmd_pos.file_id),
+            cmd_pos.pos
+        );
+        Err(Error::CorruptRecord(key))
+    }
+
+    /// Check the records of data file `file_id` from offset `from`, for about `max_bytes`.
     ///
-    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
-    /// store.set("key".to_string(), "value".to_string()).unwrap();
-    /// let val = store.get("key".to_string()).unwrap();
-    /// assert_eq!(val, Some("value".to_string()));
-    /// ```
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        // Find given key in index, and load command from data file
-        if let Some(CommandPos {
-            file_id,
-            pos,
-            size: _,
-        }) = self.index.get(&key)
-        {
-            let reader = self.readers.get_mut(&file_id).unwrap();
-            reader.seek(SeekFrom::Start(*pos))?;
-            let mut a = serde_json::Deserializer::from_reader(reader);
-            let cmd = Command::deserialize(&mut a)?;
-            if let Command::Set { value, .. } = cmd {
-                Ok(Some(value))
-            } else {
-                Err(Error::UnexpectedCommand)
+    /// Returns the offset it stopped at, and whether that is the end of the file.
+    fn verify_file(&mut self, file_id: u64, from: u64, max_bytes: u64) -> Result<(u64, bool)> {
+        let reader = self.readers.get_mut(&file_id).unwrap();
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(from))?;
+        let path = self.files.path(file_id);
+
+        let mut pos = from;
+        let mut broken = None;
+        // Key, size and expiration time of the records setting a key, by offset
+        let mut records = HashMap::new();
+        while pos < len && pos - from < max_bytes {
+            let (cmd, size) = match read_record(reader, path, pos) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e @ Error::Corruption { .. }) => {
+                    broken = Some(e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let new_pos = pos + size;
+            if let Command::Set {
+                key, expires_at, ..
+            }
+            | Command::Rename {
+                to: key,
+                expires_at,
+                ..
+            } = cmd
+            {
+                records.insert(pos, (key, new_pos - pos, expires_at));
+            }
+            pos = new_pos;
+        }
+
+        // Every live record read must be found where the index has it
+        let damaged: Vec<(Vec<u8>, u64)> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.file_id == file_id && (from..pos).contains(&cmd_pos.pos))
+            .filter(|(key, cmd_pos)| {
+                records.get(&cmd_pos.pos)
+                    != Some(&((*key).clone(), cmd_pos.size, cmd_pos.expires_at))
+            })
+            .map(|(key, cmd_pos)| (key.clone(), cmd_pos.pos))
+            .collect();
+        for (key, record_pos) in damaged {
+            if self.corrupt_records.insert((file_id, record_pos)) {
+                error!(
+                    "Corrupt record of key {:?} in {:?} at offset {}, it can't be repaired",
+                    String::from_utf8_lossy(&key),
+                    self.files.path(file_id),
+                    record_pos
+                );
+            }
+        }
+
+        // The length of a damaged record can't be trusted, so the rest of the file is lost
+        if let Some(e) = broken {
+            if self.corrupt_records.insert((file_id, pos)) {
+                let lost: Vec<_> = self
+                    .index
+                    .iter()
+                    .filter(|(_, cmd_pos)| cmd_pos.file_id == file_id && cmd_pos.pos >= pos)
+                    .map(|(key, _)| String::from_utf8_lossy(key))
+                    .collect();
+                error!(
+                    "{}, the records of keys {:?} from there can't be repaired",
+                    e, lost
+                );
             }
+            return Ok((len, true));
+        }
+        Ok((pos, pos >= len))
+    }
+
+    /// Read the value of `key` from its record at `cmd_pos`, verifying a sample of reads.
+    fn read_value(&mut self, key: &[u8], cmd_pos: &CommandPos) -> Result<Vec<u8>> {
+        let verify =
+            self.read_verify_percent > 0.0 && random_fraction() * 100.0 < self.read_verify_percent;
+        let cmd = if verify {
+            self.read_verified(key, cmd_pos)?
         } else {
-            Ok(None)
+            self.read_at(cmd_pos)?.0
+        };
+        if let Command::Set { value, .. } | Command::Rename { value, .. } = cmd {
+            Ok(value)
+        } else {
+            Err(Error::UnexpectedCommand)
         }
     }
+}
 
-    /// Remove a given key.
+impl KvsEngine for KvStore {
+    type Options = KvStoreOptions;
+
+    /// Open the KvStore at a given path with `options`, see also `KvStore::builder`.
     ///
-    ///  # Errors
+    /// # Errors
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// It returns `Error::InvalidConfig` for a compaction ratio outside of 0 to 1,
+    /// `Error::AlreadyLocked` if another process is writing the store, and propagates I/O
+    /// or serialization errors during reading the log.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{IndexKind, KvStore, KvStoreOptions, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let options = KvStoreOptions {
+    ///     index: IndexKind::Ordered,
+    ///     ..KvStoreOptions::default()
+    /// };
+    /// let mut store = KvStore::open_with(temp_dir.path(), options).unwrap();
+    /// for key in ["c", "a", "b"] {
+    ///     store.set(key.to_string(), "value".to_string()).unwrap();
+    /// }
+    /// assert!(store.keys().eq([b"a", b"b", b"c"]));
+    /// assert_eq!(store.first_key().unwrap(), b"a");
+    /// assert_eq!(store.last_key().unwrap(), b"c");
+    /// ```
+    fn open_with(path: impl AsRef<Path>, options: KvStoreOptions) -> Result<Self> {
+        if !(0.0..=1.0).contains(&options.compaction_ratio) {
+            return Err(Error::InvalidConfig(
+                "compaction-ratio".to_owned(),
+                options.compaction_ratio.to_string(),
+            ));
+        }
+        let read_only = options.read_only;
+        if !read_only {
+            create_dir_all(&path)?;
+        }
+        let open_path = OpenPath::register(fs::canonicalize(&path)?)?;
+        let dir_lock = if read_only {
+            None
+        } else {
+            DirLock::acquire(path.as_ref())?
+        };
+
+        let mut readers = HashMap::new();
+        let mut maps = HashMap::new();
+        let mut index = Index::new(options.index);
+
+        let mut dirs = vec![path.as_ref().to_path_buf()];
+        dirs.extend(options.data_dirs);
+        let mut files = DataFiles::open(dirs, read_only)?;
+        // Left behind by a compaction the process died in the middle of
+        if let Some(compacted) = read_compacted_file_id(path.as_ref())? {
+            let stale = files.remove_older(compacted, read_only)?;
+            if stale > 0 && !read_only {
+                files.sync_dirs()?;
+                warn!(
+                    "Removed {} data files left by an interrupted compaction",
+                    stale
+                );
+            }
+        }
+        let file_list = files.ids();
+
+        // Stores from before version 6 have no format file
+        let version = read_format_version(path.as_ref())?;
+        if let Some(version) = version.filter(|&version| version > FORMAT_VERSION) {
+            return Err(Error::UnsupportedFormat {
+                path: path.as_ref().to_owned(),
+                version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        // Version 6 records are those of version 7 whose bytes are UTF-8, they are kept
+        if !read_only && version.is_none() {
+            let compressor = Compressor {
+                compression: options.compression,
+                min_size: options.compression_min_size,
+            };
+            for &file_id in &file_list {
+                migrate_data_file(files.path(file_id), compressor)?;
+            }
+            if !file_list.is_empty() {
+                // Its offsets are those of the old records
+                IndexSnapshot::remove(path.as_ref())?;
+                warn!(
+                    "Migrated {} data files to format version {}",
+                    file_list.len(),
+                    FORMAT_VERSION
+                );
+            }
+        }
+        if !read_only && version != Some(FORMAT_VERSION) {
+            write_format_version(path.as_ref())?;
+        }
+
+        let mut segments: HashMap<u64, SegmentUsage> = HashMap::new();
+        let mut report = RecoveryReport {
+            opened_at: unix_millis(SystemTime::now()),
+            ..RecoveryReport::default()
+        };
+
+        // Only the records written since the snapshot are replayed
+        let snapshot = IndexSnapshot::load(path.as_ref(), &files);
+        let mut covered = HashMap::new();
+        let opened_from_snapshot = snapshot.is_some();
+        if let Some(snapshot) = snapshot {
+            covered.extend(snapshot.files);
+            for (file_id, stale) in snapshot.stale {
+                segments.entry(file_id).or_default().stale = stale;
+            }
+            for IndexEntry(key, cmd_pos) in snapshot.entries {
+                index.insert(key, cmd_pos);
+            }
+        }
+
+        for &file_id in &file_list {
+            let mut reader = BufReaderWithPos::new(File::open(files.path(file_id))?);
+            // rebuild index
+            let from = covered.get(&file_id).copied().unwrap_or(0);
+            let stale = load_index(
+                file_id,
+                files.path(file_id),
+                from,
+                &mut reader,
+                &mut index,
+                &mut report,
+                // Only the newest data file was being written to
+                !read_only && Some(&file_id) == file_list.last(),
+            )?;
+            for (file_id, bytes) in stale {
+                segments.entry(file_id).or_default().stale += bytes;
+            }
+            segments.entry(file_id).or_default().len = reader.seek(SeekFrom::End(0))?;
+
+            // Opened read-only, the newest data file may still be written by another process
+            let sealed = !read_only || Some(&file_id) != file_list.last();
+            if options.mmap && sealed {
+                if let Some(map) = Mmap::map(&File::open(files.path(file_id))?)? {
+                    maps.insert(file_id, map);
+                }
+            }
+            readers.insert(file_id, reader);
+        }
+
+        // Data loss must never go unnoticed
+        let recovery_report = if report.is_clean() {
+            None
+        } else {
+            warn!("Store at {:?} needed recovery: {:?}", path.as_ref(), report);
+            if !read_only {
+                fs::write(
+                    path.as_ref().join(RECOVERY_REPORT_FILE),
+                    serde_json::to_string_pretty(&report)?,
+                )?;
+            }
+            Some(report)
+        };
+
+        // Create new log file(active data file) and its writer, after the newest one as
+        // compaction can leave gaps in the file_ids
+        let (active_file_id, writer) = if read_only {
+            // Nothing is written, the newest data file stands for the active one
+            let newest = *file_list
+                .last()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            let mut file = File::open(files.path(newest))?;
+            file.seek(SeekFrom::End(0))?;
+            (newest, BufWriterWithPos::new(file))
+        } else {
+            let active_file_id = file_list.last().map_or(1, |file_id| file_id + 1);
+            let writer = new_data_file(&mut files, active_file_id, &mut readers)?;
+            segments.insert(active_file_id, SegmentUsage::default());
+            (active_file_id, writer)
+        };
+        let background_sync = match options.sync {
+            SyncPolicy::EveryNMillis(millis) if !read_only => Some(BackgroundSync::start(
+                Duration::from_millis(millis),
+                writer.file().try_clone()?,
+            )?),
+            _ => None,
+        };
+
+        Ok(KvStore {
+            files,
+            index,
+            readers,
+            maps,
+            mmap: options.mmap,
+            writer,
+            active_file_id,
+            segments,
+            segment_size: options.segment_size,
+            compressor: Compressor {
+                compression: options.compression,
+                min_size: options.compression_min_size,
+            },
+            compaction_ratio: options.compaction_ratio,
+            io_stats: IoStats::default(),
+            recovery_report,
+            last_compaction: None,
+            clock: Arc::new(SystemClock),
+            _open_path: open_path,
+            _dir_lock: dir_lock,
+            reads: 0,
+            old_segment_reads: 0,
+            read_verify_percent: 0.0,
+            corrupt_reads: 0,
+            verify_cursor: (0, 0),
+            verified_bytes: 0,
+            corrupt_records: HashSet::new(),
+            opened_from_snapshot,
+            sync_policy: options.sync,
+            background_sync,
+            commits: 0,
+            group_commit: false,
+            read_only,
+        })
+    }
+
+    /// Inserts a key-value pair into the kvstore.
+    ///
+    /// If the map did have this key present, the value will be updated.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_bytes(b"key".to_vec(), vec![0, 159, 146, 150]).unwrap();
+    /// ```
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_set(key, value, None)
+    }
+
+    /// Get the value of a given key.
+    ///
+    /// Returns `OK(None)` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommand` if the given command type unexpected.
     ///
     /// It propagates I/O or serialization errors during writing the log.
     ///
     /// # Example
     ///
     /// ```rust
-    /// use kvs::KvStore;
-    /// use std::env::current_dir;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_bytes(b"key".to_vec(), vec![0, 159, 146, 150]).unwrap();
+    /// let val = store.get_bytes(b"key".to_vec()).unwrap();
+    /// assert_eq!(val, Some(vec![0, 159, 146, 150]));
+    /// ```
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        // Find given key in index, and load command from data file
+        if let Some(cmd_pos) = self.live_entry(&key).cloned() {
+            let value = self.read_value(&key, &cmd_pos)?;
+            self.record_read(cmd_pos.file_id)?;
+            Ok(Some(value))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Get the values of several keys, reading their records in file and offset order.
+    ///
+    /// Records next to each other are read without seeking in between.
+    fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut lookups: Vec<(usize, CommandPos)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| Some((i, self.live_entry(key.as_bytes())?.clone())))
+            .collect();
+        lookups.sort_by_key(|(_, cmd_pos)| (cmd_pos.file_id, cmd_pos.pos));
+
+        let mut values = vec![None; keys.len()];
+        for (i, cmd_pos) in &lookups {
+            values[*i] = Some(utf8(self.read_value(keys[*i].as_bytes(), cmd_pos)?)?);
+        }
+        // Only once all are read: a compaction moves the records
+        for (_, cmd_pos) in lookups {
+            self.record_read(cmd_pos.file_id)?;
+        }
+        Ok(values)
+    }
+
+    /// Set a key only if it doesn't exist.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// assert!(store.set_nx("key".to_string(), "value1".to_string()).unwrap());
+    /// assert!(!store.set_nx("key".to_string(), "value2".to_string()).unwrap());
+    /// assert_eq!(store.get("key".to_string()).unwrap(), Some("value1".to_string()));
+    /// ```
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        if self.live_entry(key.as_bytes()).is_some() {
+            return Ok(false);
+        }
+        self.write_set(key.into_bytes(), value.into_bytes(), None)?;
+        Ok(true)
+    }
+
+    /// Set a key and return its previous value.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// assert_eq!(store.get_set("key".to_string(), "value1".to_string()).unwrap(), None);
+    /// let old = store.get_set("key".to_string(), "value2".to_string()).unwrap();
+    /// assert_eq!(old, Some("value1".to_string()));
+    /// ```
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old_value = self.get(key.clone())?;
+        self.write_set(key.into_bytes(), value.into_bytes(), None)?;
+        Ok(old_value)
+    }
+
+    /// Append `value` to the value of a key, creating it if it doesn't exist.
     ///
-    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
+    /// The whole new value is written as a single record.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// assert_eq!(store.append("key".to_string(), "Hello".to_string()).unwrap(), 5);
+    /// assert_eq!(store.append("key".to_string(), " World".to_string()).unwrap(), 11);
+    /// assert_eq!(store.get("key".to_string()).unwrap(), Some("Hello World".to_string()));
+    /// ```
+    fn append(&mut self, key: String, value: String) -> Result<usize> {
+        let expires_at = self
+            .live_entry(key.as_bytes())
+            .and_then(|cmd_pos| cmd_pos.expires_at);
+        let mut new_value = self.get(key.clone())?.unwrap_or_default();
+        new_value.push_str(&value);
+        let len = new_value.len();
+        self.write_set(key.into_bytes(), new_value.into_bytes(), expires_at)?;
+        Ok(len)
+    }
+
+    /// Whether the given key exists.
+    ///
+    /// Only the in-memory index is checked, the data files aren't read.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
     /// store.set("key".to_string(), "value".to_string()).unwrap();
-    /// store.remove("key".to_string()).unwrap();
+    /// assert!(store.contains_key("key").unwrap());
     /// ```
-    fn remove(&mut self, key: String) -> Result<()> {
-        if !self.index.contains_key(&key) {
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.live_entry(key).is_some())
+    }
+
+    /// Up to `count` keys sorting after `after`, in order.
+    ///
+    /// Unless the index is ordered, every call goes through all keys.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// for key in ["c", "a", "b"] {
+    ///     store.set(key.to_string(), "value".to_string()).unwrap();
+    /// }
+    /// assert_eq!(store.scan_bytes(None, 2).unwrap(), [b"a", b"b"]);
+    /// assert_eq!(store.scan_bytes(Some(b"b"), 2).unwrap(), [b"c"]);
+    /// ```
+    fn scan_bytes(&self, after: Option<&[u8]>, count: usize) -> Result<Vec<Vec<u8>>> {
+        if let Some(entries) = self.index.range_after(after) {
+            let now = unix_millis(self.clock.now());
+            return Ok(entries
+                .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+                .map(|(key, _)| key.clone())
+                .take(count)
+                .collect());
+        }
+
+        let mut keys: Vec<&[u8]> = self
+            .keys()
+            .filter(|&key| after.is_none_or(|after| key > after))
+            .collect();
+        if keys.len() > count {
+            keys.select_nth_unstable(count);
+            keys.truncate(count);
+        }
+        keys.sort_unstable();
+        Ok(keys.into_iter().map(<[u8]>::to_vec).collect())
+    }
+
+    /// Remove a given key.
+    ///
+    ///  # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_bytes(b"key".to_vec(), b"value".to_vec()).unwrap();
+    /// store.remove_bytes(b"key".to_vec()).unwrap();
+    /// ```
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.check_writable()?;
+        if self.live_entry(&key).is_none() {
             Err(Error::KeyNotFound)
         } else {
             let pos = self.writer.pos;
             // Write log to file, and store key/command position pairs in index
             let command = Command::Remove { key };
-            serde_json::to_writer(&mut self.writer, &command)?;
-            self.writer.flush()?;
+            write_record(&mut self.writer, &command, self.compressor)?;
+            self.commit()?;
+            self.io_stats.user_bytes_written += self.writer.pos - pos;
+
+            if let Command::Remove { key } = command {
+                // Remove key from index
+                let old_cmd = self.index.remove(&key).unwrap();
+
+                self.add_stale(old_cmd.file_id, old_cmd.size);
+                self.add_stale(self.active_file_id, self.writer.pos - pos);
+            }
+
+            self.maintain_segments()
+        }
+    }
+
+    /// Rename a key with a single `Rename` record.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("old".to_string(), "value".to_string()).unwrap();
+    /// assert!(store.rename("old".to_string(), "new".to_string(), true).unwrap());
+    /// assert_eq!(store.get("new".to_string()).unwrap(), Some("value".to_string()));
+    /// assert_eq!(store.get("old".to_string()).unwrap(), None);
+    /// ```
+    fn rename(&mut self, from: String, to: String, replace: bool) -> Result<bool> {
+        self.check_writable()?;
+        let (from, to) = (from.into_bytes(), to.into_bytes());
+        let value = self.get_bytes(from.clone())?.ok_or(Error::KeyNotFound)?;
+        if from == to || (!replace && self.live_entry(&to).is_some()) {
+            return Ok(from == to && replace);
+        }
+        let expires_at = self
+            .live_entry(&from)
+            .and_then(|cmd_pos| cmd_pos.expires_at);
+
+        let pos = self.writer.pos;
+        let command = Command::Rename {
+            from,
+            to,
+            value,
+            expires_at,
+        };
+        write_record(&mut self.writer, &command, self.compressor)?;
+        self.commit()?;
+        let size = self.writer.pos - pos;
+        self.io_stats.user_bytes_written += size;
+
+        if let Command::Rename { from, to, .. } = command {
+            let old_from = self.index.remove(&from).unwrap();
+            self.add_stale(old_from.file_id, old_from.size);
+            let cmd_pos = CommandPos {
+                file_id: self.active_file_id,
+                pos,
+                size,
+                expires_at,
+                renamed: true,
+            };
+            if let Some(old_to) = self.index.insert(to, cmd_pos) {
+                self.add_stale(old_to.file_id, old_to.size);
+            }
+        }
+
+        self.maintain_segments()?;
+        Ok(true)
+    }
+
+    /// Number of keys, counted from the in-memory index.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// assert!(store.is_empty().unwrap());
+    /// store.set("key".to_string(), "value".to_string()).unwrap();
+    /// assert_eq!(store.len().unwrap(), 1);
+    /// ```
+    fn len(&self) -> Result<usize> {
+        Ok(self.keys().count())
+    }
+
+    /// Set the time a key expires at.
+    ///
+    /// The key's record is rewritten with the new expiration time.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use std::time::{Duration, SystemTime};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("key".to_string(), "value".to_string()).unwrap();
+    /// let past = SystemTime::now() - Duration::from_secs(1);
+    /// assert!(store.set_expiration("key".to_string(), Some(past)).unwrap());
+    /// assert_eq!(store.get("key".to_string()).unwrap(), None);
+    /// ```
+    fn set_expiration(&mut self, key: String, expires_at: Option<SystemTime>) -> Result<bool> {
+        let key = key.into_bytes();
+        match self.get_bytes(key.clone())? {
+            Some(value) => {
+                self.write_set(key, value, expires_at.map(unix_millis))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn expiration(&self, key: &str) -> Result<Option<Option<SystemTime>>> {
+        Ok(self
+            .live_entry(key.as_bytes())
+            .map(|cmd_pos| cmd_pos.expires_at.map(from_unix_millis)))
+    }
+
+    /// Remove every key, and the data files along with them.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("key".to_string(), "value".to_string()).unwrap();
+    /// store.clear().unwrap();
+    /// assert_eq!(store.get("key".to_string()).unwrap(), None);
+    /// ```
+    fn clear(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.index.clear();
+        // Compaction copies nothing then, and deletes every data file but the new ones
+        self.compact()
+    }
+
+    /// Fsync the active data file.
+    ///
+    /// Unless the sync policy fsyncs them, writes are only flushed to the OS as they
+    /// happen: they survive a crash of the process but not of the machine until `sync`
+    /// is called, see `SyncPolicy`.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.sync(&mut self.io_stats)?;
+        Ok(())
+    }
+
+    /// The records of the batch follow a `Batch` record with their count, a batch
+    /// cut short by a crash is skipped on the next open.
+    ///
+    /// The index is only updated once every record is written.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
+        // Removes of keys that won't exist by then are dropped, like `remove` would reject them
+        let mut live: HashMap<Vec<u8>, bool> = HashMap::new();
+        let mut commands = vec![];
+        for op in batch {
+            match op {
+                BatchOp::Set {
+                    key,
+                    value,
+                    expires_at,
+                } => {
+                    live.insert(key.clone(), true);
+                    commands.push(Command::Set {
+                        key,
+                        value,
+                        expires_at: expires_at.map(unix_millis),
+                    });
+                }
+                BatchOp::Remove { key } => {
+                    let is_live = match live.get(&key) {
+                        Some(&is_live) => is_live,
+                        None => self.live_entry(&key).is_some(),
+                    };
+                    if is_live {
+                        live.insert(key.clone(), false);
+                        commands.push(Command::Remove { key });
+                    }
+                }
+            }
+        }
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        let start = self.writer.pos;
+        let positions = match self.write_records(&commands) {
+            Ok(positions) => positions,
+            Err(e) => {
+                // What was written of the batch is skipped on the next open, as the end of the file.
+                // Following writes go to a new data file, or they would be skipped along with it.
+                self.add_stale(self.active_file_id, self.writer.pos - start);
+                self.new_active_file(self.active_file_id + 1)?;
+                return Err(e);
+            }
+        };
+        self.io_stats.user_bytes_written += self.writer.pos - start;
+        // The Batch record itself is never read again
+        self.add_stale(
+            self.active_file_id,
+            positions.first().map_or(0, |&(pos, _)| pos - start),
+        );
+
+        for (command, (pos, size)) in commands.into_iter().zip(positions) {
+            match command {
+                Command::Set {
+                    key, expires_at, ..
+                } => {
+                    let cmd_pos = CommandPos {
+                        file_id: self.active_file_id,
+                        pos,
+                        size,
+                        expires_at,
+                        renamed: false,
+                    };
+                    if let Some(old_cmd) = self.index.insert(key, cmd_pos) {
+                        self.add_stale(old_cmd.file_id, old_cmd.size);
+                    }
+                }
+                Command::Remove { key } => {
+                    let old_cmd = self.index.remove(&key).unwrap();
+                    self.add_stale(old_cmd.file_id, old_cmd.size);
+                    self.add_stale(self.active_file_id, size);
+                }
+                Command::Rename { .. } | Command::Batch { .. } => unreachable!(),
+            }
+        }
+
+        self.maintain_segments()
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    /// Data files, disk usage, compaction and recovery.
+    ///
+    /// Times are in milliseconds since the UNIX epoch, -1 if it never happened.
+    fn stats(&self) -> Result<Vec<(&'static str, String)>> {
+        let mut disk_bytes = 0;
+        for file_id in self.readers.keys() {
+            disk_bytes += fs::metadata(self.files.path(*file_id))?.len();
+        }
+        let recovery = self.recovery_report.as_ref();
+        let skipped_bytes: u64 = recovery
+            .map(|report| report.skipped_tails.iter().map(|tail| tail.bytes).sum())
+            .unwrap_or(0);
+        Ok(vec![
+            ("keys", self.len()?.to_string()),
+            ("segments", self.readers.len().to_string()),
+            ("mapped_segments", self.maps.len().to_string()),
+            ("disk_bytes", disk_bytes.to_string()),
+            (
+                "uncompacted_bytes",
+                self.segments
+                    .values()
+                    .map(|usage| usage.stale)
+                    .sum::<u64>()
+                    .to_string(),
+            ),
+            (
+                "last_compaction_time",
+                self.last_compaction
+                    .map_or(-1, |time| unix_millis(time) as i64)
+                    .to_string(),
+            ),
+            (
+                "write_amplification",
+                format!("{:.2}", self.io_stats.write_amplification()),
+            ),
+            ("fsyncs", self.io_stats.fsyncs.to_string()),
+            ("recovered_on_open", (recovery.is_some() as u8).to_string()),
+            (
+                "opened_from_snapshot",
+                (self.opened_from_snapshot as u8).to_string(),
+            ),
+            ("recovery_skipped_bytes", skipped_bytes.to_string()),
+            ("corrupt_reads", self.corrupt_reads.to_string()),
+            ("verified_bytes", self.verified_bytes.to_string()),
+            ("corrupt_records", self.corrupt_records.len().to_string()),
+        ])
+    }
+
+    /// "segment-size": bytes the active data file is sealed at, once a write reaches it.
+    ///
+    /// "compaction-ratio": share of a sealed data file that has to be stale, from 0 to 1,
+    /// for compaction to rewrite it.
+    ///
+    /// "compression" and "compression-min-size": see `KvStoreOptions::compression`.
+    fn config(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("segment-size", self.segment_size.to_string()),
+            ("compaction-ratio", self.compaction_ratio.to_string()),
+            ("read-verify-percent", self.read_verify_percent.to_string()),
+            ("compression", self.compressor.compression.to_string()),
+            ("compression-min-size", self.compressor.min_size.to_string()),
+        ]
+    }
+
+    /// A lower segment size or compaction ratio takes effect on the next write, a ratio of
+    /// 1 turns compaction off.
+    ///
+    /// A change of compression applies to the records written from then on, including
+    /// those rewritten by compaction.
+    ///
+    /// "read-verify-percent" is the percentage of gets, e.g. 1 or 0.1, whose record is read
+    /// whole and checked against the index. A corrupt record fails the get with
+    /// `Error::CorruptRecord`, is logged and counted in the "corrupt_reads" statistic.
+    /// It is 0 by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_config("segment-size", "4096").unwrap();
+    /// store.set_config("compaction-ratio", "0.25").unwrap();
+    /// store.set_config("read-verify-percent", "0.5").unwrap();
+    /// store.set_config("compression", "lz4").unwrap();
+    /// assert_eq!(
+    ///     store.config(),
+    ///     [
+    ///         ("segment-size", "4096".to_string()),
+    ///         ("compaction-ratio", "0.25".to_string()),
+    ///         ("read-verify-percent", "0.5".to_string()),
+    ///         ("compression", "lz4".to_string()),
+    ///         ("compression-min-size", "64".to_string())
+    ///     ]
+    /// );
+    /// ```
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "segment-size" => self.segment_size = config::parse(name, value)?,
+            "compaction-ratio" => match config::parse(name, value)? {
+                ratio @ 0.0..=1.0 => self.compaction_ratio = ratio,
+                _ => return Err(Error::InvalidConfig(name.to_owned(), value.to_owned())),
+            },
+            "read-verify-percent" => match config::parse(name, value)? {
+                percent @ 0.0..=100.0 => self.read_verify_percent = percent,
+                _ => return Err(Error::InvalidConfig(name.to_owned(), value.to_owned())),
+            },
+            "compression" => self.compressor.compression = value.parse()?,
+            "compression-min-size" => self.compressor.min_size = config::parse(name, value)?,
+            _ => return Err(Error::UnknownConfig(name.to_owned())),
+        }
+        Ok(())
+    }
+
+    /// Expired keys are removed like by `remove`, so they don't come back after a restart.
+    ///
+    /// Keys that aren't UTF-8 are returned with their invalid bytes replaced by U+FFFD.
+    fn purge_expired(&mut self) -> Result<Vec<String>> {
+        self.check_writable()?;
+        let now = unix_millis(self.clock.now());
+        let expired: Vec<Vec<u8>> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        if expired.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let pos = self.writer.pos;
+        for key in &expired {
+            write_record(
+                &mut self.writer,
+                &Command::Remove { key: key.clone() },
+                self.compressor,
+            )?;
+            let old_cmd = self.index.remove(key).unwrap();
+            self.add_stale(old_cmd.file_id, old_cmd.size);
+        }
+        self.commit()?;
+        self.add_stale(self.active_file_id, self.writer.pos - pos);
+
+        self.maintain_segments()?;
+        Ok(expired
+            .iter()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .collect())
+    }
+
+    /// Sealed data files are read record by record, oldest first; the active one is left
+    /// until it is sealed. Records failing their checksum, and live records that don't
+    /// match the index, are reported once each. They can't be repaired as the store has no
+    /// other copy of them, keys whose record is lost are named in the log.
+    fn verify_data(&mut self, max_bytes: u64) -> Result<u64> {
+        let mut sealed: Vec<u64> = self
+            .readers
+            .keys()
+            .copied()
+            .filter(|&file_id| file_id != self.active_file_id)
+            .collect();
+        sealed.sort_unstable();
+
+        let start = self.verify_cursor;
+        let mut checked = 0;
+        let mut started_over = false;
+        while checked < max_bytes {
+            // Each byte at most once per call
+            if started_over && self.verify_cursor >= start {
+                break;
+            }
+            let (file_id, pos) = self.verify_cursor;
+            // The file may have been compacted away since
+            match sealed.iter().find(|&&id| id >= file_id) {
+                Some(&id) if id == file_id => {}
+                Some(&id) => {
+                    self.verify_cursor = (id, 0);
+                    continue;
+                }
+                None if started_over || sealed.is_empty() => break,
+                None => {
+                    started_over = true;
+                    self.verify_cursor = (sealed[0], 0);
+                    continue;
+                }
+            }
+            let mut budget = max_bytes - checked;
+            if started_over && file_id == start.0 {
+                budget = budget.min(start.1 - pos);
+            }
+            let (end, at_end) = self.verify_file(file_id, pos, budget)?;
+            // A broken record ends the file, whatever the budget
+            checked += (end - pos).min(budget);
+            self.verify_cursor = if at_end {
+                (file_id + 1, 0)
+            } else {
+                (file_id, end)
+            };
+        }
+        self.verified_bytes += checked;
+        Ok(checked)
+    }
+
+    /// Write the index to "index.json" with the length of the data files it covers, the
+    /// next open only replays the records written after. It is also written on drop.
+    fn checkpoint(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.writer.flush()?;
+        let mut files = vec![];
+        for &file_id in self.readers.keys() {
+            files.push((file_id, fs::metadata(self.files.path(file_id))?.len()));
+        }
+        let snapshot = IndexSnapshot {
+            files,
+            stale: self
+                .segments
+                .iter()
+                .map(|(&file_id, usage)| (file_id, usage.stale))
+                .collect(),
+            entries: self
+                .index
+                .iter()
+                .map(|(key, cmd_pos)| IndexEntry(key.clone(), cmd_pos.clone()))
+                .collect(),
+        };
+        snapshot.save(self.files.store_dir())
+    }
+
+    /// Rewrite the live records into a new data file and delete the older ones, reclaiming
+    /// the space of overwritten, removed and expired keys.
+    ///
+    /// Otherwise sealed data files are compacted one at a time as they go stale, see
+    /// "compaction-ratio"; this compacts all of them now, e.g. during a maintenance window.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("key".to_string(), "value1".to_string()).unwrap();
+    /// store.set("key".to_string(), "value2".to_string()).unwrap();
+    /// store.compact().unwrap();
+    /// assert_eq!(store.get("key".to_string()).unwrap(), Some("value2".to_string()));
+    /// ```
+    fn compact(&mut self) -> Result<()> {
+        self.check_writable()?;
+        // Expired keys are dropped rather than copied
+        let now = unix_millis(self.clock.now());
+        self.index.retain(|_, cmd_pos| !cmd_pos.is_expired(now));
+
+        // Collect set command in index into new data file, under a temporary name until it
+        // is complete: a crash before the rename leaves the stale data files as they were
+        let compaction_file_id = self.active_file_id + 1;
+        let compaction_path = self.files.add(compaction_file_id).to_owned();
+        let tmp_path = compaction_path.with_extension(COMPACT_TMP_EXTENSION);
+        let mut compaction_writer = BufWriterWithPos::new(File::create(&tmp_path)?);
+
+        let mut new_pos = 0;
+        let mut moved = vec![];
+        for (_, cmd_pos) in self.index.iter() {
+            let reader = self.readers.get_mut(&cmd_pos.file_id).unwrap();
+            if reader.pos != cmd_pos.pos {
+                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            }
+            let mut entry_reader = reader.take(cmd_pos.size);
+            let n = if cmd_pos.renamed {
+                // Copied as is, the record would remove `from` again on the next open
+                let path = self.files.path(cmd_pos.file_id);
+                let set = match read_record(&mut entry_reader, path, cmd_pos.pos)? {
+                    Some((
+                        Command::Rename {
+                            to,
+                            value,
+                            expires_at,
+                            ..
+                        },
+                        _,
+                    )) => Command::Set {
+                        key: to,
+                        value,
+                        expires_at,
+                    },
+                    Some(_) => return Err(Error::UnexpectedCommand),
+                    None => {
+                        return Err(Error::Corruption {
+                            file: path.to_owned(),
+                            offset: cmd_pos.pos,
+                        })
+                    }
+                };
+                let start = compaction_writer.pos;
+                write_record(&mut compaction_writer, &set, self.compressor)?;
+                compaction_writer.pos - start
+            } else {
+                io::copy(&mut entry_reader, &mut compaction_writer)?
+            };
+
+            moved.push((new_pos, n));
+            new_pos += n;
+        }
+        // The compacted data must be durable before the only other copy of it is deleted
+        compaction_writer.sync(&mut self.io_stats)?;
+        drop(compaction_writer);
+        fs::rename(&tmp_path, &compaction_path)?;
+        // So is its name
+        sync_dir(compaction_path.parent().unwrap())?;
+        // Keys dropped from the index have no remove record: were the process to die while the
+        // stale data files are deleted, the next open must not replay those left
+        write_compacted_file_id(self.files.store_dir(), compaction_file_id)?;
+        self.readers.insert(
+            compaction_file_id,
+            BufReaderWithPos::new(File::open(&compaction_path)?),
+        );
+        self.map_segment(compaction_file_id)?;
+        self.io_stats.compaction_bytes_written += new_pos;
+
+        // Update index map, visited in the same order as above
+        for (cmd_pos, (pos, size)) in self.index.values_mut().zip(moved) {
+            *cmd_pos = CommandPos {
+                file_id: compaction_file_id,
+                pos,
+                size,
+                expires_at: cmd_pos.expires_at,
+                renamed: false,
+            };
+        }
+
+        // remove stale data files, oldest first: if a crash interrupts this, a newer one
+        // left behind can't hold a key removed in an older one deleted already.
+        let mut stale_files: Vec<_> = self
+            .readers
+            .keys()
+            .filter(|&&file_id| file_id < compaction_file_id)
+            .cloned()
+            .collect();
+        stale_files.sort_unstable();
+        IndexSnapshot::remove(self.files.store_dir())?;
+        for stale_file_id in stale_files {
+            self.readers.remove(&stale_file_id);
+            self.maps.remove(&stale_file_id);
+            self.files.remove(stale_file_id)?;
+        }
+        self.files.sync_dirs()?;
+
+        self.segments.clear();
+        self.segments.insert(
+            compaction_file_id,
+            SegmentUsage {
+                len: new_pos,
+                stale: 0,
+            },
+        );
+        self.new_active_file(compaction_file_id + 1)?;
+        self.last_compaction = Some(self.clock.now());
+
+        Ok(())
+    }
+
+    fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+/// A shared handle to an open `KvStore`.
+///
+/// A store can only be open once per process, this is how several components
+/// of a process use the same store. Clones are handles to the same store,
+/// every operation locks it.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut store = KvStore::open(temp_dir.path()).unwrap().into_shared();
+/// let mut handle = store.clone();
+/// store.set("key".to_string(), "value".to_string()).unwrap();
+/// assert_eq!(handle.get("key".to_string()).unwrap(), Some("value".to_string()));
+/// assert!(KvStore::open(temp_dir.path()).is_err());
+/// ```
+///
+/// With `SyncPolicy::Always`, writes are fsynced once the store is unlocked: a thread
+/// fsyncs the writes of every thread made so far while those writing meanwhile wait for
+/// it, then for the next such group commit.
+#[derive(Clone)]
+pub struct SharedKvStore {
+    store: Arc<Mutex<KvStore>>,
+    group_commit: Arc<GroupCommit>,
+}
+
+impl KvStore {
+    /// Turn the store into a handle that can be cloned.
+    pub fn into_shared(mut self) -> SharedKvStore {
+        self.group_commit = self.sync_policy == SyncPolicy::Always;
+        SharedKvStore {
+            store: Arc::new(Mutex::new(self)),
+            group_commit: Arc::new(GroupCommit::default()),
+        }
+    }
+}
+
+impl SharedKvStore {
+    /// Lock the store, for the methods of `KvStore` that aren't part of `KvsEngine`.
+    pub fn lock(&self) -> MutexGuard<'_, KvStore> {
+        self.store.lock().unwrap()
+    }
+
+    /// Run `write` on the store, then wait for what it wrote to be fsynced if it has to be.
+    fn write<T>(&self, write: impl FnOnce(&mut KvStore) -> Result<T>) -> Result<T> {
+        let mut store = self.lock();
+        let before = store.commits;
+        let result = write(&mut store);
+        let (commit, group_commit) = (store.commits, store.group_commit);
+        drop(store);
+        if group_commit && commit != before {
+            self.group_commit.wait_synced(&self.store, commit)?;
+        }
+        result
+    }
+}
+
+/// Fsyncs shared by the threads writing to a `SharedKvStore` with `SyncPolicy::Always`.
+#[derive(Default)]
+struct GroupCommit {
+    /// Commits fsynced so far, and whether a thread is fsyncing
+    state: Mutex<(u64, bool)>,
+    synced: Condvar,
+}
+
+impl GroupCommit {
+    /// Wait until the writes up to commit `commit` are fsynced, fsyncing them if no other
+    /// thread is.
+    fn wait_synced(&self, store: &Mutex<KvStore>, commit: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.0 < commit && state.1 {
+            state = self.synced.wait(state).unwrap();
+        }
+        if state.0 >= commit {
+            return Ok(());
+        }
+        state.1 = true;
+        drop(state);
+
+        let result = Self::sync(store);
+        let mut state = self.state.lock().unwrap();
+        state.1 = false;
+        if let Ok(synced) = result {
+            state.0 = state.0.max(synced);
+        }
+        self.synced.notify_all();
+        result.map(|_| ())
+    }
+
+    /// Fsync the active data file of `store`, with the store unlocked so other threads
+    /// go on writing meanwhile.
+    ///
+    /// Returns the last commit fsynced.
+    fn sync(store: &Mutex<KvStore>) -> Result<u64> {
+        let (file, commit) = {
+            let store = store.lock().unwrap();
+            (store.writer.file().try_clone()?, store.commits)
+        };
+        let start = Instant::now();
+        file.sync_all()?;
+        let mut store = store.lock().unwrap();
+        store.io_stats.fsyncs += 1;
+        store.io_stats.fsync_time += start.elapsed();
+        Ok(commit)
+    }
+}
+
+impl KvsEngine for SharedKvStore {
+    type Options = KvStoreOptions;
+
+    fn open_with(path: impl AsRef<Path>, options: KvStoreOptions) -> Result<Self> {
+        KvStore::open_with(path, options).map(KvStore::into_shared)
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(|store| store.set_bytes(key, value))
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.lock().get_bytes(key)
+    }
+
+    fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.lock().multi_get(keys)
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        self.write(|store| store.set_nx(key, value))
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.write(|store| store.get_set(key, value))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.write(|store| store.compare_and_swap(key, expected, new))
+    }
+
+    fn update<F>(&mut self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        self.write(|store| store.update(key, f))
+    }
+
+    fn append(&mut self, key: String, value: String) -> Result<usize> {
+        self.write(|store| store.append(key, value))
+    }
+
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        self.lock().contains_key_bytes(key)
+    }
+
+    fn scan_bytes(&self, after: Option<&[u8]>, count: usize) -> Result<Vec<Vec<u8>>> {
+        self.lock().scan_bytes(after, count)
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.write(|store| store.remove_bytes(key))
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.lock().len()
+    }
+
+    fn set_expiration(&mut self, key: String, expires_at: Option<SystemTime>) -> Result<bool> {
+        self.write(|store| store.set_expiration(key, expires_at))
+    }
+
+    fn expiration(&self, key: &str) -> Result<Option<Option<SystemTime>>> {
+        self.lock().expiration(key)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.lock().clear()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.lock().sync()
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.write(|store| store.write_batch(batch))
+    }
+
+    fn name(&self) -> &'static str {
+        self.lock().name()
+    }
+
+    fn stats(&self) -> Result<Vec<(&'static str, String)>> {
+        self.lock().stats()
+    }
+
+    fn config(&self) -> Vec<(&'static str, String)> {
+        self.lock().config()
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        self.lock().set_config(name, value)
+    }
+
+    fn rename(&mut self, from: String, to: String, replace: bool) -> Result<bool> {
+        self.write(|store| store.rename(from, to, replace))
+    }
+
+    fn purge_expired(&mut self) -> Result<Vec<String>> {
+        self.write(|store| store.purge_expired())
+    }
+
+    fn verify_data(&mut self, max_bytes: u64) -> Result<u64> {
+        self.lock().verify_data(max_bytes)
+    }
 
-            if let Command::Remove { key } = command {
-                // Remove key from index
-                let old_cmd = self.index.remove(&key).unwrap();
+    fn checkpoint(&mut self) -> Result<()> {
+        self.lock().checkpoint()
+    }
 
-                self.uncompacted_size += old_cmd.size + (self.writer.pos - pos);
-            }
+    fn compact(&mut self) -> Result<()> {
+        self.lock().compact()
+    }
+
+    fn now(&self) -> SystemTime {
+        self.lock().now()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.lock().set_clock(clock)
+    }
+}
+
+/// Registration of a store in `OPEN_STORES`, removed on drop.
+struct OpenPath(PathBuf);
+
+impl OpenPath {
+    /// Two `KvStore`s on the same directory would overwrite each other's data files.
+    fn register(path: PathBuf) -> Result<Self> {
+        if !OPEN_STORES.lock().unwrap().insert(path.clone()) {
+            return Err(Error::AlreadyOpen(path));
+        }
+        Ok(OpenPath(path))
+    }
+}
+
+/// Advisory lock on the "LOCK" file of a store's directory, released once the file is
+/// closed, also when the process dies.
+struct DirLock {
+    _file: File,
+}
 
-            // If uncompacted_size > COMPACT_THRESHOLD, then compact
-            if self.uncompacted_size > COMPACT_THRESHOLD {
-                self.compact()?;
+impl DirLock {
+    /// Processes don't see each other's `OPEN_STORES`, the lock keeps two of them from
+    /// writing the same store. The owner writes its PID to the file.
+    ///
+    /// Where file locks aren't supported the store is opened without one.
+    fn acquire(dir: &Path) -> Result<Option<Self>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut owner = String::new();
+                file.read_to_string(&mut owner)?;
+                return Err(Error::AlreadyLocked {
+                    path: dir.to_owned(),
+                    pid: owner.trim().parse().ok(),
+                });
+            }
+            Err(TryLockError::Error(e)) if e.kind() == io::ErrorKind::Unsupported => {
+                warn!("Store at {:?} can't be locked: {}", dir, e);
+                return Ok(None);
             }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        write!(file, "{}", process::id())?;
+        Ok(Some(DirLock { _file: file }))
+    }
+}
 
-            Ok(())
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if let Err(e) = self.checkpoint() {
+            warn!("Failed to save the index snapshot: {}", e);
         }
     }
 }
 
+impl Drop for OpenPath {
+    fn drop(&mut self) {
+        OPEN_STORES.lock().unwrap().remove(&self.0);
+    }
+}
+
 struct BufReaderWithPos<T: Seek + Read> {
     buf_reader: BufReader<T>,
     pos: u64, // TODO: necessary?
@@ -274,7 +2406,7 @@ struct BufReaderWithPos<T: Seek + Read> {
 
 impl<T: Seek + Read> BufReaderWithPos<T> {
     fn new(mut inner: T) -> Self {
-        let pos = inner.seek(SeekFrom::Current(0)).unwrap();
+        let pos = inner.stream_position().unwrap();
         Self {
             buf_reader: BufReader::new(inner),
             pos,
@@ -293,6 +2425,7 @@ impl<T: Seek + Read> Read for BufReaderWithPos<T> {
 impl<T: Seek + Read> Seek for BufReaderWithPos<T> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         let n = self.buf_reader.seek(pos)?;
+        self.pos = n;
         result::Result::Ok(n)
     }
 }
@@ -304,7 +2437,7 @@ struct BufWriterWithPos<T: Seek + Write> {
 
 impl<T: Seek + Write> BufWriterWithPos<T> {
     fn new(mut inner: T) -> Self {
-        let pos = inner.seek(SeekFrom::Current(0)).unwrap(); // Initial cursor
+        let pos = inner.stream_position().unwrap(); // Initial cursor
         Self {
             buf_writer: BufWriter::new(inner),
             pos,
@@ -312,6 +2445,22 @@ impl<T: Seek + Write> BufWriterWithPos<T> {
     }
 }
 
+impl BufWriterWithPos<File> {
+    fn file(&self) -> &File {
+        self.buf_writer.get_ref()
+    }
+
+    /// Flush the buffer and fsync the file, accounting the fsync in `io_stats`.
+    fn sync(&mut self, io_stats: &mut IoStats) -> io::Result<()> {
+        self.flush()?;
+        let start = Instant::now();
+        self.buf_writer.get_ref().sync_all()?;
+        io_stats.fsyncs += 1;
+        io_stats.fsync_time += start.elapsed();
+        Ok(())
+    }
+}
+
 impl<T: Seek + Write> Write for BufWriterWithPos<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let n = self.buf_writer.write(buf)?;
@@ -324,28 +2473,418 @@ impl<T: Seek + Write> Write for BufWriterWithPos<T> {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+/// Fsyncs the active data file every interval on a thread of its own, for
+/// `SyncPolicy::EveryNMillis`. Stopping it fsyncs what is left.
+struct BackgroundSync {
+    state: Arc<BackgroundSyncState>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct BackgroundSyncState {
+    /// The active data file, and whether the thread is stopping
+    file: Mutex<(Arc<File>, bool)>,
+    wake: Condvar,
+    /// Whether the active data file was written since its last fsync
+    dirty: AtomicBool,
+    fsyncs: AtomicU64,
+    fsync_nanos: AtomicU64,
+}
+
+impl BackgroundSync {
+    fn start(interval: Duration, file: File) -> Result<Self> {
+        let state = Arc::new(BackgroundSyncState {
+            file: Mutex::new((Arc::new(file), false)),
+            wake: Condvar::new(),
+            dirty: AtomicBool::new(false),
+            fsyncs: AtomicU64::new(0),
+            fsync_nanos: AtomicU64::new(0),
+        });
+        let thread_state = Arc::clone(&state);
+        let thread = thread::Builder::new()
+            .name("kvs-sync".to_owned())
+            .spawn(move || thread_state.run(interval))?;
+        Ok(BackgroundSync {
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    /// Watch `file` from now on, once the writes to the previous one are fsynced.
+    fn replace_file(&self, file: File) -> io::Result<()> {
+        let mut guard = self.state.file.lock().unwrap();
+        self.state.sync_if_dirty(&guard.0)?;
+        guard.0 = Arc::new(file);
+        Ok(())
+    }
+}
+
+impl BackgroundSyncState {
+    fn run(&self, interval: Duration) {
+        let mut guard = self.file.lock().unwrap();
+        loop {
+            if !guard.1 {
+                guard = self.wake.wait_timeout(guard, interval).unwrap().0;
+            }
+            let (file, stopping) = (Arc::clone(&guard.0), guard.1);
+            // Writes only wait for the lock, not for the fsync
+            drop(guard);
+            if let Err(e) = self.sync_if_dirty(&file) {
+                error!("Background fsync failed: {}", e);
+            }
+            if stopping {
+                return;
+            }
+            guard = self.file.lock().unwrap();
+        }
+    }
+
+    fn sync_if_dirty(&self, file: &File) -> io::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let start = Instant::now();
+        if let Err(e) = file.sync_all() {
+            self.dirty.store(true, Ordering::Release);
+            return Err(e);
+        }
+        self.fsyncs.fetch_add(1, Ordering::Relaxed);
+        self.fsync_nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for BackgroundSync {
+    fn drop(&mut self) {
+        self.state.file.lock().unwrap().1 = true;
+        self.state.wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// New variants need a sample in `Command::samples`, which documents the format.
+// Records written before format version 6 are JSON, as are the client journal's.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum Command {
+    Set {
+        #[serde(with = "crate::binary")]
+        key: Vec<u8>,
+        #[serde(with = "crate::binary")]
+        value: Vec<u8>,
+        /// Milliseconds since the UNIX epoch, absent if the key doesn't expire
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Remove {
+        #[serde(with = "crate::binary")]
+        key: Vec<u8>,
+    },
+    /// The next `count` records are only applied if all of them can be read
+    Batch { count: u64 },
+    /// Removes `from` and sets `to` to its value, in one record so a crash can't
+    /// leave both keys or neither
+    Rename {
+        #[serde(with = "crate::binary")]
+        from: Vec<u8>,
+        #[serde(with = "crate::binary")]
+        to: Vec<u8>,
+        #[serde(with = "crate::binary")]
+        value: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+}
+
+impl Command {
+    /// One command of every kind.
+    fn samples() -> Vec<Command> {
+        vec![
+            Command::Set {
+                key: b"key".to_vec(),
+                value: b"value".to_vec(),
+                expires_at: Some(1_700_000_000_000),
+            },
+            Command::Remove {
+                key: b"key".to_vec(),
+            },
+            Command::Batch { count: 2 },
+            Command::Rename {
+                from: b"key".to_vec(),
+                to: b"new_key".to_vec(),
+                value: b"value".to_vec(),
+                expires_at: Some(1_700_000_000_000),
+            },
+        ]
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Command::Set { .. } => "Set",
+            Command::Remove { .. } => "Remove",
+            Command::Batch { .. } => "Batch",
+            Command::Rename { .. } => "Rename",
+        }
+    }
+
+    /// First byte of the payload, it can't be '{' which starts the JSON ones.
+    fn tag(&self) -> u8 {
+        match self {
+            Command::Set { .. } => 0,
+            Command::Remove { .. } => 1,
+            Command::Batch { .. } => 2,
+            Command::Rename { .. } => 3,
+        }
+    }
+
+    /// The fields of the record by name, in the order they are encoded in.
+    fn fields(&self) -> Vec<(&'static str, Field<'_>)> {
+        match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+            } => vec![
+                ("key", Field::Bytes(key)),
+                ("value", Field::Bytes(value)),
+                ("expires_at", Field::OptionalVarint(*expires_at)),
+            ],
+            Command::Remove { key } => vec![("key", Field::Bytes(key))],
+            Command::Batch { count } => vec![("count", Field::Varint(*count))],
+            Command::Rename {
+                from,
+                to,
+                value,
+                expires_at,
+            } => vec![
+                ("from", Field::Bytes(from)),
+                ("to", Field::Bytes(to)),
+                ("value", Field::Bytes(value)),
+                ("expires_at", Field::OptionalVarint(*expires_at)),
+            ],
+        }
+    }
+
+    /// The payload of the record: its tag, then its fields.
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![self.tag()];
+        for (_, field) in self.fields() {
+            field.encode(&mut payload);
+        }
+        payload
+    }
+
+    /// The command of the payload written by `encode`, `None` if it isn't one.
+    fn decode(payload: &[u8]) -> Option<Command> {
+        let mut decoder = Decoder(payload);
+        // Fields are evaluated in the order they are written in
+        let cmd = match decoder.byte()? {
+            0 => Command::Set {
+                key: decoder.bytes()?,
+                value: decoder.bytes()?,
+                expires_at: decoder.optional_varint()?,
+            },
+            1 => Command::Remove {
+                key: decoder.bytes()?,
+            },
+            2 => Command::Batch {
+                count: decoder.varint()?,
+            },
+            3 => Command::Rename {
+                from: decoder.bytes()?,
+                to: decoder.bytes()?,
+                value: decoder.bytes()?,
+                expires_at: decoder.optional_varint()?,
+            },
+            _ => return None,
+        };
+        decoder.0.is_empty().then_some(cmd)
+    }
+}
+
+/// A field of a record, see `Command::fields`.
+enum Field<'a> {
+    /// Its length as a varint, then the bytes
+    Bytes(&'a [u8]),
+    /// An unsigned LEB128 integer: 7 bits per byte from the lowest, the high bit set on
+    /// every byte but the last
+    Varint(u64),
+    /// 0 if absent, 1 then the varint otherwise
+    OptionalVarint(Option<u64>),
+}
+
+impl Field<'_> {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Field::Bytes(_) => "bytes",
+            Field::Varint(_) => "varint",
+            Field::OptionalVarint(_) => "optional varint",
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Field::Bytes(bytes) => {
+                write_varint(out, bytes.len() as u64);
+                out.extend_from_slice(bytes);
+            }
+            Field::Varint(n) => write_varint(out, n),
+            Field::OptionalVarint(None) => out.push(0),
+            Field::OptionalVarint(Some(n)) => {
+                out.push(1);
+                write_varint(out, n);
+            }
+        }
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// Reads the fields of a payload from its start, see `Field`.
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(byte)
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                return Some(n);
+            }
+        }
+        None
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = usize::try_from(self.varint()?).ok()?;
+        if len > self.0.len() {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes.to_vec())
+    }
+
+    fn optional_varint(&mut self) -> Option<Option<u64>> {
+        match self.byte()? {
+            0 => Some(None),
+            1 => self.varint().map(Some),
+            _ => None,
+        }
+    }
 }
 
 // Command position in data file, which is used in index.
+#[derive(Clone, Serialize, Deserialize)]
 struct CommandPos {
     file_id: u64,
     pos: u64,
     size: u64,
+    expires_at: Option<u64>, // Kept in memory, so expired keys are skipped without reading the log
+    renamed: bool,           // The record is a Rename, compaction rewrites it as a Set
+}
+
+impl CommandPos {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Size of a data file, and how much of it is stale records.
+#[derive(Debug, Clone, Copy, Default)]
+struct SegmentUsage {
+    len: u64, // Only known once the data file is sealed
+    stale: u64,
+}
+
+/// The index as of a checkpoint, so opening the store only replays the records written since.
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot {
+    /// Length of the data files the index covers, by file_id
+    files: Vec<(u64, u64)>,
+    /// Bytes of stale records of the data files, by file_id
+    stale: Vec<(u64, u64)>,
+    entries: Vec<IndexEntry>,
+}
+
+/// A key of the index snapshot and its entry.
+#[derive(Serialize, Deserialize)]
+struct IndexEntry(#[serde(with = "crate::binary")] Vec<u8>, CommandPos);
+
+impl IndexSnapshot {
+    /// The snapshot in `dir`, if there is one covering a prefix of `files`.
+    ///
+    /// A snapshot is stale once a compaction deleted the data files it covers.
+    fn load(dir: &Path, files: &DataFiles) -> Option<IndexSnapshot> {
+        let path = dir.join(INDEX_SNAPSHOT_FILE);
+        let data = fs::read(&path).ok()?;
+        let snapshot: IndexSnapshot = match serde_json::from_slice(&data) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("Ignored unreadable index snapshot {:?}: {}", path, e);
+                return None;
+            }
+        };
+
+        let newest_covered = snapshot.files.iter().map(|&(file_id, _)| file_id).max();
+        let covers_prefix = snapshot.files.iter().all(|&(file_id, len)| {
+            files
+                .paths
+                .get(&file_id)
+                .is_some_and(|path| fs::metadata(path).is_ok_and(|metadata| metadata.len() >= len))
+        }) && files
+            .ids()
+            .into_iter()
+            .filter(|file_id| !snapshot.files.iter().any(|&(id, _)| id == *file_id))
+            .all(|file_id| Some(file_id) > newest_covered);
+        if !covers_prefix {
+            warn!("Ignored stale index snapshot {:?}", path);
+            return None;
+        }
+        Some(snapshot)
+    }
+
+    /// Replace the snapshot in `dir`, a snapshot torn by a crash is ignored on open.
+    fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", INDEX_SNAPSHOT_FILE));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        fs::rename(tmp_path, dir.join(INDEX_SNAPSHOT_FILE))?;
+        Ok(())
+    }
+
+    /// Delete the snapshot in `dir`, e.g. once the data files it covers are deleted.
+    fn remove(dir: &Path) -> Result<()> {
+        match fs::remove_file(dir.join(INDEX_SNAPSHOT_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
 }
 
 /// Returns sorted file_ids in the given directory.
 fn sorted_file_list<P: AsRef<Path>>(path: P) -> Result<Vec<u64>> {
     let mut file_list: Vec<u64> = fs::read_dir(&path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .filter(|path| path.is_file() && path.extension() == Some(LOG_EXTENSION.as_ref()))
         .flat_map(|path| {
-            path.file_name()
+            path.file_stem()
                 .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
                 .map(str::parse::<u64>)
         })
         .flatten()
@@ -354,65 +2893,542 @@ fn sorted_file_list<P: AsRef<Path>>(path: P) -> Result<Vec<u64>> {
     Ok(file_list)
 }
 
+/// Where the data files of a store are, see `KvStoreOptions::data_dirs`.
+struct DataFiles {
+    /// The store's directory first
+    dirs: Vec<PathBuf>,
+    /// Directory the next data file goes to
+    next_dir: usize,
+    /// Path of every data file, by file_id
+    paths: HashMap<u64, PathBuf>,
+}
+
+impl DataFiles {
+    /// Find the data files in `dirs`, creating the directories that are missing unless
+    /// `read_only`.
+    ///
+    /// New data files go on from the directory after that of the newest one.
+    fn open(dirs: Vec<PathBuf>, read_only: bool) -> Result<Self> {
+        let mut paths = HashMap::new();
+        let mut newest = None;
+        for (i, dir) in dirs.iter().enumerate() {
+            if !read_only {
+                create_dir_all(dir)?;
+                remove_compaction_leftovers(dir)?;
+            }
+            for file_id in sorted_file_list(dir)? {
+                let path = log_path(dir, file_id);
+                if paths.insert(file_id, path.clone()).is_some() {
+                    return Err(Error::DuplicateDataFile(path));
+                }
+                newest = newest.max(Some((file_id, i)));
+            }
+        }
+        Ok(DataFiles {
+            next_dir: newest.map_or(0, |(_, i)| (i + 1) % dirs.len()),
+            dirs,
+            paths,
+        })
+    }
+
+    /// Sorted file_ids of the data files.
+    fn ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.paths.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn path(&self, file_id: u64) -> &Path {
+        &self.paths[&file_id]
+    }
+
+    /// The store's directory.
+    fn store_dir(&self) -> &Path {
+        &self.dirs[0]
+    }
+
+    /// Path of a new data file, in the next directory in turn.
+    fn add(&mut self, file_id: u64) -> &Path {
+        let dir = &self.dirs[self.next_dir];
+        self.next_dir = (self.next_dir + 1) % self.dirs.len();
+        self.paths
+            .entry(file_id)
+            .or_insert_with(|| log_path(dir, file_id))
+    }
+
+    /// Forget the data files older than `file_id`, deleting them unless `read_only`.
+    ///
+    /// Returns how many there were.
+    fn remove_older(&mut self, file_id: u64, read_only: bool) -> Result<usize> {
+        let older: Vec<u64> = self
+            .paths
+            .keys()
+            .copied()
+            .filter(|&id| id < file_id)
+            .collect();
+        for &id in &older {
+            if read_only {
+                self.paths.remove(&id);
+            } else {
+                self.remove(id)?;
+            }
+        }
+        Ok(older.len())
+    }
+
+    /// Delete a data file, see `sync_dirs` to make it durable.
+    fn remove(&mut self, file_id: u64) -> Result<()> {
+        if let Some(path) = self.paths.remove(&file_id) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Fsync the directories, so the data files created and deleted in them stay so after
+    /// a power loss.
+    fn sync_dirs(&self) -> Result<()> {
+        for dir in &self.dirs {
+            sync_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fsync directory `dir`, making the creation, renaming and deletion of its files durable.
+///
+/// Only Unix can open a directory for this, elsewhere it does nothing.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Delete the data files of compactions a crash interrupted in `dir`.
+fn remove_compaction_leftovers(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_leftover = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name.ends_with(&format!(".{}", COMPACT_TMP_EXTENSION)));
+        if is_leftover {
+            warn!("Removed {:?} left by an interrupted compaction", path);
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
 /// PathBuf = path + file_id.log
 fn log_path<P: AsRef<Path>>(path: P, file_id: u64) -> PathBuf {
-    path.as_ref().join(format!("{}.log", file_id))
+    path.as_ref().join(format!("{}.{}", file_id, LOG_EXTENSION))
 }
 
 /// Create a new data file with given file_id and add the reader to the readers map.
 ///
 /// Returns the writer to the log.
-fn new_data_file<P: AsRef<Path>>(
-    path: P,
+fn new_data_file(
+    files: &mut DataFiles,
     file_id: u64,
     readers: &mut HashMap<u64, BufReaderWithPos<File>>,
 ) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, file_id);
-    let writer = BufWriterWithPos::new(
-        File::options()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    );
-    readers.insert(file_id, BufReaderWithPos::new(File::open(&path)?));
+    let path = files.add(file_id);
+    let writer = BufWriterWithPos::new(File::options().create(true).append(true).open(path)?);
+    sync_dir(path.parent().unwrap())?;
+    readers.insert(file_id, BufReaderWithPos::new(File::open(path)?));
     Ok(writer)
 }
 
+/// Add the record of `cmd` at `pos..new_pos` to the index, and the bytes it makes stale to
+/// `stale`, by file_id.
+fn index_record(
+    file_id: u64,
+    cmd: Command,
+    pos: u64,
+    new_pos: u64,
+    index: &mut Index<CommandPos>,
+    stale: &mut HashMap<u64, u64>,
+    report: &mut RecoveryReport,
+) {
+    let mut add_stale = |file_id: u64, bytes: u64| *stale.entry(file_id).or_default() += bytes;
+    match cmd {
+        Command::Set {
+            key, expires_at, ..
+        } => {
+            let cmd_pos = CommandPos {
+                file_id,
+                pos,
+                size: new_pos - pos,
+                expires_at,
+                renamed: false,
+            };
+            if let Some(old_cmd) = index.insert(key, cmd_pos) {
+                add_stale(old_cmd.file_id, old_cmd.size);
+            }
+        }
+        // The record holds the value of `to` from now on
+        Command::Rename {
+            from,
+            to,
+            expires_at,
+            ..
+        } => {
+            if let Some(old_from) = index.remove(&from) {
+                add_stale(old_from.file_id, old_from.size);
+            }
+            let cmd_pos = CommandPos {
+                file_id,
+                pos,
+                size: new_pos - pos,
+                expires_at,
+                renamed: true,
+            };
+            if let Some(old_to) = index.insert(to, cmd_pos) {
+                add_stale(old_to.file_id, old_to.size);
+            }
+        }
+        Command::Remove { key } => {
+            match index.remove(&key) {
+                Some(old_cmd) => add_stale(old_cmd.file_id, old_cmd.size),
+                None => report.orphan_removes += 1,
+            }
+            // The remove command is also redundant, its size = new_pos - pos
+            add_stale(file_id, new_pos - pos);
+        }
+        // Batch records are handled by `load_index`
+        Command::Batch { .. } => add_stale(file_id, new_pos - pos),
+    }
+}
+
+/// How records are compressed, see `KvStoreOptions::compression`.
+#[derive(Debug, Clone, Copy)]
+struct Compressor {
+    compression: Compression,
+    min_size: usize,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Compressor {
+            compression: Compression::None,
+            min_size: COMPRESSION_MIN_SIZE,
+        }
+    }
+}
+
+impl Compressor {
+    /// The length of `payload` as a little-endian u32 then its compressed form, `None` if
+    /// it isn't compressed.
+    fn compress(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if self.compression == Compression::None || payload.len() < self.min_size {
+            return None;
+        }
+        let compressed = [
+            &(payload.len() as u32).to_le_bytes()[..],
+            &lz4::compress(payload),
+        ]
+        .concat();
+        (compressed.len() < payload.len()).then_some(compressed)
+    }
+}
+
+/// Append `cmd` to a data file: the length of its payload, see `Command::encode`, and the
+/// CRC32C of the payload as little-endian u32s, then the payload.
+///
+/// If `compressor` compresses the payload, the high bit of the length is set and the
+/// length and CRC32C are those of the compressed form.
+fn write_record(writer: &mut impl Write, cmd: &Command, compressor: Compressor) -> Result<()> {
+    let encoded = cmd.encode();
+    let (flag, payload) = match compressor.compress(&encoded) {
+        Some(compressed) => (COMPRESSED_FLAG, compressed),
+        None => (0, encoded),
+    };
+    let mut header = [0; RECORD_HEADER_LEN];
+    header[..4].copy_from_slice(&(payload.len() as u32 | flag).to_le_bytes());
+    header[4..].copy_from_slice(&crc32c(&payload).to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Read the record at offset `pos` of data file `path` written by `write_record`.
+///
+/// Returns the command and the size of the record, or `None` at the end of the file.
+/// A record cut short or failing its checksum is an `Error::Corruption`.
+fn read_record(reader: &mut impl Read, path: &Path, pos: u64) -> Result<Option<(Command, u64)>> {
+    let corruption = || Error::Corruption {
+        file: path.to_owned(),
+        offset: pos,
+    };
+    let mut header = [0; RECORD_HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        RECORD_HEADER_LEN => {}
+        _ => return Err(corruption()),
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let compressed = len & COMPRESSED_FLAG != 0;
+    let len = (len & !COMPRESSED_FLAG) as u64;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    // A damaged length must not make us allocate more than the file holds
+    let mut payload = vec![];
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len || crc32c(&payload) != crc {
+        return Err(corruption());
+    }
+    if compressed {
+        payload = decompress(&payload).ok_or_else(corruption)?;
+    }
+    let cmd = if payload.starts_with(b"{") {
+        serde_json::from_slice(&payload).ok()
+    } else {
+        Command::decode(&payload)
+    };
+    let cmd = cmd.ok_or_else(corruption)?;
+    Ok(Some((cmd, RECORD_HEADER_LEN as u64 + len)))
+}
+
+/// The payload of a compressed record, from its length and LZ4 block.
+fn decompress(payload: &[u8]) -> Option<Vec<u8>> {
+    let len = u32::from_le_bytes(payload.get(..4)?.try_into().unwrap()) as usize;
+    // LZ4 can't expand data more than 255 times, a larger length isn't worth allocating
+    if len > payload.len() * 255 {
+        return None;
+    }
+    lz4::decompress(&payload[4..], len)
+}
+
+/// The file_id of the data file the last compaction of the store in `dir` wrote, `None`
+/// if it was never compacted.
+fn read_compacted_file_id(dir: &Path) -> Result<Option<u64>> {
+    let path = dir.join(COMPACTED_FILE);
+    match fs::read_to_string(&path) {
+        Ok(file_id) => file_id
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::Corruption {
+                file: path,
+                offset: 0,
+            }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record durably in `dir` that the data files older than `file_id` are stale.
+fn write_compacted_file_id(dir: &Path, file_id: u64) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", COMPACTED_FILE));
+    let mut file = File::create(&tmp_path)?;
+    write!(file, "{}", file_id)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, dir.join(COMPACTED_FILE))?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// The format version recorded in `dir`, `None` if there is none.
+///
+/// # Errors
+///
+/// A format file that doesn't start with `FORMAT_MAGIC` is an `Error::Corruption`.
+fn read_format_version(dir: &Path) -> Result<Option<u32>> {
+    let path = dir.join(FORMAT_FILE);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match data.strip_prefix(FORMAT_MAGIC).map(<[u8; 4]>::try_from) {
+        Some(Ok(version)) => Ok(Some(u32::from_le_bytes(version))),
+        _ => Err(Error::Corruption {
+            file: path,
+            offset: 0,
+        }),
+    }
+}
+
+/// Record in `dir` that the store is in the current format.
+fn write_format_version(dir: &Path) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", FORMAT_FILE));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(FORMAT_MAGIC)?;
+    file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, dir.join(FORMAT_FILE))?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// Rewrite the data file at `path` in the current format. Its records may be older:
+/// JSON before format version 6, and not even framed before version 4.
+///
+/// The bytes from the first unreadable record on are kept as they are, for the open to
+/// skip and report them.
+fn migrate_data_file(path: &Path, compressor: Compressor) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let unframed = reader.fill_buf()?.starts_with(b"{")
+        && matches!(
+            read_record(&mut reader, path, 0),
+            Err(Error::Corruption { .. })
+        );
+    reader.seek(SeekFrom::Start(0))?;
+
+    let tmp_path = path.with_extension("upgrade");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let mut pos = 0;
+    if unframed {
+        let mut stream = serde_json::Deserializer::from_reader(&mut reader).into_iter::<Command>();
+        while let Some(cmd) = stream.next() {
+            match cmd {
+                Ok(cmd) => write_record(&mut writer, &cmd, compressor)?,
+                Err(e) if e.is_io() => return Err(e.into()),
+                Err(_) => break,
+            }
+            pos = stream.byte_offset() as u64;
+        }
+    } else {
+        loop {
+            match read_record(&mut reader, path, pos) {
+                Ok(Some((cmd, size))) => {
+                    write_record(&mut writer, &cmd, compressor)?;
+                    pos += size;
+                }
+                Ok(None) | Err(Error::Corruption { .. }) => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    reader.seek(SeekFrom::Start(pos))?;
+    io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(tmp_path, path)?;
+    sync_dir(path.parent().unwrap())?;
+    Ok(())
+}
+
+/// Fill `buf` from `reader` unless the end is reached first, returns the number of bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
 /// Rebuild index.
 ///
-/// Load given data file and store key/command position pairs in the index.
+/// Load given data file from offset `from` and store key/command position pairs in the index.
+/// Records that can't be trusted are skipped and accounted in `report`. With `truncate_torn`,
+/// a write cut short at the end of the file is truncated away.
+///
+/// Returns the bytes it made stale, by file_id.
 fn load_index(
     file_id: u64,
+    path: &Path,
+    from: u64,
     reader: &mut BufReaderWithPos<File>,
-    index: &mut HashMap<String, CommandPos>,
-) -> Result<u64> {
-    let mut uncompacted_size: u64 = 0;
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
-
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(
-                    key,
-                    CommandPos {
-                        file_id,
-                        pos,
-                        size: new_pos - pos,
-                    },
-                ) {
-                    uncompacted_size += old_cmd.size;
-                }
+    index: &mut Index<CommandPos>,
+    report: &mut RecoveryReport,
+    truncate_torn: bool,
+) -> Result<HashMap<u64, u64>> {
+    let mut stale = HashMap::new();
+    let mut pos = reader.seek(SeekFrom::Start(from))?;
+
+    // Position of the current batch and how many of its records are left
+    let mut batch: Option<(u64, u64)> = None;
+    // Records of the current batch read so far, with their position
+    let mut batch_records = vec![];
+    let mut tail_error = None;
+    let mut torn = false;
+    loop {
+        let (cmd, new_pos) = match read_record(reader, path, pos) {
+            Ok(Some((cmd, size))) => (cmd, pos + size),
+            Ok(None) => break,
+            // The length of a damaged record can't be trusted, so the rest of the file is skipped
+            Err(e @ Error::Corruption { .. }) => {
+                tail_error = Some(e.to_string());
+                torn = runs_past_end(reader, pos)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        match (cmd, &mut batch) {
+            (Command::Batch { .. }, Some(_)) => {
+                tail_error = Some("batch inside a batch".to_owned());
+                break;
             }
-            Command::Remove { key } => {
-                let old_cmd = index.remove(&key).unwrap();
-                // The remove command in older data file is also redundant, its size = new_pos - pos
-                uncompacted_size += old_cmd.size + (new_pos - pos);
+            (Command::Batch { count }, None) => {
+                *stale.entry(file_id).or_default() += new_pos - pos;
+                batch = Some((pos, count));
+            }
+            (cmd, Some((_, remaining))) => {
+                batch_records.push((cmd, pos, new_pos));
+                *remaining -= 1;
+            }
+            (cmd, None) => index_record(file_id, cmd, pos, new_pos, index, &mut stale, report),
+        }
+        if let Some((_, 0)) = batch {
+            batch = None;
+            for (cmd, pos, new_pos) in batch_records.drain(..) {
+                index_record(file_id, cmd, pos, new_pos, index, &mut stale, report);
             }
         }
         pos = new_pos;
     }
-    Ok(uncompacted_size)
+    // A batch cut short is skipped as a whole
+    if let Some((batch_pos, _)) = batch {
+        pos = batch_pos;
+        torn |= tail_error.is_none();
+        tail_error.get_or_insert_with(|| "incomplete batch".to_owned());
+    }
+
+    if let Some(error) = tail_error {
+        let len = reader.seek(SeekFrom::End(0))?;
+        let truncated = torn && truncate_torn;
+        if truncated {
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.set_len(pos)?;
+            file.sync_all()?;
+            warn!(
+                "Truncated {:?} to offset {}, dropping {} bytes of a torn write",
+                path,
+                pos,
+                len - pos
+            );
+        } else {
+            // Compaction drops the skipped bytes along with the rest of the file
+            *stale.entry(file_id).or_default() += len - pos;
+        }
+        report.skipped_tails.push(SkippedTail {
+            file_id,
+            offset: pos,
+            bytes: len - pos,
+            error,
+            truncated,
+        });
+    }
+    Ok(stale)
+}
+
+/// Whether the record at offset `pos` ends past the end of the file, as one cut short by a
+/// crash while it was written.
+fn runs_past_end(reader: &mut BufReaderWithPos<File>, pos: u64) -> Result<bool> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut header = [0; RECORD_HEADER_LEN];
+    if read_full(reader, &mut header)? < RECORD_HEADER_LEN {
+        return Ok(true);
+    }
+    let record_len =
+        (u32::from_le_bytes(header[..4].try_into().unwrap()) & !COMPRESSED_FLAG) as u64;
+    Ok(pos + (RECORD_HEADER_LEN as u64) + record_len > len)
 }
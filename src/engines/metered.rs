@@ -0,0 +1,234 @@
+use crate::{Clock, KvsEngine, Result, WriteBatch};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Counts and latencies of one `KvsEngine` operation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpStats {
+    pub calls: u64,
+    /// Calls that returned an error, including `Error::KeyNotFound`
+    pub errors: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+}
+
+impl OpStats {
+    /// Mean latency, zero before the first call.
+    pub fn mean_time(&self) -> Duration {
+        if self.calls == 0 {
+            Duration::ZERO
+        } else {
+            self.total_time / self.calls as u32
+        }
+    }
+}
+
+/// A `KvsEngine` recording the counts and latencies of the operations of another engine.
+///
+/// Operations are named after the `KvsEngine` methods, e.g. "get".
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine, MeteredEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut store = MeteredEngine::new(KvStore::open(temp_dir.path()).unwrap());
+/// store.set("key".to_string(), "value".to_string()).unwrap();
+/// store.get("key".to_string()).unwrap();
+/// assert_eq!(store.op_stats()["get"].calls, 1);
+/// ```
+pub struct MeteredEngine<E> {
+    engine: E,
+    stats: Mutex<BTreeMap<&'static str, OpStats>>,
+}
+
+impl<E> MeteredEngine<E> {
+    pub fn new(engine: E) -> Self {
+        MeteredEngine {
+            engine,
+            stats: Mutex::default(),
+        }
+    }
+
+    /// The wrapped engine, operations on it aren't recorded.
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    /// The wrapped engine, operations on it aren't recorded.
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.engine
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    /// Stats of the operations called so far, by operation name.
+    pub fn op_stats(&self) -> BTreeMap<&'static str, OpStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn reset_op_stats(&self) {
+        self.stats.lock().unwrap().clear();
+    }
+}
+
+// A free function rather than a method, so that `op` can borrow the engine mutably
+fn measure<T>(
+    stats: &Mutex<BTreeMap<&'static str, OpStats>>,
+    name: &'static str,
+    op: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let start = Instant::now();
+    let result = op();
+    let elapsed = start.elapsed();
+
+    let mut stats = stats.lock().unwrap();
+    let op_stats = stats.entry(name).or_default();
+    op_stats.calls += 1;
+    if result.is_err() {
+        op_stats.errors += 1;
+    }
+    op_stats.total_time += elapsed;
+    op_stats.max_time = op_stats.max_time.max(elapsed);
+    result
+}
+
+impl<E: KvsEngine> KvsEngine for MeteredEngine<E> {
+    type Options = E::Options;
+
+    fn open_with(path: impl AsRef<Path>, options: E::Options) -> Result<Self> {
+        E::open_with(path, options).map(MeteredEngine::new)
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        measure(&self.stats, "set", || self.engine.set_bytes(key, value))
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        measure(&self.stats, "get", || self.engine.get_bytes(key))
+    }
+
+    fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        measure(&self.stats, "multi_get", || self.engine.multi_get(keys))
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        measure(&self.stats, "set_nx", || self.engine.set_nx(key, value))
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        measure(&self.stats, "get_set", || self.engine.get_set(key, value))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        measure(&self.stats, "compare_and_swap", || {
+            self.engine.compare_and_swap(key, expected, new)
+        })
+    }
+
+    fn append(&mut self, key: String, value: String) -> Result<usize> {
+        measure(&self.stats, "append", || self.engine.append(key, value))
+    }
+
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        measure(&self.stats, "contains_key", || {
+            self.engine.contains_key_bytes(key)
+        })
+    }
+
+    fn scan_bytes(&self, after: Option<&[u8]>, count: usize) -> Result<Vec<Vec<u8>>> {
+        measure(&self.stats, "scan", || self.engine.scan_bytes(after, count))
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        measure(&self.stats, "remove", || self.engine.remove_bytes(key))
+    }
+
+    fn len(&self) -> Result<usize> {
+        measure(&self.stats, "len", || self.engine.len())
+    }
+
+    fn set_expiration(&mut self, key: String, expires_at: Option<SystemTime>) -> Result<bool> {
+        measure(&self.stats, "set_expiration", || {
+            self.engine.set_expiration(key, expires_at)
+        })
+    }
+
+    fn expiration(&self, key: &str) -> Result<Option<Option<SystemTime>>> {
+        measure(&self.stats, "expiration", || self.engine.expiration(key))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        measure(&self.stats, "clear", || self.engine.clear())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        measure(&self.stats, "sync", || self.engine.sync())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        measure(&self.stats, "write_batch", || {
+            self.engine.write_batch(batch)
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    // Not recorded, it isn't an operation on the data
+    fn stats(&self) -> Result<Vec<(&'static str, String)>> {
+        self.engine.stats()
+    }
+
+    fn config(&self) -> Vec<(&'static str, String)> {
+        self.engine.config()
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        self.engine.set_config(name, value)
+    }
+
+    fn rename(&mut self, from: String, to: String, replace: bool) -> Result<bool> {
+        measure(&self.stats, "rename", || {
+            self.engine.rename(from, to, replace)
+        })
+    }
+
+    fn purge_expired(&mut self) -> Result<Vec<String>> {
+        measure(&self.stats, "purge_expired", || self.engine.purge_expired())
+    }
+
+    fn verify_data(&mut self, max_bytes: u64) -> Result<u64> {
+        measure(&self.stats, "verify_data", || {
+            self.engine.verify_data(max_bytes)
+        })
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        measure(&self.stats, "checkpoint", || self.engine.checkpoint())
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        measure(&self.stats, "compact", || self.engine.compact())
+    }
+
+    fn now(&self) -> SystemTime {
+        self.engine.now()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.engine.set_clock(clock)
+    }
+}
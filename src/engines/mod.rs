@@ -1,17 +1,450 @@
-use crate::Result;
+use crate::{BatchOp, Clock, Error, Result, WriteBatch};
+use range::RangeIter;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+pub mod batch;
+pub mod index;
 pub mod kvstore;
+pub mod metered;
+pub mod notifying;
+mod range;
 pub mod redb;
 pub mod sled;
+pub mod transaction;
+pub mod views;
 
 pub trait KvsEngine {
+    /// Engine-specific options of `open_with`, `open` uses their default.
+    type Options: Default;
+
     fn open(path: impl AsRef<std::path::Path>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::open_with(path, Self::Options::default())
+    }
+
+    fn open_with(path: impl AsRef<std::path::Path>, options: Self::Options) -> Result<Self>
     where
         Self: Sized;
 
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    /// Set a key to a value, both arbitrary bytes.
+    ///
+    /// This is what engines store, the string methods are layered on it: a key or value
+    /// written with `set` is its UTF-8 bytes, and keys sort by their bytes.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_bytes(b"key".to_vec(), vec![0xff, 0x00]).unwrap();
+    /// assert_eq!(store.get_bytes(b"key".to_vec()).unwrap(), Some(vec![0xff, 0x00]));
+    /// assert!(store.get("key".to_string()).is_err());
+    /// store.set("text".to_string(), "value".to_string()).unwrap();
+    /// assert_eq!(store.get_bytes(b"text".to_vec()).unwrap(), Some(b"value".to_vec()));
+    /// ```
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Get the value of a key as bytes, `None` if the key doesn't exist.
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Remove a key given as bytes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::KeyNotFound` if the key doesn't exist.
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()>;
+
+    /// Whether a key given as bytes exists, without reading its value.
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool>;
+
+    /// Up to `count` keys sorting after `after` (or from the first key if `None`), in byte
+    /// order.
+    ///
+    /// Fewer than `count` keys are returned only at the end of the keyspace,
+    /// so paging through the keyspace goes on with the last key returned.
+    fn scan_bytes(&self, after: Option<&[u8]>, count: usize) -> Result<Vec<Vec<u8>>>;
+
+    /// Set a key to a string value, see `set_bytes`.
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Get the string value of a key, `None` if the key doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::NotUtf8` if the value isn't UTF-8, see `get_bytes`.
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())?.map(utf8).transpose()
+    }
+
+    /// Get the values of `keys`, in the same order, `None` for keys that don't exist.
+    ///
+    /// The default gets them one by one. Engines override it to look them all up at once.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("a".to_string(), "1".to_string()).unwrap();
+    /// let values = store.multi_get(&["a".to_string(), "b".to_string()]).unwrap();
+    /// assert_eq!(values, [Some("1".to_string()), None]);
+    /// ```
+    fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key.clone())).collect()
+    }
+
+    /// Set a key only if it doesn't exist, returns whether it was set.
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool>;
+
+    /// Set a key and return its previous value, `None` if it didn't exist.
+    ///
+    /// Like `set`, this makes the key persistent.
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>>;
 
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// Set a key to `new`, or remove it if `new` is `None`, only if its value is `expected`,
+    /// `None` meaning that the key doesn't exist. Returns whether the key was written.
+    ///
+    /// Like `set`, this makes the key persistent. The default reads then writes, which no
+    /// other write can come between as the engine is borrowed mutably. Engines shared
+    /// between several handles override it.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// let key = "counter".to_string();
+    /// assert!(store.compare_and_swap(key.clone(), None, Some("1".to_string())).unwrap());
+    /// assert!(!store.compare_and_swap(key.clone(), None, Some("1".to_string())).unwrap());
+    /// assert!(store
+    ///     .compare_and_swap(key.clone(), Some("1".to_string()), Some("2".to_string()))
+    ///     .unwrap());
+    /// assert!(store.compare_and_swap(key.clone(), Some("2".to_string()), None).unwrap());
+    /// assert!(!store.contains_key(&key).unwrap());
+    /// ```
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        if self.get(key.clone())? != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if expected.is_some() => self.remove(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Set a key to what `f` makes of its value, or remove it if `f` returns `None`.
+    /// `f` is given `None` if the key doesn't exist. Returns the new value.
+    ///
+    /// Like `set`, this makes the key persistent. No other write comes between reading and
+    /// writing the key, as for `compare_and_swap`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// let increment = |count: Option<String>| {
+    ///     let count: u64 = count.map_or(0, |count| count.parse().unwrap());
+    ///     Some((count + 1).to_string())
+    /// };
+    /// store.update("counter".to_string(), increment).unwrap();
+    /// let count = store.update("counter".to_string(), increment).unwrap();
+    /// assert_eq!(count, Some("2".to_string()));
+    /// ```
+    fn update<F>(&mut self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+        Self: Sized,
+    {
+        let value = self.get(key.clone())?;
+        let existed = value.is_some();
+        let new_value = f(value);
+        match &new_value {
+            Some(value) => self.set(key, value.clone())?,
+            None if existed => self.remove(key)?,
+            None => {}
+        }
+        Ok(new_value)
+    }
+
+    /// Append `value` to the value of a key, creating it if it doesn't exist.
+    ///
+    /// The expiration time of the key is kept. Returns the length of the new value in bytes.
+    fn append(&mut self, key: String, value: String) -> Result<usize>;
+
+    /// Whether the given key exists, without reading its value.
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.contains_key_bytes(key.as_bytes())
+    }
+
+    /// Up to `count` keys sorting after `after` (or from the first key if `None`), in order.
+    ///
+    /// Fewer than `count` keys are returned only at the end of the keyspace,
+    /// so paging through the keyspace goes on with the last key returned. Keys that aren't
+    /// UTF-8 are skipped, see `scan_bytes`.
+    fn scan(&self, after: Option<&str>, count: usize) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut after = after.map(|after| after.as_bytes().to_vec());
+        while keys.len() < count {
+            let wanted = count - keys.len();
+            let page = self.scan_bytes(after.as_deref(), wanted)?;
+            let end = page.len() < wanted;
+            after = page.last().cloned();
+            keys.extend(
+                page.into_iter()
+                    .filter_map(|key| String::from_utf8(key).ok()),
+            );
+            if end {
+                break;
+            }
+        }
+        Ok(keys)
+    }
+
+    /// The keys in `range` and their values, in key order.
+    ///
+    /// Keys are listed a page at a time with `scan` and read with `get` as the iterator
+    /// advances, so writes made meanwhile may or may not be seen. The iterator ends after
+    /// the first error.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// for key in ["a", "b", "c"] {
+    ///     store.set(key.to_string(), key.to_uppercase()).unwrap();
+    /// }
+    /// let pairs: Vec<_> = store
+    ///     .range("b".to_string()..)
+    ///     .collect::<kvs::Result<_>>()
+    ///     .unwrap();
+    /// assert_eq!(pairs, [("b".to_string(), "B".to_string()), ("c".to_string(), "C".to_string())]);
+    /// ```
+    fn range(
+        &mut self,
+        range: impl RangeBounds<String>,
+    ) -> impl Iterator<Item = Result<(String, String)>> + '_
+    where
+        Self: Sized,
+    {
+        RangeIter::new(self, range)
+    }
+
+    /// Remove a string key, see `remove_bytes`.
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Set a key to `value` serialized as JSON, read it back with `get_typed`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use serde::{Deserialize, Serialize};
+    /// use tempfile::TempDir;
+    ///
+    /// #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    /// struct User {
+    ///     name: String,
+    ///     age: u32,
+    /// }
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// let user = User { name: "alice".to_string(), age: 42 };
+    /// store.set_typed("user:1".to_string(), &user).unwrap();
+    /// assert_eq!(store.get_typed::<User>("user:1".to_string()).unwrap(), Some(user));
+    /// ```
+    fn set_typed<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.set(key, serde_json::to_string(value)?)
+    }
+
+    /// Get the value of a key deserialized from JSON, see `set_typed`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::SerdeJson` if the value isn't the JSON of a `T`.
+    fn get_typed<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>>
+    where
+        Self: Sized,
+    {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Number of keys, expired keys aren't counted.
+    fn len(&self) -> Result<usize>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Set the time a key expires at, or make it persistent with `None`.
+    ///
+    /// Expired keys are treated as missing. Setting a key with `set` makes it persistent.
+    ///
+    /// Returns `false` if the key doesn't exist.
+    fn set_expiration(&mut self, key: String, expires_at: Option<SystemTime>) -> Result<bool>;
+
+    /// The time a key expires at.
+    ///
+    /// Returns `Ok(None)` if the key doesn't exist, `Ok(Some(None))` if it doesn't expire.
+    fn expiration(&self, key: &str) -> Result<Option<Option<SystemTime>>>;
+
+    /// Remove every key.
+    fn clear(&mut self) -> Result<()>;
+
+    /// Make the writes so far durable on disk.
+    fn sync(&mut self) -> Result<()>;
+
+    /// Apply the writes of `batch` in order, atomically: after a crash, either all of them
+    /// or none are found.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()>;
+
+    /// Name of the engine, e.g. "kvs".
+    fn name(&self) -> &'static str;
+
+    /// Engine-specific statistics as name/value pairs, reported by INFO.
+    fn stats(&self) -> Result<Vec<(&'static str, String)>>;
+
+    /// Engine-specific settings and their values, changed by `set_config`.
+    fn config(&self) -> Vec<(&'static str, String)> {
+        vec![]
+    }
+
+    /// Change a setting listed by `config` on the open engine.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::UnknownConfig` if the engine has no such setting, and
+    /// `Error::InvalidConfig` if `value` can't be parsed.
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        let _ = value;
+        Err(Error::UnknownConfig(name.to_owned()))
+    }
+
+    /// Move the value of `from` to `to`, along with its expiration time, and remove `from`.
+    ///
+    /// If `to` exists it is overwritten, unless `replace` is false: then nothing changes and
+    /// `false` is returned. Renaming a key to itself changes nothing either. The default
+    /// writes a batch, so it is atomic.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::KeyNotFound` if `from` does not exist.
+    fn rename(&mut self, from: String, to: String, replace: bool) -> Result<bool> {
+        let value = self
+            .get_bytes(from.clone().into_bytes())?
+            .ok_or(Error::KeyNotFound)?;
+        if from == to || (!replace && self.contains_key(&to)?) {
+            return Ok(from == to && replace);
+        }
+        let expires_at = self.expiration(&from)?.flatten();
+        let mut batch = WriteBatch::new();
+        batch.push(BatchOp::Set {
+            key: to.into_bytes(),
+            value,
+            expires_at,
+        });
+        batch.remove(from);
+        self.write_batch(batch)?;
+        Ok(true)
+    }
+
+    /// Remove the keys that have expired, and return them.
+    ///
+    /// Expired keys are already treated as missing, this frees what they hold.
+    /// The default removes nothing, expired keys then stay until they are written again.
+    fn purge_expired(&mut self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Check up to about `max_bytes` more of the stored data for corruption, continuing
+    /// where the previous call stopped and starting over once all of it is checked.
+    /// Returns the number of bytes checked.
+    ///
+    /// Corruption that can't be repaired is logged and counted in `stats`. The default
+    /// checks nothing, for engines that check their data as they read it.
+    fn verify_data(&mut self, _max_bytes: u64) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Save what makes the next open faster, e.g. a snapshot of an in-memory index.
+    ///
+    /// The default saves nothing.
+    fn checkpoint(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reclaim the space of overwritten and removed keys now, rather than when the engine
+    /// would on its own.
+    ///
+    /// The default does nothing, for engines reusing the space as they go.
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The current time as seen by the engine, keys expiring at it or before have expired.
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// Read the current time from `clock` from now on, see `now`.
+    ///
+    /// The default keeps the system clock, for engines whose keys don't expire.
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        let _ = clock;
+    }
+}
+
+/// A value read as bytes, as a string.
+pub(crate) fn utf8(value: Vec<u8>) -> Result<String> {
+    String::from_utf8(value).map_err(|_| Error::NotUtf8)
+}
+
+/// Milliseconds since the UNIX epoch, expiration times are stored in this unit.
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
 
-    fn remove(&mut self, key: String) -> Result<()>;
+pub(crate) fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
 }
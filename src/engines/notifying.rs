@@ -0,0 +1,286 @@
+use crate::{BatchOp, Clock, KvsEngine, Result, WriteBatch};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// What happened to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEventKind {
+    /// The value was written, by any of the writes creating or changing a value
+    Set,
+    /// The key was removed
+    Del,
+    /// The key expired and was purged, see `KvsEngine::purge_expired`
+    Expired,
+    /// The key was renamed, the value is now under another key
+    RenameFrom,
+    /// The value of another key was renamed to this key
+    RenameTo,
+}
+
+impl KeyEventKind {
+    /// Name of the event in keyspace notifications, e.g. "del".
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyEventKind::Set => "set",
+            KeyEventKind::Del => "del",
+            KeyEventKind::Expired => "expired",
+            KeyEventKind::RenameFrom => "rename_from",
+            KeyEventKind::RenameTo => "rename_to",
+        }
+    }
+}
+
+/// A change to a key, see `NotifyingEngine`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// Bytes of the key that aren't UTF-8 are replaced by U+FFFD
+    pub key: String,
+    pub kind: KeyEventKind,
+}
+
+type Listener = Arc<dyn Fn(&KeyEvent) + Send + Sync>;
+
+/// A `KvsEngine` telling listeners about the keys another engine changes.
+///
+/// Listeners are called after a write succeeded, with the engine borrowed, so they
+/// should only hand the event over. Changing the expiration time of a key and `clear`
+/// aren't reported.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KeyEventKind, KvStore, KvsEngine, NotifyingEngine};
+/// use std::sync::mpsc;
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut store = NotifyingEngine::new(KvStore::open(temp_dir.path()).unwrap());
+/// let (sender, receiver) = mpsc::channel();
+/// store.add_listener(move |event| sender.send(event.clone()).unwrap());
+///
+/// store.set("key".to_string(), "value".to_string()).unwrap();
+/// assert_eq!(receiver.recv().unwrap().kind, KeyEventKind::Set);
+/// ```
+pub struct NotifyingEngine<E> {
+    engine: E,
+    listeners: Vec<Listener>,
+}
+
+impl<E> NotifyingEngine<E> {
+    pub fn new(engine: E) -> Self {
+        NotifyingEngine {
+            engine,
+            listeners: Vec::new(),
+        }
+    }
+
+    pub fn add_listener(&mut self, listener: impl Fn(&KeyEvent) + Send + Sync + 'static) {
+        self.listeners.push(Arc::new(listener));
+    }
+
+    /// The wrapped engine, its changes aren't reported.
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    /// The wrapped engine, its changes aren't reported.
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.engine
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    fn notify(&self, key: &[u8], kind: KeyEventKind) {
+        if self.listeners.is_empty() {
+            return;
+        }
+        let event = KeyEvent {
+            key: String::from_utf8_lossy(key).into_owned(),
+            kind,
+        };
+        for listener in &self.listeners {
+            listener(&event);
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for NotifyingEngine<E> {
+    type Options = E::Options;
+
+    fn open_with(path: impl AsRef<Path>, options: E::Options) -> Result<Self> {
+        E::open_with(path, options).map(NotifyingEngine::new)
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.engine.set_bytes(key.clone(), value)?;
+        self.notify(&key, KeyEventKind::Set);
+        Ok(())
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.engine.get_bytes(key)
+    }
+
+    fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.engine.multi_get(keys)
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        let set = self.engine.set_nx(key.clone(), value)?;
+        if set {
+            self.notify(key.as_bytes(), KeyEventKind::Set);
+        }
+        Ok(set)
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old_value = self.engine.get_set(key.clone(), value)?;
+        self.notify(key.as_bytes(), KeyEventKind::Set);
+        Ok(old_value)
+    }
+
+    // Swapping a missing key for nothing changes nothing, so it isn't reported
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let kind = match (&expected, &new) {
+            (_, Some(_)) => Some(KeyEventKind::Set),
+            (Some(_), None) => Some(KeyEventKind::Del),
+            (None, None) => None,
+        };
+        let swapped = self.engine.compare_and_swap(key.clone(), expected, new)?;
+        if let (true, Some(kind)) = (swapped, kind) {
+            self.notify(key.as_bytes(), kind);
+        }
+        Ok(swapped)
+    }
+
+    fn append(&mut self, key: String, value: String) -> Result<usize> {
+        let len = self.engine.append(key.clone(), value)?;
+        self.notify(key.as_bytes(), KeyEventKind::Set);
+        Ok(len)
+    }
+
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        self.engine.contains_key_bytes(key)
+    }
+
+    fn scan_bytes(&self, after: Option<&[u8]>, count: usize) -> Result<Vec<Vec<u8>>> {
+        self.engine.scan_bytes(after, count)
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.engine.remove_bytes(key.clone())?;
+        self.notify(&key, KeyEventKind::Del);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.engine.len()
+    }
+
+    fn rename(&mut self, from: String, to: String, replace: bool) -> Result<bool> {
+        let renamed = self.engine.rename(from.clone(), to.clone(), replace)?;
+        if renamed && from != to {
+            self.notify(from.as_bytes(), KeyEventKind::RenameFrom);
+            self.notify(to.as_bytes(), KeyEventKind::RenameTo);
+        }
+        Ok(renamed)
+    }
+
+    fn set_expiration(&mut self, key: String, expires_at: Option<SystemTime>) -> Result<bool> {
+        self.engine.set_expiration(key, expires_at)
+    }
+
+    fn expiration(&self, key: &str) -> Result<Option<Option<SystemTime>>> {
+        self.engine.expiration(key)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.engine.clear()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.engine.sync()
+    }
+
+    // Removing a missing key changes nothing, so it isn't reported
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut events = Vec::with_capacity(batch.len());
+        // Whether the keys written so far exist after the ops before
+        let mut exists = HashMap::new();
+        for op in batch.ops() {
+            match op {
+                BatchOp::Set { key, .. } => {
+                    exists.insert(key.as_slice(), true);
+                    events.push((key.clone(), KeyEventKind::Set));
+                }
+                BatchOp::Remove { key } => {
+                    let existed = match exists.insert(key.as_slice(), false) {
+                        Some(existed) => existed,
+                        None => self.engine.contains_key_bytes(key)?,
+                    };
+                    if existed {
+                        events.push((key.clone(), KeyEventKind::Del));
+                    }
+                }
+            }
+        }
+        self.engine.write_batch(batch)?;
+        for (key, kind) in events {
+            self.notify(&key, kind);
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn stats(&self) -> Result<Vec<(&'static str, String)>> {
+        self.engine.stats()
+    }
+
+    fn config(&self) -> Vec<(&'static str, String)> {
+        self.engine.config()
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        self.engine.set_config(name, value)
+    }
+
+    fn purge_expired(&mut self) -> Result<Vec<String>> {
+        let expired = self.engine.purge_expired()?;
+        for key in &expired {
+            self.notify(key.as_bytes(), KeyEventKind::Expired);
+        }
+        Ok(expired)
+    }
+
+    fn verify_data(&mut self, max_bytes: u64) -> Result<u64> {
+        self.engine.verify_data(max_bytes)
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        self.engine.checkpoint()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn now(&self) -> SystemTime {
+        self.engine.now()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.engine.set_clock(clock)
+    }
+}
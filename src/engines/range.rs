@@ -0,0 +1,94 @@
+use crate::{KvsEngine, Result};
+use std::ops::{Bound, RangeBounds};
+
+/// Keys asked from `KvsEngine::scan` at a time
+const PAGE_SIZE: usize = 100;
+
+/// The iterator of `KvsEngine::range`.
+pub(crate) struct RangeIter<'a, E> {
+    engine: &'a mut E,
+    // The start key of an inclusive range, it isn't returned by scan
+    start: Option<String>,
+    end: Bound<String>,
+    page: std::vec::IntoIter<String>,
+    // Last key scanned, the next page starts after it
+    after: Option<String>,
+    last_page: bool,
+    done: bool,
+}
+
+impl<'a, E: KvsEngine> RangeIter<'a, E> {
+    pub(crate) fn new(engine: &'a mut E, range: impl RangeBounds<String>) -> Self {
+        let (start, after) = match range.start_bound() {
+            Bound::Included(start) => (Some(start.clone()), Some(start.clone())),
+            Bound::Excluded(start) => (None, Some(start.clone())),
+            Bound::Unbounded => (None, None),
+        };
+        RangeIter {
+            engine,
+            start,
+            end: range.end_bound().cloned(),
+            page: Vec::new().into_iter(),
+            after,
+            last_page: false,
+            done: false,
+        }
+    }
+
+    fn before_end(&self, key: &str) -> bool {
+        match &self.end {
+            Bound::Included(end) => key <= end.as_str(),
+            Bound::Excluded(end) => key < end.as_str(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// The next key of the range, `None` once past its end.
+    fn next_key(&mut self) -> Result<Option<String>> {
+        if let Some(start) = self.start.take() {
+            if self.engine.contains_key(&start)? {
+                return Ok(Some(start));
+            }
+        }
+        loop {
+            if let Some(key) = self.page.next() {
+                return Ok(Some(key));
+            }
+            if self.last_page {
+                return Ok(None);
+            }
+            let page = self.engine.scan(self.after.as_deref(), PAGE_SIZE)?;
+            self.last_page = page.len() < PAGE_SIZE;
+            self.after = page.last().cloned();
+            self.page = page.into_iter();
+        }
+    }
+}
+
+impl<E: KvsEngine> Iterator for RangeIter<'_, E> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let key = match self.next_key() {
+                Ok(Some(key)) if self.before_end(&key) => key,
+                Ok(_) => break,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            // A key expiring or removed since it was scanned is skipped
+            match self.engine.get(key.clone()) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.done = true;
+        None
+    }
+}
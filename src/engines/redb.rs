@@ -1,64 +1,425 @@
-use crate::{KvsEngine, Result};
-use redb::{Database, ReadableTable, TableDefinition};
+use crate::engines::{from_unix_millis, unix_millis, utf8};
+use crate::{BatchOp, Clock, Error, KvsEngine, Result, SystemClock, WriteBatch};
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
 
-const TABLE: TableDefinition<&str, &str> = TableDefinition::new("table_1");
+const TABLE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("values");
+// Expiration times of the keys that expire, in milliseconds since the UNIX epoch
+const EXPIRES: TableDefinition<&[u8], u64> = TableDefinition::new("expires_at");
+// Tables of databases written when keys and values were strings, moved to those above on open
+const STR_TABLE: TableDefinition<&str, &str> = TableDefinition::new("table_1");
+const STR_EXPIRES: TableDefinition<&str, u64> = TableDefinition::new("expires");
 
 pub struct Redb {
     db: Database,
+    path: PathBuf,
+    clock: Arc<dyn Clock>,
+}
+
+fn is_expired(
+    expires: &impl ReadableTable<&'static [u8], u64>,
+    key: &[u8],
+    now: u64,
+) -> Result<bool> {
+    let rst = expires
+        .get(key)?
+        .is_some_and(|expires_at| expires_at.value() <= now);
+    Ok(rst)
+}
+
+/// Copy the string tables to the byte tables, and delete them.
+fn migrate_str_tables(write_txn: &WriteTransaction<'_>) -> Result<()> {
+    {
+        let str_table = write_txn.open_table(STR_TABLE)?;
+        let str_expires = write_txn.open_table(STR_EXPIRES)?;
+        let mut table = write_txn.open_table(TABLE)?;
+        let mut expires = write_txn.open_table(EXPIRES)?;
+        for (key, value) in str_table.iter()? {
+            table.insert(key.value().as_bytes(), value.value().as_bytes())?;
+        }
+        for (key, expires_at) in str_expires.iter()? {
+            expires.insert(key.value().as_bytes(), &expires_at.value())?;
+        }
+    }
+    write_txn.delete_table(STR_TABLE)?;
+    write_txn.delete_table(STR_EXPIRES)?;
+    Ok(())
 }
 
 impl KvsEngine for Redb {
+    type Options = ();
+
     // The final component of path is redb file(not dir), which is different from KvStore(KvStore is a dir)
-    fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
+    fn open_with(path: impl AsRef<std::path::Path>, _options: ()) -> Result<Self> {
         if let Some(parent_of_path) = path.as_ref().parent() {
             std::fs::create_dir_all(parent_of_path)?;
         }
 
-        let db = Database::create(path)?;
-        Ok(Redb { db })
+        let path = path.as_ref().to_path_buf();
+        let db = Database::create(&path)?;
+        // Create the tables, so that reads don't fail on a new database
+        let write_txn = db.begin_write()?;
+        if write_txn.list_tables()?.any(|name| name == "table_1") {
+            migrate_str_tables(&write_txn)?;
+        }
+        write_txn.open_table(TABLE)?;
+        write_txn.open_table(EXPIRES)?;
+        write_txn.commit()?;
+        Ok(Redb {
+            db,
+            path,
+            clock: Arc::new(SystemClock),
+        })
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         let write_txn = self.db.begin_write()?;
         {
             let mut table = write_txn.open_table(TABLE)?;
             table.insert(&key, &value)?;
+            let mut expires = write_txn.open_table(EXPIRES)?;
+            expires.remove(&key)?;
         }
         write_txn.commit()?;
 
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         let read_txn = self.db.begin_read()?;
         let table = read_txn.open_table(TABLE)?;
+        let expires = read_txn.open_table(EXPIRES)?;
+        if is_expired(&expires, &key, unix_millis(self.clock.now()))? {
+            return Ok(None);
+        }
 
         // need a local var: rst
         let rst = table
             .get(&key)?
-            .map_or_else(|| Ok(None), |value| Ok(Some(value.value().to_string())));
+            .map_or_else(|| Ok(None), |value| Ok(Some(value.value().to_vec())));
         rst
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        // All from one read transaction, so they are read from the same snapshot
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(TABLE)?;
+        let expires = read_txn.open_table(EXPIRES)?;
+        let now = unix_millis(self.clock.now());
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = if is_expired(&expires, key.as_bytes(), now)? {
+                None
+            } else {
+                match table.get(key.as_bytes())? {
+                    Some(value) => Some(utf8(value.value().to_vec())?),
+                    None => None,
+                }
+            };
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(TABLE)?;
+            let mut expires = write_txn.open_table(EXPIRES)?;
+            let key = key.as_bytes();
+            if table.get(key)?.is_some()
+                && !is_expired(&expires, key, unix_millis(self.clock.now()))?
+            {
+                return Ok(false);
+            }
+            table.insert(key, value.as_bytes())?;
+            expires.remove(key)?;
+        }
+        write_txn.commit()?;
+
+        Ok(true)
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
         let write_txn = self.db.begin_write()?;
+        let old_value;
         {
             let mut table = write_txn.open_table(TABLE)?;
-            table.remove(&key)?;
+            let mut expires = write_txn.open_table(EXPIRES)?;
+            let key = key.as_bytes();
+            let expired = is_expired(&expires, key, unix_millis(self.clock.now()))?;
+            old_value = match table.insert(key, value.as_bytes())? {
+                Some(old_value) if !expired => Some(utf8(old_value.value().to_vec())?),
+                _ => None,
+            };
+            expires.remove(key)?;
+        }
+        write_txn.commit()?;
+
+        Ok(old_value)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(TABLE)?;
+            let mut expires = write_txn.open_table(EXPIRES)?;
+            let key = key.as_bytes();
+            let expired = is_expired(&expires, key, unix_millis(self.clock.now()))?;
+            let current = table
+                .get(key)?
+                .filter(|_| !expired)
+                .map(|value| value.value().to_vec());
+            if current != expected.map(String::into_bytes) {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => table.insert(key, value.as_bytes())?,
+                None => table.remove(key)?,
+            };
+            expires.remove(key)?;
+        }
+        write_txn.commit()?;
+
+        Ok(true)
+    }
+
+    fn append(&mut self, key: String, value: String) -> Result<usize> {
+        let write_txn = self.db.begin_write()?;
+        let len;
+        {
+            let mut table = write_txn.open_table(TABLE)?;
+            let mut expires = write_txn.open_table(EXPIRES)?;
+            let key = key.as_bytes();
+            let mut new_value = String::new();
+            if is_expired(&expires, key, unix_millis(self.clock.now()))? {
+                expires.remove(key)?;
+            } else if let Some(old_value) = table.get(key)? {
+                new_value = utf8(old_value.value().to_vec())?;
+            }
+            new_value.push_str(&value);
+            len = new_value.len();
+            table.insert(key, new_value.as_bytes())?;
+        }
+        write_txn.commit()?;
+
+        Ok(len)
+    }
+
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(TABLE)?;
+        let expires = read_txn.open_table(EXPIRES)?;
+
+        let rst =
+            table.get(key)?.is_some() && !is_expired(&expires, key, unix_millis(self.clock.now()))?;
+        Ok(rst)
+    }
+
+    fn scan_bytes(&self, after: Option<&[u8]>, count: usize) -> Result<Vec<Vec<u8>>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(TABLE)?;
+        let expires = read_txn.open_table(EXPIRES)?;
+        let now = unix_millis(self.clock.now());
+
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut keys = vec![];
+        for (key, _) in table.range::<&[u8]>((start, Bound::Unbounded))? {
+            if keys.len() == count {
+                break;
+            }
+            if !is_expired(&expires, key.value(), now)? {
+                keys.push(key.value().to_vec());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut expires = write_txn.open_table(EXPIRES)?;
+            let expired = is_expired(&expires, &key, unix_millis(self.clock.now()))?;
+            expires.remove(&key)?;
+            let mut table = write_txn.open_table(TABLE)?;
+            if table.remove(&key)?.is_none() || expired {
+                return Err(Error::KeyNotFound);
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(TABLE)?;
+        let expires = read_txn.open_table(EXPIRES)?;
+        let now = unix_millis(self.clock.now());
+
+        // Every key in EXPIRES is also in TABLE
+        let mut expired = 0;
+        for (_, expires_at) in expires.iter()? {
+            if expires_at.value() <= now {
+                expired += 1;
+            }
+        }
+        Ok(table.len()? - expired)
+    }
+
+    fn set_expiration(&mut self, key: String, expires_at: Option<SystemTime>) -> Result<bool> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let table = write_txn.open_table(TABLE)?;
+            let mut expires = write_txn.open_table(EXPIRES)?;
+            let key = key.as_bytes();
+            if table.get(key)?.is_none()
+                || is_expired(&expires, key, unix_millis(self.clock.now()))?
+            {
+                return Ok(false);
+            }
+            match expires_at {
+                Some(expires_at) => expires.insert(key, &unix_millis(expires_at))?,
+                None => expires.remove(key)?,
+            };
+        }
+        write_txn.commit()?;
+
+        Ok(true)
+    }
+
+    fn expiration(&self, key: &str) -> Result<Option<Option<SystemTime>>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(TABLE)?;
+        let expires = read_txn.open_table(EXPIRES)?;
+        let key = key.as_bytes();
+        if table.get(key)?.is_none() {
+            return Ok(None);
+        }
+
+        let rst = match expires.get(key)?.map(|expires_at| expires_at.value()) {
+            Some(expires_at) if expires_at <= unix_millis(self.clock.now()) => None,
+            expires_at => Some(expires_at.map(from_unix_millis)),
+        };
+        Ok(rst)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        write_txn.delete_table(TABLE)?;
+        write_txn.delete_table(EXPIRES)?;
+        write_txn.open_table(TABLE)?;
+        write_txn.open_table(EXPIRES)?;
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    // Every write transaction is durable once committed
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The whole batch is a single write transaction.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(TABLE)?;
+            let mut expires = write_txn.open_table(EXPIRES)?;
+            for op in batch {
+                match op {
+                    BatchOp::Set {
+                        key,
+                        value,
+                        expires_at,
+                    } => {
+                        table.insert(&key, &value)?;
+                        match expires_at {
+                            Some(expires_at) => expires.insert(&key, &unix_millis(expires_at))?,
+                            None => expires.remove(&key)?,
+                        };
+                    }
+                    BatchOp::Remove { key } => {
+                        table.remove(&key)?;
+                        expires.remove(&key)?;
+                    }
+                }
+            }
         }
         write_txn.commit()?;
 
         Ok(())
     }
+
+    /// The expired keys are removed in a single write transaction.
+    ///
+    /// Keys that aren't UTF-8 are returned with their invalid bytes replaced by U+FFFD.
+    fn purge_expired(&mut self) -> Result<Vec<String>> {
+        let now = unix_millis(self.clock.now());
+        let write_txn = self.db.begin_write()?;
+        let mut purged = Vec::new();
+        {
+            let mut table = write_txn.open_table(TABLE)?;
+            let mut expires = write_txn.open_table(EXPIRES)?;
+            for (key, expires_at) in expires.iter()? {
+                if expires_at.value() <= now {
+                    purged.push(key.value().to_vec());
+                }
+            }
+            for key in &purged {
+                table.remove(key)?;
+                expires.remove(key)?;
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(purged
+            .iter()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .collect())
+    }
+
+    fn name(&self) -> &'static str {
+        "redb"
+    }
+
+    fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    fn stats(&self) -> Result<Vec<(&'static str, String)>> {
+        Ok(vec![
+            ("keys", self.len()?.to_string()),
+            (
+                "disk_bytes",
+                std::fs::metadata(&self.path)?.len().to_string(),
+            ),
+        ])
+    }
 }
 
 // TODO: unit test -> doc test
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempfile::TempDir;
+
     #[test]
     fn it_works() {
-        let mut redb = Redb::open("tmp").unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let mut redb = Redb::open(temp_dir.path().join("tmp")).unwrap();
         redb.set("key1".to_owned(), "value1".to_owned()).unwrap();
         println!("{:?}", redb.get("key1".to_owned()).unwrap());
         redb.remove("key1".to_owned()).unwrap();
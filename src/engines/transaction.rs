@@ -0,0 +1,256 @@
+use crate::{BatchOp, Clock, Error, KvsEngine, Result, WriteBatch};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+type Staged = (Vec<u8>, Option<SystemTime>);
+
+/// Writes staged on top of an engine, applied atomically by `commit`.
+///
+/// Reads through the transaction see its own writes, the engine isn't changed until
+/// `commit`. Dropping the transaction discards its writes.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine, Transaction};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut store = KvStore::open(temp_dir.path()).unwrap();
+///
+/// let mut transaction = Transaction::new(&mut store);
+/// transaction.set("key".to_string(), "value".to_string()).unwrap();
+/// assert_eq!(transaction.get("key".to_string()).unwrap(), Some("value".to_string()));
+/// transaction.commit().unwrap();
+/// assert_eq!(store.get("key".to_string()).unwrap(), Some("value".to_string()));
+/// ```
+pub struct Transaction<'a, E> {
+    engine: &'a mut E,
+    // Staged value and expiration time of the keys written so far, `None` if removed
+    staged: BTreeMap<Vec<u8>, Option<Staged>>,
+    batch: WriteBatch,
+}
+
+impl<'a, E: KvsEngine> Transaction<'a, E> {
+    pub fn new(engine: &'a mut E) -> Self {
+        Transaction {
+            engine,
+            staged: BTreeMap::new(),
+            batch: WriteBatch::new(),
+        }
+    }
+
+    /// Apply the writes of the transaction to the engine, all of them or none.
+    pub fn commit(self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        self.engine.write_batch(self.batch)
+    }
+
+    /// The staged value and expiration time of a key, `Some(None)` if it is staged as missing.
+    fn staged(&self, key: &[u8]) -> Option<Option<(&[u8], Option<SystemTime>)>> {
+        let now = self.engine.now();
+        self.staged.get(key).map(|entry| {
+            entry
+                .as_ref()
+                .filter(|(_, expires_at)| expires_at.is_none_or(|expires_at| expires_at > now))
+                .map(|(value, expires_at)| (value.as_slice(), *expires_at))
+        })
+    }
+
+    fn stage(&mut self, op: BatchOp) {
+        match &op {
+            BatchOp::Set {
+                key,
+                value,
+                expires_at,
+            } => self
+                .staged
+                .insert(key.clone(), Some((value.clone(), *expires_at))),
+            BatchOp::Remove { key } => self.staged.insert(key.clone(), None),
+        };
+        self.batch.push(op);
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Transaction<'_, E> {
+    type Options = ();
+
+    /// A transaction is started on an open engine with `Transaction::new`.
+    fn open_with(_path: impl AsRef<Path>, _options: ()) -> Result<Self> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.stage(BatchOp::Set {
+            key,
+            value,
+            expires_at: None,
+        });
+        Ok(())
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.staged(&key) {
+            Some(entry) => Ok(entry.map(|(value, _)| value.to_vec())),
+            None => self.engine.get_bytes(key),
+        }
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        if self.contains_key(&key)? {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old_value = self.get(key.clone())?;
+        self.set(key, value)?;
+        Ok(old_value)
+    }
+
+    fn append(&mut self, key: String, value: String) -> Result<usize> {
+        let expires_at = self.expiration(&key)?.flatten();
+        let mut new_value = self.get(key.clone())?.unwrap_or_default();
+        new_value.push_str(&value);
+        let len = new_value.len();
+        self.stage(BatchOp::Set {
+            key: key.into_bytes(),
+            value: new_value.into_bytes(),
+            expires_at,
+        });
+        Ok(len)
+    }
+
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        match self.staged(key) {
+            Some(entry) => Ok(entry.is_some()),
+            None => self.engine.contains_key_bytes(key),
+        }
+    }
+
+    // Every staged key hides at most one key of the engine, so a page of the engine
+    // that much longer has enough keys left
+    fn scan_bytes(&self, after: Option<&[u8]>, count: usize) -> Result<Vec<Vec<u8>>> {
+        let engine_count = count.saturating_add(self.staged.len());
+        let engine_keys = self.engine.scan_bytes(after, engine_count)?;
+        // Past the end of a full page, there may be keys of the engine that weren't read
+        let last = match engine_keys.last() {
+            Some(last) if engine_keys.len() == engine_count => Some(last.clone()),
+            _ => None,
+        };
+
+        let mut keys: Vec<Vec<u8>> = engine_keys
+            .into_iter()
+            .filter(|key| self.staged(key).is_none())
+            .collect();
+        keys.extend(
+            self.staged
+                .keys()
+                .filter(|key| after.is_none_or(|after| key.as_slice() > after))
+                .filter(|key| last.as_ref().is_none_or(|last| *key <= last))
+                .filter(|key| matches!(self.staged(key), Some(Some(_))))
+                .cloned(),
+        );
+        keys.sort_unstable();
+        keys.truncate(count);
+        Ok(keys)
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if !self.contains_key_bytes(&key)? {
+            return Err(Error::KeyNotFound);
+        }
+        self.stage(BatchOp::Remove { key });
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize> {
+        let mut len = self.engine.len()?;
+        for key in self.staged.keys() {
+            match (
+                self.engine.contains_key_bytes(key)?,
+                self.staged(key).unwrap().is_some(),
+            ) {
+                (false, true) => len += 1,
+                (true, false) => len -= 1,
+                _ => {}
+            }
+        }
+        Ok(len)
+    }
+
+    fn set_expiration(&mut self, key: String, expires_at: Option<SystemTime>) -> Result<bool> {
+        let key = key.into_bytes();
+        let value = match self.get_bytes(key.clone())? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        self.stage(BatchOp::Set {
+            key,
+            value,
+            expires_at,
+        });
+        Ok(true)
+    }
+
+    fn expiration(&self, key: &str) -> Result<Option<Option<SystemTime>>> {
+        match self.staged(key.as_bytes()) {
+            Some(entry) => Ok(entry.map(|(_, expires_at)| expires_at)),
+            None => self.engine.expiration(key),
+        }
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        for key in self.scan_bytes(None, usize::MAX)? {
+            self.stage(BatchOp::Remove { key });
+        }
+        Ok(())
+    }
+
+    // Nothing is written before the commit
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        for op in batch {
+            match op {
+                BatchOp::Remove { key } if !self.contains_key_bytes(&key)? => {}
+                op => self.stage(op),
+            }
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn stats(&self) -> Result<Vec<(&'static str, String)>> {
+        self.engine.stats()
+    }
+
+    fn config(&self) -> Vec<(&'static str, String)> {
+        self.engine.config()
+    }
+
+    // Settings aren't part of the transaction
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        self.engine.set_config(name, value)
+    }
+
+    fn now(&self) -> SystemTime {
+        self.engine.now()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.engine.set_clock(clock)
+    }
+}
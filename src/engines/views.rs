@@ -0,0 +1,371 @@
+use crate::{BatchOp, Clock, Error, KvsEngine, Result, WriteBatch};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// Keys read at a time when going through the keys of a scope
+const SCOPE_PAGE: usize = 1000;
+
+/// A `KvsEngine` view of another engine that only reads.
+///
+/// Every write, including changing expiration times and settings, fails with `Error::ReadOnly`.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{Error, KvStore, KvsEngine, ReadOnly};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut store = KvStore::open(temp_dir.path()).unwrap().into_shared();
+/// store.set("key".to_string(), "value".to_string()).unwrap();
+///
+/// let mut view = ReadOnly::new(store.clone());
+/// assert_eq!(view.get("key".to_string()).unwrap(), Some("value".to_string()));
+/// assert!(matches!(view.remove("key".to_string()), Err(Error::ReadOnly)));
+/// ```
+pub struct ReadOnly<E> {
+    engine: E,
+}
+
+impl<E> ReadOnly<E> {
+    pub fn new(engine: E) -> Self {
+        ReadOnly { engine }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReadOnly<E> {
+    type Options = E::Options;
+
+    fn open_with(path: impl AsRef<Path>, options: E::Options) -> Result<Self> {
+        E::open_with(path, options).map(ReadOnly::new)
+    }
+
+    fn set_bytes(&mut self, _key: Vec<u8>, _value: Vec<u8>) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.engine.get_bytes(key)
+    }
+
+    fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.engine.multi_get(keys)
+    }
+
+    fn set_nx(&mut self, _key: String, _value: String) -> Result<bool> {
+        Err(Error::ReadOnly)
+    }
+
+    fn get_set(&mut self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(Error::ReadOnly)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        _key: String,
+        _expected: Option<String>,
+        _new: Option<String>,
+    ) -> Result<bool> {
+        Err(Error::ReadOnly)
+    }
+
+    fn append(&mut self, _key: String, _value: String) -> Result<usize> {
+        Err(Error::ReadOnly)
+    }
+
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        self.engine.contains_key_bytes(key)
+    }
+
+    fn scan_bytes(&self, after: Option<&[u8]>, count: usize) -> Result<Vec<Vec<u8>>> {
+        self.engine.scan_bytes(after, count)
+    }
+
+    fn remove_bytes(&mut self, _key: Vec<u8>) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn rename(&mut self, _from: String, _to: String, _replace: bool) -> Result<bool> {
+        Err(Error::ReadOnly)
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.engine.len()
+    }
+
+    fn set_expiration(&mut self, _key: String, _expires_at: Option<SystemTime>) -> Result<bool> {
+        Err(Error::ReadOnly)
+    }
+
+    fn expiration(&self, key: &str) -> Result<Option<Option<SystemTime>>> {
+        self.engine.expiration(key)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    // Nothing was written through the view
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_batch(&mut self, _batch: WriteBatch) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn stats(&self) -> Result<Vec<(&'static str, String)>> {
+        self.engine.stats()
+    }
+
+    fn config(&self) -> Vec<(&'static str, String)> {
+        self.engine.config()
+    }
+
+    fn set_config(&mut self, _name: &str, _value: &str) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn now(&self) -> SystemTime {
+        self.engine.now()
+    }
+
+    // The clock is a setting too, it is left alone
+    fn set_clock(&mut self, _clock: Arc<dyn Clock>) {}
+}
+
+/// A `KvsEngine` view of the keys of another engine starting with a prefix.
+///
+/// Keys are given and returned without the prefix, keys outside of the scope can't be
+/// reached, and `len`, `scan` and `clear` only cover the scope.
+/// Statistics and settings are those of the whole engine.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine, Scoped};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap().into_shared();
+/// let mut jobs = Scoped::new(store.clone(), "jobs:");
+/// jobs.set("1".to_string(), "pending".to_string()).unwrap();
+///
+/// assert_eq!(store.lock().get("jobs:1".to_string()).unwrap(), Some("pending".to_string()));
+/// assert_eq!(jobs.scan(None, 10).unwrap(), ["1"]);
+/// ```
+pub struct Scoped<E> {
+    engine: E,
+    prefix: String,
+}
+
+impl<E> Scoped<E> {
+    pub fn new(engine: E, prefix: impl Into<String>) -> Self {
+        Scoped {
+            engine,
+            prefix: prefix.into(),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn full_key_bytes(&self, key: &[u8]) -> Vec<u8> {
+        [self.prefix.as_bytes(), key].concat()
+    }
+}
+
+impl<E: KvsEngine> Scoped<E> {
+    /// Every key of the scope, with the prefix.
+    fn full_keys(&self) -> Result<Vec<Vec<u8>>> {
+        let mut keys = vec![];
+        let mut after = None;
+        loop {
+            let page = self.scan_bytes(after.as_deref(), SCOPE_PAGE)?;
+            let done = page.len() < SCOPE_PAGE;
+            after = page.last().cloned();
+            keys.extend(page.iter().map(|key| self.full_key_bytes(key)));
+            if done {
+                return Ok(keys);
+            }
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Scoped<E> {
+    type Options = E::Options;
+
+    /// A view of the whole engine, with an empty prefix.
+    fn open_with(path: impl AsRef<Path>, options: E::Options) -> Result<Self> {
+        E::open_with(path, options).map(|engine| Scoped::new(engine, ""))
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.engine.set_bytes(self.full_key_bytes(&key), value)
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.engine.get_bytes(self.full_key_bytes(&key))
+    }
+
+    fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let keys: Vec<String> = keys.iter().map(|key| self.full_key(key)).collect();
+        self.engine.multi_get(&keys)
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        self.engine.set_nx(self.full_key(&key), value)
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.engine.get_set(self.full_key(&key), value)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.engine
+            .compare_and_swap(self.full_key(&key), expected, new)
+    }
+
+    fn append(&mut self, key: String, value: String) -> Result<usize> {
+        self.engine.append(self.full_key(&key), value)
+    }
+
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        self.engine.contains_key_bytes(&self.full_key_bytes(key))
+    }
+
+    // The keys of the scope are contiguous in key order, from the prefix itself on
+    fn scan_bytes(&self, after: Option<&[u8]>, count: usize) -> Result<Vec<Vec<u8>>> {
+        let mut keys = vec![];
+        let after = match after {
+            Some(after) => self.full_key_bytes(after),
+            None => {
+                // The prefix itself is the key "" of the scope, scan would skip it
+                if count > 0 && self.engine.contains_key(&self.prefix)? {
+                    keys.push(vec![]);
+                }
+                self.prefix.clone().into_bytes()
+            }
+        };
+        for key in self.engine.scan_bytes(Some(&after), count - keys.len())? {
+            match key.strip_prefix(self.prefix.as_bytes()) {
+                Some(key) => keys.push(key.to_vec()),
+                None => break,
+            }
+        }
+        Ok(keys)
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.engine.remove_bytes(self.full_key_bytes(&key))
+    }
+
+    fn rename(&mut self, from: String, to: String, replace: bool) -> Result<bool> {
+        self.engine
+            .rename(self.full_key(&from), self.full_key(&to), replace)
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.full_keys()?.len())
+    }
+
+    fn set_expiration(&mut self, key: String, expires_at: Option<SystemTime>) -> Result<bool> {
+        self.engine.set_expiration(self.full_key(&key), expires_at)
+    }
+
+    fn expiration(&self, key: &str) -> Result<Option<Option<SystemTime>>> {
+        self.engine.expiration(&self.full_key(key))
+    }
+
+    /// Remove every key of the scope, one by one.
+    fn clear(&mut self) -> Result<()> {
+        for key in self.full_keys()? {
+            match self.engine.remove_bytes(key) {
+                // Expired since it was listed
+                Ok(()) | Err(Error::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.engine.sync()
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut scoped = WriteBatch::new();
+        for op in batch {
+            scoped.push(match op {
+                BatchOp::Set {
+                    key,
+                    value,
+                    expires_at,
+                } => BatchOp::Set {
+                    key: self.full_key_bytes(&key),
+                    value,
+                    expires_at,
+                },
+                BatchOp::Remove { key } => BatchOp::Remove {
+                    key: self.full_key_bytes(&key),
+                },
+            });
+        }
+        self.engine.write_batch(scoped)
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn stats(&self) -> Result<Vec<(&'static str, String)>> {
+        self.engine.stats()
+    }
+
+    fn config(&self) -> Vec<(&'static str, String)> {
+        self.engine.config()
+    }
+
+    fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        self.engine.set_config(name, value)
+    }
+
+    fn now(&self) -> SystemTime {
+        self.engine.now()
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.engine.set_clock(clock)
+    }
+}
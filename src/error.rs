@@ -1,3 +1,4 @@
+use redis_protocol::types::RedisProtocolError;
 use thiserror::Error;
 
 /// This type represents all possible errors in kvs lib.
@@ -7,6 +8,79 @@ pub enum Error {
     KeyNotFound,
     #[error("Unexpected Command")]
     UnexpectedCommand,
+    #[error("Unexpected Response")]
+    UnexpectedResponse,
+    #[error("{0}")]
+    Server(String),
+    #[error("Protocol: {0}")]
+    Protocol(String),
+    #[error("Protocol error: {0}")]
+    FrameRejected(&'static str),
+    #[error("Unknown job: {0}")]
+    UnknownJob(String),
+    #[error("Unknown config: {0}")]
+    UnknownConfig(String),
+    #[error("Invalid value {1:?} for config {0}")]
+    InvalidConfig(String, String),
+    #[error("DUMP payload version or checksum are wrong")]
+    InvalidDump,
+    #[error("Not valid hex or base64 of a UTF-8 string")]
+    InvalidEncoding,
+    #[error("Not a capture file written by kvs-server")]
+    InvalidCapture,
+    #[error("invalid cursor")]
+    InvalidCursor,
+    #[error("invalid key: {0}")]
+    InvalidKey(crate::KeyViolation),
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("Value isn't UTF-8, it can only be read as bytes")]
+    NotUtf8,
+    #[error("Record of key {0:?} is corrupt on disk")]
+    CorruptRecord(String),
+    #[error("Corrupt record in {file:?} at offset {offset}")]
+    Corruption {
+        file: std::path::PathBuf,
+        offset: u64,
+    },
+    #[error("Key is locked by another connection")]
+    KeyLocked,
+    #[error("Server is shutting down")]
+    ShuttingDown,
+    #[error("Server can't be upgraded in place")]
+    UpgradeUnsupported,
+    #[error("Transaction aborted, a watched key was written")]
+    TransactionAborted,
+    #[error("Store is read-only")]
+    ReadOnly,
+    #[error("Store at {0:?} is already open")]
+    AlreadyOpen(std::path::PathBuf),
+    #[error("Store at {path:?} is locked by another process{}", .pid.map_or(String::new(), |pid| format!(" (PID {})", pid)))]
+    AlreadyLocked {
+        path: std::path::PathBuf,
+        /// Process holding the lock, if it could be read
+        pid: Option<u32>,
+    },
+    #[error("Store at {path:?} has format version {version}, newer than version {supported} this build reads")]
+    UnsupportedFormat {
+        path: std::path::PathBuf,
+        version: u32,
+        supported: u32,
+    },
+    #[error("Data file {0:?} is in several data directories")]
+    DuplicateDataFile(std::path::PathBuf),
+    #[error("Authentication required")]
+    AuthRequired,
+    #[error("Invalid username-password pair")]
+    WrongPass,
+    #[error("Line {1} of user file {0:?} isn't a username and a password")]
+    InvalidUserFile(std::path::PathBuf, usize),
+    #[error("Server {0} is down")]
+    ShardDown(String),
+    #[error("Keys of the batch belong to different servers")]
+    CrossShardBatch,
+    #[error("Request")]
+    Request(#[from] crate::RequestError),
     #[error("IO")]
     IO(#[from] std::io::Error),
     #[error("Serde_json")]
@@ -15,5 +89,12 @@ pub enum Error {
     Redb(#[from] redb::Error),
 }
 
+// RedisProtocolError doesn't implement std::error::Error, so it can't be a #[source]
+impl From<RedisProtocolError> for Error {
+    fn from(err: RedisProtocolError) -> Self {
+        Error::Protocol(err.to_string())
+    }
+}
+
 /// Alias for a Result with the error type kvs::Error
 pub type Result<T> = std::result::Result<T, crate::Error>;
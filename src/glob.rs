@@ -0,0 +1,113 @@
+/// Returns whether `string` matches the glob-style `pattern`.
+///
+/// Supports the same subset of glob syntax as redis:
+///
+/// - `?` matches any single character
+/// - `*` matches any sequence of characters, including an empty one
+/// - `[abc]`, `[a-z]` and `[^abc]` match one character of (or not of) a class
+/// - `\x` matches the character `x` literally
+pub(crate) fn glob_match(pattern: &str, string: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let string: Vec<char> = string.chars().collect();
+    match_from(&pattern, &string)
+}
+
+fn match_from(pattern: &[char], string: &[char]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Position to retry from after the last `*`: (pattern index, string index)
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let matched = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, s));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match_class(pattern, p, string[s]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == string[s]).then_some(p + 2),
+            Some(&c) => (c == string[s]).then_some(p + 1),
+            None => None,
+        };
+
+        match (matched, backtrack) {
+            (Some(next_p), _) => {
+                p = next_p;
+                s += 1;
+            }
+            // Let the last `*` swallow one more character and retry
+            (None, Some((star_p, star_s))) => {
+                backtrack = Some((star_p, star_s + 1));
+                p = star_p + 1;
+                s = star_s + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Match `c` against the character class starting at `pattern[start] == '['`.
+///
+/// Returns the pattern index just after the class if `c` matches.
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<usize> {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != ']' {
+        if pattern[p] == '\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == '-' && pattern[p + 2] != ']' {
+            let (lo, hi) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            matched |= lo <= c && c <= hi;
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+
+    // An unterminated class runs to the end of the pattern
+    let next = (p + 1).min(pattern.len());
+    (matched != negate).then_some(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("user:*", "user:42"));
+        assert!(glob_match("*:42", "user:42"));
+        assert!(glob_match("u?er:*", "user:"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("user:*", "users:42"));
+        assert!(!glob_match("a*b*c", "aXXbYY"));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("key[0-9]", "key7"));
+        assert!(!glob_match("key[0-9]", "keyx"));
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::engines::kvstore::Command;
+use crate::random::random_u64;
+use crate::{Remove, Request, Result, Set};
+use log::warn;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const JOURNAL_FILE: &str = "journal.log"; // Pending writes, as a stream of JSON commands
+const ID_FILE: &str = "journal-id"; // Id of the current run of pending writes
+
+/// Writes a client made while the server was unreachable, see `KvsClient::connect_journaled`.
+///
+/// Writes are numbered from 1 within a run, the pending writes between two replays.
+/// A run has a random id, together they form the idempotency token of a write: the
+/// server keeps the number of the last write replayed under `marker_key`, so a replay
+/// cut short by a crash is resumed rather than started over.
+pub(crate) struct Journal {
+    dir: PathBuf,
+    id: String,
+    writer: BufWriter<File>,
+    pending: Vec<Command>,
+}
+
+impl Journal {
+    /// Open the journal in `dir`, creating it if needed.
+    ///
+    /// A write torn by a crash at the end of the journal is dropped.
+    pub(crate) fn open(dir: impl AsRef<Path>) -> Result<Journal> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let id = match fs::read_to_string(dir.join(ID_FILE)) {
+            Ok(id) => id,
+            Err(_) => new_id(&dir)?,
+        };
+
+        let mut pending = vec![];
+        if let Ok(file) = File::open(dir.join(JOURNAL_FILE)) {
+            let stream =
+                serde_json::Deserializer::from_reader(BufReader::new(file)).into_iter::<Command>();
+            for command in stream {
+                match command {
+                    Ok(command @ (Command::Set { .. } | Command::Remove { .. })) => {
+                        pending.push(command)
+                    }
+                    Ok(command) => warn!("Skipped a record of the journal: {:?}", command),
+                    Err(e) if e.is_io() => return Err(e.into()),
+                    Err(e) => {
+                        warn!("Dropped the end of the journal: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Rewritten so that new writes don't follow a torn one
+        let tmp_path = dir.join(format!("{}.tmp", JOURNAL_FILE));
+        let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+        for command in &pending {
+            serde_json::to_writer(&mut tmp, command)?;
+        }
+        tmp.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, dir.join(JOURNAL_FILE))?;
+
+        let writer = BufWriter::new(
+            OpenOptions::new()
+                .append(true)
+                .open(dir.join(JOURNAL_FILE))?,
+        );
+        Ok(Journal {
+            dir,
+            id,
+            writer,
+            pending,
+        })
+    }
+
+    /// Add a write to the journal, it is synced before returning.
+    pub(crate) fn append(&mut self, command: Command) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &command)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.pending.push(command);
+        Ok(())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Key under which the server keeps the number of the last write of the run replayed.
+    pub(crate) fn marker_key(&self) -> String {
+        format!("__journal__:{}", self.id)
+    }
+
+    /// The pending writes with their number, in the order they were made.
+    pub(crate) fn requests(&self) -> impl Iterator<Item = (u64, Request)> + '_ {
+        self.pending
+            .iter()
+            .zip(1..)
+            .map(|(command, seq)| (seq, Request::from(command.clone())))
+    }
+
+    /// Forget the pending writes once they are all replayed, and start a new run.
+    ///
+    /// The journal is emptied before the id changes: a crash in between leaves
+    /// an empty run, rather than writes that would be replayed under a new token.
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        let file = self.writer.get_ref();
+        file.set_len(0)?;
+        file.sync_all()?;
+        self.pending.clear();
+        self.id = new_id(&self.dir)?;
+        Ok(())
+    }
+}
+
+/// The record journaling `request`, only SET and single-key REMOVE can be journaled.
+pub(crate) fn command(request: &Request) -> Option<Command> {
+    match request {
+        Request::Set(Set { key, value }) => Some(Command::Set {
+            key: key.clone().into_bytes(),
+            value: value.clone().into_bytes(),
+            expires_at: None,
+        }),
+        Request::Rm(Remove { keys }) if keys.len() == 1 => Some(Command::Remove {
+            key: keys[0].clone().into_bytes(),
+        }),
+        _ => None,
+    }
+}
+
+// Journaled commands are made from requests, so their keys and values are UTF-8
+impl From<Command> for Request {
+    fn from(command: Command) -> Self {
+        let string = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).into_owned();
+        match command {
+            Command::Set { key, value, .. } => Request::Set(Set {
+                key: string(key),
+                value: string(value),
+            }),
+            Command::Remove { key } => Request::Rm(Remove {
+                keys: vec![string(key)],
+            }),
+            Command::Rename { .. } | Command::Batch { .. } => {
+                unreachable!("only sets and removes are journaled")
+            }
+        }
+    }
+}
+
+/// Pick the id of a new run and persist it.
+fn new_id(dir: &Path) -> Result<String> {
+    let id = format!("{:016x}", random_u64());
+    let tmp_path = dir.join(format!("{}.tmp", ID_FILE));
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(id.as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dir.join(ID_FILE))?;
+    Ok(id)
+}
@@ -0,0 +1,117 @@
+use crate::{Error, Result};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Constraints on keys, checked by the server before a write is executed.
+///
+/// Keys written before the rules changed are left as they are.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{Error, KeyRules, KeyViolation};
+///
+/// let rules = KeyRules {
+///     max_len: Some(8),
+///     allowed_bytes: Some("48-57,97-122,58".parse().unwrap()),
+///     case_insensitive: true,
+/// };
+/// let mut key = "User:1".to_owned();
+/// rules.normalize(&mut key);
+/// assert_eq!(key, "user:1");
+/// assert!(rules.check(&key).is_ok());
+/// assert!(matches!(
+///     rules.check("user 1"),
+///     Err(Error::InvalidKey(KeyViolation::ByteNotAllowed(b' ')))
+/// ));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyRules {
+    /// Longest key in bytes, `None` for no limit
+    pub max_len: Option<usize>,
+    /// Bytes keys may contain, `None` for any
+    pub allowed_bytes: Option<ByteRanges>,
+    /// Fold ASCII letters of keys to lower case, so "User:1" and "user:1" are the same key
+    pub case_insensitive: bool,
+}
+
+impl KeyRules {
+    /// Turn `key` into the key the server stores, for reads and writes alike.
+    pub fn normalize(&self, key: &mut str) {
+        if self.case_insensitive {
+            key.make_ascii_lowercase();
+        }
+    }
+
+    /// # Errors
+    ///
+    /// It returns `Error::InvalidKey` with the first rule `key` breaks.
+    pub fn check(&self, key: &str) -> Result<()> {
+        if let Some(max) = self.max_len.filter(|&max| key.len() > max) {
+            return Err(Error::InvalidKey(KeyViolation::TooLong {
+                len: key.len(),
+                max,
+            }));
+        }
+        if let Some(allowed_bytes) = &self.allowed_bytes {
+            if let Some(byte) = key.bytes().find(|&byte| !allowed_bytes.contains(byte)) {
+                return Err(Error::InvalidKey(KeyViolation::ByteNotAllowed(byte)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The rule a key breaks, see `KeyRules::check`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum KeyViolation {
+    #[error("key is {len} bytes long, longer than max-key-len ({max})")]
+    TooLong { len: usize, max: usize },
+    #[error("key contains byte {0}, which is not in key-allowed-bytes")]
+    ByteNotAllowed(u8),
+}
+
+/// Ranges of byte values, written as comma-separated decimal values and ranges,
+/// e.g. "48-57,97-122,58" for digits, lower case letters and ':'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteRanges(pub Vec<RangeInclusive<u8>>);
+
+impl ByteRanges {
+    pub fn contains(&self, byte: u8) -> bool {
+        self.0.iter().any(|range| range.contains(&byte))
+    }
+}
+
+impl FromStr for ByteRanges {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let ranges = s
+            .split(',')
+            .map(|range| match range.split_once('-') {
+                Some((start, end)) => Ok(start.trim().parse()?..=end.trim().parse()?),
+                None => {
+                    let byte = range.trim().parse()?;
+                    Ok(byte..=byte)
+                }
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(ByteRanges(ranges))
+    }
+}
+
+impl fmt::Display for ByteRanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self
+            .0
+            .iter()
+            .map(|range| match (range.start(), range.end()) {
+                (start, end) if start == end => start.to_string(),
+                (start, end) => format!("{}-{}", start, end),
+            })
+            .collect();
+        write!(f, "{}", ranges.join(","))
+    }
+}
@@ -1,12 +1,75 @@
 //! A on-disk key-value store.
 
+pub use api::KvsApi;
+pub use auth::{AuthProvider, ExternalVerifier, StaticPassword, UserFile, DEFAULT_USER};
+pub use capture::{CaptureReader, CapturedRequest};
+pub use client::{KvsClient, RetryPolicy, Subscriber};
+pub use clock::{Clock, ManualClock, SystemClock};
+#[cfg(feature = "async")]
+pub use codec::AsyncCodec;
+pub use codec::Codec;
+pub use commands::{CommandDoc, CommandFlag, CommandSpec, COMMANDS};
+pub use config::ServerConfig;
+pub use dump::DumpPayload;
+pub use encoding::Encoding;
 pub use error::{Error, Result};
+pub use key_rules::{ByteRanges, KeyRules, KeyViolation};
+pub use local::LocalClient;
+pub use pool::{KvsClientPool, PooledClient};
+pub use priority::Priority;
+pub use protocol::{
+    Append, Auth, Client, CommandArgs, CommandQuery, Config, Dump, Exists, Expire, Flushdb,
+    FrameLimits, Get, Getset, Hello, Info, Keys, Lockkey, Migrate, Mset, Persist, PriorityArgs,
+    Protocol, Psubscribe, Publish, Punsubscribe, Remove, Rename, Renamenx, Request, RequestError,
+    Response, Restore, Sadd, Scan, Scard, Select, Set, Setnx, Sinter, Sismember, Smembers, Srem,
+    Subscribe, Sunion, Ttl, Unlockkey, Unsubscribe, Watch,
+};
+pub use pubsub::Message;
+pub use recent::{RecentRequest, RecentRequests};
+pub use scheduler::{JobConfig, JobStatus, Scheduler};
+pub use server::{KvsServer, ShutdownHandle, SlowClientPolicy};
+pub use sharded::{HashRingConfig, ShardStatus, ShardedClient};
 
+pub use engines::batch::{BatchOp, WriteBatch};
+pub use engines::index::IndexKind;
 pub use engines::kvstore::*;
+pub use engines::metered::{MeteredEngine, OpStats};
+pub use engines::notifying::{KeyEvent, KeyEventKind, NotifyingEngine};
 pub use engines::redb::*;
+pub use engines::transaction::Transaction;
+pub use engines::views::{ReadOnly, Scoped};
 pub use engines::KvsEngine;
 
+mod api;
+mod auth;
+mod binary;
+mod capture;
+mod checksum;
 mod client;
+mod clients;
+mod clock;
+mod codec;
+mod commands;
+mod config;
+mod dump;
+mod encoding;
 mod engines;
 mod error;
+mod glob;
+mod journal;
+mod key_rules;
+mod local;
+mod locks;
+mod lz4;
+mod mmap;
+mod pool;
+mod priority;
+mod protocol;
+mod pubsub;
+mod random;
+mod recent;
+mod scheduler;
 mod server;
+mod set;
+mod sharded;
+mod watch;
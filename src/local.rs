@@ -0,0 +1,88 @@
+use crate::server::scan_keys;
+use crate::{JobConfig, KvsApi, KvsEngine, Result, Scan, Scheduler, WriteBatch};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// A client calling an engine in the same process, with no server, socket or encoding
+/// in between.
+///
+/// Clones share the engine, so they can be handed to other threads. Writes go straight
+/// to the engine: they aren't seen by WATCH, and there is no write batching.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KvStore, KvsApi, KvsEngine, LocalClient};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut client = LocalClient::new(KvStore::open(temp_dir.path()).unwrap());
+/// let mut other = client.clone();
+/// client.set("key".to_owned(), "value".to_owned()).unwrap();
+/// assert_eq!(other.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+/// ```
+pub struct LocalClient<E> {
+    engine: Arc<Mutex<E>>,
+}
+
+impl<E: KvsEngine> LocalClient<E> {
+    pub fn new(engine: E) -> Self {
+        LocalClient {
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// The engine, for the operations `KvsApi` doesn't have.
+    ///
+    /// Other clones wait for the guard to be dropped.
+    pub fn engine(&self) -> MutexGuard<'_, E> {
+        self.engine.lock().unwrap()
+    }
+
+    /// Purge the expired keys every `interval` on `scheduler`, see `KvsEngine::purge_expired`.
+    ///
+    /// This is the sweep `KvsServer::add_expire_sweep` runs, for an engine with no server:
+    /// with a `NotifyingEngine`, listeners are told about the keys that expire.
+    pub fn add_expire_sweep(&self, scheduler: &Scheduler, interval: Duration)
+    where
+        E: Send + 'static,
+    {
+        let engine = Arc::clone(&self.engine);
+        scheduler.add_job("expire-sweep", JobConfig::every(interval), move || {
+            engine.lock().unwrap().purge_expired()?;
+            Ok(())
+        });
+    }
+}
+
+// Derived Clone would require E: Clone
+impl<E> Clone for LocalClient<E> {
+    fn clone(&self) -> Self {
+        LocalClient {
+            engine: Arc::clone(&self.engine),
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsApi for LocalClient<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.engine().set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.engine().get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.engine().remove(key)
+    }
+
+    /// Cursors are the same as the server's, an invalid one fails with `Error::InvalidCursor`.
+    fn scan(&mut self, scan: Scan) -> Result<(String, Vec<String>)> {
+        scan_keys(&*self.engine(), scan)
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.engine().write_batch(batch)
+    }
+}
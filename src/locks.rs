@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keys locked with LOCKKEY, by database.
+///
+/// A lock is held by a connection until it unlocks the key, its timeout passes or the
+/// connection closes. Expired locks are dropped when they are come across.
+#[derive(Default)]
+pub(crate) struct KeyLocks {
+    dbs: Mutex<HashMap<usize, HashMap<String, KeyLock>>>,
+}
+
+struct KeyLock {
+    /// Number of the connection holding the lock
+    owner: u64,
+    expires_at: Instant,
+}
+
+impl KeyLocks {
+    /// Lock `key` for connection `owner` for `timeout`, or extend its lock if it holds it.
+    ///
+    /// Returns `false` if another connection holds the lock.
+    pub(crate) fn lock(&self, db: usize, key: String, owner: u64, timeout: Duration) -> bool {
+        let now = Instant::now();
+        let mut dbs = self.dbs.lock().unwrap();
+        let locks = dbs.entry(db).or_default();
+        match locks.get(&key) {
+            Some(lock) if lock.owner != owner && lock.expires_at > now => false,
+            _ => {
+                locks.insert(
+                    key,
+                    KeyLock {
+                        owner,
+                        expires_at: now + timeout,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Returns `false` if connection `owner` doesn't hold the lock of `key`.
+    pub(crate) fn unlock(&self, db: usize, key: &str, owner: u64) -> bool {
+        let mut dbs = self.dbs.lock().unwrap();
+        let Some(locks) = dbs.get_mut(&db) else {
+            return false;
+        };
+        match locks.get(key) {
+            Some(lock) if lock.owner == owner => {
+                let held = lock.expires_at > Instant::now();
+                locks.remove(key);
+                held
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether one of `keys` is locked by another connection than `owner`.
+    pub(crate) fn locked_by_other<'a>(
+        &self,
+        db: usize,
+        keys: impl IntoIterator<Item = &'a str>,
+        owner: u64,
+    ) -> bool {
+        let now = Instant::now();
+        let mut dbs = self.dbs.lock().unwrap();
+        let Some(locks) = dbs.get_mut(&db) else {
+            return false;
+        };
+        let mut locked = false;
+        for key in keys {
+            match locks.get(key) {
+                Some(lock) if lock.expires_at <= now => {
+                    locks.remove(key);
+                }
+                Some(lock) if lock.owner != owner => locked = true,
+                _ => {}
+            }
+        }
+        locked
+    }
+
+    /// Whether another connection than `owner` holds a lock in `db`.
+    pub(crate) fn held_by_other(&self, db: usize, owner: u64) -> bool {
+        let now = Instant::now();
+        let mut dbs = self.dbs.lock().unwrap();
+        let Some(locks) = dbs.get_mut(&db) else {
+            return false;
+        };
+        locks.retain(|_, lock| lock.expires_at > now);
+        locks.values().any(|lock| lock.owner != owner)
+    }
+
+    /// Release every lock held by connection `owner`, e.g. once it is closed.
+    pub(crate) fn release_all(&self, owner: u64) {
+        for locks in self.dbs.lock().unwrap().values_mut() {
+            locks.retain(|_, lock| lock.owner != owner);
+        }
+    }
+}
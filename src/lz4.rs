@@ -0,0 +1,152 @@
+//! The LZ4 block format: sequences of literals followed by a copy of earlier output.
+//!
+//! Every sequence starts with a token, the number of literals in its high 4 bits and the
+//! length of the copy minus 4 in its low 4 bits. A count of 15 continues in the next
+//! bytes, which are added until one isn't 255. The copy is given by its offset back into
+//! the output, a little-endian u16. The last sequence only has literals.
+
+const MIN_MATCH: usize = 4;
+const LAST_LITERALS: usize = 5; // The last bytes of a block are always literals
+const MATCH_FIND_LIMIT: usize = 12; // No copy starts closer to the end of a block
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+/// Compress `input` into an LZ4 block.
+///
+/// It looks for copies greedily through a hash table of the last positions of 4-byte
+/// sequences, favoring speed over ratio.
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut i = 0;
+    let match_end_limit = input.len().saturating_sub(LAST_LITERALS);
+    while i + MATCH_FIND_LIMIT <= input.len() {
+        let sequence = read_u32(input, i);
+        let hash = (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[hash];
+        table[hash] = i;
+        if candidate < i && i - candidate <= MAX_OFFSET && read_u32(input, candidate) == sequence {
+            let mut len = MIN_MATCH;
+            while i + len < match_end_limit && input[candidate + len] == input[i + len] {
+                len += 1;
+            }
+            write_sequence(&mut out, &input[anchor..i], Some((i - candidate, len)));
+            i += len;
+            anchor = i;
+        } else {
+            i += 1;
+        }
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// Decompress the LZ4 block `input` of `len` bytes once decompressed.
+///
+/// Returns `None` if it isn't a valid block of that length.
+pub(crate) fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    loop {
+        let token = *input.get(i)?;
+        i += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = literals.checked_add(read_length(input, &mut i)?)?;
+        }
+        if literals > len - out.len() {
+            return None;
+        }
+        out.extend_from_slice(input.get(i..i + literals)?);
+        i += literals;
+        if i == input.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes(input.get(i..i + 2)?.try_into().unwrap()) as usize;
+        i += 2;
+        let mut match_len = (token & 15) as usize;
+        if match_len == 15 {
+            match_len = match_len.checked_add(read_length(input, &mut i)?)?;
+        }
+        match_len += MIN_MATCH;
+        if offset == 0 || offset > out.len() || match_len > len - out.len() {
+            return None;
+        }
+        // The copy may overlap what it appends, e.g. to repeat a byte
+        let start = out.len() - offset;
+        for k in start..start + match_len {
+            let byte = out[k];
+            out.push(byte);
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+fn read_u32(input: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(input[i..i + 4].try_into().unwrap())
+}
+
+/// Append the sequence of `literals` followed by the copy of (offset, length) `copy`.
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], copy: Option<(usize, usize)>) {
+    let match_len = copy.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = copy {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_length(out, match_len - 15);
+        }
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        out.push(255);
+        n -= 255;
+    }
+    out.push(n as u8);
+}
+
+fn read_length(input: &[u8], i: &mut usize) -> Option<usize> {
+    let mut n = 0usize;
+    loop {
+        let byte = *input.get(*i)?;
+        *i += 1;
+        n = n.checked_add(byte as usize)?;
+        if byte != 255 {
+            return Some(n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let json =
+            br#"{"Set":{"key":"user:1","value":"{\"name\":\"a\",\"tags\":[\"x\",\"x\",\"x\"]}"}}"#;
+        let long = b"abcd".repeat(1000);
+        for input in [&b""[..], b"short", json, &long] {
+            let block = compress(input);
+            assert_eq!(decompress(&block, input.len()).as_deref(), Some(input));
+        }
+        assert!(compress(&long).len() < 50);
+        // A sequence with a long overlapping copy, then the last literals
+        let block = [
+            0x3f, b'a', b'b', b'c', 0x03, 0x00, 0x05, 0x50, b'a', b'b', b'c', b'a', b'b',
+        ];
+        assert_eq!(
+            decompress(&block, 32).as_deref(),
+            Some(&b"abc".repeat(11)[..32])
+        );
+        assert_eq!(decompress(&compress(&long), 100), None);
+        assert_eq!(decompress(&[0x1f, b'a', 0x02, 0x00], 30), None);
+    }
+}
@@ -0,0 +1,93 @@
+//! Read-only memory maps of files, for data files that are no longer written to.
+
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+
+/// The whole of a file mapped into memory as it was when mapped.
+///
+/// The file must not be truncated while mapped: reading the missing pages would kill the
+/// process with SIGBUS. Appending to it is harmless, the map just doesn't see the new bytes.
+pub(crate) struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is read-only and owned by the `Mmap`
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map `file`, `None` if it is empty or memory maps aren't supported here.
+    #[cfg(unix)]
+    pub(crate) fn map(file: &File) -> io::Result<Option<Mmap>> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = file.metadata()?.len();
+        if len == 0 {
+            return Ok(None);
+        }
+        let len = usize::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        // SAFETY: a new private mapping of the file, checked for failure
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(Mmap {
+            ptr: ptr.cast(),
+            len,
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn map(_file: &File) -> io::Result<Option<Mmap>> {
+        Ok(None)
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `len` readable bytes are mapped at `ptr` until the `Mmap` is dropped
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: unmaps exactly the mapping made by `map`, which nothing borrows anymore
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr.cast(), self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn map() {
+        let mut file = tempfile::tempfile().unwrap();
+        assert!(Mmap::map(&file).unwrap().is_none());
+        file.write_all(b"sealed segment").unwrap();
+        if let Some(map) = Mmap::map(&file).unwrap() {
+            assert_eq!(&map[..], b"sealed segment");
+            // Appending doesn't change what was mapped
+            file.write_all(b" and more").unwrap();
+            assert_eq!(map.len(), 14);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{KvsClient, Result, RetryPolicy};
+use log::debug;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A pool of reusable `KvsClient` connections to one server.
+///
+/// At most `size` connections are open at the same time. They are opened lazily on checkout,
+/// returned to the pool when the `PooledClient` is dropped, and checked for health before
+/// being handed out again.
+///
+/// `KvsClientPool` is a cheap handle: clones share the same connections, so it can be
+/// passed to other threads.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use kvs::KvsClientPool;
+///
+/// let pool = KvsClientPool::new("127.0.0.1:7878", 4).unwrap();
+/// let mut client = pool.checkout().unwrap();
+/// client.set("key".to_string(), "value".to_string()).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct KvsClientPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    addrs: Vec<SocketAddr>,
+    policy: RetryPolicy,
+    size: usize,
+    state: Mutex<PoolState>,
+    checked_in: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<KvsClient>,
+    // Idle and checked out connections
+    open: usize,
+}
+
+impl KvsClientPool {
+    /// Create a pool of at most `size` connections to `addr` with the default `RetryPolicy`.
+    pub fn new<A: ToSocketAddrs>(addr: A, size: usize) -> Result<Self> {
+        Self::with_policy(addr, size, RetryPolicy::default())
+    }
+
+    /// Create a pool of at most `size` connections to `addr`, each using `policy`.
+    pub fn with_policy<A: ToSocketAddrs>(
+        addr: A,
+        size: usize,
+        policy: RetryPolicy,
+    ) -> Result<Self> {
+        assert!(size > 0, "pool size must be positive");
+        Ok(KvsClientPool {
+            shared: Arc::new(Shared {
+                addrs: addr.to_socket_addrs()?.collect(),
+                policy,
+                size,
+                state: Mutex::new(PoolState {
+                    idle: Vec::with_capacity(size),
+                    open: 0,
+                }),
+                checked_in: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Take a connection out of the pool, blocking while all `size` connections are in use.
+    ///
+    /// Idle connections closed by the server are discarded and replaced by new ones.
+    ///
+    /// # Errors
+    ///
+    /// It returns the connection error if a new connection has to be opened and that fails.
+    pub fn checkout(&self) -> Result<PooledClient> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            while let Some(client) = state.idle.pop() {
+                if client.is_healthy() {
+                    return Ok(self.pooled(client));
+                }
+                debug!("dropping unhealthy pooled connection");
+                state.open -= 1;
+            }
+
+            if state.open < self.shared.size {
+                state.open += 1;
+                drop(state);
+                return match KvsClient::connect_with(
+                    &self.shared.addrs[..],
+                    self.shared.policy.clone(),
+                ) {
+                    Ok(client) => Ok(self.pooled(client)),
+                    Err(e) => {
+                        self.shared.state.lock().unwrap().open -= 1;
+                        self.shared.checked_in.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+
+            state = self.shared.checked_in.wait(state).unwrap();
+        }
+    }
+
+    /// Maximum number of connections.
+    pub fn size(&self) -> usize {
+        self.shared.size
+    }
+
+    /// Number of currently open connections, idle or checked out.
+    pub fn open_connections(&self) -> usize {
+        self.shared.state.lock().unwrap().open
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient {
+        PooledClient {
+            client: Some(client),
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+/// A `KvsClient` checked out of a `KvsClientPool`, it is checked back in when dropped.
+pub struct PooledClient {
+    client: Option<KvsClient>,
+    shared: Arc<Shared>,
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let client = self.client.take().unwrap();
+        let mut state = self.shared.state.lock().unwrap();
+        if client.is_healthy() {
+            state.idle.push(client);
+        } else {
+            state.open -= 1;
+        }
+        self.shared.checked_in.notify_one();
+    }
+}
@@ -0,0 +1,166 @@
+use clap::ValueEnum;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+/// How urgently a request gets the engine when requests wait for it.
+///
+/// A connection has the class set with PRIORITY, normal until then, unless the command
+/// is listed in "high-priority-commands" or "low-priority-commands" (see CONFIG SET).
+/// The server's background jobs are low priority.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Priority {
+    /// Waits for no other class, e.g. for replication and health checks
+    High,
+    #[default]
+    Normal,
+    /// Only gets the engine when no other class waits for it, e.g. for bulk exports
+    Low,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl FromStr for Priority {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Priority::ALL
+            .into_iter()
+            .find(|priority| priority.to_string().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A mutex handed to the waiting thread of the highest priority when it is unlocked.
+///
+/// Threads of a class only get it once no thread of a higher class waits, so a steady
+/// flow of high priority requests holds low priority ones back for as long as it lasts.
+pub(crate) struct PriorityMutex<T> {
+    value: Mutex<T>,
+    gate: Mutex<Gate>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct Gate {
+    held: bool,
+    /// Threads waiting for the mutex, by priority
+    waiting: [usize; 3],
+}
+
+impl Gate {
+    fn outranked(&self, priority: Priority) -> bool {
+        self.waiting[..priority.index()].iter().any(|&n| n > 0)
+    }
+}
+
+impl<T> PriorityMutex<T> {
+    pub(crate) fn new(value: T) -> Self {
+        PriorityMutex {
+            value: Mutex::new(value),
+            gate: Mutex::default(),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until no thread holds the mutex or waits for it with a higher priority.
+    pub(crate) fn lock(&self, priority: Priority) -> PriorityGuard<'_, T> {
+        let mut gate = self.gate.lock().unwrap();
+        gate.waiting[priority.index()] += 1;
+        while gate.held || gate.outranked(priority) {
+            gate = self.released.wait(gate).unwrap();
+        }
+        gate.waiting[priority.index()] -= 1;
+        gate.held = true;
+        drop(gate);
+        PriorityGuard {
+            guard: Some(self.value.lock().unwrap()),
+            mutex: self,
+        }
+    }
+}
+
+/// The mutex is unlocked when the guard is dropped.
+pub(crate) struct PriorityGuard<'a, T> {
+    guard: Option<MutexGuard<'a, T>>,
+    mutex: &'a PriorityMutex<T>,
+}
+
+impl<T> Deref for PriorityGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().expect("held until dropped")
+    }
+}
+
+impl<T> DerefMut for PriorityGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().expect("held until dropped")
+    }
+}
+
+impl<T> Drop for PriorityGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        self.mutex.gate.lock().unwrap().held = false;
+        self.mutex.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn higher_priority_first() {
+        let mutex = Arc::new(PriorityMutex::new(vec![]));
+        let guard = mutex.lock(Priority::Normal);
+
+        let mut waiters = vec![];
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            let waiter = Arc::clone(&mutex);
+            waiters.push(thread::spawn(move || waiter.lock(priority).push(priority)));
+            // Wait for the thread to queue up before the next one
+            while mutex.gate.lock().unwrap().waiting[priority.index()] == 0 {
+                thread::yield_now();
+            }
+        }
+        drop(guard);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+
+        assert_eq!(
+            *mutex.lock(Priority::Low),
+            [Priority::High, Priority::Normal, Priority::Low]
+        );
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("HIGH".parse(), Ok(Priority::High));
+        assert_eq!(Priority::Low.to_string().parse(), Ok(Priority::Low));
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+}
@@ -0,0 +1,1637 @@
+use crate::commands::{self, CommandSpec};
+use crate::{Error, Priority, Result};
+use bytes::Bytes;
+use clap::{Args, Subcommand};
+use redis_protocol::resp2::prelude::*;
+use redis_protocol::resp3::types as resp3;
+use std::fmt;
+use std::str::from_utf8;
+use thiserror::Error;
+
+/// Reply to a request, encoded as RESP2 or RESP3 depending on the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Ok,
+    Nil,
+    Integer(i64),
+    Bulk(Bytes),
+    Array(Vec<Response>),
+    /// Name/value pairs, a flat array of names and values in RESP2
+    Map(Vec<(Response, Response)>),
+    /// `code` is an upper case word like `ERR`, sent ahead of `msg`
+    Error {
+        code: String,
+        msg: String,
+    },
+    /// Sent without a request, e.g. a published message, an array in RESP2
+    Push(Vec<Response>),
+    /// `response` with metadata, sent ahead of it as an attribute in RESP3 and left out in RESP2
+    Attributed {
+        attributes: Vec<(Response, Response)>,
+        response: Box<Response>,
+    },
+}
+
+impl Response {
+    pub fn bulk(value: impl Into<Bytes>) -> Self {
+        Response::Bulk(value.into())
+    }
+
+    /// A generic `ERR` error.
+    pub fn error(msg: impl Into<String>) -> Self {
+        Response::error_with_code("ERR", msg)
+    }
+
+    pub fn error_with_code(code: impl Into<String>, msg: impl Into<String>) -> Self {
+        Response::Error {
+            code: code.into(),
+            msg: msg.into(),
+        }
+    }
+
+    /// Add an attribute to the response, see `Response::Attributed`.
+    pub fn with_attribute(self, name: &str, value: Response) -> Self {
+        let attribute = (Response::bulk(name.to_owned()), value);
+        match self {
+            Response::Attributed {
+                mut attributes,
+                response,
+            } => {
+                attributes.push(attribute);
+                Response::Attributed {
+                    attributes,
+                    response,
+                }
+            }
+            response => Response::Attributed {
+                attributes: vec![attribute],
+                response: Box::new(response),
+            },
+        }
+    }
+
+    /// Encode the response as a RESP3 frame.
+    pub fn into_resp3(self) -> resp3::Frame {
+        match self {
+            Response::Ok => resp3::Frame::SimpleString {
+                data: "OK".into(),
+                attributes: None,
+            },
+            Response::Nil => resp3::Frame::Null,
+            Response::Integer(n) => resp3::Frame::Number {
+                data: n,
+                attributes: None,
+            },
+            Response::Bulk(data) => resp3::Frame::BlobString {
+                data,
+                attributes: None,
+            },
+            Response::Array(responses) => resp3::Frame::Array {
+                data: responses.into_iter().map(Response::into_resp3).collect(),
+                attributes: None,
+            },
+            Response::Map(pairs) => resp3::Frame::Map {
+                data: pairs
+                    .into_iter()
+                    .map(|(name, value)| (name.into_resp3(), value.into_resp3()))
+                    .collect(),
+                attributes: None,
+            },
+            Response::Error { code, msg } => resp3::Frame::SimpleError {
+                data: format!("{} {}", code, msg).into(),
+                attributes: None,
+            },
+            Response::Push(responses) => resp3::Frame::Push {
+                data: responses.into_iter().map(Response::into_resp3).collect(),
+                attributes: None,
+            },
+            Response::Attributed {
+                attributes,
+                response,
+            } => {
+                let mut frame = response.into_resp3();
+                // Nulls can't have attributes, they are dropped
+                let _ = frame.add_attributes(
+                    attributes
+                        .into_iter()
+                        .map(|(name, value)| (name.into_resp3(), value.into_resp3()))
+                        .collect(),
+                );
+                frame
+            }
+        }
+    }
+}
+
+impl From<Response> for Frame {
+    fn from(response: Response) -> Self {
+        match response {
+            Response::Ok => Frame::SimpleString("OK".into()),
+            Response::Nil => Frame::Null,
+            Response::Integer(n) => Frame::Integer(n),
+            Response::Bulk(value) => Frame::BulkString(value),
+            Response::Array(responses) | Response::Push(responses) => {
+                Frame::Array(responses.into_iter().map(Frame::from).collect())
+            }
+            Response::Map(pairs) => Frame::Array(
+                pairs
+                    .into_iter()
+                    .flat_map(|(name, value)| [Frame::from(name), Frame::from(value)])
+                    .collect(),
+            ),
+            Response::Error { code, msg } => Frame::Error(format!("{} {}", code, msg).into()),
+            Response::Attributed { response, .. } => Frame::from(*response),
+        }
+    }
+}
+
+// RESP2 can't tell maps and pushes from arrays, they are decoded as arrays
+impl TryFrom<Frame> for Response {
+    type Error = Error;
+
+    fn try_from(frame: Frame) -> Result<Self> {
+        match frame {
+            Frame::SimpleString(s) if s == "OK" => Ok(Response::Ok),
+            Frame::SimpleString(s) | Frame::BulkString(s) => Ok(Response::Bulk(s)),
+            Frame::Integer(n) => Ok(Response::Integer(n)),
+            Frame::Array(frames) => Ok(Response::Array(
+                frames
+                    .into_iter()
+                    .map(Response::try_from)
+                    .collect::<Result<_>>()?,
+            )),
+            Frame::Null => Ok(Response::Nil),
+            Frame::Error(err) => {
+                // Errors without a code, e.g. from other servers, are generic errors
+                let (code, msg) = match err.split_once(' ') {
+                    Some((code, msg))
+                        if !code.is_empty() && code.bytes().all(|b| b.is_ascii_uppercase()) =>
+                    {
+                        (code, msg)
+                    }
+                    _ => ("ERR", &*err),
+                };
+                Ok(Response::error_with_code(code, msg))
+            }
+        }
+    }
+}
+
+/// Version of RESP spoken on a connection, RESP2 until the client switches with HELLO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn version(self) -> u8 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Request {
+    /// Set the value of a string key to a string
+    Set(Set),
+    /// Get the string value of a given string key
+    Get(Get),
+    /// Set a key only if it doesn't exist, replying with 1 if it was set and 0 if not
+    Setnx(Setnx),
+    /// Set a key and reply with its previous value
+    Getset(Getset),
+    /// Remove the given keys, replying with the number of keys removed
+    Rm(Remove),
+    /// Set the values of several string keys
+    Mset(Mset),
+    /// Append a string to the value of a key, replying with the new length
+    Append(Append),
+    /// Count how many of the given keys exist
+    Exists(Exists),
+    /// List all keys matching a glob-style pattern
+    Keys(Keys),
+    /// List keys incrementally, starting from a cursor returned by the previous call
+    Scan(Scan),
+    /// Set a key to expire after a number of seconds
+    Expire(Expire),
+    /// Get the remaining time to live of a key in seconds, -1 if it doesn't expire, -2 if it doesn't exist
+    Ttl(Ttl),
+    /// Make a key persistent
+    Persist(Persist),
+    /// Move a key to another server
+    Migrate(Migrate),
+    /// Rename a key, overwriting the new key if it exists
+    Rename(Rename),
+    /// Rename a key, unless the new key exists
+    Renamenx(Renamenx),
+    /// Add members to the set of a key, replying with the number of members added
+    Sadd(Sadd),
+    /// Remove members from the set of a key, replying with the number of members removed
+    Srem(Srem),
+    /// List the members of the set of a key
+    Smembers(Smembers),
+    /// Whether a string is a member of the set of a key, replying with 1 or 0
+    Sismember(Sismember),
+    /// Count the members of the set of a key
+    Scard(Scard),
+    /// List the members of any of the sets of the given keys
+    Sunion(Sunion),
+    /// List the members of all the sets of the given keys
+    Sinter(Sinter),
+    /// Serialize the entry of a key, to be restored by RESTORE
+    Dump(Dump),
+    /// Create a key from the output of DUMP
+    Restore(Restore),
+    /// Count the keys
+    Dbsize,
+    /// Remove every key
+    Flushdb(Flushdb),
+    /// Show statistics of the server and its engine
+    Info(Info),
+    /// Show or change the settings of the server and its engine
+    #[command(subcommand)]
+    Config(Config),
+    /// Show the state of the server's background jobs
+    Tasks,
+    /// Show the requests the server is serving, and for how long
+    Currentops,
+    /// Reclaim the disk space of overwritten and removed keys of the database now
+    Compact,
+    /// Stop the server once the requests it is serving are answered
+    Shutdown,
+    /// Hand the listening socket over to a new server process, then shut down, see
+    /// `KvsServer::set_upgrade_handler`
+    Upgrade,
+    /// Queue the following commands of the connection until EXEC
+    Multi,
+    /// Execute the commands queued since MULTI atomically
+    Exec,
+    /// Drop the commands queued since MULTI
+    Discard,
+    /// Make the next EXEC fail if one of the given keys is written before it
+    Watch(Watch),
+    /// Forget the keys watched so far
+    Unwatch,
+    /// Make writes to a key from other connections fail until UNLOCKKEY, the timeout or
+    /// the end of the connection, replying 0 if another connection holds the lock
+    Lockkey(Lockkey),
+    /// Release a lock taken with LOCKKEY, replying 0 if the connection didn't hold it
+    Unlockkey(Unlockkey),
+    /// Switch the protocol of the connection and show information about the server
+    Hello(Hello),
+    /// Authenticate the connection, or manage the tokens it can authenticate with
+    #[command(subcommand)]
+    Auth(Auth),
+    /// Describe the commands the server accepts
+    Command(CommandArgs),
+    /// Receive the messages published on the given channels
+    Subscribe(Subscribe),
+    /// Receive the messages published on channels matching the given glob-style patterns
+    Psubscribe(Psubscribe),
+    /// Stop receiving the messages of the given channels, or of every channel
+    Unsubscribe(Unsubscribe),
+    /// Stop receiving the messages of the given patterns, or of every pattern
+    Punsubscribe(Punsubscribe),
+    /// Send a message to the subscribers of a channel, replying with how many received it
+    Publish(Publish),
+    /// Work on the numbered database from now on, the connection starts on database 0
+    Select(Select),
+    /// Set the priority of the connection's requests when they wait for the engine
+    Priority(PriorityArgs),
+    /// Name the connection or describe its client library, or list the connections
+    #[command(subcommand)]
+    Client(Client),
+}
+
+impl Request {
+    /// Whether sending the request twice has the same effect as sending it once.
+    ///
+    /// Only idempotent requests are retried after the connection broke mid-request,
+    /// since the server may or may not have executed the first attempt.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Request::Get(_)
+                | Request::Exists(_)
+                | Request::Keys(_)
+                | Request::Scan(_)
+                | Request::Ttl(_)
+                | Request::Dump(_)
+                | Request::Smembers(_)
+                | Request::Sismember(_)
+                | Request::Scard(_)
+                | Request::Sunion(_)
+                | Request::Sinter(_)
+                | Request::Dbsize
+                | Request::Info(_)
+                | Request::Config(Config::Get { .. })
+                | Request::Tasks
+                | Request::Currentops
+                | Request::Compact
+                | Request::Command(_)
+                | Request::Auth(Auth::Login { .. })
+                | Request::Select(_)
+                | Request::Priority(_)
+                | Request::Client(_)
+        )
+    }
+
+    /// Name of the command on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Set(_) => "set",
+            Request::Get(_) => "get",
+            Request::Setnx(_) => "setnx",
+            Request::Getset(_) => "getset",
+            Request::Rm(_) => "remove",
+            Request::Mset(_) => "mset",
+            Request::Append(_) => "append",
+            Request::Exists(_) => "exists",
+            Request::Keys(_) => "keys",
+            Request::Scan(_) => "scan",
+            Request::Expire(_) => "expire",
+            Request::Ttl(_) => "ttl",
+            Request::Persist(_) => "persist",
+            Request::Rename(_) => "rename",
+            Request::Renamenx(_) => "renamenx",
+            Request::Sadd(_) => "sadd",
+            Request::Srem(_) => "srem",
+            Request::Smembers(_) => "smembers",
+            Request::Sismember(_) => "sismember",
+            Request::Scard(_) => "scard",
+            Request::Sunion(_) => "sunion",
+            Request::Sinter(_) => "sinter",
+            Request::Migrate(_) => "migrate",
+            Request::Dump(_) => "dump",
+            Request::Restore(_) => "restore",
+            Request::Dbsize => "dbsize",
+            Request::Flushdb(_) => "flushdb",
+            Request::Info(_) => "info",
+            Request::Config(_) => "config",
+            Request::Tasks => "tasks",
+            Request::Currentops => "currentops",
+            Request::Compact => "compact",
+            Request::Shutdown => "shutdown",
+            Request::Upgrade => "upgrade",
+            Request::Multi => "multi",
+            Request::Exec => "exec",
+            Request::Discard => "discard",
+            Request::Watch(_) => "watch",
+            Request::Unwatch => "unwatch",
+            Request::Lockkey(_) => "lockkey",
+            Request::Unlockkey(_) => "unlockkey",
+            Request::Hello(_) => "hello",
+            Request::Auth(_) => "auth",
+            Request::Command(_) => "command",
+            Request::Subscribe(_) => "subscribe",
+            Request::Psubscribe(_) => "psubscribe",
+            Request::Unsubscribe(_) => "unsubscribe",
+            Request::Punsubscribe(_) => "punsubscribe",
+            Request::Publish(_) => "publish",
+            Request::Select(_) => "select",
+            Request::Priority(_) => "priority",
+            Request::Client(_) => "client",
+        }
+    }
+
+    /// Entry of the command in the command table.
+    pub fn spec(&self) -> &'static CommandSpec {
+        commands::lookup(self.name()).expect("every request is in the command table")
+    }
+
+    /// Keys the request works on.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Request::Set(Set { key, .. })
+            | Request::Get(Get { key })
+            | Request::Setnx(Setnx { key, .. })
+            | Request::Getset(Getset { key, .. })
+            | Request::Append(Append { key, .. })
+            | Request::Expire(Expire { key, .. })
+            | Request::Ttl(Ttl { key })
+            | Request::Persist(Persist { key })
+            | Request::Lockkey(Lockkey { key, .. })
+            | Request::Unlockkey(Unlockkey { key })
+            | Request::Migrate(Migrate { key, .. })
+            | Request::Dump(Dump { key })
+            | Request::Restore(Restore { key, .. })
+            | Request::Sadd(Sadd { key, .. })
+            | Request::Srem(Srem { key, .. })
+            | Request::Smembers(Smembers { key })
+            | Request::Sismember(Sismember { key, .. })
+            | Request::Scard(Scard { key }) => vec![key],
+            Request::Rm(Remove { keys })
+            | Request::Exists(Exists { keys })
+            | Request::Sunion(Sunion { keys })
+            | Request::Sinter(Sinter { keys })
+            | Request::Watch(Watch { keys }) => keys.iter().map(String::as_str).collect(),
+            Request::Mset(Mset { pairs }) => pairs.iter().step_by(2).map(String::as_str).collect(),
+            Request::Rename(Rename { key, newkey })
+            | Request::Renamenx(Renamenx { key, newkey }) => {
+                vec![key, newkey]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Keys the request works on, to be changed in place, see `keys`.
+    pub fn keys_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Request::Set(Set { key, .. })
+            | Request::Get(Get { key })
+            | Request::Setnx(Setnx { key, .. })
+            | Request::Getset(Getset { key, .. })
+            | Request::Append(Append { key, .. })
+            | Request::Expire(Expire { key, .. })
+            | Request::Ttl(Ttl { key })
+            | Request::Persist(Persist { key })
+            | Request::Lockkey(Lockkey { key, .. })
+            | Request::Unlockkey(Unlockkey { key })
+            | Request::Migrate(Migrate { key, .. })
+            | Request::Dump(Dump { key })
+            | Request::Restore(Restore { key, .. })
+            | Request::Sadd(Sadd { key, .. })
+            | Request::Srem(Srem { key, .. })
+            | Request::Smembers(Smembers { key })
+            | Request::Sismember(Sismember { key, .. })
+            | Request::Scard(Scard { key }) => vec![key],
+            Request::Rm(Remove { keys })
+            | Request::Exists(Exists { keys })
+            | Request::Sunion(Sunion { keys })
+            | Request::Sinter(Sinter { keys })
+            | Request::Watch(Watch { keys }) => keys.iter_mut().collect(),
+            Request::Mset(Mset { pairs }) => pairs.iter_mut().step_by(2).collect(),
+            Request::Rename(Rename { key, newkey })
+            | Request::Renamenx(Renamenx { key, newkey }) => {
+                vec![key, newkey]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Values written by the request.
+    pub fn values(&self) -> Box<dyn Iterator<Item = &String> + '_> {
+        match self {
+            Request::Set(Set { value, .. })
+            | Request::Setnx(Setnx { value, .. })
+            | Request::Getset(Getset { value, .. })
+            | Request::Append(Append { value, .. }) => Box::new(std::iter::once(value)),
+            Request::Mset(Mset { pairs }) => Box::new(pairs.iter().skip(1).step_by(2)),
+            Request::Sadd(Sadd { members, .. }) => Box::new(members.iter()),
+            _ => Box::new(std::iter::empty()),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Set {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Get {
+    pub key: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Setnx {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Getset {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Append {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Remove {
+    #[arg(required = true)]
+    pub keys: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Exists {
+    #[arg(required = true)]
+    pub keys: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Keys {
+    /// Glob-style pattern, e.g. "user:*"
+    pub pattern: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Scan {
+    /// "0" to start, then the cursor returned by the previous call until it returns "0"
+    pub cursor: String,
+    /// Only return keys matching this glob-style pattern
+    #[arg(long = "match", value_name = "PATTERN")]
+    pub pattern: Option<String>,
+    /// How many keys to go through, the server decides if not given
+    #[arg(long)]
+    pub count: Option<usize>,
+}
+
+#[derive(Args, Debug)]
+pub struct Mset {
+    /// Alternating keys and values
+    #[arg(required = true, num_args = 2.., value_names = ["KEY", "VALUE"])]
+    pub pairs: Vec<String>,
+}
+
+impl Mset {
+    /// Build a request from key/value pairs.
+    pub fn new(pairs: impl IntoIterator<Item = (String, String)>) -> Self {
+        Mset {
+            pairs: pairs
+                .into_iter()
+                .flat_map(|(key, value)| [key, value])
+                .collect(),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Expire {
+    pub key: String,
+    /// The key is removed right away if not positive
+    #[arg(allow_negative_numbers = true)]
+    pub seconds: i64,
+}
+
+#[derive(Args, Debug)]
+pub struct Ttl {
+    pub key: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Persist {
+    pub key: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Rename {
+    pub key: String,
+    pub newkey: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Renamenx {
+    pub key: String,
+    pub newkey: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Sadd {
+    pub key: String,
+    #[arg(required = true)]
+    pub members: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Srem {
+    pub key: String,
+    #[arg(required = true)]
+    pub members: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Smembers {
+    pub key: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Sismember {
+    pub key: String,
+    pub member: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Scard {
+    pub key: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Sunion {
+    #[arg(required = true)]
+    pub keys: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Sinter {
+    #[arg(required = true)]
+    pub keys: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Migrate {
+    /// Host of the destination server
+    pub host: String,
+    /// Port of the destination server
+    pub port: u16,
+    pub key: String,
+    /// Timeout in milliseconds of connecting and talking to the destination, 0 for the
+    /// default of 10 seconds
+    pub timeout: u64,
+    /// Keep the key on the source server
+    #[arg(long)]
+    pub copy: bool,
+    /// Overwrite the key if it exists on the destination server
+    #[arg(long)]
+    pub replace: bool,
+    /// Only report what would move: the key must exist, and the destination be reachable
+    /// and accept it
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Read the key back from the destination and compare checksums before removing it
+    /// from the source server
+    #[arg(long)]
+    pub verify: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct Dump {
+    pub key: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Restore {
+    pub key: String,
+    /// Time to live in milliseconds, 0 if the key doesn't expire
+    pub ttl: u64,
+    /// Output of DUMP
+    pub blob: String,
+    /// Overwrite the key if it exists
+    #[arg(long)]
+    pub replace: bool,
+}
+
+#[derive(Args, Debug, Default)]
+pub struct Flushdb {
+    /// Confirm removing every key, kvs-client refuses to send FLUSHDB without it
+    #[arg(long)]
+    pub yes: bool,
+}
+
+#[derive(Args, Debug, Default)]
+pub struct Info {
+    /// Only show this section: server, clients, persistence, engine or keyspace
+    pub section: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Watch {
+    #[arg(required = true)]
+    pub keys: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Lockkey {
+    pub key: String,
+    /// Milliseconds the lock is held for at most
+    pub timeout: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct Unlockkey {
+    pub key: String,
+}
+
+#[derive(Args, Debug, Default)]
+pub struct Hello {
+    /// RESP version to speak from now on, 2 or 3, unchanged if not given
+    pub protover: Option<u8>,
+    /// Name the connection, like CLIENT SETNAME, only sent along with PROTOVER
+    #[arg(long, requires = "protover")]
+    pub setname: Option<String>,
+}
+
+/// On the wire "AUTH [USERNAME] PASSWORD", "AUTH ROTATE" and "AUTH REVOKE USERNAME", so
+/// "rotate" can't be a password given alone and "revoke" can't be a username.
+#[derive(Subcommand, Clone)]
+pub enum Auth {
+    /// Authenticate with a password, or with a token of AUTH ROTATE
+    Login {
+        /// The default user if not given, see `DEFAULT_USER`, or the token's user
+        #[arg(long)]
+        username: Option<String>,
+        password: String,
+    },
+    /// Get a new token of the connection's user, the token it authenticated with, if
+    /// any, expires within a minute
+    Rotate,
+    /// Revoke every token of a user, replying with how many there were
+    Revoke { username: String },
+}
+
+// Requests are logged, passwords and tokens shouldn't be
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Auth::Login { username, .. } => f
+                .debug_struct("Login")
+                .field("username", username)
+                .field("password", &"<hidden>")
+                .finish(),
+            Auth::Rotate => f.write_str("Rotate"),
+            Auth::Revoke { username } => f
+                .debug_struct("Revoke")
+                .field("username", username)
+                .finish(),
+        }
+    }
+}
+
+/// Keyspace notifications are published on "__keyspace__:KEY" channels.
+#[derive(Args, Debug)]
+pub struct Subscribe {
+    #[arg(required = true)]
+    pub channels: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Psubscribe {
+    /// Glob-style patterns, e.g. "__keyspace__:user:*"
+    #[arg(required = true)]
+    pub patterns: Vec<String>,
+}
+
+#[derive(Args, Debug, Default)]
+pub struct Unsubscribe {
+    pub channels: Vec<String>,
+}
+
+#[derive(Args, Debug, Default)]
+pub struct Punsubscribe {
+    pub patterns: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Publish {
+    pub channel: String,
+    pub message: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Select {
+    pub index: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct PriorityArgs {
+    pub class: Priority,
+}
+
+#[derive(Args, Debug, Default)]
+pub struct CommandArgs {
+    /// Every command if not given
+    #[command(subcommand)]
+    pub query: Option<CommandQuery>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CommandQuery {
+    /// Count the commands
+    Count,
+    /// Describe the given commands, in order
+    Info {
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Client {
+    /// Name the connection for CLIENT LIST, an empty name removes it
+    Setname { name: String },
+    /// Show the name of the connection
+    Getname,
+    /// Tell the name ("lib-name") or version ("lib-ver") of the client library in use
+    Setinfo { attr: String, value: String },
+    /// List the connections, a line of "field=value" pairs each
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Config {
+    /// Show the settings whose name matches a glob-style pattern, e.g. "client-*"
+    Get { pattern: String },
+    /// Change a setting, until the server restarts
+    Set { name: String, value: String },
+}
+
+impl From<Request> for Frame {
+    fn from(request: Request) -> Self {
+        let mut frame_vec = vec![];
+        match request {
+            Request::Set(Set { key, value }) => {
+                frame_vec.push(Frame::BulkString("set".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(value.into()));
+            }
+            Request::Get(Get { key }) => {
+                frame_vec.push(Frame::BulkString("get".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+            }
+            Request::Rm(Remove { keys }) => {
+                frame_vec.push(Frame::BulkString("remove".into()));
+                for key in keys {
+                    frame_vec.push(Frame::BulkString(key.into()));
+                }
+            }
+            Request::Exists(Exists { keys }) => {
+                frame_vec.push(Frame::BulkString("exists".into()));
+                for key in keys {
+                    frame_vec.push(Frame::BulkString(key.into()));
+                }
+            }
+            Request::Keys(Keys { pattern }) => {
+                frame_vec.push(Frame::BulkString("keys".into()));
+                frame_vec.push(Frame::BulkString(pattern.into()));
+            }
+            Request::Scan(Scan {
+                cursor,
+                pattern,
+                count,
+            }) => {
+                frame_vec.push(Frame::BulkString("scan".into()));
+                frame_vec.push(Frame::BulkString(cursor.into()));
+                if let Some(pattern) = pattern {
+                    frame_vec.push(Frame::BulkString("match".into()));
+                    frame_vec.push(Frame::BulkString(pattern.into()));
+                }
+                if let Some(count) = count {
+                    frame_vec.push(Frame::BulkString("count".into()));
+                    frame_vec.push(Frame::BulkString(count.to_string().into()));
+                }
+            }
+            Request::Mset(Mset { pairs }) => {
+                frame_vec.push(Frame::BulkString("mset".into()));
+                for s in pairs {
+                    frame_vec.push(Frame::BulkString(s.into()));
+                }
+            }
+            Request::Setnx(Setnx { key, value }) => {
+                frame_vec.push(Frame::BulkString("setnx".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(value.into()));
+            }
+            Request::Getset(Getset { key, value }) => {
+                frame_vec.push(Frame::BulkString("getset".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(value.into()));
+            }
+            Request::Append(Append { key, value }) => {
+                frame_vec.push(Frame::BulkString("append".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(value.into()));
+            }
+            Request::Expire(Expire { key, seconds }) => {
+                frame_vec.push(Frame::BulkString("expire".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(seconds.to_string().into()));
+            }
+            Request::Ttl(Ttl { key }) => {
+                frame_vec.push(Frame::BulkString("ttl".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+            }
+            Request::Persist(Persist { key }) => {
+                frame_vec.push(Frame::BulkString("persist".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+            }
+            Request::Rename(Rename { key, newkey }) => {
+                frame_vec.push(Frame::BulkString("rename".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(newkey.into()));
+            }
+            Request::Renamenx(Renamenx { key, newkey }) => {
+                frame_vec.push(Frame::BulkString("renamenx".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(newkey.into()));
+            }
+            Request::Sadd(Sadd { key, members }) => {
+                frame_vec.push(Frame::BulkString("sadd".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                for member in members {
+                    frame_vec.push(Frame::BulkString(member.into()));
+                }
+            }
+            Request::Srem(Srem { key, members }) => {
+                frame_vec.push(Frame::BulkString("srem".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                for member in members {
+                    frame_vec.push(Frame::BulkString(member.into()));
+                }
+            }
+            Request::Smembers(Smembers { key }) => {
+                frame_vec.push(Frame::BulkString("smembers".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+            }
+            Request::Sismember(Sismember { key, member }) => {
+                frame_vec.push(Frame::BulkString("sismember".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(member.into()));
+            }
+            Request::Scard(Scard { key }) => {
+                frame_vec.push(Frame::BulkString("scard".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+            }
+            Request::Sunion(Sunion { keys }) => {
+                frame_vec.push(Frame::BulkString("sunion".into()));
+                for key in keys {
+                    frame_vec.push(Frame::BulkString(key.into()));
+                }
+            }
+            Request::Sinter(Sinter { keys }) => {
+                frame_vec.push(Frame::BulkString("sinter".into()));
+                for key in keys {
+                    frame_vec.push(Frame::BulkString(key.into()));
+                }
+            }
+            Request::Migrate(Migrate {
+                host,
+                port,
+                key,
+                timeout,
+                copy,
+                replace,
+                dry_run,
+                verify,
+            }) => {
+                frame_vec.push(Frame::BulkString("migrate".into()));
+                frame_vec.push(Frame::BulkString(host.into()));
+                frame_vec.push(Frame::BulkString(port.to_string().into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(timeout.to_string().into()));
+                if copy {
+                    frame_vec.push(Frame::BulkString("copy".into()));
+                }
+                if replace {
+                    frame_vec.push(Frame::BulkString("replace".into()));
+                }
+                if dry_run {
+                    frame_vec.push(Frame::BulkString("dryrun".into()));
+                }
+                if verify {
+                    frame_vec.push(Frame::BulkString("verify".into()));
+                }
+            }
+            Request::Dump(Dump { key }) => {
+                frame_vec.push(Frame::BulkString("dump".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+            }
+            Request::Restore(Restore {
+                key,
+                ttl,
+                blob,
+                replace,
+            }) => {
+                frame_vec.push(Frame::BulkString("restore".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(ttl.to_string().into()));
+                frame_vec.push(Frame::BulkString(blob.into()));
+                if replace {
+                    frame_vec.push(Frame::BulkString("replace".into()));
+                }
+            }
+            Request::Dbsize => {
+                frame_vec.push(Frame::BulkString("dbsize".into()));
+            }
+            // The confirmation is up to the sender, it isn't part of the request
+            Request::Flushdb(_) => {
+                frame_vec.push(Frame::BulkString("flushdb".into()));
+            }
+            Request::Info(Info { section }) => {
+                frame_vec.push(Frame::BulkString("info".into()));
+                if let Some(section) = section {
+                    frame_vec.push(Frame::BulkString(section.into()));
+                }
+            }
+            Request::Config(Config::Get { pattern }) => {
+                frame_vec.push(Frame::BulkString("config".into()));
+                frame_vec.push(Frame::BulkString("get".into()));
+                frame_vec.push(Frame::BulkString(pattern.into()));
+            }
+            Request::Config(Config::Set { name, value }) => {
+                frame_vec.push(Frame::BulkString("config".into()));
+                frame_vec.push(Frame::BulkString("set".into()));
+                frame_vec.push(Frame::BulkString(name.into()));
+                frame_vec.push(Frame::BulkString(value.into()));
+            }
+            Request::Client(client) => {
+                frame_vec.push(Frame::BulkString("client".into()));
+                match client {
+                    Client::Setname { name } => {
+                        frame_vec.push(Frame::BulkString("setname".into()));
+                        frame_vec.push(Frame::BulkString(name.into()));
+                    }
+                    Client::Getname => frame_vec.push(Frame::BulkString("getname".into())),
+                    Client::Setinfo { attr, value } => {
+                        frame_vec.push(Frame::BulkString("setinfo".into()));
+                        frame_vec.push(Frame::BulkString(attr.into()));
+                        frame_vec.push(Frame::BulkString(value.into()));
+                    }
+                    Client::List => frame_vec.push(Frame::BulkString("list".into())),
+                }
+            }
+            Request::Tasks => {
+                frame_vec.push(Frame::BulkString("tasks".into()));
+            }
+            Request::Currentops => {
+                frame_vec.push(Frame::BulkString("currentops".into()));
+            }
+            Request::Compact => {
+                frame_vec.push(Frame::BulkString("compact".into()));
+            }
+            Request::Shutdown => {
+                frame_vec.push(Frame::BulkString("shutdown".into()));
+            }
+            Request::Upgrade => {
+                frame_vec.push(Frame::BulkString("upgrade".into()));
+            }
+            Request::Multi => {
+                frame_vec.push(Frame::BulkString("multi".into()));
+            }
+            Request::Exec => {
+                frame_vec.push(Frame::BulkString("exec".into()));
+            }
+            Request::Discard => {
+                frame_vec.push(Frame::BulkString("discard".into()));
+            }
+            Request::Watch(Watch { keys }) => {
+                frame_vec.push(Frame::BulkString("watch".into()));
+                for key in keys {
+                    frame_vec.push(Frame::BulkString(key.into()));
+                }
+            }
+            Request::Unwatch => {
+                frame_vec.push(Frame::BulkString("unwatch".into()));
+            }
+            Request::Lockkey(Lockkey { key, timeout }) => {
+                frame_vec.push(Frame::BulkString("lockkey".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+                frame_vec.push(Frame::BulkString(timeout.to_string().into()));
+            }
+            Request::Unlockkey(Unlockkey { key }) => {
+                frame_vec.push(Frame::BulkString("unlockkey".into()));
+                frame_vec.push(Frame::BulkString(key.into()));
+            }
+            Request::Command(CommandArgs { query }) => {
+                frame_vec.push(Frame::BulkString("command".into()));
+                match query {
+                    None => {}
+                    Some(CommandQuery::Count) => {
+                        frame_vec.push(Frame::BulkString("count".into()));
+                    }
+                    Some(CommandQuery::Info { names }) => {
+                        frame_vec.push(Frame::BulkString("info".into()));
+                        for name in names {
+                            frame_vec.push(Frame::BulkString(name.into()));
+                        }
+                    }
+                }
+            }
+            Request::Hello(Hello { protover, setname }) => {
+                frame_vec.push(Frame::BulkString("hello".into()));
+                if let Some(protover) = protover {
+                    frame_vec.push(Frame::BulkString(protover.to_string().into()));
+                    if let Some(name) = setname {
+                        frame_vec.push(Frame::BulkString("setname".into()));
+                        frame_vec.push(Frame::BulkString(name.into()));
+                    }
+                }
+            }
+            Request::Subscribe(Subscribe { channels }) => {
+                frame_vec.push(Frame::BulkString("subscribe".into()));
+                for channel in channels {
+                    frame_vec.push(Frame::BulkString(channel.into()));
+                }
+            }
+            Request::Psubscribe(Psubscribe { patterns }) => {
+                frame_vec.push(Frame::BulkString("psubscribe".into()));
+                for pattern in patterns {
+                    frame_vec.push(Frame::BulkString(pattern.into()));
+                }
+            }
+            Request::Unsubscribe(Unsubscribe { channels }) => {
+                frame_vec.push(Frame::BulkString("unsubscribe".into()));
+                for channel in channels {
+                    frame_vec.push(Frame::BulkString(channel.into()));
+                }
+            }
+            Request::Punsubscribe(Punsubscribe { patterns }) => {
+                frame_vec.push(Frame::BulkString("punsubscribe".into()));
+                for pattern in patterns {
+                    frame_vec.push(Frame::BulkString(pattern.into()));
+                }
+            }
+            Request::Publish(Publish { channel, message }) => {
+                frame_vec.push(Frame::BulkString("publish".into()));
+                frame_vec.push(Frame::BulkString(channel.into()));
+                frame_vec.push(Frame::BulkString(message.into()));
+            }
+            Request::Auth(auth) => {
+                frame_vec.push(Frame::BulkString("auth".into()));
+                match auth {
+                    Auth::Login { username, password } => {
+                        if let Some(username) = username {
+                            frame_vec.push(Frame::BulkString(username.into()));
+                        }
+                        frame_vec.push(Frame::BulkString(password.into()));
+                    }
+                    Auth::Rotate => frame_vec.push(Frame::BulkString("rotate".into())),
+                    Auth::Revoke { username } => {
+                        frame_vec.push(Frame::BulkString("revoke".into()));
+                        frame_vec.push(Frame::BulkString(username.into()));
+                    }
+                }
+            }
+            Request::Select(Select { index }) => {
+                frame_vec.push(Frame::BulkString("select".into()));
+                frame_vec.push(Frame::BulkString(index.to_string().into()));
+            }
+            Request::Priority(PriorityArgs { class }) => {
+                frame_vec.push(Frame::BulkString("priority".into()));
+                frame_vec.push(Frame::BulkString(class.to_string().into()));
+            }
+        }
+        Frame::Array(frame_vec)
+    }
+}
+
+impl TryFrom<Frame> for Request {
+    type Error = RequestError;
+
+    // 一坨答辩
+    // need refactor
+    fn try_from(frame: Frame) -> std::result::Result<Self, Self::Error> {
+        if let Frame::Array(bulk_string_vec) = frame {
+            let mut v: Vec<Bytes> = vec![];
+            for bulk_string in bulk_string_vec {
+                if let Frame::BulkString(s) = bulk_string {
+                    v.push(s);
+                }
+            }
+            // println!("{:?}", &v);
+
+            if let Some(a) = v.first() {
+                if a == &Bytes::from(&b"set"[..]) && v.len() == 3 {
+                    Ok(Request::Set(Set {
+                        key: from_utf8(&v[1])?.to_string(),
+                        value: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"get"[..]) && v.len() == 2 {
+                    Ok(Request::Get(Get {
+                        key: from_utf8(&v[1])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"remove"[..]) && v.len() >= 2 {
+                    Ok(Request::Rm(Remove {
+                        keys: v[1..]
+                            .iter()
+                            .map(|s| Ok(from_utf8(s)?.to_string()))
+                            .collect::<std::result::Result<_, Self::Error>>()?,
+                    }))
+                } else if a == &Bytes::from(&b"exists"[..]) && v.len() >= 2 {
+                    Ok(Request::Exists(Exists {
+                        keys: v[1..]
+                            .iter()
+                            .map(|s| Ok(from_utf8(s)?.to_string()))
+                            .collect::<std::result::Result<_, Self::Error>>()?,
+                    }))
+                } else if a == &Bytes::from(&b"keys"[..]) && v.len() == 2 {
+                    Ok(Request::Keys(Keys {
+                        pattern: from_utf8(&v[1])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"scan"[..]) && v.len().is_multiple_of(2) {
+                    let mut scan = Scan {
+                        cursor: from_utf8(&v[1])?.to_string(),
+                        pattern: None,
+                        count: None,
+                    };
+                    for option in v[2..].chunks(2) {
+                        let value = from_utf8(&option[1])?;
+                        match &option[0][..] {
+                            b"match" => scan.pattern = Some(value.to_string()),
+                            b"count" => {
+                                scan.count =
+                                    Some(value.parse().map_err(|_| RequestError::ParseFrameErr)?)
+                            }
+                            _ => return Err(RequestError::ParseFrameErr),
+                        }
+                    }
+                    Ok(Request::Scan(scan))
+                } else if a == &Bytes::from(&b"mset"[..]) && v.len() >= 3 && v.len() % 2 == 1 {
+                    Ok(Request::Mset(Mset {
+                        pairs: v[1..]
+                            .iter()
+                            .map(|s| Ok(from_utf8(s)?.to_string()))
+                            .collect::<std::result::Result<_, Self::Error>>()?,
+                    }))
+                } else if a == &Bytes::from(&b"setnx"[..]) && v.len() == 3 {
+                    Ok(Request::Setnx(Setnx {
+                        key: from_utf8(&v[1])?.to_string(),
+                        value: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"getset"[..]) && v.len() == 3 {
+                    Ok(Request::Getset(Getset {
+                        key: from_utf8(&v[1])?.to_string(),
+                        value: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"append"[..]) && v.len() == 3 {
+                    Ok(Request::Append(Append {
+                        key: from_utf8(&v[1])?.to_string(),
+                        value: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"expire"[..]) && v.len() == 3 {
+                    Ok(Request::Expire(Expire {
+                        key: from_utf8(&v[1])?.to_string(),
+                        seconds: from_utf8(&v[2])?
+                            .parse()
+                            .map_err(|_| RequestError::ParseFrameErr)?,
+                    }))
+                } else if a == &Bytes::from(&b"ttl"[..]) && v.len() == 2 {
+                    Ok(Request::Ttl(Ttl {
+                        key: from_utf8(&v[1])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"persist"[..]) && v.len() == 2 {
+                    Ok(Request::Persist(Persist {
+                        key: from_utf8(&v[1])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"rename"[..]) && v.len() == 3 {
+                    Ok(Request::Rename(Rename {
+                        key: from_utf8(&v[1])?.to_string(),
+                        newkey: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"renamenx"[..]) && v.len() == 3 {
+                    Ok(Request::Renamenx(Renamenx {
+                        key: from_utf8(&v[1])?.to_string(),
+                        newkey: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"sadd"[..]) && v.len() >= 3 {
+                    Ok(Request::Sadd(Sadd {
+                        key: from_utf8(&v[1])?.to_string(),
+                        members: strings(&v[2..])?,
+                    }))
+                } else if a == &Bytes::from(&b"srem"[..]) && v.len() >= 3 {
+                    Ok(Request::Srem(Srem {
+                        key: from_utf8(&v[1])?.to_string(),
+                        members: strings(&v[2..])?,
+                    }))
+                } else if a == &Bytes::from(&b"smembers"[..]) && v.len() == 2 {
+                    Ok(Request::Smembers(Smembers {
+                        key: from_utf8(&v[1])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"sismember"[..]) && v.len() == 3 {
+                    Ok(Request::Sismember(Sismember {
+                        key: from_utf8(&v[1])?.to_string(),
+                        member: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"scard"[..]) && v.len() == 2 {
+                    Ok(Request::Scard(Scard {
+                        key: from_utf8(&v[1])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"sunion"[..]) && v.len() >= 2 {
+                    Ok(Request::Sunion(Sunion {
+                        keys: strings(&v[1..])?,
+                    }))
+                } else if a == &Bytes::from(&b"sinter"[..]) && v.len() >= 2 {
+                    Ok(Request::Sinter(Sinter {
+                        keys: strings(&v[1..])?,
+                    }))
+                } else if a == &Bytes::from(&b"migrate"[..]) && v.len() >= 5 {
+                    let options: Vec<&str> = v[5..]
+                        .iter()
+                        .map(|s| from_utf8(s))
+                        .collect::<std::result::Result<_, _>>()?;
+                    if options
+                        .iter()
+                        .any(|o| !["copy", "replace", "dryrun", "verify"].contains(o))
+                    {
+                        return Err(RequestError::ParseFrameErr);
+                    }
+                    Ok(Request::Migrate(Migrate {
+                        host: from_utf8(&v[1])?.to_string(),
+                        port: from_utf8(&v[2])?
+                            .parse()
+                            .map_err(|_| RequestError::ParseFrameErr)?,
+                        key: from_utf8(&v[3])?.to_string(),
+                        timeout: from_utf8(&v[4])?
+                            .parse()
+                            .map_err(|_| RequestError::ParseFrameErr)?,
+                        copy: options.contains(&"copy"),
+                        replace: options.contains(&"replace"),
+                        dry_run: options.contains(&"dryrun"),
+                        verify: options.contains(&"verify"),
+                    }))
+                } else if a == &Bytes::from(&b"dump"[..]) && v.len() == 2 {
+                    Ok(Request::Dump(Dump {
+                        key: from_utf8(&v[1])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"restore"[..])
+                    && (v.len() == 4 || v.len() == 5 && v[4] == b"replace"[..])
+                {
+                    Ok(Request::Restore(Restore {
+                        key: from_utf8(&v[1])?.to_string(),
+                        ttl: from_utf8(&v[2])?
+                            .parse()
+                            .map_err(|_| RequestError::ParseFrameErr)?,
+                        blob: from_utf8(&v[3])?.to_string(),
+                        replace: v.len() == 5,
+                    }))
+                } else if a == &Bytes::from(&b"dbsize"[..]) && v.len() == 1 {
+                    Ok(Request::Dbsize)
+                } else if a == &Bytes::from(&b"flushdb"[..]) && v.len() == 1 {
+                    Ok(Request::Flushdb(Flushdb { yes: true }))
+                } else if a == &Bytes::from(&b"info"[..]) && v.len() <= 2 {
+                    Ok(Request::Info(Info {
+                        section: v
+                            .get(1)
+                            .map(|s| from_utf8(s))
+                            .transpose()?
+                            .map(str::to_owned),
+                    }))
+                } else if a == &Bytes::from(&b"config"[..]) && v.len() == 3 && v[1] == b"get"[..] {
+                    Ok(Request::Config(Config::Get {
+                        pattern: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"config"[..]) && v.len() == 4 && v[1] == b"set"[..] {
+                    Ok(Request::Config(Config::Set {
+                        name: from_utf8(&v[2])?.to_string(),
+                        value: from_utf8(&v[3])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"client"[..])
+                    && v.len() == 3
+                    && v[1] == b"setname"[..]
+                {
+                    Ok(Request::Client(Client::Setname {
+                        name: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"client"[..])
+                    && v.len() == 2
+                    && v[1] == b"getname"[..]
+                {
+                    Ok(Request::Client(Client::Getname))
+                } else if a == &Bytes::from(&b"client"[..])
+                    && v.len() == 4
+                    && v[1] == b"setinfo"[..]
+                {
+                    Ok(Request::Client(Client::Setinfo {
+                        attr: from_utf8(&v[2])?.to_string(),
+                        value: from_utf8(&v[3])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"client"[..]) && v.len() == 2 && v[1] == b"list"[..] {
+                    Ok(Request::Client(Client::List))
+                } else if a == &Bytes::from(&b"tasks"[..]) && v.len() == 1 {
+                    Ok(Request::Tasks)
+                } else if a == &Bytes::from(&b"currentops"[..]) && v.len() == 1 {
+                    Ok(Request::Currentops)
+                } else if a == &Bytes::from(&b"compact"[..]) && v.len() == 1 {
+                    Ok(Request::Compact)
+                } else if a == &Bytes::from(&b"shutdown"[..]) && v.len() == 1 {
+                    Ok(Request::Shutdown)
+                } else if a == &Bytes::from(&b"upgrade"[..]) && v.len() == 1 {
+                    Ok(Request::Upgrade)
+                } else if a == &Bytes::from(&b"multi"[..]) && v.len() == 1 {
+                    Ok(Request::Multi)
+                } else if a == &Bytes::from(&b"exec"[..]) && v.len() == 1 {
+                    Ok(Request::Exec)
+                } else if a == &Bytes::from(&b"discard"[..]) && v.len() == 1 {
+                    Ok(Request::Discard)
+                } else if a == &Bytes::from(&b"watch"[..]) && v.len() >= 2 {
+                    Ok(Request::Watch(Watch {
+                        keys: v[1..]
+                            .iter()
+                            .map(|s| Ok(from_utf8(s)?.to_string()))
+                            .collect::<std::result::Result<_, RequestError>>()?,
+                    }))
+                } else if a == &Bytes::from(&b"unwatch"[..]) && v.len() == 1 {
+                    Ok(Request::Unwatch)
+                } else if a == &Bytes::from(&b"lockkey"[..]) && v.len() == 3 {
+                    Ok(Request::Lockkey(Lockkey {
+                        key: from_utf8(&v[1])?.to_string(),
+                        timeout: from_utf8(&v[2])?
+                            .parse()
+                            .map_err(|_| RequestError::ParseFrameErr)?,
+                    }))
+                } else if a == &Bytes::from(&b"unlockkey"[..]) && v.len() == 2 {
+                    Ok(Request::Unlockkey(Unlockkey {
+                        key: from_utf8(&v[1])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"command"[..]) && v.len() == 1 {
+                    Ok(Request::Command(CommandArgs { query: None }))
+                } else if a == &Bytes::from(&b"command"[..]) && v.len() == 2 && v[1] == b"count"[..]
+                {
+                    Ok(Request::Command(CommandArgs {
+                        query: Some(CommandQuery::Count),
+                    }))
+                } else if a == &Bytes::from(&b"command"[..]) && v.len() >= 3 && v[1] == b"info"[..]
+                {
+                    Ok(Request::Command(CommandArgs {
+                        query: Some(CommandQuery::Info {
+                            names: v[2..]
+                                .iter()
+                                .map(|s| Ok(from_utf8(s)?.to_string()))
+                                .collect::<std::result::Result<_, RequestError>>()?,
+                        }),
+                    }))
+                } else if a == &Bytes::from(&b"hello"[..])
+                    && (v.len() <= 2 || v.len() == 4 && v[2] == b"setname"[..])
+                {
+                    Ok(Request::Hello(Hello {
+                        protover: v
+                            .get(1)
+                            .map(|s| {
+                                from_utf8(s)?
+                                    .parse()
+                                    .map_err(|_| RequestError::ParseFrameErr)
+                            })
+                            .transpose()?,
+                        setname: v
+                            .get(3)
+                            .map(|s| from_utf8(s))
+                            .transpose()?
+                            .map(str::to_owned),
+                    }))
+                } else if a == &Bytes::from(&b"auth"[..]) && v.len() == 2 && v[1] == b"rotate"[..] {
+                    Ok(Request::Auth(Auth::Rotate))
+                } else if a == &Bytes::from(&b"auth"[..]) && v.len() == 3 && v[1] == b"revoke"[..] {
+                    Ok(Request::Auth(Auth::Revoke {
+                        username: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"auth"[..]) && (v.len() == 2 || v.len() == 3) {
+                    Ok(Request::Auth(Auth::Login {
+                        username: match v.len() {
+                            3 => Some(from_utf8(&v[1])?.to_string()),
+                            _ => None,
+                        },
+                        password: from_utf8(&v[v.len() - 1])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"subscribe"[..]) && v.len() >= 2 {
+                    Ok(Request::Subscribe(Subscribe {
+                        channels: strings(&v[1..])?,
+                    }))
+                } else if a == &Bytes::from(&b"psubscribe"[..]) && v.len() >= 2 {
+                    Ok(Request::Psubscribe(Psubscribe {
+                        patterns: strings(&v[1..])?,
+                    }))
+                } else if a == &Bytes::from(&b"unsubscribe"[..]) {
+                    Ok(Request::Unsubscribe(Unsubscribe {
+                        channels: strings(&v[1..])?,
+                    }))
+                } else if a == &Bytes::from(&b"punsubscribe"[..]) {
+                    Ok(Request::Punsubscribe(Punsubscribe {
+                        patterns: strings(&v[1..])?,
+                    }))
+                } else if a == &Bytes::from(&b"publish"[..]) && v.len() == 3 {
+                    Ok(Request::Publish(Publish {
+                        channel: from_utf8(&v[1])?.to_string(),
+                        message: from_utf8(&v[2])?.to_string(),
+                    }))
+                } else if a == &Bytes::from(&b"select"[..]) && v.len() == 2 {
+                    Ok(Request::Select(Select {
+                        index: from_utf8(&v[1])?
+                            .parse()
+                            .map_err(|_| RequestError::ParseFrameErr)?,
+                    }))
+                } else if a == &Bytes::from(&b"priority"[..]) && v.len() == 2 {
+                    Ok(Request::Priority(PriorityArgs {
+                        class: from_utf8(&v[1])?
+                            .parse()
+                            .map_err(|_| RequestError::ParseFrameErr)?,
+                    }))
+                } else {
+                    Err(RequestError::ParseFrameErr)
+                }
+            } else {
+                Err(RequestError::ParseFrameErr)
+            }
+        } else {
+            Err(RequestError::ParseFrameErr)
+        }
+    }
+}
+
+fn strings(args: &[Bytes]) -> std::result::Result<Vec<String>, RequestError> {
+    args.iter().map(|s| Ok(from_utf8(s)?.to_string())).collect()
+}
+
+#[derive(Error, Debug)]
+pub enum RequestError {
+    #[error("Cannot parse Frame into Request")]
+    ParseFrameErr,
+    #[error("Utf8Error")]
+    Utf8Error(#[from] std::str::Utf8Error),
+}
+
+/// Caps on the size of a decoded frame.
+///
+/// They are checked against the headers of a frame as soon as they are received, so an
+/// oversized request is rejected before its data is buffered.
+#[derive(Debug, Clone)]
+pub struct FrameLimits {
+    /// Maximum encoded size of a frame in bytes
+    pub max_frame_size: usize,
+    /// Maximum number of elements of an array
+    pub max_array_len: usize,
+    /// Maximum length of a bulk string in bytes
+    pub max_bulk_len: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_frame_size: 64 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_bulk_len: 16 * 1024 * 1024,
+        }
+    }
+}
+
+// Longer header lines can't hold a valid length
+const MAX_LENGTH_LINE: usize = 32;
+
+impl FrameLimits {
+    /// Check the first frame in `buf`, which may not be complete yet.
+    ///
+    /// Returns `Error::FrameRejected` as soon as a header exceeds the limits.
+    pub(crate) fn check(&self, buf: &[u8]) -> Result<()> {
+        if self.frame_size(buf)? > self.max_frame_size {
+            return Err(Error::FrameRejected("too big frame"));
+        }
+        Ok(())
+    }
+
+    /// Walk the headers of the first frame in `buf`, checking array and bulk string lengths.
+    ///
+    /// Returns the size of the frame if complete, or a lower bound of it.
+    fn frame_size(&self, buf: &[u8]) -> Result<usize> {
+        // An incomplete frame is the last thing in the buffer
+        let incomplete = |pos: usize| Ok(pos.max(buf.len()));
+        // Elements still expected by the enclosing arrays
+        let mut pending: Vec<usize> = vec![1];
+        let mut pos = 0;
+        while let Some(remaining) = pending.last_mut() {
+            if *remaining == 0 {
+                pending.pop();
+                continue;
+            }
+            *remaining -= 1;
+
+            let kind = match buf.get(pos) {
+                Some(&kind) => kind,
+                None => return incomplete(pos),
+            };
+            let line_end = match buf[pos..].windows(2).position(|w| w == b"\r\n") {
+                Some(len) => pos + len,
+                None if (kind == b'*' || kind == b'$') && buf.len() - pos > MAX_LENGTH_LINE => {
+                    return Err(Error::FrameRejected("invalid length"));
+                }
+                None => return incomplete(pos),
+            };
+            let header = &buf[pos + 1..line_end];
+            pos = line_end + 2;
+
+            match kind {
+                b'*' => {
+                    let len = parse_length(header)?;
+                    if len > self.max_array_len {
+                        return Err(Error::FrameRejected("invalid multibulk length"));
+                    }
+                    pending.push(len);
+                }
+                b'$' => {
+                    let len = parse_length(header)?;
+                    if len > self.max_bulk_len {
+                        return Err(Error::FrameRejected("invalid bulk length"));
+                    }
+                    pos += len + 2;
+                }
+                _ => {}
+            }
+        }
+        Ok(pos)
+    }
+}
+
+fn parse_length(header: &[u8]) -> Result<usize> {
+    // Null arrays and bulk strings have a length of -1
+    let len: i64 = from_utf8(header)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .ok_or(Error::FrameRejected("invalid length"))?;
+    Ok(len.max(0) as usize)
+}
@@ -0,0 +1,189 @@
+use crate::glob::glob_match;
+use crate::Response;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// A message published on a channel, see `KvsClient::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub channel: String,
+    /// The pattern the channel matched, `None` if the channel was subscribed by name
+    pub pattern: Option<String>,
+    pub payload: String,
+}
+
+/// Messages are pushed as ["message", channel, payload], or
+/// ["pmessage", pattern, channel, payload] if the channel matched a pattern.
+impl From<Message> for Response {
+    fn from(message: Message) -> Self {
+        let Message {
+            channel,
+            pattern,
+            payload,
+        } = message;
+        match pattern {
+            Some(pattern) => Response::Push(vec![
+                Response::bulk("pmessage"),
+                Response::bulk(pattern),
+                Response::bulk(channel),
+                Response::bulk(payload),
+            ]),
+            None => Response::Push(vec![
+                Response::bulk("message"),
+                Response::bulk(channel),
+                Response::bulk(payload),
+            ]),
+        }
+    }
+}
+
+/// Subscribers of the channels and patterns of a server.
+#[derive(Default)]
+pub(crate) struct PubSub {
+    subscribers: Mutex<Subscribers>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: u64,
+    senders: HashMap<u64, Sender<Message>>,
+    channels: HashMap<String, HashSet<u64>>,
+    patterns: HashMap<String, HashSet<u64>>,
+}
+
+impl PubSub {
+    /// Send `payload` to the subscribers of `channel`, returns how many received it.
+    ///
+    /// A subscriber of both the channel and patterns matching it receives it once for each.
+    pub(crate) fn publish(&self, channel: &str, payload: &str) -> usize {
+        let subscribers = self.subscribers.lock().unwrap();
+        let message = |pattern: Option<&String>| Message {
+            channel: channel.to_owned(),
+            pattern: pattern.cloned(),
+            payload: payload.to_owned(),
+        };
+        let mut received = 0;
+        for id in subscribers.channels.get(channel).into_iter().flatten() {
+            if subscribers.senders[id].send(message(None)).is_ok() {
+                received += 1;
+            }
+        }
+        for (pattern, ids) in &subscribers.patterns {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for id in ids {
+                if subscribers.senders[id].send(message(Some(pattern))).is_ok() {
+                    received += 1;
+                }
+            }
+        }
+        received
+    }
+}
+
+/// Channels and patterns subscribed by a connection, they are unsubscribed when it is dropped.
+pub(crate) struct Subscription {
+    pubsub: Arc<PubSub>,
+    id: u64,
+    receiver: Receiver<Message>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+}
+
+impl Subscription {
+    pub(crate) fn new(pubsub: Arc<PubSub>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let id = {
+            let mut subscribers = pubsub.subscribers.lock().unwrap();
+            let id = subscribers.next_id;
+            subscribers.next_id += 1;
+            subscribers.senders.insert(id, sender);
+            id
+        };
+        Subscription {
+            pubsub,
+            id,
+            receiver,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+        }
+    }
+
+    pub(crate) fn subscribe(&mut self, channel: String) {
+        if self.channels.insert(channel.clone()) {
+            let mut subscribers = self.pubsub.subscribers.lock().unwrap();
+            subscribers
+                .channels
+                .entry(channel)
+                .or_default()
+                .insert(self.id);
+        }
+    }
+
+    pub(crate) fn psubscribe(&mut self, pattern: String) {
+        if self.patterns.insert(pattern.clone()) {
+            let mut subscribers = self.pubsub.subscribers.lock().unwrap();
+            subscribers
+                .patterns
+                .entry(pattern)
+                .or_default()
+                .insert(self.id);
+        }
+    }
+
+    pub(crate) fn unsubscribe(&mut self, channel: &str) {
+        if self.channels.remove(channel) {
+            let mut subscribers = self.pubsub.subscribers.lock().unwrap();
+            remove_subscriber(&mut subscribers.channels, channel, self.id);
+        }
+    }
+
+    pub(crate) fn punsubscribe(&mut self, pattern: &str) {
+        if self.patterns.remove(pattern) {
+            let mut subscribers = self.pubsub.subscribers.lock().unwrap();
+            remove_subscriber(&mut subscribers.patterns, pattern, self.id);
+        }
+    }
+
+    pub(crate) fn channels(&self) -> Vec<String> {
+        self.channels.iter().cloned().collect()
+    }
+
+    pub(crate) fn patterns(&self) -> Vec<String> {
+        self.patterns.iter().cloned().collect()
+    }
+
+    /// Number of channels and patterns subscribed.
+    pub(crate) fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// The next message received, `None` if there is none yet.
+    pub(crate) fn try_recv(&self) -> Option<Message> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut subscribers = self.pubsub.subscribers.lock().unwrap();
+        for channel in &self.channels {
+            remove_subscriber(&mut subscribers.channels, channel, self.id);
+        }
+        for pattern in &self.patterns {
+            remove_subscriber(&mut subscribers.patterns, pattern, self.id);
+        }
+        subscribers.senders.remove(&self.id);
+    }
+}
+
+fn remove_subscriber(subscribed: &mut HashMap<String, HashSet<u64>>, name: &str, id: u64) {
+    if let Some(ids) = subscribed.get_mut(name) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            subscribed.remove(name);
+        }
+    }
+}
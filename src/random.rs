@@ -0,0 +1,40 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+#[cfg(not(unix))]
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use std::{fs::File, io::Read};
+
+/// A random number in [0, 1), good enough for jitter.
+pub(crate) fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A random number, good enough to tell apart things made at the same time.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// `N` random bytes nobody can guess, for secrets.
+///
+/// They are read from the OS on Unix. Elsewhere they are hashed with the random keys the
+/// OS seeds `RandomState` with, along with the time.
+pub(crate) fn secret_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    #[cfg(unix)]
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    #[cfg(not(unix))]
+    for chunk in bytes.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,62 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+/// A request a server started serving, as kept by `RecentRequests`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentRequest {
+    /// When the server started serving it
+    pub at: SystemTime,
+    /// Number of the connection it was received on, connections are numbered from 0
+    pub connection: u64,
+    /// Name of its command, e.g. "set"
+    pub command: &'static str,
+    /// Its first key, if it has any; values and passwords aren't kept
+    pub key: Option<String>,
+}
+
+/// The last requests a server started serving, in memory, see
+/// `KvsServer::keep_recent_requests`.
+///
+/// Clones share the same requests, so one can be kept to read them after the server got
+/// it, e.g. by a panic hook writing a crash report.
+#[derive(Clone)]
+pub struct RecentRequests {
+    requests: Arc<Mutex<VecDeque<RecentRequest>>>,
+    capacity: usize,
+}
+
+impl RecentRequests {
+    /// Keep the last `capacity` requests.
+    pub fn new(capacity: usize) -> Self {
+        RecentRequests {
+            requests: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub(crate) fn record(&self, connection: u64, command: &'static str, key: Option<&str>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        if requests.len() == self.capacity {
+            requests.pop_front();
+        }
+        requests.push_back(RecentRequest {
+            at: SystemTime::now(),
+            connection,
+            command,
+            key: key.map(str::to_owned),
+        });
+    }
+
+    /// The requests kept, oldest first.
+    ///
+    /// It can be called from a panic hook: a thread that panicked while recording a
+    /// request doesn't keep the others from being read.
+    pub fn requests(&self) -> Vec<RecentRequest> {
+        let requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        requests.iter().cloned().collect()
+    }
+}
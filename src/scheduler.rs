@@ -0,0 +1,350 @@
+use crate::config::{parse, parse_yes_no, yes_no};
+use crate::random::random_fraction;
+use crate::{Error, Result};
+use chrono::{DateTime, Local};
+use log::{debug, error};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often and whether a scheduled job runs.
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+    /// Time between two runs of the job
+    pub interval: Duration,
+    /// Up to this fraction of `interval` is randomly added to every wait,
+    /// so jobs sharing an interval don't fire in lockstep
+    pub jitter: f64,
+    /// Disabled jobs stay registered but are skipped
+    pub enabled: bool,
+}
+
+impl JobConfig {
+    /// An enabled job running every `interval`, with 10% jitter.
+    pub fn every(interval: Duration) -> Self {
+        JobConfig {
+            interval,
+            jitter: 0.1,
+            enabled: true,
+        }
+    }
+
+    fn next_delay(&self) -> Duration {
+        self.interval
+            .mul_f64(1.0 + self.jitter.clamp(0.0, 1.0) * random_fraction())
+    }
+}
+
+/// A snapshot of a scheduled job, as reported by the TASKS command.
+#[derive(Debug, Clone)]
+pub struct JobStatus {
+    pub name: String,
+    pub config: JobConfig,
+    pub running: bool,
+    pub runs: u64,
+    pub last_run: Option<SystemTime>,
+    pub last_duration: Option<Duration>,
+    pub last_error: Option<String>,
+    /// `None` if the job is disabled or running
+    pub next_run: Option<SystemTime>,
+}
+
+impl fmt::Display for JobStatus {
+    /// Formats the status as `field:value` lines under a `# name` header.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |time: Option<SystemTime>| {
+            time.map_or("-".to_owned(), |time| {
+                DateTime::<Local>::from(time)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+        };
+
+        writeln!(f, "# {}", self.name)?;
+        writeln!(f, "enabled:{}", self.config.enabled)?;
+        writeln!(f, "interval_ms:{}", self.config.interval.as_millis())?;
+        writeln!(f, "running:{}", self.running)?;
+        writeln!(f, "runs:{}", self.runs)?;
+        writeln!(f, "last_run:{}", time(self.last_run))?;
+        match self.last_duration {
+            Some(duration) => writeln!(f, "last_duration_ms:{}", duration.as_millis())?,
+            None => writeln!(f, "last_duration_ms:-")?,
+        }
+        writeln!(
+            f,
+            "last_error:{}",
+            self.last_error.as_deref().unwrap_or("-")
+        )?;
+        writeln!(f, "next_run:{}", time(self.next_run))
+    }
+}
+
+type Task = Box<dyn FnMut() -> Result<()> + Send>;
+
+struct Job {
+    name: String,
+    config: JobConfig,
+    next_run: Instant,
+    // Taken out while the job is running, so the job list isn't locked during the run
+    task: Option<Task>,
+    runs: u64,
+    last_run: Option<SystemTime>,
+    last_duration: Option<Duration>,
+    last_error: Option<String>,
+}
+
+impl Job {
+    fn status(&self) -> JobStatus {
+        let running = self.task.is_none();
+        let next_run = (self.config.enabled && !running)
+            .then(|| SystemTime::now() + self.next_run.saturating_duration_since(Instant::now()));
+        JobStatus {
+            name: self.name.clone(),
+            config: self.config.clone(),
+            running,
+            runs: self.runs,
+            last_run: self.last_run,
+            last_duration: self.last_duration,
+            last_error: self.last_error.clone(),
+            next_run,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    jobs: Vec<Job>,
+    started: bool,
+    shutdown: bool,
+}
+
+/// Runs periodic background jobs (compaction checks, expiry sweeps, stats snapshots, ...)
+/// on a single thread, instead of one ad-hoc thread per feature.
+///
+/// `Scheduler` is a cheap handle: clones share the same jobs.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{JobConfig, Scheduler};
+/// use std::time::Duration;
+///
+/// let scheduler = Scheduler::new();
+/// scheduler.add_job("heartbeat", JobConfig::every(Duration::from_secs(1)), || {
+///     println!("still alive");
+///     Ok(())
+/// });
+/// scheduler.start();
+/// scheduler.set_enabled("heartbeat", false).unwrap();
+/// scheduler.shutdown();
+/// ```
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    shared: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a job. Its first run is one interval from now.
+    ///
+    /// A job with the same name is replaced.
+    pub fn add_job<F>(&self, name: &str, config: JobConfig, task: F)
+    where
+        F: FnMut() -> Result<()> + Send + 'static,
+    {
+        let (state, wakeup) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.jobs.retain(|job| job.name != name);
+        state.jobs.push(Job {
+            name: name.to_owned(),
+            next_run: Instant::now() + config.next_delay(),
+            config,
+            task: Some(Box::new(task)),
+            runs: 0,
+            last_run: None,
+            last_duration: None,
+            last_error: None,
+        });
+        wakeup.notify_all();
+    }
+
+    /// Enable or disable the job with the given name.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::UnknownJob` if there is no such job.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> Result<()> {
+        self.update_job(name, |job| job.config.enabled = enabled)
+    }
+
+    /// Change the interval of the job with the given name, its next run is rescheduled.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::UnknownJob` if there is no such job.
+    pub fn set_interval(&self, name: &str, interval: Duration) -> Result<()> {
+        self.update_job(name, |job| {
+            job.config.interval = interval;
+            job.next_run = Instant::now() + job.config.next_delay();
+        })
+    }
+
+    /// Returns the configuration of the job with the given name.
+    pub fn job_config(&self, name: &str) -> Option<JobConfig> {
+        let state = self.shared.0.lock().unwrap();
+        let job = state.jobs.iter().find(|job| job.name == name)?;
+        Some(job.config.clone())
+    }
+
+    /// Settings of every job, by the names used by CONFIG GET and CONFIG SET:
+    /// "job-NAME-enabled" is "yes" or "no", "job-NAME-interval" is in milliseconds.
+    pub fn config(&self) -> Vec<(String, String)> {
+        let state = self.shared.0.lock().unwrap();
+        state
+            .jobs
+            .iter()
+            .flat_map(|job| {
+                [
+                    (
+                        format!("job-{}-enabled", job.name),
+                        yes_no(job.config.enabled),
+                    ),
+                    (
+                        format!("job-{}-interval", job.name),
+                        job.config.interval.as_millis().to_string(),
+                    ),
+                ]
+            })
+            .collect()
+    }
+
+    /// Change the job setting `name`, see `config`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::UnknownConfig` if there is no such setting, and
+    /// `Error::InvalidConfig` if `value` can't be parsed.
+    pub fn set_config(&self, name: &str, value: &str) -> Result<()> {
+        let unknown = || Error::UnknownConfig(name.to_owned());
+        let setting = name.strip_prefix("job-").ok_or_else(unknown)?;
+        let result = if let Some(job) = setting.strip_suffix("-enabled") {
+            self.set_enabled(job, parse_yes_no(name, value)?)
+        } else if let Some(job) = setting.strip_suffix("-interval") {
+            let millis = parse(name, value)?;
+            // The job would run back to back
+            if millis == 0 {
+                return Err(Error::InvalidConfig(name.to_owned(), value.to_owned()));
+            }
+            self.set_interval(job, Duration::from_millis(millis))
+        } else {
+            return Err(unknown());
+        };
+        result.map_err(|_| unknown())
+    }
+
+    /// Returns the status of all jobs, in registration order.
+    pub fn jobs(&self) -> Vec<JobStatus> {
+        let state = self.shared.0.lock().unwrap();
+        state.jobs.iter().map(Job::status).collect()
+    }
+
+    /// Start running jobs on a background thread. Calling it again has no effect.
+    pub fn start(&self) {
+        {
+            let mut state = self.shared.0.lock().unwrap();
+            if state.started {
+                return;
+            }
+            state.started = true;
+        }
+
+        let shared = Arc::clone(&self.shared);
+        thread::Builder::new()
+            .name("kvs-scheduler".to_owned())
+            .spawn(move || run(&shared))
+            .expect("failed to spawn scheduler thread");
+    }
+
+    /// Stop the background thread after the currently running job, if any, has finished.
+    pub fn shutdown(&self) {
+        let (state, wakeup) = &*self.shared;
+        state.lock().unwrap().shutdown = true;
+        wakeup.notify_all();
+    }
+
+    fn update_job(&self, name: &str, f: impl FnOnce(&mut Job)) -> Result<()> {
+        let (state, wakeup) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        let job = state
+            .jobs
+            .iter_mut()
+            .find(|job| job.name == name)
+            .ok_or_else(|| Error::UnknownJob(name.to_owned()))?;
+        f(job);
+        wakeup.notify_all();
+        Ok(())
+    }
+}
+
+fn run(shared: &(Mutex<State>, Condvar)) {
+    let (state, wakeup) = shared;
+    let mut guard = state.lock().unwrap();
+    loop {
+        if guard.shutdown {
+            debug!("scheduler stopped");
+            return;
+        }
+
+        let now = Instant::now();
+        let next = guard
+            .jobs
+            .iter_mut()
+            .filter(|job| job.config.enabled && job.task.is_some())
+            .min_by_key(|job| job.next_run);
+
+        let job = match next {
+            Some(job) if job.next_run <= now => job,
+            Some(job) => {
+                let timeout = job.next_run - now;
+                guard = wakeup.wait_timeout(guard, timeout).unwrap().0;
+                continue;
+            }
+            None => {
+                guard = wakeup.wait(guard).unwrap();
+                continue;
+            }
+        };
+
+        let name = job.name.clone();
+        let mut task = job.task.take().unwrap();
+        drop(guard);
+
+        debug!("running job {}", name);
+        let started_at = SystemTime::now();
+        let start = Instant::now();
+        let result = task();
+        let duration = start.elapsed();
+        if let Err(e) = &result {
+            error!("job {} failed: {}", name, e);
+        }
+
+        guard = state.lock().unwrap();
+        // The job may have been replaced while it was running, keep the new one then
+        if let Some(job) = guard
+            .jobs
+            .iter_mut()
+            .find(|job| job.name == name && job.task.is_none())
+        {
+            job.task = Some(task);
+            job.next_run = Instant::now() + job.config.next_delay();
+            job.runs += 1;
+            job.last_run = Some(started_at);
+            job.last_duration = Some(duration);
+            job.last_error = result.err().map(|e| e.to_string());
+        }
+    }
+}
@@ -1 +1,2073 @@
-pub struct KvsServer {}
+use crate::auth::{TokenStore, DEFAULT_USER};
+use crate::capture::CaptureWriter;
+use crate::checksum::crc32;
+use crate::clients::{is_valid_client_value, ClientRegistry, CLIENT_INFO_ATTRS};
+use crate::commands::{self, CommandFlag, COMMANDS};
+use crate::glob::glob_match;
+use crate::locks::KeyLocks;
+use crate::priority::{PriorityGuard, PriorityMutex};
+use crate::pubsub::{PubSub, Subscription};
+use crate::random::random_fraction;
+use crate::set::{self, Members};
+use crate::watch::{WatchRegistry, WatchedKeys};
+use crate::{
+    Append, Auth, AuthProvider, BatchOp, Client, Clock, Codec, CommandArgs, CommandQuery, Config,
+    Dump, DumpPayload, Error, Exists, Expire, FrameLimits, Get, Getset, Hello, Info, JobConfig,
+    JobStatus, KeyRules, Keys, KvsClient, KvsEngine, Lockkey, MeteredEngine, Migrate, Mset,
+    NotifyingEngine, OpStats, Persist, Priority, PriorityArgs, Protocol, Psubscribe, Publish,
+    Punsubscribe, RecentRequests, Remove, Rename, Renamenx, Request, Response, Restore, Result,
+    RetryPolicy, Sadd, Scan, Scard, Scheduler, Select, ServerConfig, Set, Setnx, Sinter, Sismember,
+    Smembers, Srem, Subscribe, Sunion, Transaction, Ttl, Unlockkey, Unsubscribe, Watch, WriteBatch,
+};
+use log::{debug, error, info, warn};
+use redis_protocol::resp2::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::net::{self, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Keys gone through by a SCAN without COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// How long a subscribed connection waits for a request before delivering its messages
+const SUBSCRIBER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long other connections wait for a request before checking for a shutdown
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest a connection closed by a shutdown waits for the client to close its end
+const CLOSE_LINGER: Duration = Duration::from_secs(1);
+
+/// Longest the token a connection rotated away from stays valid
+const TOKEN_ROTATION_GRACE: Duration = Duration::from_secs(60);
+
+/// Timeout of MIGRATE without one, the engine stays locked while the destination answers
+const MIGRATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default of `KvsServer::set_drain_timeout`
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When a connection is evicted for being slow to take its responses.
+///
+/// A client on a bad network makes the server block on writing to it,
+/// which ties up the connection's thread and the memory of its pending responses.
+#[derive(Debug, Clone)]
+pub struct SlowClientPolicy {
+    /// Longest a single response may block on writing
+    pub write_timeout: Duration,
+    /// Most time a connection may spend blocked on writes per `window`
+    pub max_blocked_time: Duration,
+    pub window: Duration,
+}
+
+impl Default for SlowClientPolicy {
+    fn default() -> Self {
+        SlowClientPolicy {
+            write_timeout: Duration::from_secs(10),
+            max_blocked_time: Duration::from_secs(5),
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Time a connection spent blocked on writes in the current window.
+struct WriteTracker {
+    /// Write timeout set on the socket
+    write_timeout: Duration,
+    window_start: Instant,
+    blocked: Duration,
+}
+
+impl WriteTracker {
+    /// Account a write, returns whether the connection went over `policy.max_blocked_time`.
+    fn record(&mut self, policy: &SlowClientPolicy, elapsed: Duration) -> bool {
+        if self.window_start.elapsed() >= policy.window {
+            self.window_start = Instant::now();
+            self.blocked = Duration::ZERO;
+        }
+        self.blocked += elapsed;
+        self.blocked > policy.max_blocked_time
+    }
+}
+
+/// Counters of a running server, reported by INFO.
+struct ServerStats {
+    started_at: Instant,
+    connected_clients: AtomicU64,
+    total_connections: AtomicU64,
+    total_commands: AtomicU64,
+}
+
+impl ServerStats {
+    fn new() -> Self {
+        ServerStats {
+            started_at: Instant::now(),
+            connected_clients: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            total_commands: AtomicU64::new(0),
+        }
+    }
+}
+
+/// What the requests of a connection change for the following ones.
+#[derive(Default)]
+struct Session {
+    /// Number of the connection within the server's run
+    connection: u64,
+    protocol: Protocol,
+    /// Index of the database selected with SELECT
+    db: usize,
+    /// Set with PRIORITY
+    priority: Priority,
+    multi: Option<MultiState>,
+    watch: Option<WatchedKeys>,
+    /// `None` unless something is subscribed
+    subscription: Option<Subscription>,
+    /// User authenticated as with AUTH
+    user: Option<String>,
+    /// Token of AUTH ROTATE the connection authenticated with, it has to stay valid
+    token: Option<String>,
+}
+
+/// The requests a connection queued since MULTI.
+#[derive(Default)]
+struct MultiState {
+    queued: Vec<Request>,
+    /// Whether a request failed to be queued, EXEC then fails
+    aborted: bool,
+}
+
+/// The connection a request was received on, and the priority it is executed with.
+#[derive(Clone, Copy)]
+struct Origin {
+    connection: u64,
+    priority: Priority,
+}
+
+/// A write waiting for its batch, with its database, its origin and where to send its response.
+type PendingWrite = (usize, Origin, Request, Sender<Response>);
+
+/// An engine as shared by the connections, reporting the keys changed and its operations.
+type SharedEngine<E> = Arc<PriorityMutex<MeteredEngine<NotifyingEngine<E>>>>;
+
+/// Starts the server process taking over the listening socket, see
+/// `KvsServer::set_upgrade_handler`.
+type UpgradeHandler = Arc<dyn Fn(&TcpListener) -> Result<()> + Send + Sync>;
+
+// Trait Object or Generic Type
+// A generic type parameter can work with one concrete type at a time,
+// whereas trait objects allow for multiple concrete types to fill in for the trait object at run-time.
+// We don't need multiple concrete types
+pub struct KvsServer<E: KvsEngine> {
+    /// Engines of the databases, by index
+    engines: Arc<Vec<SharedEngine<E>>>,
+    scheduler: Scheduler,
+    commands: Arc<CommandNames>,
+    config: Arc<RwLock<ServerConfig>>,
+    write_batch_window: Option<Duration>,
+    stats: Arc<ServerStats>,
+    watches: Arc<WatchRegistry>,
+    pubsub: Arc<PubSub>,
+    /// Sequence number of the last write acknowledged
+    write_seq: Arc<AtomicU64>,
+    capture: Option<Arc<CaptureWriter>>,
+    recent: Option<RecentRequests>,
+    locks: Arc<KeyLocks>,
+    clients: Arc<ClientRegistry>,
+    shutdown: Arc<ShutdownState>,
+    drain_timeout: Duration,
+    auth: Option<Arc<dyn AuthProvider>>,
+    tokens: Arc<TokenStore>,
+    upgrade: Option<UpgradeHandler>,
+}
+
+impl<E: KvsEngine + Send + 'static> KvsServer<E> {
+    pub fn new(engine: E) -> Self {
+        let mut server = KvsServer {
+            engines: Arc::default(),
+            scheduler: Scheduler::new(),
+            commands: Arc::default(),
+            config: Arc::default(),
+            write_batch_window: None,
+            stats: Arc::new(ServerStats::new()),
+            watches: Arc::default(),
+            pubsub: Arc::default(),
+            write_seq: Arc::default(),
+            capture: None,
+            recent: None,
+            locks: Arc::default(),
+            clients: Arc::default(),
+            shutdown: Arc::default(),
+            drain_timeout: DRAIN_TIMEOUT,
+            auth: None,
+            tokens: Arc::default(),
+            upgrade: None,
+        };
+        server.add_database(engine);
+        server
+    }
+
+    /// Read the current time from `clock` for expiration times, in every database so far,
+    /// see `KvsEngine::set_clock`.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        for engine in self.engines.iter() {
+            engine.lock(Priority::Normal).set_clock(Arc::clone(&clock));
+        }
+    }
+
+    /// Add a database on `engine`, clients select it with SELECT and the next index.
+    ///
+    /// The engine passed to `new` is database 0. Databases don't share keys, e.g. each
+    /// engine should have a directory of its own. Changes to keys of database `n` are
+    /// published on "__keyspace@n__:KEY" channels, except for database 0.
+    pub fn add_database(&mut self, engine: E) {
+        let engines = Arc::make_mut(&mut self.engines);
+        let prefix = match engines.len() {
+            0 => "__keyspace__:".to_owned(),
+            db => format!("__keyspace@{}__:", db),
+        };
+        let mut engine = NotifyingEngine::new(engine);
+        engine.add_listener({
+            let config = Arc::clone(&self.config);
+            let pubsub = Arc::clone(&self.pubsub);
+            move |event| {
+                if config.read().unwrap().notify_keyspace_events {
+                    let channel = format!("{}{}", prefix, event.key);
+                    pubsub.publish(&channel, event.kind.name());
+                }
+            }
+        });
+        engines.push(Arc::new(PriorityMutex::new(MeteredEngine::new(engine))));
+    }
+
+    /// Scheduler of the server's background jobs, they start running with the server.
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Register a background job working on the engine, it runs on the engine of every
+    /// database in turn.
+    ///
+    /// The engine is locked while the job runs, so keep the job short. The job gets the
+    /// engine with low priority. Databases added afterwards aren't covered.
+    pub fn add_engine_job<F>(&self, name: &str, config: JobConfig, mut task: F)
+    where
+        F: FnMut(&mut E) -> Result<()> + Send + 'static,
+    {
+        let engines = Arc::clone(&self.engines);
+        self.scheduler.add_job(name, config, move || {
+            for engine in engines.iter() {
+                task(engine.lock(Priority::Low).inner_mut().inner_mut())?;
+            }
+            Ok(())
+        });
+    }
+
+    /// Purge the expired keys of every database every `interval`, see `KvsEngine::purge_expired`.
+    ///
+    /// Expired keys are otherwise only dropped by compaction, and aren't published as
+    /// "expired" keyspace events.
+    pub fn add_expire_sweep(&self, interval: Duration) {
+        let engines = Arc::clone(&self.engines);
+        self.scheduler
+            .add_job("expire-sweep", JobConfig::every(interval), move || {
+                for engine in engines.iter() {
+                    let expired = engine.lock(Priority::Low).inner_mut().purge_expired()?;
+                    if !expired.is_empty() {
+                        debug!("Purged {} expired keys", expired.len());
+                    }
+                }
+                Ok(())
+            });
+    }
+
+    /// Check the data of every database for corruption in the background, reading about
+    /// `bytes_per_sec` bytes a second in all, see `KvsEngine::verify_data`.
+    ///
+    /// The job runs every second, with low priority, so it holds the engine for a short
+    /// while at a time. Corruption found is reported by INFO in the engine's statistics.
+    pub fn add_verify_job(&self, bytes_per_sec: u64) {
+        let engines = Arc::clone(&self.engines);
+        self.scheduler.add_job(
+            "verify",
+            JobConfig::every(Duration::from_secs(1)),
+            move || {
+                let max_bytes = (bytes_per_sec / engines.len() as u64).max(1);
+                for engine in engines.iter() {
+                    engine
+                        .lock(Priority::Low)
+                        .inner_mut()
+                        .inner_mut()
+                        .verify_data(max_bytes)?;
+                }
+                Ok(())
+            },
+        );
+    }
+
+    /// Checkpoint the engine of every database every `interval`, see `KvsEngine::checkpoint`.
+    ///
+    /// Engines also checkpoint when they are dropped.
+    pub fn add_checkpoint_job(&self, interval: Duration) {
+        let engines = Arc::clone(&self.engines);
+        self.scheduler
+            .add_job("checkpoint", JobConfig::every(interval), move || {
+                for engine in engines.iter() {
+                    engine
+                        .lock(Priority::Low)
+                        .inner_mut()
+                        .inner_mut()
+                        .checkpoint()?;
+                }
+                Ok(())
+            });
+    }
+
+    /// Counts and latencies of the engine operations executed for clients, see `MeteredEngine`.
+    ///
+    /// Operations on all databases are added up.
+    pub fn engine_stats(&self) -> BTreeMap<&'static str, OpStats> {
+        let mut stats: BTreeMap<&'static str, OpStats> = BTreeMap::new();
+        for engine in self.engines.iter() {
+            for (op, op_stats) in engine.lock(Priority::Normal).op_stats() {
+                let total = stats.entry(op).or_default();
+                total.calls += op_stats.calls;
+                total.errors += op_stats.errors;
+                total.total_time += op_stats.total_time;
+                total.max_time = total.max_time.max(op_stats.max_time);
+            }
+        }
+        stats
+    }
+
+    /// Accept the command `name` under `new_name` only, or disable it if `new_name` is empty.
+    ///
+    /// Names are the lowercase names sent on the wire, e.g. "remove" for `Request::Rm`.
+    /// Takes effect on connections accepted afterwards.
+    pub fn rename_command(&mut self, name: &str, new_name: &str) {
+        let commands = Arc::make_mut(&mut self.commands);
+        commands.hidden.insert(name.to_lowercase());
+        if !new_name.is_empty() {
+            commands
+                .renamed
+                .insert(new_name.to_lowercase(), name.to_lowercase());
+        }
+    }
+
+    /// Current settings of the server, CONFIG SET changes them while it runs.
+    pub fn config(&self) -> ServerConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Limit the size of requests, a client sending a larger request is disconnected.
+    pub fn set_frame_limits(&mut self, limits: FrameLimits) {
+        self.config.write().unwrap().limits = limits;
+    }
+
+    /// Set when connections that are slow to take their responses are evicted.
+    pub fn set_slow_client_policy(&mut self, policy: SlowClientPolicy) {
+        self.config.write().unwrap().slow_clients = policy;
+    }
+
+    /// Reject writes of values longer than `size` bytes, `None`, the default, accepts any value.
+    pub fn set_max_value_size(&mut self, size: Option<usize>) {
+        self.config.write().unwrap().max_value_size = size;
+    }
+
+    /// Constrain the keys of requests, by default any key is accepted as is.
+    ///
+    /// Writes breaking a rule are rejected with an INVALIDKEY error, except for removals,
+    /// so that keys stored before the rules changed can be cleaned up.
+    pub fn set_key_rules(&mut self, rules: KeyRules) {
+        self.config.write().unwrap().key_rules = rules;
+    }
+
+    /// Lengthen the timeouts set by EXPIRE by a random amount of up to `percent` percent.
+    ///
+    /// Keys set with the same timeout then expire spread over time instead of all at once.
+    /// 0, the default, disables the jitter.
+    pub fn set_ttl_jitter(&mut self, percent: u32) {
+        self.config.write().unwrap().ttl_jitter = percent;
+    }
+
+    /// Publish changes to keys on their "__keyspace__:KEY" channel, as "set", "del" or "expired".
+    ///
+    /// Keys are only reported as expired once purged, see `add_expire_sweep`.
+    pub fn set_notify_keyspace_events(&mut self, enabled: bool) {
+        self.config.write().unwrap().notify_keyspace_events = enabled;
+    }
+
+    /// Group writes arriving within `window` of each other into one batch, made durable
+    /// with a single sync of the engine before any of them is acknowledged.
+    ///
+    /// Writes wait for up to `window` longer, but the cost of a sync is shared by the whole batch.
+    /// With `None`, the default, writes are executed right away and not synced.
+    pub fn set_write_batch_window(&mut self, window: Option<Duration>) {
+        self.write_batch_window = window;
+    }
+
+    /// Record the requests received by the server to a capture file at `path`, replacing
+    /// any file there, see `CaptureReader`.
+    ///
+    /// Requests are recorded as received, with the time and the connection they came on,
+    /// so that `kvs-admin replay` can send them to another server at the same pace.
+    pub fn capture_to(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.capture = Some(Arc::new(CaptureWriter::create(path)?));
+        Ok(())
+    }
+
+    /// Keep the requests the server starts serving in `recent`, which holds the last ones.
+    ///
+    /// Only their command and first key are kept, in memory, e.g. for a panic hook to
+    /// tell what the server was doing when it crashed.
+    pub fn keep_recent_requests(&mut self, recent: RecentRequests) {
+        self.recent = Some(recent);
+    }
+
+    /// Longest `start_server` waits, once shut down, for its connections to answer the
+    /// requests they are serving and close. 5 seconds by default.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Require connections to authenticate with AUTH before any other command, checking
+    /// their credentials with `provider`, see `AuthProvider`.
+    ///
+    /// Connections can also get a token with AUTH ROTATE and authenticate with it
+    /// afterwards, until it expires, see `set_token_ttl`, or is revoked with AUTH REVOKE.
+    ///
+    /// Takes effect on connections accepted afterwards.
+    pub fn set_auth_provider(&mut self, provider: impl AuthProvider + 'static) {
+        self.auth = Some(Arc::new(provider));
+    }
+
+    /// Make the tokens of AUTH ROTATE valid for `ttl`, an hour by default.
+    pub fn set_token_ttl(&mut self, ttl: Duration) {
+        self.config.write().unwrap().token_ttl = ttl;
+    }
+
+    /// Let an UPGRADE request replace this server process by a new one without refusing
+    /// connections: `handler` starts the new process and hands it the listening socket,
+    /// then this server shuts down like on SHUTDOWN.
+    ///
+    /// The new process accepts the connections from the socket once it has opened the
+    /// databases, i.e. once this one has released them; connections wait in the socket's
+    /// backlog meanwhile. Clients of this one reconnect to it as their connection closes.
+    ///
+    /// An error of `handler` fails the UPGRADE, the server goes on serving.
+    pub fn set_upgrade_handler(
+        &mut self,
+        handler: impl Fn(&TcpListener) -> Result<()> + Send + Sync + 'static,
+    ) {
+        self.upgrade = Some(Arc::new(handler));
+    }
+
+    /// A handle to stop the server from another thread, like a SHUTDOWN request does.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            state: Arc::clone(&self.shutdown),
+        }
+    }
+
+    /// Serve connections on `addr` until the server is shut down, see `ShutdownHandle`.
+    pub fn start_server<A: ToSocketAddrs>(&mut self, addr: &A) -> Result<()> {
+        self.start_server_on(TcpListener::bind(addr)?)
+    }
+
+    /// Serve the connections of `listener` until the server is shut down, e.g. those of a
+    /// socket handed over by the server process being upgraded, see `set_upgrade_handler`.
+    pub fn start_server_on(&mut self, listener: TcpListener) -> Result<()> {
+        debug!("start server");
+        // Clients keep their connection open across requests,
+        // so every connection is served by its own thread.
+        self.shutdown.listening_on(&listener)?;
+        if self.shutdown.requested() {
+            return Ok(());
+        }
+        self.scheduler.start();
+        self.stats = Arc::new(ServerStats::new());
+
+        // Writes of all connections are batched by a single thread
+        let batcher = self.write_batch_window.map(|window| {
+            let (sender, receiver) = mpsc::channel();
+            let context = self.context(None);
+            thread::Builder::new()
+                .name("kvs-write-batcher".to_owned())
+                .spawn(move || context.run_write_batcher(window, receiver))
+                .map(|_| sender)
+        });
+        let batcher = batcher.transpose()?;
+
+        for stream in listener.incoming() {
+            // A shutdown wakes the server up with a connection of its own
+            if self.shutdown.requested() {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let context = self.context(batcher.clone());
+                    let connection = self.stats.total_connections.fetch_add(1, Ordering::Relaxed);
+                    self.stats.connected_clients.fetch_add(1, Ordering::Relaxed);
+                    thread::spawn(move || {
+                        if let Err(e) = context.handle_connection(stream, connection) {
+                            error!("Error on serving connection: {}", e);
+                        }
+                        context.locks.release_all(connection);
+                        context.clients.remove(connection);
+                        // Not counted anymore once its engines are released, for shutdown
+                        let stats = Arc::clone(&context.stats);
+                        drop(context);
+                        stats.connected_clients.fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+                Err(e) => error!("Connection failed: {}", e),
+            }
+        }
+        drop(listener);
+        self.shutdown.listener.lock().unwrap().take();
+
+        // Connections close once they answered the request they are serving
+        let deadline = Instant::now() + self.drain_timeout;
+        while self.stats.connected_clients.load(Ordering::Relaxed) > 0 && Instant::now() < deadline
+        {
+            thread::sleep(SUBSCRIBER_POLL_INTERVAL);
+        }
+        let open = self.stats.connected_clients.load(Ordering::Relaxed);
+        if open > 0 {
+            warn!("Shutting down with {} connections still open", open);
+        }
+        self.scheduler.shutdown();
+        debug!("end server");
+        Ok(())
+    }
+
+    fn context(&self, batcher: Option<Sender<PendingWrite>>) -> Context<E> {
+        Context {
+            engines: Arc::clone(&self.engines),
+            scheduler: self.scheduler.clone(),
+            commands: Arc::clone(&self.commands),
+            config: Arc::clone(&self.config),
+            batcher,
+            stats: Arc::clone(&self.stats),
+            watches: Arc::clone(&self.watches),
+            pubsub: Arc::clone(&self.pubsub),
+            write_seq: Arc::clone(&self.write_seq),
+            capture: self.capture.clone(),
+            recent: self.recent.clone(),
+            locks: Arc::clone(&self.locks),
+            clients: Arc::clone(&self.clients),
+            shutdown: Arc::clone(&self.shutdown),
+            auth: self.auth.clone(),
+            tokens: Arc::clone(&self.tokens),
+            upgrade: self.upgrade.clone(),
+        }
+    }
+}
+
+/// Stops a running server, see `KvsServer::shutdown_handle`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+impl ShutdownHandle {
+    /// Make the server stop accepting connections and close those it has once they
+    /// answered the request they are serving; requests they receive after are refused.
+    ///
+    /// `start_server` returns when they are closed, or when the drain timeout is over.
+    pub fn shutdown(&self) {
+        self.state.request();
+    }
+}
+
+/// Whether the server is shutting down, shared with its connections.
+#[derive(Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    /// Address the server listens on, once started
+    addr: Mutex<Option<SocketAddr>>,
+    /// The listening socket while the server is started, for an upgrade to hand over
+    listener: Mutex<Option<TcpListener>>,
+}
+
+impl ShutdownState {
+    fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    fn request(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Accepting a connection wakes the server up to see the request
+        if let Some(mut addr) = *self.addr.lock().unwrap() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            if let Err(e) = TcpStream::connect(addr) {
+                warn!("Failed to wake the server up for shutdown: {}", e);
+            }
+        }
+    }
+
+    fn listening_on(&self, listener: &TcpListener) -> io::Result<()> {
+        *self.addr.lock().unwrap() = Some(listener.local_addr()?);
+        *self.listener.lock().unwrap() = Some(listener.try_clone()?);
+        Ok(())
+    }
+}
+
+/// Renamed and disabled commands, by wire name.
+#[derive(Clone, Default)]
+struct CommandNames {
+    /// New name -> original name
+    renamed: HashMap<String, String>,
+    /// Original names that are no longer accepted
+    hidden: HashSet<String>,
+}
+
+impl CommandNames {
+    /// Replace the command name in `frame` with its original name.
+    ///
+    /// Returns the name sent by the client as error if the command is disabled.
+    fn resolve(&self, frame: &mut Frame) -> std::result::Result<(), String> {
+        if let Frame::Array(frames) = frame {
+            if let Some(Frame::BulkString(name)) = frames.first_mut() {
+                let sent = String::from_utf8_lossy(name).to_lowercase();
+                match self.original(&sent) {
+                    Some(original) => *name = original.into(),
+                    None => return Err(sent),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Original name of a command sent as `sent`, `None` if it is disabled.
+    fn original(&self, sent: &str) -> Option<String> {
+        let sent = sent.to_lowercase();
+        if let Some(original) = self.renamed.get(&sent) {
+            Some(original.clone())
+        } else if self.hidden.contains(&sent) {
+            None
+        } else {
+            Some(sent)
+        }
+    }
+
+    /// Name clients send a command as, `None` if it is disabled.
+    fn sent(&self, original: &str) -> Option<String> {
+        match self.renamed.iter().find(|(_, name)| *name == original) {
+            Some((sent, _)) => Some(sent.clone()),
+            None if self.hidden.contains(original) => None,
+            None => Some(original.to_owned()),
+        }
+    }
+}
+
+/// What a connection thread shares with the server.
+struct Context<E: KvsEngine> {
+    engines: Arc<Vec<SharedEngine<E>>>,
+    scheduler: Scheduler,
+    commands: Arc<CommandNames>,
+    config: Arc<RwLock<ServerConfig>>,
+    batcher: Option<Sender<PendingWrite>>,
+    stats: Arc<ServerStats>,
+    watches: Arc<WatchRegistry>,
+    pubsub: Arc<PubSub>,
+    write_seq: Arc<AtomicU64>,
+    capture: Option<Arc<CaptureWriter>>,
+    recent: Option<RecentRequests>,
+    locks: Arc<KeyLocks>,
+    clients: Arc<ClientRegistry>,
+    shutdown: Arc<ShutdownState>,
+    auth: Option<Arc<dyn AuthProvider>>,
+    tokens: Arc<TokenStore>,
+    upgrade: Option<UpgradeHandler>,
+}
+
+/// A connection of the server.
+type Connection = Codec<TcpStream, TcpStream>;
+
+impl<E: KvsEngine> Context<E> {
+    /// Serve requests on `stream` until the client closes the connection.
+    ///
+    /// `connection` numbers the connection within the server's run.
+    fn handle_connection(&self, stream: TcpStream, connection: u64) -> Result<()> {
+        let peer_addr = stream.peer_addr()?;
+        debug!("Connection from {}", peer_addr);
+        self.clients.add(connection, peer_addr);
+
+        let write_timeout = self.config().slow_clients.write_timeout;
+        stream.set_write_timeout(Some(write_timeout))?;
+        let mut codec = Codec::from_stream(stream)?;
+        let mut writes = WriteTracker {
+            write_timeout,
+            window_start: Instant::now(),
+            blocked: Duration::ZERO,
+        };
+        let mut session = Session {
+            connection,
+            ..Session::default()
+        };
+        let mut polling = false;
+        codec
+            .stream()
+            .set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+
+        loop {
+            if self.shutdown.requested() {
+                return self.close_for_shutdown(&mut codec, &mut writes, &session, peer_addr);
+            }
+            // Messages are delivered between requests, so a subscribed connection
+            // stops waiting for its next request every now and then
+            if let Some(subscription) = &session.subscription {
+                while let Some(message) = subscription.try_recv() {
+                    let response = Response::from(message);
+                    if !self.send(
+                        &mut codec,
+                        &mut writes,
+                        session.protocol,
+                        response,
+                        peer_addr,
+                    )? {
+                        return Ok(());
+                    }
+                }
+            }
+            if polling != session.subscription.is_some() {
+                polling = !polling;
+                let timeout = if polling {
+                    SUBSCRIBER_POLL_INTERVAL
+                } else {
+                    SHUTDOWN_POLL_INTERVAL
+                };
+                codec.stream().set_read_timeout(Some(timeout))?;
+            }
+
+            codec.set_limits(Some(self.config().limits));
+            let frame = match codec.read_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(Error::IO(e)) if is_timeout(&e) => continue,
+                Err(e @ Error::FrameRejected(_)) => {
+                    // The rest of the request can't be skipped reliably, so the connection is closed
+                    codec.write_response(Response::error(e.to_string()))?;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+            // Passwords are neither logged nor captured
+            if !is_auth(&frame) {
+                debug!("Parsed frame {:?}", frame);
+                if let Some(capture) = &self.capture {
+                    if let Err(e) = capture.record(connection, &frame) {
+                        warn!("Failed to capture a request: {}", e);
+                    }
+                }
+            }
+            self.stats.total_commands.fetch_add(1, Ordering::Relaxed);
+
+            // The reply to AUTH ROTATE is a token, so AUTH replies aren't logged either
+            let mut auth = false;
+            let responses = match self.parse_request(frame) {
+                Ok(request) => {
+                    info!("Request: {:?}", request);
+                    auth = matches!(request, Request::Auth(_));
+                    let name = request.name();
+                    let key = request.keys().first().map(|&key| key.to_owned());
+                    self.clients.start_request(connection, name, key.as_deref());
+                    if let Some(recent) = &self.recent {
+                        recent.record(connection, name, key.as_deref());
+                    }
+                    let responses = self.serve(request, &mut session);
+                    self.clients.record_request(connection, name, session.db);
+                    responses
+                }
+                Err(response) => vec![response],
+            };
+            for response in responses {
+                if !auth {
+                    debug!("Response: {:?}", response);
+                }
+                if let (Some(multi), Response::Error { .. }) = (&mut session.multi, &response) {
+                    multi.aborted = true;
+                }
+                if !self.send(
+                    &mut codec,
+                    &mut writes,
+                    session.protocol,
+                    response,
+                    peer_addr,
+                )? {
+                    return Ok(());
+                }
+            }
+        }
+
+        debug!("Connection from {} closed", peer_addr);
+        Ok(())
+    }
+
+    /// Close the connection as the server shuts down, telling subscribers why.
+    ///
+    /// Requests the client sent already are refused. The server closes its side first,
+    /// then waits for the client to close its own: closing with requests unread would
+    /// reset the connection, and the client could lose the responses before them.
+    fn close_for_shutdown(
+        &self,
+        codec: &mut Connection,
+        writes: &mut WriteTracker,
+        session: &Session,
+        peer_addr: SocketAddr,
+    ) -> Result<()> {
+        let shutting_down = || Response::error_with_code("SHUTDOWN", "in progress");
+        // Subscribers wait for messages rather than for responses
+        if session.subscription.is_some()
+            && !self.send(codec, writes, session.protocol, shutting_down(), peer_addr)?
+        {
+            return Ok(());
+        }
+        codec
+            .stream()
+            .set_read_timeout(Some(SUBSCRIBER_POLL_INTERVAL))?;
+        loop {
+            match codec.read_frame() {
+                Ok(Some(_)) => {
+                    if !self.send(codec, writes, session.protocol, shutting_down(), peer_addr)? {
+                        return Ok(());
+                    }
+                }
+                Ok(None) => return Ok(()),
+                Err(Error::IO(e)) if is_timeout(&e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        codec.stream().shutdown(net::Shutdown::Write)?;
+        let linger_until = Instant::now() + CLOSE_LINGER;
+        while Instant::now() < linger_until {
+            match codec.read_frame() {
+                Ok(Some(_)) => {}
+                Err(Error::IO(e)) if is_timeout(&e) => {}
+                Ok(None) | Err(_) => break,
+            }
+        }
+        debug!("Connection from {} closed for shutdown", peer_addr);
+        Ok(())
+    }
+
+    /// Write `response` in `protocol`, returns `false` if the connection was evicted for being slow.
+    fn send(
+        &self,
+        codec: &mut Connection,
+        writes: &mut WriteTracker,
+        protocol: Protocol,
+        response: Response,
+        peer_addr: SocketAddr,
+    ) -> Result<bool> {
+        let slow_clients = self.config().slow_clients;
+        if slow_clients.write_timeout != writes.write_timeout {
+            writes.write_timeout = slow_clients.write_timeout;
+            codec
+                .stream()
+                .set_write_timeout(Some(writes.write_timeout))?;
+        }
+        let start = Instant::now();
+        codec.set_protocol(protocol);
+        match codec.write_response(response) {
+            Err(Error::IO(e)) if is_timeout(&e) => {
+                warn!(
+                    "Evicting connection from {}: a response blocked for over {:?}",
+                    peer_addr, writes.write_timeout
+                );
+                return Ok(false);
+            }
+            result => result?,
+        }
+        if writes.record(&slow_clients, start.elapsed()) {
+            warn!(
+                "Evicting connection from {}: blocked on writes for {:?} within {:?}",
+                peer_addr, writes.blocked, slow_clients.window
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Resolve the command name of `frame` and check its arguments against the command table.
+    fn parse_request(&self, mut frame: Frame) -> std::result::Result<Request, Response> {
+        if let Err(name) = self.commands.resolve(&mut frame) {
+            return Err(Response::error(format!("unknown command '{}'", name)));
+        }
+        // Frames that aren't requests at all are left to the parser
+        if let Frame::Array(args) = &frame {
+            if let Some(Frame::BulkString(name)) = args.first() {
+                let name = String::from_utf8_lossy(name).to_lowercase();
+                match commands::lookup(&name) {
+                    None => return Err(Response::error(format!("unknown command '{}'", name))),
+                    Some(spec) if !spec.accepts(args.len()) => {
+                        return Err(Response::error(format!(
+                            "wrong number of arguments for '{}' command",
+                            name
+                        )))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Request::try_from(frame).map_err(|e| Response::error(e.to_string()))
+    }
+
+    /// Subscription requests have a reply for every channel, the other requests a single one.
+    ///
+    /// Only AUTH is served before the connection authenticated, if the server requires it.
+    fn serve(&self, request: Request, session: &mut Session) -> Vec<Response> {
+        match request {
+            Request::Auth(auth) => vec![self.authenticate(auth, session)],
+            _ if self.auth.is_some() && !self.is_authenticated(session) => {
+                vec![error_response(Error::AuthRequired)]
+            }
+            Request::Subscribe(_)
+            | Request::Psubscribe(_)
+            | Request::Unsubscribe(_)
+            | Request::Punsubscribe(_)
+                if session.multi.is_none() =>
+            {
+                self.subscribe(request, session)
+            }
+            // Replies couldn't be told from messages in RESP2
+            request if session.subscription.is_some() && session.protocol == Protocol::Resp2 => {
+                vec![Response::error(format!(
+                    "Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE are allowed in this context",
+                    request.name()
+                ))]
+            }
+            request => vec![self.handle_request(request, session)],
+        }
+    }
+
+    /// Every channel or pattern is confirmed with the number subscribed after it.
+    ///
+    /// Unsubscribing without names unsubscribes everything.
+    fn subscribe(&self, request: Request, session: &mut Session) -> Vec<Response> {
+        let kind = request.name();
+        let subscription = session
+            .subscription
+            .get_or_insert_with(|| Subscription::new(Arc::clone(&self.pubsub)));
+        let confirm = |name: Option<String>, count: usize| {
+            Response::Push(vec![
+                Response::bulk(kind),
+                name.map_or(Response::Nil, Response::bulk),
+                Response::Integer(count as i64),
+            ])
+        };
+
+        let mut responses = vec![];
+        match request {
+            Request::Subscribe(Subscribe { channels }) => {
+                for channel in channels {
+                    subscription.subscribe(channel.clone());
+                    responses.push(confirm(Some(channel), subscription.count()));
+                }
+            }
+            Request::Psubscribe(Psubscribe { patterns }) => {
+                for pattern in patterns {
+                    subscription.psubscribe(pattern.clone());
+                    responses.push(confirm(Some(pattern), subscription.count()));
+                }
+            }
+            Request::Unsubscribe(Unsubscribe { mut channels }) => {
+                if channels.is_empty() {
+                    channels = subscription.channels();
+                }
+                for channel in channels {
+                    subscription.unsubscribe(&channel);
+                    responses.push(confirm(Some(channel), subscription.count()));
+                }
+            }
+            Request::Punsubscribe(Punsubscribe { mut patterns }) => {
+                if patterns.is_empty() {
+                    patterns = subscription.patterns();
+                }
+                for pattern in patterns {
+                    subscription.punsubscribe(&pattern);
+                    responses.push(confirm(Some(pattern), subscription.count()));
+                }
+            }
+            _ => {}
+        }
+        // Unsubscribing with nothing subscribed is confirmed too
+        if responses.is_empty() {
+            responses.push(confirm(None, subscription.count()));
+        }
+        if subscription.count() == 0 {
+            session.subscription = None;
+        }
+        responses
+    }
+
+    /// Check the credentials of AUTH, tokens of AUTH ROTATE first then with the auth provider.
+    ///
+    /// A failed AUTH leaves the connection authenticated as it was.
+    fn authenticate(&self, auth: Auth, session: &mut Session) -> Response {
+        let provider = match &self.auth {
+            Some(provider) => provider,
+            None => return Response::error("AUTH called without any auth provider configured"),
+        };
+        let (username, password) = match auth {
+            Auth::Login { username, password } => (username, password),
+            Auth::Rotate | Auth::Revoke { .. } if !self.is_authenticated(session) => {
+                return error_response(Error::AuthRequired)
+            }
+            Auth::Rotate => return self.rotate_token(session),
+            Auth::Revoke { username } => {
+                return Response::Integer(self.tokens.revoke(&username) as i64)
+            }
+        };
+
+        if let Some(user) = self.tokens.user_of(&password) {
+            if username.as_ref().is_none_or(|username| *username == user) {
+                session.user = Some(user);
+                session.token = Some(password);
+                return Response::Ok;
+            }
+        }
+        let username = username.unwrap_or_else(|| DEFAULT_USER.to_owned());
+        match provider.authenticate(&username, &password) {
+            Ok(true) => {
+                session.user = Some(username);
+                session.token = None;
+                Response::Ok
+            }
+            Ok(false) => {
+                warn!(
+                    "Failed AUTH as {:?} on connection {}",
+                    username, session.connection
+                );
+                error_response(Error::WrongPass)
+            }
+            Err(e) => {
+                error!("Failed to check the credentials of {:?}: {}", username, e);
+                Response::error("credentials couldn't be checked")
+            }
+        }
+    }
+
+    /// Whether the connection authenticated, with a token that is still valid if it used one.
+    fn is_authenticated(&self, session: &mut Session) -> bool {
+        if let Some(token) = &session.token {
+            if self.tokens.user_of(token).is_none() {
+                session.user = None;
+                session.token = None;
+            }
+        }
+        session.user.is_some()
+    }
+
+    /// Issue a token of the connection's user, which it is authenticated with from now on.
+    ///
+    /// The token it used so far stays valid for `TOKEN_ROTATION_GRACE` at most, so other
+    /// connections of the same client can move to the new token meanwhile.
+    fn rotate_token(&self, session: &mut Session) -> Response {
+        let user = session
+            .user
+            .clone()
+            .expect("the connection is authenticated");
+        match self.tokens.issue(&user, self.config().token_ttl) {
+            Ok(token) => {
+                if let Some(old) = session.token.replace(token.clone()) {
+                    self.tokens.expire_within(&old, TOKEN_ROTATION_GRACE);
+                }
+                Response::bulk(token)
+            }
+            Err(e) => Response::error(e.to_string()),
+        }
+    }
+
+    /// Requests after MULTI are queued in the session until EXEC, the others are dispatched.
+    fn handle_request(&self, mut request: Request, session: &mut Session) -> Response {
+        let config = self.config();
+        if let Some(max_value_size) = config.max_value_size {
+            if request.values().any(|value| value.len() > max_value_size) {
+                return Response::error(format!(
+                    "value is longer than max-value-size ({} bytes)",
+                    max_value_size
+                ));
+            }
+        }
+        for key in request.keys_mut() {
+            config.key_rules.normalize(key);
+        }
+        // Keys already stored can still be read and removed
+        if request.spec().has_flag(CommandFlag::Write) && !matches!(request, Request::Rm(_)) {
+            if let Some(e) = request
+                .keys()
+                .into_iter()
+                .find_map(|key| config.key_rules.check(key).err())
+            {
+                return error_response(e);
+            }
+        }
+        let origin = Origin {
+            connection: session.connection,
+            priority: config
+                .command_priority(request.name())
+                .unwrap_or(session.priority),
+        };
+        let multi = &mut session.multi;
+        match (request, multi.is_some()) {
+            (Request::Multi, false) => {
+                *multi = Some(MultiState::default());
+                Response::Ok
+            }
+            (Request::Multi, true) => Response::error("MULTI calls can not be nested"),
+            (Request::Exec, true) => {
+                let MultiState { queued, aborted } = multi.take().unwrap();
+                let watch = session.watch.take();
+                if aborted {
+                    Response::error_with_code(
+                        "EXECABORT",
+                        "Transaction discarded because of previous errors",
+                    )
+                } else {
+                    self.execute_transaction(session.db, origin, queued, watch)
+                }
+            }
+            (Request::Discard, true) => {
+                *multi = None;
+                session.watch = None;
+                Response::Ok
+            }
+            (Request::Exec, false) => Response::error("EXEC without MULTI"),
+            (Request::Discard, false) => Response::error("DISCARD without MULTI"),
+            (request, true) if !request.spec().has_flag(CommandFlag::NoMulti) => {
+                multi.as_mut().unwrap().queued.push(request);
+                Response::bulk("QUEUED")
+            }
+            (Request::Watch(_), true) => Response::error("WATCH inside MULTI is not allowed"),
+            (_, true) => Response::error("command not allowed in MULTI"),
+            (Request::Watch(Watch { keys }), false) => {
+                let watch = session
+                    .watch
+                    .get_or_insert_with(|| WatchedKeys::new(Arc::clone(&self.watches)));
+                for key in keys {
+                    watch.add(session.db, key);
+                }
+                Response::Ok
+            }
+            (Request::Unwatch, false) => {
+                session.watch = None;
+                Response::Ok
+            }
+            (Request::Hello(Hello { protover, setname }), false) => {
+                if setname
+                    .as_deref()
+                    .is_some_and(|name| !is_valid_client_value(name))
+                {
+                    return invalid_client_value();
+                }
+                let response = hello(&mut session.protocol, protover);
+                if let (Some(name), false) = (setname, matches!(response, Response::Error { .. })) {
+                    self.clients.set_name(session.connection, name);
+                }
+                response
+            }
+            (Request::Client(client), false) => self.client(session.connection, client),
+            (Request::Lockkey(Lockkey { key, timeout }), false) => {
+                // Taken with the engine locked, so no write let through before is still running
+                let _engine = self.engine(session.db, origin.priority);
+                let timeout = Duration::from_millis(timeout);
+                let locked = self
+                    .locks
+                    .lock(session.db, key, session.connection, timeout);
+                Response::Integer(locked as i64)
+            }
+            (Request::Unlockkey(Unlockkey { key }), false) => {
+                let unlocked = self.locks.unlock(session.db, &key, session.connection);
+                Response::Integer(unlocked as i64)
+            }
+            (Request::Select(Select { index }), false) => {
+                if index >= self.engines.len() {
+                    return Response::error("DB index is out of range");
+                }
+                session.db = index;
+                Response::Ok
+            }
+            (Request::Priority(PriorityArgs { class }), false) => {
+                session.priority = class;
+                Response::Ok
+            }
+            (request, false) => self.dispatch(session.db, origin, request),
+        }
+    }
+
+    /// Execute the requests queued by a MULTI on a `Transaction`, the reply is their responses.
+    ///
+    /// The engine stays locked, so no other request sees the transaction half done.
+    /// Nothing is executed if a key of `watch` was written, the reply is then nil,
+    /// or if a key written is locked by another connection than that of `origin`.
+    fn execute_transaction(
+        &self,
+        db: usize,
+        origin: Origin,
+        requests: Vec<Request>,
+        watch: Option<WatchedKeys>,
+    ) -> Response {
+        let mut engine = self.engine(db, origin.priority);
+        if watch.is_some_and(|watch| watch.is_dirty()) {
+            return Response::Nil;
+        }
+        let written = requests
+            .iter()
+            .filter(|request| request.spec().has_flag(CommandFlag::Write))
+            .flat_map(Request::keys);
+        if self.locks.locked_by_other(db, written, origin.connection) {
+            return error_response(Error::KeyLocked);
+        }
+        for request in &requests {
+            self.touch(db, request);
+        }
+        let writes = requests
+            .iter()
+            .any(|request| request.spec().has_flag(CommandFlag::Write));
+        let mut transaction = Transaction::new(&mut *engine);
+        let responses = requests
+            .into_iter()
+            .map(|request| self.execute_on(&mut transaction, request))
+            .collect();
+        let mut result = transaction.commit();
+        // Batched writes are only acknowledged once synced, so are transactions then
+        if result.is_ok() && self.batcher.is_some() {
+            result = engine.sync();
+        }
+        match result {
+            Ok(()) if writes => self.acknowledge(Response::Array(responses)),
+            Ok(()) => Response::Array(responses),
+            Err(e) => Response::error(e.to_string()),
+        }
+    }
+
+    /// Writes go through the write batcher if there is one, everything else is executed right away.
+    fn dispatch(&self, db: usize, origin: Origin, request: Request) -> Response {
+        match &self.batcher {
+            // MIGRATE talks to another server, it would hold up the whole batch
+            Some(batcher)
+                if request.spec().has_flag(CommandFlag::Write)
+                    && !matches!(request, Request::Migrate(_)) =>
+            {
+                let (sender, receiver) = mpsc::channel();
+                if batcher.send((db, origin, request, sender)).is_err() {
+                    return Response::error("write batcher stopped");
+                }
+                receiver
+                    .recv()
+                    .unwrap_or_else(|_| Response::error("write batcher stopped"))
+            }
+            _ => self.execute(db, origin, request),
+        }
+    }
+
+    /// Execute writes in batches: a batch takes the writes arriving within `window`
+    /// of the first one, and its responses are sent once the engine has synced them.
+    fn run_write_batcher(&self, window: Duration, receiver: Receiver<PendingWrite>) {
+        while let Ok(first) = receiver.recv() {
+            let deadline = Instant::now() + window;
+            let mut batch = vec![first];
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                match receiver.recv_timeout(timeout) {
+                    Ok(write) => batch.push(write),
+                    Err(_) => break,
+                }
+            }
+            debug!("Executing a batch of {} writes", batch.len());
+
+            let mut responses: Vec<(usize, Response, Sender<Response>)> = batch
+                .into_iter()
+                .map(|(db, origin, request, sender)| {
+                    (db, self.execute(db, origin, request), sender)
+                })
+                .collect();
+            let dbs: BTreeSet<usize> = responses.iter().map(|(db, _, _)| *db).collect();
+            for db in dbs {
+                if let Err(e) = self.engine(db, Priority::Normal).sync() {
+                    error!("Failed to sync a batch of writes: {}", e);
+                    for (_, response, _) in responses.iter_mut().filter(|(d, _, _)| *d == db) {
+                        *response = Response::error(e.to_string());
+                    }
+                }
+            }
+            for (_, response, sender) in responses {
+                // The connection may be gone already
+                let _ = sender.send(response);
+            }
+        }
+    }
+
+    // cmd excutor
+    //
+    // Writes to keys locked by another connection than that of `origin` are rejected, as is
+    // FLUSHDB while another connection holds a lock in the database.
+    fn execute(&self, db: usize, origin: Origin, request: Request) -> Response {
+        let priority = origin.priority;
+        let result = match request {
+            Request::Migrate(migrate) => self.migrate(db, origin, migrate),
+            Request::Info(Info { section }) => self.info(db, priority, section.as_deref()),
+            Request::Config(Config::Get { pattern }) => Ok(self.config_get(db, priority, &pattern)),
+            Request::Config(Config::Set { name, value }) => {
+                self.config_set(priority, &name, &value)
+            }
+            Request::Tasks => Ok(self.tasks()),
+            Request::Currentops => Ok(Response::bulk(self.clients.current_ops())),
+            Request::Shutdown => {
+                self.shutdown.request();
+                Ok(Response::Ok)
+            }
+            Request::Upgrade => self.upgrade(),
+            Request::Command(CommandArgs { query }) => Ok(self.command(query)),
+            Request::Publish(Publish { channel, message }) => Ok(Response::Integer(
+                self.pubsub.publish(&channel, &message) as i64,
+            )),
+            request => {
+                let mut engine = self.engine(db, priority);
+                let write = request.spec().has_flag(CommandFlag::Write);
+                let locked = match &request {
+                    Request::Flushdb(_) => self.locks.held_by_other(db, origin.connection),
+                    request => {
+                        write
+                            && self
+                                .locks
+                                .locked_by_other(db, request.keys(), origin.connection)
+                    }
+                };
+                if locked {
+                    return error_response(Error::KeyLocked);
+                }
+                self.touch(db, &request);
+                let response = self.execute_on(&mut *engine, request);
+                return if write {
+                    self.acknowledge(response)
+                } else {
+                    response
+                };
+            }
+        };
+        result.unwrap_or_else(|e| Response::error(e.to_string()))
+    }
+
+    /// Give the response of a write the next sequence number, as the "seq" attribute.
+    ///
+    /// The engine has to be locked, so writes are numbered in the order they are applied.
+    /// Failed writes aren't numbered, numbers start over from 1 when the server restarts.
+    fn acknowledge(&self, response: Response) -> Response {
+        if let Response::Error { .. } = response {
+            return response;
+        }
+        let seq = self.write_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        response.with_attribute("seq", Response::Integer(seq as i64))
+    }
+
+    /// Mark the connections watching the keys written by `request`, the engine has to be locked.
+    fn touch(&self, db: usize, request: &Request) {
+        match request {
+            Request::Flushdb(_) => self.watches.touch_all(db),
+            request if request.spec().has_flag(CommandFlag::Write) => {
+                self.watches.touch(db, request.keys())
+            }
+            _ => {}
+        }
+    }
+
+    /// Execute a request working on the keys only, those without the `NoMulti` flag.
+    fn execute_on(&self, engine: &mut impl KvsEngine, request: Request) -> Response {
+        let result = match request {
+            Request::Set(Set { key, value }) => engine.set(key, value).map(|_| Response::Ok),
+            Request::Get(Get { key }) => set::get_string(engine, key)
+                .map(|value| value.map_or(Response::Nil, Response::bulk)),
+            Request::Setnx(Setnx { key, value }) => engine
+                .set_nx(key, value)
+                .map(|set| Response::Integer(set.into())),
+            Request::Getset(Getset { key, value }) => set::get_string(engine, key.clone())
+                .and_then(|_| engine.get_set(key, value))
+                .map(|value| value.map_or(Response::Nil, Response::bulk)),
+            Request::Rm(Remove { keys }) => remove(engine, keys),
+            Request::Exists(Exists { keys }) => exists(engine, keys),
+            Request::Keys(Keys { pattern }) => keys(engine, &pattern),
+            Request::Scan(scan) => self::scan(engine, scan),
+            Request::Mset(Mset { pairs }) => mset(engine, pairs),
+            Request::Append(Append { key, value }) => set::get_string(engine, key.clone())
+                .and_then(|_| engine.append(key, value))
+                .map(|len| Response::Integer(len as i64)),
+            Request::Expire(Expire { key, seconds }) => self.expire(engine, key, seconds),
+            Request::Ttl(Ttl { key }) => ttl(engine, &key),
+            Request::Persist(Persist { key }) => persist(engine, key),
+            Request::Rename(Rename { key, newkey }) => rename(engine, key, newkey, true),
+            Request::Renamenx(Renamenx { key, newkey }) => rename(engine, key, newkey, false),
+            Request::Sadd(Sadd { key, members }) => sadd(engine, key, members),
+            Request::Srem(Srem { key, members }) => srem(engine, key, members),
+            Request::Smembers(Smembers { key }) => set::members(engine, key).map(members_response),
+            Request::Sismember(Sismember { key, member }) => set::members(engine, key)
+                .map(|members| Response::Integer(members.contains(&member).into())),
+            Request::Scard(Scard { key }) => {
+                set::members(engine, key).map(|members| Response::Integer(members.len() as i64))
+            }
+            Request::Sunion(Sunion { keys }) => sunion(engine, keys),
+            Request::Sinter(Sinter { keys }) => sinter(engine, keys),
+            Request::Dump(Dump { key }) => dump(engine, key),
+            Request::Restore(restore) => self::restore(engine, restore),
+            Request::Dbsize => engine.len().map(|len| Response::Integer(len as i64)),
+            Request::Flushdb(_) => engine.clear().map(|_| Response::Ok),
+            Request::Compact => engine.compact().map(|_| Response::Ok),
+            request => Ok(Response::error(format!(
+                "{:?} can't be executed here",
+                request
+            ))),
+        };
+        result.unwrap_or_else(error_response)
+    }
+
+    fn engine(
+        &self,
+        db: usize,
+        priority: Priority,
+    ) -> PriorityGuard<'_, MeteredEngine<NotifyingEngine<E>>> {
+        self.engines[db].lock(priority)
+    }
+
+    fn config(&self) -> ServerConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// The engine stays locked during the transfer,
+    /// so the key can't change between reading it and removing it.
+    ///
+    /// A dry run stops before the transfer. With `verify`, a key read back from the
+    /// destination with another checksum is kept on the source and fails with VERIFYFAILED.
+    /// Unless it is only copied, a key locked by another connection than that of `origin`
+    /// isn't moved.
+    fn migrate(&self, db: usize, origin: Origin, migrate: Migrate) -> Result<Response> {
+        let Migrate {
+            host,
+            port,
+            key,
+            timeout,
+            copy,
+            replace,
+            dry_run,
+            verify,
+        } = migrate;
+        let mut engine = self.engine(db, origin.priority);
+        if !copy
+            && self
+                .locks
+                .locked_by_other(db, [key.as_str()], origin.connection)
+        {
+            return Ok(error_response(Error::KeyLocked));
+        }
+        let (value, expires_at) = match (engine.get(key.clone())?, engine.expiration(&key)?) {
+            (Some(value), Some(expires_at)) => (value, expires_at),
+            _ => return Ok(Response::bulk("NOKEY".to_owned())),
+        };
+        // RESTORE takes a relative TTL, a key about to expire still gets 1ms
+        let ttl = expires_at.map_or(0, |expires_at| {
+            let ttl = expires_at.duration_since(engine.now()).unwrap_or_default();
+            (ttl.as_millis() as u64).max(1)
+        });
+
+        let timeout = match timeout {
+            0 => MIGRATE_TIMEOUT,
+            timeout => Duration::from_millis(timeout),
+        };
+        let policy = RetryPolicy {
+            connect_timeout: timeout,
+            max_retries: 0,
+            ..RetryPolicy::default()
+        };
+        let mut dest = KvsClient::connect_with((host.as_str(), port), policy)?;
+        dest.set_io_timeout(Some(timeout))?;
+        if dry_run {
+            let exists = dest.exists(key.clone())?;
+            if exists && !replace {
+                return Ok(Response::error_with_code(
+                    "BUSYKEY",
+                    "Target key name already exists",
+                ));
+            }
+            let mut report = format!(
+                "{:?} would {} to {}:{}, {} byte(s)",
+                key,
+                if copy { "be copied" } else { "move" },
+                host,
+                port,
+                value.len()
+            );
+            if ttl > 0 {
+                report += &format!(", ttl {}ms", ttl);
+            }
+            if exists {
+                report += ", replacing the existing key";
+            }
+            return Ok(Response::bulk(report));
+        }
+        let checksum = crc32(value.as_bytes());
+        dest.restore(Restore {
+            key: key.clone(),
+            ttl,
+            blob: DumpPayload { value }.to_blob()?,
+            replace,
+        })?;
+        if verify {
+            let moved = match dest.dump(key.clone())? {
+                Some(blob) => Some(DumpPayload::from_blob(&blob)?.value),
+                None => None,
+            };
+            if moved.is_none_or(|value| crc32(value.as_bytes()) != checksum) {
+                return Ok(Response::error_with_code(
+                    "VERIFYFAILED",
+                    "The key read back from the destination doesn't match, it was kept",
+                ));
+            }
+        }
+
+        if !copy {
+            self.watches.touch(db, [key.as_str()]);
+            engine.remove(key)?;
+        }
+        Ok(Response::Ok)
+    }
+
+    /// A non-positive timeout removes the key right away.
+    fn expire(&self, engine: &mut impl KvsEngine, key: String, seconds: i64) -> Result<Response> {
+        let updated = if seconds > 0 {
+            let ttl = Duration::from_secs(seconds as u64);
+            let ttl_jitter = self.config().ttl_jitter;
+            let fraction = ttl_jitter as f64 / 100.0 * random_fraction();
+            let expires_at = Duration::try_from_secs_f64(ttl.as_secs_f64() * fraction)
+                .ok()
+                .and_then(|jitter| engine.now().checked_add(ttl)?.checked_add(jitter))
+                // Engines keep it in milliseconds since the UNIX epoch
+                .filter(|expires_at| {
+                    expires_at
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .is_ok_and(|since| u64::try_from(since.as_millis()).is_ok())
+                });
+            let Some(expires_at) = expires_at else {
+                return Ok(Response::error("invalid expire time in 'expire' command"));
+            };
+            engine.set_expiration(key, Some(expires_at))?
+        } else {
+            match engine.remove(key) {
+                Ok(()) => true,
+                Err(Error::KeyNotFound) => false,
+                Err(e) => return Err(e),
+            }
+        };
+        Ok(Response::Integer(updated.into()))
+    }
+
+    /// Sections of `name: value` lines, only the given section if there is one.
+    ///
+    /// Persistence and Engine are about the database `db`, Keyspace lists the non-empty ones.
+    fn info(&self, db: usize, priority: Priority, section: Option<&str>) -> Result<Response> {
+        // Counted before locking `db`, a connection only ever holds one engine lock
+        let mut keyspace = vec![];
+        for (index, engine) in self.engines.iter().enumerate() {
+            let keys = engine.lock(priority).len()?;
+            if keys > 0 {
+                keyspace.push((format!("db{}", index), format!("keys={}", keys)));
+            }
+        }
+        let engine = self.engine(db, priority);
+        let mut sections: Vec<(&str, Vec<(String, String)>)> = vec![];
+        sections.push((
+            "Server",
+            vec![
+                (
+                    "kvs_version".to_owned(),
+                    env!("CARGO_PKG_VERSION").to_owned(),
+                ),
+                (
+                    "uptime_in_seconds".to_owned(),
+                    self.stats.started_at.elapsed().as_secs().to_string(),
+                ),
+                ("engine".to_owned(), engine.name().to_owned()),
+                (
+                    "last_write_seq".to_owned(),
+                    self.write_seq.load(Ordering::Relaxed).to_string(),
+                ),
+            ],
+        ));
+        sections.push((
+            "Clients",
+            vec![
+                (
+                    "connected_clients".to_owned(),
+                    self.stats
+                        .connected_clients
+                        .load(Ordering::Relaxed)
+                        .to_string(),
+                ),
+                (
+                    "total_connections_received".to_owned(),
+                    self.stats
+                        .total_connections
+                        .load(Ordering::Relaxed)
+                        .to_string(),
+                ),
+                (
+                    "total_commands_processed".to_owned(),
+                    self.stats
+                        .total_commands
+                        .load(Ordering::Relaxed)
+                        .to_string(),
+                ),
+            ],
+        ));
+        sections.push((
+            "Persistence",
+            engine
+                .stats()?
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect(),
+        ));
+        sections.push((
+            "Engine",
+            engine
+                .op_stats()
+                .into_iter()
+                .map(|(op, stats)| {
+                    let value = format!(
+                        "calls={},errors={},mean_usec={},max_usec={}",
+                        stats.calls,
+                        stats.errors,
+                        stats.mean_time().as_micros(),
+                        stats.max_time.as_micros()
+                    );
+                    (format!("op_{}", op), value)
+                })
+                .collect(),
+        ));
+        sections.push(("Keyspace", keyspace));
+
+        let text: Vec<String> = sections
+            .into_iter()
+            .filter(|(name, _)| section.is_none_or(|section| name.eq_ignore_ascii_case(section)))
+            .map(|(name, fields)| {
+                let mut lines = vec![format!("# {}", name)];
+                lines.extend(fields.into_iter().map(|(k, v)| format!("{}:{}", k, v)));
+                lines.join("\r\n")
+            })
+            .collect();
+        Ok(Response::bulk(text.join("\r\n\r\n")))
+    }
+
+    /// Hand the listening socket over to the upgrade handler, then shut down.
+    fn upgrade(&self) -> Result<Response> {
+        let handler = self.upgrade.as_ref().ok_or(Error::UpgradeUnsupported)?;
+        // Held until shut down, so a concurrent UPGRADE can't start a second process
+        let listener = self.shutdown.listener.lock().unwrap();
+        let listener = match &*listener {
+            Some(listener) if !self.shutdown.requested() => listener,
+            _ => return Err(Error::ShuttingDown),
+        };
+        handler(listener)?;
+        self.shutdown.request();
+        Ok(Response::Ok)
+    }
+
+    /// Name/value pairs of the server and engine settings matching `pattern`.
+    fn config_get(&self, db: usize, priority: Priority, pattern: &str) -> Response {
+        let mut entries: Vec<(String, String)> = self
+            .config()
+            .entries()
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value))
+            .collect();
+        entries.extend(self.scheduler.config());
+        entries.extend(
+            self.engine(db, priority)
+                .config()
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value)),
+        );
+        Response::Map(
+            entries
+                .into_iter()
+                .filter(|(name, _)| glob_match(pattern, name))
+                .map(|(name, value)| (Response::bulk(name), Response::bulk(value)))
+                .collect(),
+        )
+    }
+
+    /// Settings of the server are looked up first, then those of the background jobs, then
+    /// those of the engine.
+    fn config_set(&self, priority: Priority, name: &str, value: &str) -> Result<Response> {
+        let name = name.to_ascii_lowercase();
+        // The config lock is released before locking the engine
+        let result = match self.config.write().unwrap().set(&name, value) {
+            Err(Error::UnknownConfig(_)) => self.scheduler.set_config(&name, value),
+            result => result,
+        };
+        match result {
+            Err(Error::UnknownConfig(_)) => {
+                for engine in self.engines.iter() {
+                    engine.lock(priority).set_config(&name, value)?;
+                }
+            }
+            result => result?,
+        }
+        info!("CONFIG SET {} {}", name, value);
+        Ok(Response::Ok)
+    }
+
+    /// Commands are described under the name clients send them as, disabled ones are left out.
+    fn command(&self, query: Option<CommandQuery>) -> Response {
+        let visible = COMMANDS
+            .iter()
+            .filter_map(|spec| Some((self.commands.sent(spec.name)?, spec)));
+        match query {
+            None => Response::Array(
+                visible
+                    .map(|(name, spec)| spec.to_response(&name))
+                    .collect(),
+            ),
+            Some(CommandQuery::Count) => Response::Integer(visible.count() as i64),
+            Some(CommandQuery::Info { names }) => Response::Array(
+                names
+                    .into_iter()
+                    .map(|name| {
+                        match self
+                            .commands
+                            .original(&name)
+                            .and_then(|name| commands::lookup(&name))
+                        {
+                            Some(spec) => spec.to_response(&name.to_lowercase()),
+                            None => Response::Nil,
+                        }
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// CLIENT subcommands, about connection `connection`.
+    fn client(&self, connection: u64, client: Client) -> Response {
+        match client {
+            Client::Setname { name } => {
+                if !is_valid_client_value(&name) {
+                    return invalid_client_value();
+                }
+                self.clients.set_name(connection, name);
+                Response::Ok
+            }
+            Client::Getname => match self.clients.name(connection) {
+                Some(name) => Response::bulk(name),
+                None => Response::Nil,
+            },
+            Client::Setinfo { attr, value } => {
+                let attr = attr.to_ascii_lowercase();
+                if !CLIENT_INFO_ATTRS.contains(&attr.as_str()) {
+                    return Response::error(format!("Unrecognized option '{}'", attr));
+                }
+                if !is_valid_client_value(&value) {
+                    return invalid_client_value();
+                }
+                self.clients.set_info(connection, &attr, value);
+                Response::Ok
+            }
+            Client::List => Response::bulk(self.clients.list()),
+        }
+    }
+
+    fn tasks(&self) -> Response {
+        let tasks: Vec<String> = self
+            .scheduler
+            .jobs()
+            .iter()
+            .map(JobStatus::to_string)
+            .collect();
+        Response::bulk(tasks.join("\n"))
+    }
+}
+
+/// Missing keys are skipped, the response is the number of keys removed.
+fn remove(engine: &mut impl KvsEngine, keys: Vec<String>) -> Result<Response> {
+    let mut removed = 0;
+    for key in keys {
+        match engine.remove(key) {
+            Ok(()) => removed += 1,
+            Err(Error::KeyNotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Response::Integer(removed))
+}
+
+fn exists(engine: &impl KvsEngine, keys: Vec<String>) -> Result<Response> {
+    let mut count = 0;
+    for key in keys {
+        if engine.contains_key(&key)? {
+            count += 1;
+        }
+    }
+    Ok(Response::Integer(count))
+}
+
+fn keys(engine: &impl KvsEngine, pattern: &str) -> Result<Response> {
+    let keys = engine.scan(None, usize::MAX)?;
+    Ok(Response::Array(
+        keys.into_iter()
+            .filter(|key| glob_match(pattern, key))
+            .map(Response::bulk)
+            .collect(),
+    ))
+}
+
+/// The reply is the next cursor and the keys of this page matching the pattern,
+/// the page may be empty even if the scan isn't done.
+fn scan(engine: &impl KvsEngine, scan: Scan) -> Result<Response> {
+    let (cursor, keys) = scan_keys(engine, scan)?;
+    Ok(Response::Array(vec![
+        Response::bulk(cursor),
+        Response::Array(keys.into_iter().map(Response::bulk).collect()),
+    ]))
+}
+
+/// The next cursor and the keys of a SCAN step, also used by `LocalClient`.
+///
+/// # Errors
+///
+/// It returns `Error::InvalidCursor` if the cursor wasn't returned by a previous step.
+pub(crate) fn scan_keys(engine: &impl KvsEngine, scan: Scan) -> Result<(String, Vec<String>)> {
+    let after = decode_cursor(&scan.cursor).ok_or(Error::InvalidCursor)?;
+    let count = scan.count.unwrap_or(DEFAULT_SCAN_COUNT).max(1);
+    let keys = engine.scan(after.as_deref(), count)?;
+
+    let cursor = match keys.last() {
+        Some(last) if keys.len() == count => encode_cursor(last),
+        _ => "0".to_owned(),
+    };
+    let keys = keys
+        .into_iter()
+        .filter(|key| scan.pattern.as_deref().is_none_or(|p| glob_match(p, key)))
+        .collect();
+    Ok((cursor, keys))
+}
+
+fn mset(engine: &mut impl KvsEngine, pairs: Vec<String>) -> Result<Response> {
+    let mut pairs = pairs.into_iter();
+    while let (Some(key), Some(value)) = (pairs.next(), pairs.next()) {
+        engine.set(key, value)?;
+    }
+    Ok(Response::Ok)
+}
+
+/// Remaining time to live in seconds, -1 if the key doesn't expire, -2 if it doesn't exist.
+fn ttl(engine: &impl KvsEngine, key: &str) -> Result<Response> {
+    let ttl = match engine.expiration(key)? {
+        Some(Some(expires_at)) => {
+            let ttl = expires_at.duration_since(engine.now()).unwrap_or_default();
+            ((ttl.as_millis() + 500) / 1000) as i64
+        }
+        Some(None) => -1,
+        None => -2,
+    };
+    Ok(Response::Integer(ttl))
+}
+
+fn persist(engine: &mut impl KvsEngine, key: String) -> Result<Response> {
+    let persisted = match engine.expiration(&key)? {
+        Some(Some(_)) => engine.set_expiration(key, None)?,
+        _ => false,
+    };
+    Ok(Response::Integer(persisted.into()))
+}
+
+fn sadd(engine: &mut impl KvsEngine, key: String, members: Vec<String>) -> Result<Response> {
+    let mut set = set::members(engine, key.clone())?;
+    let len = set.len();
+    set.extend(members);
+    let added = set.len() - len;
+    if added > 0 {
+        set::store(engine, key, &set)?;
+    }
+    Ok(Response::Integer(added as i64))
+}
+
+fn srem(engine: &mut impl KvsEngine, key: String, members: Vec<String>) -> Result<Response> {
+    let mut set = set::members(engine, key.clone())?;
+    let len = set.len();
+    for member in &members {
+        set.remove(member);
+    }
+    let removed = len - set.len();
+    if removed > 0 {
+        set::store(engine, key, &set)?;
+    }
+    Ok(Response::Integer(removed as i64))
+}
+
+fn sunion(engine: &mut impl KvsEngine, keys: Vec<String>) -> Result<Response> {
+    let mut union = Members::new();
+    for key in keys {
+        union.extend(set::members(engine, key)?);
+    }
+    Ok(members_response(union))
+}
+
+// Every key is checked for its type, even once the intersection is empty
+fn sinter(engine: &mut impl KvsEngine, keys: Vec<String>) -> Result<Response> {
+    let mut inter: Option<Members> = None;
+    for key in keys {
+        let members = set::members(engine, key)?;
+        inter = Some(match inter {
+            Some(inter) => inter.intersection(&members).cloned().collect(),
+            None => members,
+        });
+    }
+    Ok(members_response(inter.unwrap_or_default()))
+}
+
+/// Members are sorted, so replies are the same for the same set.
+fn members_response(members: Members) -> Response {
+    Response::Array(members.into_iter().map(Response::bulk).collect())
+}
+
+/// The error reply of a failed request, with the code clients match on where there is one.
+fn error_response(e: Error) -> Response {
+    match e {
+        Error::WrongType => Response::error_with_code("WRONGTYPE", e.to_string()),
+        Error::KeyLocked => Response::error_with_code("LOCKED", e.to_string()),
+        Error::AuthRequired => Response::error_with_code("NOAUTH", e.to_string()),
+        Error::WrongPass => Response::error_with_code("WRONGPASS", e.to_string()),
+        Error::InvalidKey(violation) => {
+            Response::error_with_code("INVALIDKEY", violation.to_string())
+        }
+        e => Response::error(e.to_string()),
+    }
+}
+
+/// RENAME replies OK, RENAMENX whether it renamed the key.
+fn rename(
+    engine: &mut impl KvsEngine,
+    key: String,
+    newkey: String,
+    replace: bool,
+) -> Result<Response> {
+    match engine.rename(key, newkey, replace) {
+        Ok(_) if replace => Ok(Response::Ok),
+        Ok(renamed) => Ok(Response::Integer(renamed.into())),
+        Err(Error::KeyNotFound) => Ok(Response::error("no such key")),
+        Err(e) => Err(e),
+    }
+}
+
+fn dump(engine: &mut impl KvsEngine, key: String) -> Result<Response> {
+    match engine.get(key)? {
+        Some(value) => Ok(Response::bulk(DumpPayload { value }.to_blob()?)),
+        None => Ok(Response::Nil),
+    }
+}
+
+fn restore(engine: &mut impl KvsEngine, restore: Restore) -> Result<Response> {
+    let Restore {
+        key,
+        ttl,
+        blob,
+        replace,
+    } = restore;
+    let payload = DumpPayload::from_blob(&blob)?;
+
+    if !replace && engine.contains_key(&key)? {
+        return Ok(Response::error_with_code(
+            "BUSYKEY",
+            "Target key name already exists",
+        ));
+    }
+    // One batch, so the key is never seen without its expiration time
+    let expires_at = (ttl > 0).then(|| engine.now() + Duration::from_millis(ttl));
+    let mut batch = WriteBatch::new();
+    batch.push(BatchOp::Set {
+        key: key.into_bytes(),
+        value: payload.value.into_bytes(),
+        expires_at,
+    });
+    engine.write_batch(batch)?;
+    Ok(Response::Ok)
+}
+
+fn invalid_client_value() -> Response {
+    Response::error(
+        "Client names and attributes cannot contain spaces, newlines or special characters",
+    )
+}
+
+/// Switch the protocol of the session, the reply describes the server.
+fn hello(protocol: &mut Protocol, protover: Option<u8>) -> Response {
+    match protover {
+        None => {}
+        Some(2) => *protocol = Protocol::Resp2,
+        Some(3) => *protocol = Protocol::Resp3,
+        Some(_) => return Response::error_with_code("NOPROTO", "unsupported protocol version"),
+    }
+    Response::Map(vec![
+        (Response::bulk("server"), Response::bulk("kvs")),
+        (
+            Response::bulk("version"),
+            Response::bulk(env!("CARGO_PKG_VERSION")),
+        ),
+        (
+            Response::bulk("proto"),
+            Response::Integer(protocol.version().into()),
+        ),
+    ])
+}
+
+/// Whether `frame` is an AUTH request, before the command name is resolved.
+fn is_auth(frame: &Frame) -> bool {
+    match frame {
+        Frame::Array(args) => matches!(
+            args.first(),
+            Some(Frame::BulkString(name)) if name.eq_ignore_ascii_case(b"auth")
+        ),
+        _ => false,
+    }
+}
+
+/// Whether an I/O error is a timeout of a socket.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// A SCAN cursor is the hex-encoded last key of the previous page, or "0" at the start and end.
+// "0" can't be mistaken for a key since hex encodings have an even length.
+fn encode_cursor(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns `Some(None)` for the start of the scan, `None` if the cursor is invalid.
+fn decode_cursor(cursor: &str) -> Option<Option<String>> {
+    if cursor == "0" {
+        return Some(None);
+    }
+    if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+        return None;
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok().map(Some)
+}
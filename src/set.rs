@@ -0,0 +1,60 @@
+use crate::{BatchOp, Error, KvsEngine, Result, WriteBatch};
+use std::collections::BTreeSet;
+
+// Engines only hold strings, a set is stored as a string with this tag ahead of its members
+const SET_TAG: &str = "\0set:";
+
+/// Members of a set, as stored in a value.
+pub(crate) type Members = BTreeSet<String>;
+
+/// The members of a set value, `None` if the value is a string.
+pub(crate) fn decode(value: &str) -> Option<Members> {
+    serde_json::from_str(value.strip_prefix(SET_TAG)?).ok()
+}
+
+pub(crate) fn encode(members: &Members) -> String {
+    let members = serde_json::to_string(members).expect("a set of strings serializes");
+    format!("{}{}", SET_TAG, members)
+}
+
+/// The string value of a key.
+///
+/// # Errors
+///
+/// It returns `Error::WrongType` if the key holds a set.
+pub(crate) fn get_string(engine: &mut impl KvsEngine, key: String) -> Result<Option<String>> {
+    match engine.get(key)? {
+        Some(value) if decode(&value).is_some() => Err(Error::WrongType),
+        value => Ok(value),
+    }
+}
+
+/// The members of the set at a key, empty if the key doesn't exist.
+///
+/// # Errors
+///
+/// It returns `Error::WrongType` if the key holds a string.
+pub(crate) fn members(engine: &mut impl KvsEngine, key: String) -> Result<Members> {
+    match engine.get(key)? {
+        Some(value) => decode(&value).ok_or(Error::WrongType),
+        None => Ok(Members::new()),
+    }
+}
+
+/// Store the members of the set at a key, keeping its expiration time.
+///
+/// The key is removed if there are no members left.
+pub(crate) fn store(engine: &mut impl KvsEngine, key: String, members: &Members) -> Result<()> {
+    let mut batch = WriteBatch::new();
+    if members.is_empty() {
+        batch.remove(key);
+    } else {
+        let expires_at = engine.expiration(&key)?.flatten();
+        batch.push(BatchOp::Set {
+            key: key.into_bytes(),
+            value: encode(members).into_bytes(),
+            expires_at,
+        });
+    }
+    engine.write_batch(batch)
+}
@@ -0,0 +1,231 @@
+use crate::checksum::crc32c;
+use crate::{BatchOp, Error, KvsApi, KvsClient, Result, RetryPolicy, Scan, WriteBatch};
+use log::warn;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// How a `ShardedClient` spreads keys over servers, see `KvsClient::sharded`.
+#[derive(Debug, Clone)]
+pub struct HashRingConfig {
+    /// Points of every server on the hash ring, more of them spread keys more evenly
+    pub virtual_nodes: u32,
+    /// How long a server is left alone after a request to it failed, requests for its
+    /// keys fail right away with `Error::ShardDown` meanwhile
+    pub down_time: Duration,
+    /// Policy of the connection to every server
+    pub retry_policy: RetryPolicy,
+}
+
+impl Default for HashRingConfig {
+    fn default() -> Self {
+        HashRingConfig {
+            virtual_nodes: 160,
+            down_time: Duration::from_secs(5),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Health of one server of a `ShardedClient`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShardStatus {
+    pub addr: String,
+    /// Whether requests are sent to the server, false while it is left alone after a failure
+    pub up: bool,
+    /// Requests that failed to reach the server
+    pub failures: u64,
+}
+
+/// A client spreading keys over independent servers by consistent hashing.
+///
+/// Every key belongs to one server: the first one clockwise of the key's hash on a ring
+/// holding `virtual_nodes` points of every server. Adding or removing a server only moves
+/// the keys of the ring arcs it gains or loses. Servers don't know about each other, there
+/// is no rebalancing or replication.
+///
+/// Connections are opened on first use. A server that can't be reached is marked down
+/// for `down_time`, its keys are unavailable meanwhile; those of other servers aren't
+/// affected.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use kvs::{HashRingConfig, KvsApi, KvsClient};
+///
+/// let addrs = ["127.0.0.1:7878", "127.0.0.1:7879"];
+/// let mut client = KvsClient::sharded(addrs, HashRingConfig::default());
+/// client.set("key".to_string(), "value".to_string()).unwrap();
+/// assert_eq!(client.get("key".to_string()).unwrap(), Some("value".to_string()));
+/// ```
+pub struct ShardedClient {
+    shards: Vec<Shard>,
+    /// Points of the servers on the ring, to their index in `shards`
+    ring: BTreeMap<u32, usize>,
+    config: HashRingConfig,
+}
+
+struct Shard {
+    addr: String,
+    client: Option<KvsClient>,
+    failures: u64,
+    down_until: Option<Instant>,
+}
+
+impl KvsClient {
+    /// A client spreading keys over the servers at `addrs`, see `ShardedClient`.
+    ///
+    /// Keys are placed by the address strings, so every client of the same servers has
+    /// to list them the same way, in any order.
+    pub fn sharded<A: Into<String>>(
+        addrs: impl IntoIterator<Item = A>,
+        config: HashRingConfig,
+    ) -> ShardedClient {
+        let shards: Vec<Shard> = addrs
+            .into_iter()
+            .map(|addr| Shard {
+                addr: addr.into(),
+                client: None,
+                failures: 0,
+                down_until: None,
+            })
+            .collect();
+        assert!(!shards.is_empty(), "a sharded client needs servers");
+
+        let mut ring = BTreeMap::new();
+        for (i, shard) in shards.iter().enumerate() {
+            for node in 0..config.virtual_nodes.max(1) {
+                ring.insert(crc32c(format!("{}#{}", shard.addr, node).as_bytes()), i);
+            }
+        }
+        ShardedClient {
+            shards,
+            ring,
+            config,
+        }
+    }
+}
+
+impl ShardedClient {
+    /// Address of the server `key` belongs to.
+    pub fn shard_of(&self, key: &str) -> &str {
+        &self.shards[self.shard_index(key.as_bytes())].addr
+    }
+
+    /// Health of every server, in the order they were given.
+    pub fn shard_status(&self) -> Vec<ShardStatus> {
+        let now = Instant::now();
+        self.shards
+            .iter()
+            .map(|shard| ShardStatus {
+                addr: shard.addr.clone(),
+                up: shard.down_until.is_none_or(|until| until <= now),
+                failures: shard.failures,
+            })
+            .collect()
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        let hash = crc32c(key);
+        let (_, &index) = self
+            .ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .expect("the ring has points of every server");
+        index
+    }
+
+    /// Run `request` on the client of shard `index`, connecting first if needed.
+    ///
+    /// An I/O error marks the server down.
+    fn on_shard<T>(
+        &mut self,
+        index: usize,
+        request: impl FnOnce(&mut KvsClient) -> Result<T>,
+    ) -> Result<T> {
+        let shard = &mut self.shards[index];
+        if shard.down_until.is_some_and(|until| until > Instant::now()) {
+            return Err(Error::ShardDown(shard.addr.clone()));
+        }
+        shard.down_until = None;
+
+        let result = match &mut shard.client {
+            Some(client) => request(client),
+            None => KvsClient::connect_with(&shard.addr, self.config.retry_policy.clone())
+                .and_then(|client| request(shard.client.insert(client))),
+        };
+        if let Err(Error::IO(e)) = &result {
+            warn!(
+                "Server {} is down for {:?}: {}",
+                shard.addr, self.config.down_time, e
+            );
+            shard.failures += 1;
+            shard.down_until = Some(Instant::now() + self.config.down_time);
+        }
+        result
+    }
+}
+
+impl KvsApi for ShardedClient {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let index = self.shard_index(key.as_bytes());
+        self.on_shard(index, |client| client.set(key, value))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let index = self.shard_index(key.as_bytes());
+        self.on_shard(index, |client| client.get(key))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let index = self.shard_index(key.as_bytes());
+        self.on_shard(index, |client| client.remove(key))
+    }
+
+    /// Servers are scanned one after the other, the cursor is "SHARD-CURSOR" in between.
+    fn scan(&mut self, scan: Scan) -> Result<(String, Vec<String>)> {
+        let (index, cursor) = match scan.cursor.as_str() {
+            "0" => (0, "0".to_owned()),
+            cursor => {
+                let (index, cursor) = cursor.split_once('-').ok_or(Error::InvalidCursor)?;
+                let index: usize = index.parse().map_err(|_| Error::InvalidCursor)?;
+                if index >= self.shards.len() {
+                    return Err(Error::InvalidCursor);
+                }
+                (index, cursor.to_owned())
+            }
+        };
+        let (next, keys) = self.on_shard(index, |client| {
+            client.scan(Scan {
+                cursor,
+                pattern: scan.pattern,
+                count: scan.count,
+            })
+        })?;
+        let next = match (next.as_str(), index + 1) {
+            ("0", next_index) if next_index == self.shards.len() => "0".to_owned(),
+            ("0", next_index) => format!("{}-0", next_index),
+            (next, _) => format!("{}-{}", index, next),
+        };
+        Ok((next, keys))
+    }
+
+    /// A batch is only atomic on one server, so all its keys must belong to the same one.
+    ///
+    /// # Errors
+    ///
+    /// It returns `Error::CrossShardBatch` if they don't.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut indexes = batch.ops().iter().map(|op| match op {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => self.shard_index(key),
+        });
+        let index = match indexes.next() {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        if indexes.any(|other| other != index) {
+            return Err(Error::CrossShardBatch);
+        }
+        self.on_shard(index, |client| client.write_batch(batch))
+    }
+}
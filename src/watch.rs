@@ -0,0 +1,89 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A key of a database.
+type DbKey = (usize, String);
+
+/// Connections watching keys, by database and key.
+///
+/// Writes touch their keys before they are executed, with the engine locked, so an EXEC
+/// checking its flag with the engine locked sees every write since the WATCH.
+/// Keys that expire aren't touched.
+#[derive(Default)]
+pub(crate) struct WatchRegistry {
+    watchers: Mutex<HashMap<DbKey, Vec<Arc<AtomicBool>>>>,
+}
+
+impl WatchRegistry {
+    /// Mark the connections watching any of `keys` of the database `db` as dirty.
+    pub(crate) fn touch<'a>(&self, db: usize, keys: impl IntoIterator<Item = &'a str>) {
+        let watchers = self.watchers.lock().unwrap();
+        if watchers.is_empty() {
+            return;
+        }
+        for key in keys {
+            for dirty in watchers.get(&(db, key.to_owned())).into_iter().flatten() {
+                dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Mark the connections watching keys of the database `db` as dirty, e.g. after FLUSHDB.
+    pub(crate) fn touch_all(&self, db: usize) {
+        let watchers = self.watchers.lock().unwrap();
+        let watched = watchers.iter().filter(|((key_db, _), _)| *key_db == db);
+        for dirty in watched.flat_map(|(_, dirty)| dirty) {
+            dirty.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Keys watched by a connection, they are unwatched when it is dropped.
+pub(crate) struct WatchedKeys {
+    registry: Arc<WatchRegistry>,
+    keys: HashSet<DbKey>,
+    dirty: Arc<AtomicBool>,
+}
+
+impl WatchedKeys {
+    pub(crate) fn new(registry: Arc<WatchRegistry>) -> Self {
+        WatchedKeys {
+            registry,
+            keys: HashSet::new(),
+            dirty: Arc::default(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, db: usize, key: String) {
+        let key = (db, key);
+        if self.keys.contains(&key) {
+            return;
+        }
+        let mut watchers = self.registry.watchers.lock().unwrap();
+        watchers
+            .entry(key.clone())
+            .or_default()
+            .push(Arc::clone(&self.dirty));
+        self.keys.insert(key);
+    }
+
+    /// Whether a watched key was written since it was watched.
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for WatchedKeys {
+    fn drop(&mut self) {
+        let mut watchers = self.registry.watchers.lock().unwrap();
+        for key in &self.keys {
+            if let Some(dirty) = watchers.get_mut(key) {
+                dirty.retain(|dirty| !Arc::ptr_eq(dirty, &self.dirty));
+                if dirty.is_empty() {
+                    watchers.remove(key);
+                }
+            }
+        }
+    }
+}
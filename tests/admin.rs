@@ -0,0 +1,211 @@
+use assert_cmd::prelude::*;
+use kvs::{CaptureReader, KvStore, KvsClient, KvsEngine, KvsServer, Result, RetryPolicy};
+use predicates::str::contains;
+use std::collections::HashSet;
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Serve a KvStore in a temporary directory on `addr` until the test process exits.
+fn start_server(addr: &'static str) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+    Ok(temp_dir)
+}
+
+// A kvs-server working directory holding the given keys.
+fn source_dir(pairs: &[(&str, &str)]) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("engine"), r#""KvStore""#)?;
+    let mut store = KvStore::open(temp_dir.path().join("kvstore"))?;
+    for (key, value) in pairs {
+        store.set(key.to_string(), value.to_string())?;
+    }
+    Ok(temp_dir)
+}
+
+#[test]
+fn copy_to_with_prefix_and_resume() -> Result<()> {
+    let addr = "127.0.0.1:4301";
+    let _dest_dir = start_server(addr)?;
+    let pairs: Vec<_> = (0..25)
+        .map(|i| (format!("user:{:02}", i), format!("value{}", i)))
+        .collect();
+    let mut pairs: Vec<_> = pairs
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    pairs.push(("order:1", "value"));
+    let source_dir = source_dir(&pairs)?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["copy-to", "--dest", addr, "--prefix", "user:"])
+        .args([
+            "--resume-after",
+            "user:09",
+            "--batch-size",
+            "4",
+            "--pipeline",
+            "2",
+        ])
+        .current_dir(&source_dir)
+        .assert()
+        .success()
+        .stdout(contains("Copied 15 key(s)"));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("user:09".to_owned())?, None);
+    assert_eq!(
+        client.get("user:10".to_owned())?,
+        Some("value10".to_owned())
+    );
+    assert_eq!(
+        client.get("user:24".to_owned())?,
+        Some("value24".to_owned())
+    );
+    assert_eq!(client.get("order:1".to_owned())?, None);
+
+    Ok(())
+}
+
+// A dry run reports the keys it would copy and writes nothing to the destination.
+#[test]
+fn copy_to_dry_run() -> Result<()> {
+    let addr = "127.0.0.1:4306";
+    let _dest_dir = start_server(addr)?;
+    let source_dir = source_dir(&[("key1", "value1"), ("key2", "value22")])?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["copy-to", "--dest", addr, "--dry-run"])
+        .current_dir(&source_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            r#"Would copy 2 key(s) (13 byte(s) of values) to 127.0.0.1:4306, from "key1" to "key2""#,
+        ));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// With --verify, every key is read back from the destination after it is copied.
+#[test]
+fn copy_to_verify() -> Result<()> {
+    let addr = "127.0.0.1:4307";
+    let _dest_dir = start_server(addr)?;
+    let source_dir = source_dir(&[("key1", "value1"), ("key2", "value2")])?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["copy-to", "--dest", addr, "--verify"])
+        .current_dir(&source_dir)
+        .assert()
+        .success()
+        .stdout(contains("Copied 2 key(s)"));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn compact_running_server() -> Result<()> {
+    let addr = "127.0.0.1:4305";
+    let _temp_dir = start_server(addr)?;
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..10 {
+        client.set("key".to_owned(), format!("value{}", i))?;
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["compact", "--addr", addr])
+        .assert()
+        .success();
+
+    assert_eq!(client.get("key".to_owned())?, Some("value9".to_owned()));
+    let info = client.info(Some("persistence".to_owned()))?;
+    assert!(info.contains("uncompacted_bytes:0"), "{}", info);
+
+    Ok(())
+}
+
+#[test]
+fn copy_to_unreachable_server() -> Result<()> {
+    let source_dir = source_dir(&[("key1", "value1")])?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["copy-to", "--dest", "127.0.0.1:4302"])
+        .current_dir(&source_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn replay_capture() -> Result<()> {
+    let source_addr = "127.0.0.1:4303";
+    let dest_addr = "127.0.0.1:4304";
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let capture = source_dir.path().join("capture.bin");
+    let mut server = KvsServer::new(KvStore::open(source_dir.path())?);
+    server.capture_to(&capture)?;
+    thread::spawn(move || server.start_server(&source_addr).unwrap());
+    let _dest_dir = start_server(dest_addr)?;
+
+    let policy = RetryPolicy {
+        connect_timeout: Duration::from_millis(200),
+        max_retries: 10,
+        base_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(100),
+    };
+    let mut client1 = KvsClient::connect_with(source_addr, policy.clone())?;
+    let mut client2 = KvsClient::connect_with(source_addr, policy)?;
+    client1.set("key1".to_owned(), "value1".to_owned())?;
+    client2.set("key2".to_owned(), "value2".to_owned())?;
+    client1.remove("key2".to_owned())?;
+    client2.set("key3".to_owned(), "value3".to_owned())?;
+
+    let requests = CaptureReader::open(&capture)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(requests.len(), 4);
+    assert!(requests.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    let connections: HashSet<_> = requests.iter().map(|request| request.connection).collect();
+    assert_eq!(connections.len(), 2);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["replay", "--dest", dest_addr, "--speed", "2x"])
+        .arg(&capture)
+        .assert()
+        .success()
+        .stdout(contains("Replayed 4 request(s) on 2 connection(s)"))
+        .stdout(contains("0 error reply(ies)"));
+
+    let mut client = KvsClient::connect(dest_addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    assert_eq!(client.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    let not_capture = source_dir.path().join("not-capture.bin");
+    fs::write(&not_capture, "*1\r\n$4\r\nPING\r\n")?;
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["replay", "--dest", dest_addr])
+        .arg(&not_capture)
+        .assert()
+        .failure()
+        .stderr(contains("Not a capture file"));
+
+    Ok(())
+}
@@ -0,0 +1,1928 @@
+use kvs::{
+    AuthProvider, BatchOp, Codec, CommandDoc, DumpPayload, Error, FrameLimits, Get, Getset,
+    HashRingConfig, Hello, KvStore, KvsApi, KvsClient, KvsClientPool, KvsEngine, KvsServer,
+    LocalClient, ManualClock, Migrate, Mset, Priority, RecentRequests, Remove, Request, Response,
+    Restore, Result, RetryPolicy, Scan, Set, SlowClientPolicy, StaticPassword, UserFile, Watch,
+    WriteBatch, COMMANDS,
+};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+// Keeps every message logged by the process, to check what the server doesn't log.
+struct CapturedLog(Mutex<String>);
+
+impl log::Log for CapturedLog {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let mut log = self.0.lock().unwrap();
+        log.push_str(&record.args().to_string());
+        log.push('\n');
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURED_LOG: CapturedLog = CapturedLog(Mutex::new(String::new()));
+
+fn fast_retry_policy(max_retries: u32) -> RetryPolicy {
+    RetryPolicy {
+        connect_timeout: Duration::from_millis(200),
+        max_retries,
+        base_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(100),
+    }
+}
+
+// Accept `drops` connections and close them right after reading a request,
+// then answer every request on the next connection with `reply`.
+fn flaky_server(addr: &str, drops: usize, reply: &'static [u8]) -> thread::JoinHandle<()> {
+    let listener = TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        let mut buf = [0; 1024];
+        for _ in 0..drops {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut buf);
+        }
+        let (mut stream, _) = listener.accept().unwrap();
+        while let Ok(n) = stream.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            stream.write_all(reply).unwrap();
+        }
+    })
+}
+
+// Serve a KvStore in a temporary directory on `addr` until the test process exits.
+fn start_server(addr: &'static str) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+    Ok(temp_dir)
+}
+
+#[test]
+fn client_get_set_remove() -> Result<()> {
+    let addr = "127.0.0.1:4101";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    client.remove("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn client_mset_and_pipeline() -> Result<()> {
+    let addr = "127.0.0.1:4109";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.mset(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ])?;
+
+    let responses = client.pipeline(vec![
+        Request::Mset(Mset::new([("key3".to_owned(), "value3".to_owned())])),
+        Request::Get(Get {
+            key: "key2".to_owned(),
+        }),
+        Request::Get(Get {
+            key: "key3".to_owned(),
+        }),
+    ])?;
+    assert!(matches!(
+        responses.as_slice(),
+        [Response::Ok, Response::Bulk(v2), Response::Bulk(v3)] if v2 == "value2" && v3 == "value3"
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn client_exists_and_remove_keys() -> Result<()> {
+    let addr = "127.0.0.1:4110";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    let keys = vec!["key1".to_owned(), "key1".to_owned(), "key3".to_owned()];
+    assert_eq!(client.count_existing(keys)?, 2);
+    let keys = vec!["key1".to_owned(), "key2".to_owned(), "key3".to_owned()];
+    assert_eq!(client.remove_keys(keys.clone())?, 2);
+    assert_eq!(client.remove_keys(keys)?, 0);
+    assert!(!client.exists("key1".to_owned())?);
+
+    Ok(())
+}
+
+#[test]
+fn client_migrate() -> Result<()> {
+    let (src_addr, dest_addr) = ("127.0.0.1:4111", "127.0.0.1:4112");
+    let _src_dir = start_server(src_addr)?;
+    let _dest_dir = start_server(dest_addr)?;
+    let migrate = |key: &str, copy, replace| Migrate {
+        host: "127.0.0.1".to_owned(),
+        port: 4112,
+        key: key.to_owned(),
+        timeout: 1000,
+        copy,
+        replace,
+        dry_run: false,
+        verify: false,
+    };
+
+    let mut src = KvsClient::connect_with(src_addr, fast_retry_policy(10))?;
+    let mut dest = KvsClient::connect_with(dest_addr, fast_retry_policy(10))?;
+    src.set("key1".to_owned(), "value1".to_owned())?;
+    src.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert!(src.migrate(migrate("key1", false, false))?);
+    assert_eq!(src.get("key1".to_owned())?, None);
+    assert_eq!(dest.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!src.migrate(migrate("key1", false, false))?);
+
+    src.expire("key2".to_owned(), 100)?;
+    assert!(src.migrate(migrate("key2", true, false))?);
+    assert_eq!(src.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(dest.ttl("key2".to_owned())?, 100);
+
+    src.set("key2".to_owned(), "value3".to_owned())?;
+    assert!(matches!(
+        src.migrate(migrate("key2", false, false)),
+        Err(Error::Server(msg)) if msg.starts_with("BUSYKEY")
+    ));
+    assert!(src.migrate(migrate("key2", false, true))?);
+    assert_eq!(dest.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    // A dry run checks the destination but moves nothing
+    src.set("key3".to_owned(), "value4".to_owned())?;
+    dest.set("key3".to_owned(), "value5".to_owned())?;
+    let dry_run = |key, replace| Migrate {
+        dry_run: true,
+        ..migrate(key, false, replace)
+    };
+    assert!(matches!(
+        src.migrate(dry_run("key3", false)),
+        Err(Error::Server(msg)) if msg.starts_with("BUSYKEY")
+    ));
+    let report = src.migrate_report(dry_run("key3", true))?.unwrap();
+    assert!(
+        report.starts_with("\"key3\" would move to 127.0.0.1:"),
+        "{}",
+        report
+    );
+    assert!(report.contains("6 byte(s)"), "{}", report);
+    assert!(report.contains("replacing the existing key"), "{}", report);
+    assert!(!src.migrate(dry_run("key4", true))?);
+    assert_eq!(src.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(dest.get("key3".to_owned())?, Some("value5".to_owned()));
+
+    let verified = Migrate {
+        verify: true,
+        ..migrate("key3", false, true)
+    };
+    assert!(src.migrate(verified)?);
+    assert_eq!(src.get("key3".to_owned())?, None);
+    assert_eq!(dest.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn client_dump_restore() -> Result<()> {
+    let (src_addr, dest_addr) = ("127.0.0.1:4113", "127.0.0.1:4114");
+    let _src_dir = start_server(src_addr)?;
+    let _dest_dir = start_server(dest_addr)?;
+    let restore = |blob: &str, replace| Restore {
+        key: "key1".to_owned(),
+        ttl: 0,
+        blob: blob.to_owned(),
+        replace,
+    };
+
+    let mut src = KvsClient::connect_with(src_addr, fast_retry_policy(10))?;
+    let mut dest = KvsClient::connect_with(dest_addr, fast_retry_policy(10))?;
+    assert_eq!(src.dump("key1".to_owned())?, None);
+    src.set("key1".to_owned(), "value1".to_owned())?;
+    let blob = src.dump("key1".to_owned())?.unwrap();
+    assert_eq!(
+        DumpPayload::from_blob(&blob)?,
+        DumpPayload {
+            value: "value1".to_owned()
+        }
+    );
+
+    dest.restore(restore(&blob, false))?;
+    assert_eq!(dest.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        dest.restore(restore(&blob, false)),
+        Err(Error::Server(msg)) if msg.starts_with("BUSYKEY")
+    ));
+    dest.restore(Restore {
+        ttl: 5000,
+        ..restore(&blob, true)
+    })?;
+    assert_eq!(dest.ttl("key1".to_owned())?, 5);
+
+    let corrupted = blob.replace("value1", "value2");
+    assert!(matches!(
+        dest.restore(restore(&corrupted, true)),
+        Err(Error::Server(msg)) if msg == Error::InvalidDump.to_string()
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn client_keys_and_scan() -> Result<()> {
+    let addr = "127.0.0.1:4115";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    for i in 0..20 {
+        client.set(format!("user:{:02}", i), "value".to_owned())?;
+        client.set(format!("order:{:02}", i), "value".to_owned())?;
+    }
+
+    let mut keys = client.keys("user:1*".to_owned())?;
+    keys.sort();
+    let expected: Vec<_> = (10..20).map(|i| format!("user:{:02}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let mut cursor = "0".to_owned();
+    let mut scanned = vec![];
+    loop {
+        let (next, keys) = client.scan(Scan {
+            cursor,
+            pattern: Some("user:*".to_owned()),
+            count: Some(7),
+        })?;
+        assert!(keys.len() <= 7);
+        scanned.extend(keys);
+        if next == "0" {
+            break;
+        }
+        cursor = next;
+    }
+    let expected: Vec<_> = (0..20).map(|i| format!("user:{:02}", i)).collect();
+    assert_eq!(scanned, expected);
+
+    assert!(matches!(
+        client.scan(Scan {
+            cursor: "xyz".to_owned(),
+            pattern: None,
+            count: None,
+        }),
+        Err(Error::Server(msg)) if msg == "invalid cursor"
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn renamed_and_disabled_commands() -> Result<()> {
+    let addr = "127.0.0.1:4116";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.rename_command("remove", "");
+    server.rename_command("get", "fetch");
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(Error::Server(msg)) if msg == "unknown command 'remove'"
+    ));
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(Error::Server(msg)) if msg == "unknown command 'get'"
+    ));
+
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$5\r\nfetch\r\n$4\r\nkey1\r\n")?;
+    let mut buf = [0; 64];
+    let n = stream.read(&mut buf)?;
+    assert_eq!(&buf[..n], b"$6\r\nvalue1\r\n");
+
+    // COMMAND shows the names clients have to use
+    let docs = client.command_info(vec!["fetch".to_owned(), "get".to_owned()])?;
+    assert_eq!(docs[0].as_ref().map(|doc| doc.name.as_str()), Some("fetch"));
+    assert_eq!(docs[1], None);
+    let names: Vec<String> = client.commands()?.into_iter().map(|doc| doc.name).collect();
+    assert!(names.contains(&"fetch".to_owned()));
+    assert!(!names.contains(&"get".to_owned()) && !names.contains(&"remove".to_owned()));
+    assert_eq!(client.command_count()?, COMMANDS.len() as u64 - 1);
+
+    Ok(())
+}
+
+#[test]
+fn client_command_info() -> Result<()> {
+    let addr = "127.0.0.1:4129";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    assert_eq!(client.command_count()?, COMMANDS.len() as u64);
+    let docs = client.command_info(vec!["MSET".to_owned(), "unknown".to_owned()])?;
+    assert_eq!(
+        docs,
+        [
+            Some(CommandDoc {
+                name: "mset".to_owned(),
+                arity: -3,
+                flags: vec!["write".to_owned()],
+                first_key: 1,
+                last_key: -1,
+                step: 2,
+            }),
+            None
+        ]
+    );
+
+    // The arity is checked before the request is parsed
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.write_all(b"*1\r\n$3\r\nget\r\n*1\r\n$4\r\nnope\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert_eq!(
+        reply,
+        "-ERR wrong number of arguments for 'get' command\r\n-ERR unknown command 'nope'\r\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn client_set_nx_and_get_set() -> Result<()> {
+    let addr = "127.0.0.1:4122";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    assert!(client.set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert!(!client.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(
+        client.get_set("key1".to_owned(), "value3".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        client.get_set("key2".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(client.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn client_flush_db() -> Result<()> {
+    let addr = "127.0.0.1:4124";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.db_size()?, 2);
+    client.flush_db()?;
+    assert_eq!(client.db_size()?, 0);
+    assert_eq!(client.keys("*".to_owned())?, Vec::<String>::new());
+
+    Ok(())
+}
+
+#[test]
+fn client_info() -> Result<()> {
+    let addr = "127.0.0.1:4125";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let info = client.info(None)?;
+    assert!(info.contains("# Server\r\n"));
+    assert!(info.contains("engine:kvs\r\n"));
+    assert!(info.contains("connected_clients:1\r\n"));
+    assert!(info.contains("# Persistence\r\nkeys:1\r\n"));
+    assert!(info.contains("uncompacted_bytes:"));
+    assert!(info.contains("op_set:calls=1,"));
+
+    let info = client.info(Some("CLIENTS".to_owned()))?;
+    assert!(info.starts_with("# Clients\r\n"));
+    assert!(!info.contains("# Server"));
+
+    Ok(())
+}
+
+#[test]
+fn client_config_get_set() -> Result<()> {
+    let addr = "127.0.0.1:4126";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    let config = client.config_get("*".to_owned())?;
+    assert!(config.contains(&("max-value-size".to_owned(), "0".to_owned())));
+    assert!(config.contains(&("compaction-ratio".to_owned(), "0.5".to_owned())));
+    assert_eq!(
+        client.config_get("client-*".to_owned())?,
+        [
+            ("client-write-timeout".to_owned(), "10000".to_owned()),
+            ("client-max-blocked".to_owned(), "5000".to_owned()),
+        ]
+    );
+
+    client.config_set("max-value-size".to_owned(), "5".to_owned())?;
+    client.set("key1".to_owned(), "short".to_owned())?;
+    assert!(matches!(
+        client.set("key1".to_owned(), "too long".to_owned()),
+        Err(Error::Server(_))
+    ));
+    assert_eq!(client.get("key1".to_owned())?, Some("short".to_owned()));
+
+    client.config_set("COMPACTION-RATIO".to_owned(), "0.25".to_owned())?;
+    assert_eq!(
+        client.config_get("compaction-ratio".to_owned())?,
+        [("compaction-ratio".to_owned(), "0.25".to_owned())]
+    );
+    assert!(matches!(
+        client.config_set("no-such-setting".to_owned(), "1".to_owned()),
+        Err(Error::Server(_))
+    ));
+    assert!(matches!(
+        client.config_set("ttl-jitter".to_owned(), "-1".to_owned()),
+        Err(Error::Server(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn client_transaction() -> Result<()> {
+    let addr = "127.0.0.1:4127";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let responses = client.transaction(vec![
+        Request::Get(Get {
+            key: "key1".to_owned(),
+        }),
+        Request::Getset(Getset {
+            key: "key1".to_owned(),
+            value: "value2".to_owned(),
+        }),
+        Request::Rm(Remove {
+            keys: vec!["key2".to_owned()],
+        }),
+        Request::Get(Get {
+            key: "key1".to_owned(),
+        }),
+    ])?;
+    assert!(matches!(
+        &responses[..],
+        [
+            Response::Bulk(v1),
+            Response::Bulk(v2),
+            Response::Integer(0),
+            Response::Bulk(v3)
+        ] if v1 == "value1" && v2 == "value1" && v3 == "value2"
+    ));
+
+    // A request that can't be queued aborts the transaction
+    let result = client.transaction(vec![
+        Request::Set(Set {
+            key: "key1".to_owned(),
+            value: "value3".to_owned(),
+        }),
+        Request::Tasks,
+    ]);
+    assert!(matches!(result, Err(Error::Server(e)) if e.starts_with("EXECABORT")));
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    let responses = client.pipeline(vec![
+        Request::Exec,
+        Request::Multi,
+        Request::Set(Set {
+            key: "key1".to_owned(),
+            value: "value3".to_owned(),
+        }),
+        Request::Discard,
+    ])?;
+    assert!(
+        matches!(&responses[0], Response::Error { code, msg } if code == "ERR" && msg == "EXEC without MULTI")
+    );
+    assert!(matches!(&responses[2], Response::Bulk(v) if v == "QUEUED"));
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn resp3_responses() -> Result<()> {
+    let addr = "127.0.0.1:4128";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+    // Makes sure the server is up
+    KvsClient::connect_with(addr, fast_retry_policy(10))?;
+
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$5\r\nhello\r\n$1\r\n3\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*3\r\n$6\r\nconfig\r\n$3\r\nget\r\n$14\r\nmax-value-size\r\n")?;
+    stream.write_all(b"*2\r\n$5\r\nhello\r\n$1\r\n4\r\n")?;
+    stream.write_all(b"*2\r\n$5\r\nhello\r\n$1\r\n2\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*3\r\n$6\r\nconfig\r\n$3\r\nget\r\n$14\r\nmax-value-size\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    let version = env!("CARGO_PKG_VERSION");
+    // RESP2 maps are flat arrays of twice the length
+    let hello = |header: &str, proto: u8| {
+        format!(
+            "{}\r\n$6\r\nserver\r\n$3\r\nkvs\r\n$7\r\nversion\r\n${}\r\n{}\r\n\
+             $5\r\nproto\r\n:{}\r\n",
+            header,
+            version.len(),
+            version,
+            proto
+        )
+    };
+    let expected = [
+        hello("%3", 3),
+        "_\r\n".to_owned(),
+        "%1\r\n$14\r\nmax-value-size\r\n$1\r\n0\r\n".to_owned(),
+        "-NOPROTO unsupported protocol version\r\n".to_owned(),
+        hello("*6", 2),
+        "$-1\r\n".to_owned(),
+        "*2\r\n$14\r\nmax-value-size\r\n$1\r\n0\r\n".to_owned(),
+    ];
+    assert_eq!(reply, expected.concat());
+
+    Ok(())
+}
+
+#[test]
+fn write_sequence_numbers() -> Result<()> {
+    let addr = "127.0.0.1:4132";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$5\r\nhello\r\n$1\r\n3\r\n")?;
+    stream.write_all(b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$1\r\na\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*3\r\n$6\r\nappend\r\n$3\r\nkey\r\n$1\r\nb\r\n")?;
+    stream.write_all(b"*3\r\n$6\r\nexpire\r\n$3\r\nkey\r\n$3\r\nbad\r\n")?;
+    stream.write_all(b"*1\r\n$5\r\nmulti\r\n")?;
+    stream.write_all(b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$1\r\nc\r\n")?;
+    stream.write_all(b"*1\r\n$4\r\nexec\r\n")?;
+    stream.write_all(b"*2\r\n$5\r\nhello\r\n$1\r\n2\r\n")?;
+    stream.write_all(b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$1\r\nd\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    let seq = |seq: u64| format!("|1\r\n$3\r\nseq\r\n:{}\r\n", seq);
+    let expected = [
+        seq(1),
+        "+OK\r\n$1\r\na\r\n".to_owned(),
+        seq(2),
+        ":2\r\n".to_owned(),
+        // Failed writes aren't numbered
+        "-ERR Cannot parse Frame into Request\r\n+OK\r\n$6\r\nQUEUED\r\n".to_owned(),
+        seq(3),
+        "*1\r\n+OK\r\n".to_owned(),
+    ];
+    // After the reply of HELLO
+    let start = reply.find('|').unwrap();
+    assert!(reply[start..].starts_with(&expected.concat()));
+    // RESP2 replies don't have attributes
+    assert!(reply.ends_with("$5\r\nproto\r\n:2\r\n+OK\r\n"));
+    assert!(client
+        .info(Some("server".to_owned()))?
+        .contains("last_write_seq:4"));
+
+    Ok(())
+}
+
+#[test]
+fn select_databases() -> Result<()> {
+    let addr = "127.0.0.1:4133";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path().join("db0"))?);
+    server.add_database(KvStore::open(temp_dir.path().join("db1"))?);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+
+    client.set("key".to_owned(), "zero".to_owned())?;
+    client.select(1)?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    client.set("key".to_owned(), "one".to_owned())?;
+    client.set("other".to_owned(), "one".to_owned())?;
+    assert_eq!(client.db_size()?, 2);
+    assert!(client
+        .info(Some("keyspace".to_owned()))?
+        .contains("db0:keys=1\r\ndb1:keys=2"));
+
+    client.flush_db()?;
+    assert_eq!(client.db_size()?, 0);
+    client.select(0)?;
+    assert_eq!(client.get("key".to_owned())?, Some("zero".to_owned()));
+
+    assert!(matches!(
+        client.select(2),
+        Err(Error::Server(msg)) if msg == "DB index is out of range"
+    ));
+    // The connection stays on the database it was on
+    assert_eq!(client.db_size()?, 1);
+
+    Ok(())
+}
+
+#[test]
+fn journaled_writes() -> Result<()> {
+    let addr = "127.0.0.1:4134";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let journal_dir = temp_dir.path().join("journal");
+
+    // Nothing listens on the address yet
+    let mut client = KvsClient::connect_journaled(addr, fast_retry_policy(0), &journal_dir)?;
+    client.set("a".to_owned(), "1".to_owned())?;
+    client.set("b".to_owned(), "2".to_owned())?;
+    client.remove("a".to_owned())?;
+    client.set("c".to_owned(), "3".to_owned())?;
+    assert_eq!(client.pending_writes(), 4);
+    drop(client);
+    let mut client = KvsClient::connect_journaled(addr, fast_retry_policy(0), &journal_dir)?;
+    assert_eq!(client.pending_writes(), 4);
+
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path().join("store"))?);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+    let mut other = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    // As if a replay had sent the first two writes before it was cut short
+    let id = std::fs::read_to_string(journal_dir.join("journal-id"))?;
+    other.set(format!("__journal__:{}", id), "2".to_owned())?;
+
+    assert_eq!(client.replay_journal()?, 2);
+    assert_eq!(client.pending_writes(), 0);
+    assert_eq!(other.get("b".to_owned())?, None);
+    assert_eq!(other.get("c".to_owned())?, Some("3".to_owned()));
+    // The marker is gone
+    assert_eq!(other.db_size()?, 1);
+
+    client.set("d".to_owned(), "4".to_owned())?;
+    assert_eq!(client.pending_writes(), 0);
+    assert_eq!(other.get("d".to_owned())?, Some("4".to_owned()));
+
+    Ok(())
+}
+
+// The same calls against a server and an embedded engine
+fn exercise_api(client: &mut impl KvsApi) -> Result<()> {
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    client.remove("key".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    assert!(matches!(
+        client.remove("key".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.push(BatchOp::Set {
+        key: b"key3".to_vec(),
+        value: b"value3".to_vec(),
+        expires_at: Some(SystemTime::now() + Duration::from_secs(100)),
+    });
+    batch.remove("key1".to_owned());
+    client.write_batch(batch)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    let mut cursor = "0".to_owned();
+    let mut scanned = vec![];
+    loop {
+        let (next, keys) = client.scan(Scan {
+            cursor,
+            pattern: Some("key*".to_owned()),
+            count: Some(1),
+        })?;
+        scanned.extend(keys);
+        if next == "0" {
+            break;
+        }
+        cursor = next;
+    }
+    assert_eq!(scanned, ["key2", "key3"]);
+    Ok(())
+}
+
+#[test]
+fn local_and_network_clients() -> Result<()> {
+    let addr = "127.0.0.1:4135";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path().join("server"))?);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+    exercise_api(&mut KvsClient::connect_with(addr, fast_retry_policy(10))?)?;
+
+    let mut local = LocalClient::new(KvStore::open(temp_dir.path().join("local"))?);
+    exercise_api(&mut local)?;
+    let mut other = local.clone();
+    thread::spawn(move || other.set("shared".to_owned(), "value".to_owned()))
+        .join()
+        .unwrap()?;
+    assert_eq!(local.get("shared".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Keys are spread over the servers, each key is on the server the ring gives it to.
+#[test]
+fn sharded_client() -> Result<()> {
+    let addrs = ["127.0.0.1:4144", "127.0.0.1:4145"];
+    let _temp_dirs = [start_server(addrs[0])?, start_server(addrs[1])?];
+    let config = HashRingConfig {
+        retry_policy: fast_retry_policy(10),
+        ..HashRingConfig::default()
+    };
+    let mut client = KvsClient::sharded(addrs, config.clone());
+
+    for i in 0..20 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let mut direct = [
+        KvsClient::connect_with(addrs[0], fast_retry_policy(10))?,
+        KvsClient::connect_with(addrs[1], fast_retry_policy(10))?,
+    ];
+    for i in 0..20 {
+        let key = format!("key{}", i);
+        let shard = addrs.iter().position(|&addr| addr == client.shard_of(&key));
+        let shard = shard.unwrap();
+        assert_eq!(direct[shard].get(key.clone())?, Some(format!("value{}", i)));
+        assert_eq!(direct[1 - shard].get(key.clone())?, None);
+        assert_eq!(client.get(key)?, Some(format!("value{}", i)));
+    }
+    assert!(direct[0].db_size()? > 0 && direct[1].db_size()? > 0);
+
+    // The order the servers are listed in doesn't matter
+    let reversed = KvsClient::sharded([addrs[1], addrs[0]], config.clone());
+    assert!((0..20).all(|i| {
+        let key = format!("key{}", i);
+        reversed.shard_of(&key) == client.shard_of(&key)
+    }));
+
+    // A scan goes through every server
+    let mut cursor = "0".to_owned();
+    let mut scanned = vec![];
+    loop {
+        let (next, keys) = client.scan(Scan {
+            cursor,
+            pattern: None,
+            count: Some(5),
+        })?;
+        scanned.extend(keys);
+        if next == "0" {
+            break;
+        }
+        cursor = next;
+    }
+    scanned.sort();
+    let mut expected: Vec<String> = (0..20).map(|i| format!("key{}", i)).collect();
+    expected.sort();
+    assert_eq!(scanned, expected);
+
+    // Batches are only atomic on one server
+    let (key1, key2) = (0..20)
+        .map(|i| format!("key{}", i))
+        .find_map(|key| {
+            let other = (0..20)
+                .map(|i| format!("key{}", i))
+                .find(|other| client.shard_of(other) != client.shard_of(&key))?;
+            Some((key, other))
+        })
+        .unwrap();
+    let mut batch = WriteBatch::new();
+    batch.remove(key1.clone());
+    batch.remove(key2);
+    assert!(matches!(
+        client.write_batch(batch),
+        Err(Error::CrossShardBatch)
+    ));
+    let mut batch = WriteBatch::new();
+    batch.set(key1.clone(), "batched".to_owned());
+    client.write_batch(batch)?;
+    assert_eq!(client.get(key1)?, Some("batched".to_owned()));
+
+    // A server that can't be reached is left alone for a while, the others still serve
+    let down = "127.0.0.1:4146";
+    let config = HashRingConfig {
+        retry_policy: fast_retry_policy(0),
+        down_time: Duration::from_secs(60),
+        ..config
+    };
+    let mut client = KvsClient::sharded([addrs[0], down], config);
+    let key_on = |client: &kvs::ShardedClient, addr: &str| {
+        (0..)
+            .map(|i| format!("key{}", i))
+            .find(|key| client.shard_of(key) == addr)
+            .unwrap()
+    };
+    let (up_key, down_key) = (key_on(&client, addrs[0]), key_on(&client, down));
+    assert!(matches!(client.get(down_key.clone()), Err(Error::IO(_))));
+    assert!(matches!(client.get(down_key), Err(Error::ShardDown(_))));
+    client.set(up_key.clone(), "value".to_owned())?;
+    assert_eq!(client.get(up_key)?, Some("value".to_owned()));
+    let status = client.shard_status();
+    assert!(status[0].up && status[0].failures == 0);
+    assert!(!status[1].up && status[1].failures == 1);
+
+    Ok(())
+}
+
+#[test]
+fn client_watch() -> Result<()> {
+    let addr = "127.0.0.1:4130";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    let mut other = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    let set = |value: &str| {
+        Request::Set(Set {
+            key: "key1".to_owned(),
+            value: value.to_owned(),
+        })
+    };
+
+    client.watch(vec!["key1".to_owned(), "key2".to_owned()])?;
+    other.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        client.transaction(vec![set("value2")]),
+        Err(Error::TransactionAborted)
+    ));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // The keys are unwatched by the transaction, aborted or not
+    other.set("key1".to_owned(), "value3".to_owned())?;
+    client.transaction(vec![set("value2")])?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    client.watch(vec!["key1".to_owned()])?;
+    other.set("key2".to_owned(), "value1".to_owned())?;
+    client.transaction(vec![set("value3")])?;
+
+    client.watch(vec!["key1".to_owned()])?;
+    client.unwatch()?;
+    other.set("key1".to_owned(), "value4".to_owned())?;
+    client.transaction(vec![set("value5")])?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value5".to_owned()));
+
+    client.watch(vec!["key3".to_owned()])?;
+    other.flush_db()?;
+    assert!(matches!(
+        client.transaction(vec![set("value6")]),
+        Err(Error::TransactionAborted)
+    ));
+
+    let responses = client.pipeline(vec![
+        Request::Multi,
+        Request::Watch(Watch {
+            keys: vec!["key1".to_owned()],
+        }),
+        Request::Discard,
+    ])?;
+    assert!(
+        matches!(&responses[1], Response::Error { msg, .. } if msg == "WATCH inside MULTI is not allowed")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn keyspace_notifications() -> Result<()> {
+    let addr = "127.0.0.1:4131";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_notify_keyspace_events(true);
+    server.add_expire_sweep(Duration::from_millis(20));
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set_io_timeout(Some(Duration::from_secs(5)))?;
+    let mut keyspace = client.psubscribe(vec!["__keyspace__:key*".to_owned()])?;
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set_io_timeout(Some(Duration::from_secs(5)))?;
+    let mut news = client.subscribe(vec!["news".to_owned()])?;
+
+    let mut other = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    other.set("key1".to_owned(), "value1".to_owned())?;
+    other.remove("key1".to_owned())?;
+    // Missing keys aren't reported
+    assert!(other.remove("key1".to_owned()).is_err());
+    other.set("other".to_owned(), "value1".to_owned())?;
+    let blob = other.dump("other".to_owned())?.unwrap();
+    other.restore(Restore {
+        key: "key2".to_owned(),
+        ttl: 50,
+        blob,
+        replace: false,
+    })?;
+
+    let mut events = vec![];
+    for _ in 0..4 {
+        let message = keyspace.next_message()?;
+        assert_eq!(message.pattern.as_deref(), Some("__keyspace__:key*"));
+        events.push(format!("{} {}", message.channel, message.payload));
+    }
+    assert_eq!(
+        events,
+        [
+            "__keyspace__:key1 set",
+            "__keyspace__:key1 del",
+            "__keyspace__:key2 set",
+            "__keyspace__:key2 expired",
+        ]
+    );
+    assert_eq!(other.get("key2".to_owned())?, None);
+
+    assert_eq!(other.publish("news".to_owned(), "hello".to_owned())?, 1);
+    assert_eq!(other.publish("nobody".to_owned(), "hello".to_owned())?, 0);
+    let message = news.next_message()?;
+    assert_eq!(
+        (
+            message.channel.as_str(),
+            message.pattern,
+            message.payload.as_str()
+        ),
+        ("news", None, "hello")
+    );
+
+    // Subscribed connections only take subscription commands in RESP2
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n*2\r\n$3\r\nget\r\n$4\r\nkey1\r\n*1\r\n$11\r\nunsubscribe\r\n")?;
+    let expected = "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n\
+        -ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE are allowed in this context\r\n\
+        *3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n";
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+
+    Ok(())
+}
+
+#[test]
+fn client_append() -> Result<()> {
+    let addr = "127.0.0.1:4120";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    assert_eq!(client.append("key1".to_owned(), "Hello".to_owned())?, 5);
+    assert_eq!(client.append("key1".to_owned(), " World".to_owned())?, 11);
+    assert_eq!(
+        client.get("key1".to_owned())?,
+        Some("Hello World".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn client_expire_ttl_persist() -> Result<()> {
+    let addr = "127.0.0.1:4117";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    assert_eq!(client.ttl("key1".to_owned())?, -2);
+    assert!(!client.expire("key1".to_owned(), 100)?);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.ttl("key1".to_owned())?, -1);
+    assert!(!client.persist("key1".to_owned())?);
+
+    assert!(client.expire("key1".to_owned(), 100)?);
+    assert_eq!(client.ttl("key1".to_owned())?, 100);
+    assert!(client.persist("key1".to_owned())?);
+    assert_eq!(client.ttl("key1".to_owned())?, -1);
+
+    assert!(client.expire("key1".to_owned(), 0)?);
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.ttl("key1".to_owned())?, -2);
+
+    Ok(())
+}
+
+#[test]
+fn client_rename() -> Result<()> {
+    let addr = "127.0.0.1:4136";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.expire("key1".to_owned(), 100)?);
+    client.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.ttl("key2".to_owned())?, 100);
+    assert!(matches!(
+        client.rename("key1".to_owned(), "key2".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    client.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(!client.rename_nx("key1".to_owned(), "key2".to_owned())?);
+    assert!(client.rename_nx("key1".to_owned(), "key3".to_owned())?);
+    assert_eq!(client.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Sets keep their expiration time, and string commands refuse them like set commands refuse strings.
+#[test]
+fn client_sets() -> Result<()> {
+    let addr = "127.0.0.1:4137";
+    let _temp_dir = start_server(addr)?;
+    let strings = |items: &[&str]| items.iter().map(|&s| s.to_owned()).collect::<Vec<_>>();
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    assert_eq!(
+        client.sadd("set1".to_owned(), strings(&["b", "a", "b"]))?,
+        2
+    );
+    assert_eq!(client.sadd("set1".to_owned(), strings(&["a", "c"]))?, 1);
+    assert_eq!(client.smembers("set1".to_owned())?, ["a", "b", "c"]);
+    assert!(client.sismember("set1".to_owned(), "c".to_owned())?);
+    assert!(!client.sismember("set1".to_owned(), "d".to_owned())?);
+    assert_eq!(client.scard("set1".to_owned())?, 3);
+    assert!(client.expire("set1".to_owned(), 100)?);
+    assert_eq!(client.srem("set1".to_owned(), strings(&["c", "d"]))?, 1);
+    assert_eq!(client.ttl("set1".to_owned())?, 100);
+
+    client.sadd("set2".to_owned(), strings(&["b", "d"]))?;
+    assert_eq!(
+        client.sunion(strings(&["set1", "set2", "missing"]))?,
+        ["a", "b", "d"]
+    );
+    assert_eq!(client.sinter(strings(&["set1", "set2"]))?, ["b"]);
+    assert!(client.sinter(strings(&["set1", "missing"]))?.is_empty());
+    assert!(client.smembers("missing".to_owned())?.is_empty());
+    assert_eq!(client.scard("missing".to_owned())?, 0);
+
+    assert_eq!(client.srem("set2".to_owned(), strings(&["b", "d"]))?, 2);
+    assert!(!client.exists("set2".to_owned())?);
+
+    client.set("string".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        client.sadd("string".to_owned(), strings(&["a"])),
+        Err(Error::WrongType)
+    ));
+    assert!(matches!(
+        client.sunion(strings(&["set1", "string"])),
+        Err(Error::WrongType)
+    ));
+    assert!(matches!(
+        client.get("set1".to_owned()),
+        Err(Error::WrongType)
+    ));
+    assert!(matches!(
+        client.append("set1".to_owned(), "value".to_owned()),
+        Err(Error::WrongType)
+    ));
+    assert_eq!(client.smembers("set1".to_owned())?, ["a", "b"]);
+
+    // SET replaces a set like any other value
+    client.set("set1".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("set1".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// TTLs count down on the server's clock, not on the wall clock.
+#[test]
+fn server_clock() -> Result<()> {
+    let addr = "127.0.0.1:4138";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    let clock = ManualClock::new(SystemTime::now());
+    server.set_clock(Arc::new(clock.clone()));
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.expire("key1".to_owned(), 100)?);
+    clock.advance(Duration::from_secs(40));
+    assert_eq!(client.ttl("key1".to_owned())?, 60);
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.ttl("key1".to_owned())?, -2);
+
+    Ok(())
+}
+
+// Writes of keys breaking the rules are rejected, keys stored before can still be removed.
+#[test]
+fn key_rules() -> Result<()> {
+    let addr = "127.0.0.1:4139";
+    let _temp_dir = start_server(addr)?;
+    let invalid_key = |result: Result<()>| matches!(result, Err(Error::Server(msg)) if msg.starts_with("INVALIDKEY"));
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("long-key:1".to_owned(), "value".to_owned())?;
+    client.config_set("max-key-len".to_owned(), "8".to_owned())?;
+    client.config_set("key-allowed-bytes".to_owned(), "48-57,97-122,58".to_owned())?;
+    client.config_set("case-insensitive-keys".to_owned(), "yes".to_owned())?;
+    assert_eq!(
+        client.config_get("key-allowed-bytes".to_owned())?,
+        [("key-allowed-bytes".to_owned(), "48-57,97-122,58".to_owned())]
+    );
+
+    client.set("User:1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("user:1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("USER:1".to_owned())?, Some("value1".to_owned()));
+    assert!(invalid_key(
+        client.set("user:1234".to_owned(), "value".to_owned())
+    ));
+    assert!(invalid_key(
+        client.set("user 1".to_owned(), "value".to_owned())
+    ));
+    assert!(invalid_key(
+        client.rename("user:1".to_owned(), "user_1".to_owned())
+    ));
+    assert!(!client.exists("user_1".to_owned())?);
+
+    assert_eq!(
+        client.get("long-key:1".to_owned())?,
+        Some("value".to_owned())
+    );
+    client.remove("long-key:1".to_owned())?;
+
+    client.config_set("key-allowed-bytes".to_owned(), "".to_owned())?;
+    client.set("user_1".to_owned(), "value".to_owned())?;
+    assert!(client
+        .config_set("key-allowed-bytes".to_owned(), "48-300".to_owned())
+        .is_err());
+
+    Ok(())
+}
+
+// A key locked by a connection can only be written by it until it is unlocked.
+#[test]
+fn key_locks() -> Result<()> {
+    let addr = "127.0.0.1:4140";
+    let _temp_dir = start_server(addr)?;
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    let mut other = KvsClient::connect(addr)?;
+    let minute = Duration::from_secs(60);
+
+    assert!(client.lock_key("key1".to_owned(), minute)?);
+    assert!(client.lock_key("key1".to_owned(), minute)?);
+    assert!(!other.lock_key("key1".to_owned(), minute)?);
+    assert!(!other.unlock_key("key1".to_owned())?);
+
+    assert!(matches!(
+        other.set("key1".to_owned(), "value1".to_owned()),
+        Err(Error::KeyLocked)
+    ));
+    assert!(matches!(
+        other.transaction(vec![
+            Request::Set(Set {
+                key: "key2".to_owned(),
+                value: "value1".to_owned(),
+            }),
+            Request::Rm(Remove {
+                keys: vec!["key1".to_owned()],
+            }),
+        ]),
+        Err(Error::KeyLocked)
+    ));
+    assert_eq!(other.get("key2".to_owned())?, None);
+    other.set("key2".to_owned(), "value2".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    // Neither moved away nor flushed
+    assert!(matches!(
+        other.migrate(Migrate {
+            host: "127.0.0.1".to_owned(),
+            port: 4141,
+            key: "key1".to_owned(),
+            timeout: 1000,
+            copy: false,
+            replace: false,
+            dry_run: false,
+            verify: false,
+        }),
+        Err(Error::KeyLocked)
+    ));
+    assert!(matches!(other.flush_db(), Err(Error::KeyLocked)));
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    assert!(client.unlock_key("key1".to_owned())?);
+    other.set("key1".to_owned(), "value2".to_owned())?;
+
+    // Locks expire after their timeout
+    assert!(other.lock_key("key1".to_owned(), Duration::from_millis(50))?);
+    thread::sleep(Duration::from_millis(100));
+    client.remove("key1".to_owned())?;
+
+    // and are released when their connection closes
+    assert!(other.lock_key("key2".to_owned(), minute)?);
+    drop(other);
+    thread::sleep(Duration::from_millis(200));
+    assert!(client.lock_key("key2".to_owned(), minute)?);
+
+    Ok(())
+}
+
+#[test]
+fn request_priorities() -> Result<()> {
+    let addr = "127.0.0.1:4141";
+    let _temp_dir = start_server(addr)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set_priority(Priority::Low)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set_priority(Priority::High)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    client.config_set("high-priority-commands".to_owned(), "GET info".to_owned())?;
+    client.config_set("low-priority-commands".to_owned(), "scan".to_owned())?;
+    assert_eq!(
+        client.config_get("*-priority-commands".to_owned())?,
+        [
+            ("high-priority-commands".to_owned(), "get info".to_owned()),
+            ("low-priority-commands".to_owned(), "scan".to_owned()),
+        ]
+    );
+    assert!(client
+        .config_set("low-priority-commands".to_owned(), "scan export".to_owned())
+        .is_err());
+    let scan = Scan {
+        cursor: "0".to_owned(),
+        pattern: None,
+        count: None,
+    };
+    assert_eq!(client.scan(scan)?.1, ["key1".to_owned()]);
+
+    Ok(())
+}
+
+// Connections are listed with the name and library their client gave.
+#[test]
+fn client_names() -> Result<()> {
+    let addr = "127.0.0.1:4143";
+    let _temp_dir = start_server(addr)?;
+    let mut client = KvsClient::connect(addr)?;
+    let mut other = KvsClient::connect(addr)?;
+
+    assert_eq!(client.client_name()?, None);
+    client.set_name("billing".to_owned())?;
+    assert_eq!(client.client_name()?, Some("billing".to_owned()));
+    assert!(client.set_name("two words".to_owned()).is_err());
+    client.set_client_info("lib-name".to_owned(), "kvs".to_owned())?;
+    client.set_client_info("LIB-VER".to_owned(), "0.1.0".to_owned())?;
+    assert!(client
+        .set_client_info("lib-color".to_owned(), "red".to_owned())
+        .is_err());
+
+    // The name can also be given at handshake
+    let hello = Request::Hello(Hello {
+        protover: Some(2),
+        setname: Some("search".to_owned()),
+    });
+    other.pipeline(vec![hello])?;
+    assert_eq!(other.client_name()?, Some("search".to_owned()));
+
+    client.get("key1".to_owned())?;
+    let list = client.client_list()?;
+    let lines: Vec<_> = list.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(" name=billing "));
+    assert!(lines[0].contains(" cmd=get "));
+    assert!(lines[0].ends_with(" lib-name=kvs lib-ver=0.1.0"));
+    assert!(lines[1].contains(" name=search "));
+    assert!(lines[1].contains(" cmd=client "));
+
+    // An empty name removes it
+    other.set_name(String::new())?;
+    assert_eq!(other.client_name()?, None);
+    drop(other);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.client_list()?.lines().count(), 1);
+
+    Ok(())
+}
+
+// CURRENTOPS lists the requests being served, such as a MIGRATE to a server that doesn't answer.
+#[test]
+fn current_ops() -> Result<()> {
+    let addr = "127.0.0.1:4147";
+    let _temp_dir = start_server(addr)?;
+    // Accepts connections but never answers
+    let _silent = TcpListener::bind("127.0.0.1:4148")?;
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    let mut other = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    other.set("key 1".to_owned(), "value1".to_owned())?;
+    let ops = client.current_ops()?;
+    assert_eq!(ops.lines().count(), 1);
+    assert!(ops.contains(" cmd=currentops key= "));
+
+    let migrate = thread::spawn(move || {
+        other.migrate(Migrate {
+            host: "127.0.0.1".to_owned(),
+            port: 4148,
+            key: "key 1".to_owned(),
+            timeout: 1000,
+            copy: false,
+            replace: false,
+            dry_run: false,
+            verify: false,
+        })
+    });
+    thread::sleep(Duration::from_millis(300));
+    let ops = client.current_ops()?;
+    let lines: Vec<_> = ops.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(r#" cmd=migrate key="key 1" elapsed="#));
+    let elapsed: u64 = lines[0].rsplit('=').next().unwrap().parse().unwrap();
+    assert!(elapsed >= 200);
+    assert!(migrate.join().unwrap().is_err());
+    assert_eq!(client.current_ops()?.lines().count(), 1);
+
+    Ok(())
+}
+
+// Only AUTH is accepted until the connection authenticated.
+#[test]
+fn authentication() -> Result<()> {
+    let addr = "127.0.0.1:4149";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_auth_provider(StaticPassword::new("secret"));
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(Error::AuthRequired)
+    ));
+    assert!(matches!(
+        client.auth(None, "wrong".to_owned()),
+        Err(Error::WrongPass)
+    ));
+    assert!(matches!(
+        client.auth(Some("alice".to_owned()), "secret".to_owned()),
+        Err(Error::WrongPass)
+    ));
+    client.auth(None, "secret".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    // A failed AUTH doesn't log the connection out
+    assert!(client.auth(None, "wrong".to_owned()).is_err());
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Servers without a provider reject AUTH
+    let addr = "127.0.0.1:4150";
+    let _temp_dir = start_server(addr)?;
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    assert!(client.auth(None, "secret".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Tokens of AUTH ROTATE stand for the password until they expire or are revoked.
+#[test]
+fn auth_tokens() -> Result<()> {
+    let addr = "127.0.0.1:4151";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_auth_provider(StaticPassword::new("secret"));
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    // Moves to the token it gets, like every connection rotating
+    let mut issuer = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    assert!(matches!(issuer.rotate_token(), Err(Error::AuthRequired)));
+    issuer.auth(None, "secret".to_owned())?;
+    let token = issuer.rotate_token()?;
+
+    let mut service = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    service.auth(None, token.clone())?;
+    service.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        service.auth(Some("alice".to_owned()), token.clone()),
+        Err(Error::WrongPass)
+    ));
+
+    // The old token stays valid for a while after a rotation
+    let rotated = service.rotate_token()?;
+    assert_ne!(rotated, token);
+    let mut other = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    other.auth(Some("default".to_owned()), token)?;
+    other.auth(None, rotated)?;
+
+    // Revoked tokens log their connections out, password ones stay
+    let mut admin = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    admin.auth(None, "secret".to_owned())?;
+    assert_eq!(admin.revoke_tokens("default".to_owned())?, 2);
+    assert!(matches!(issuer.tasks(), Err(Error::AuthRequired)));
+    assert!(matches!(
+        service.get("key1".to_owned()),
+        Err(Error::AuthRequired)
+    ));
+    assert!(matches!(
+        other.get("key1".to_owned()),
+        Err(Error::AuthRequired)
+    ));
+    assert_eq!(admin.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    admin.config_set("token-ttl".to_owned(), "100".to_owned())?;
+    service.auth(None, "secret".to_owned())?;
+    let token = service.rotate_token()?;
+    thread::sleep(Duration::from_millis(200));
+    assert!(matches!(
+        service.get("key1".to_owned()),
+        Err(Error::AuthRequired)
+    ));
+    assert!(matches!(service.auth(None, token), Err(Error::WrongPass)));
+
+    Ok(())
+}
+
+// Users are read from a file, blank lines and comments aside.
+#[test]
+fn user_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("users");
+    std::fs::write(
+        &path,
+        "# service accounts\n\nbilling  s3cret\nreports hunter2\n",
+    )?;
+    let users = UserFile::load(&path)?;
+    assert!(users.authenticate("billing", "s3cret")?);
+    assert!(users.authenticate("reports", "hunter2")?);
+    assert!(!users.authenticate("reports", "s3cret")?);
+    assert!(!users.authenticate("default", "s3cret")?);
+
+    std::fs::write(&path, "billing s3cret\nreports\n")?;
+    assert!(matches!(
+        UserFile::load(&path),
+        Err(Error::InvalidUserFile(_, 2))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4142";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_drain_timeout(Duration::from_secs(2));
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.start_server(&addr));
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let mut subscriber = KvsClient::connect(addr)?.subscribe(vec!["news".to_owned()])?;
+
+    // Pipelined after SHUTDOWN, GET is refused, then the server closes the connection
+    let mut codec = Codec::from_stream(TcpStream::connect(addr)?)?;
+    codec.write_request(Request::Shutdown)?;
+    codec.write_request(Request::Get(Get {
+        key: "key1".to_owned(),
+    }))?;
+    assert_eq!(codec.read_response()?, Some(Response::Ok));
+    assert!(matches!(
+        codec.read_response()?,
+        Some(Response::Error { code, .. }) if code == "SHUTDOWN"
+    ));
+    assert_eq!(codec.read_response()?, None);
+    drop(codec);
+
+    assert!(matches!(
+        subscriber.next_message(),
+        Err(Error::ShuttingDown)
+    ));
+    drop(subscriber);
+    drop(client);
+    server.join().unwrap()?;
+    assert!(TcpStream::connect(addr).is_err());
+    handle.shutdown();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.keys().eq([b"key1"]));
+    Ok(())
+}
+
+// UPGRADE hands the listening socket over to a new server, no connection is refused meanwhile.
+#[test]
+fn upgrade_handover() -> Result<()> {
+    let addr = "127.0.0.1:4152";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    server.set_upgrade_handler(move |listener: &TcpListener| {
+        sender.send(listener.try_clone()?).unwrap();
+        Ok(())
+    });
+    let old_server = thread::spawn(move || server.start_server(&addr));
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.upgrade()?;
+    let listener = receiver.recv().unwrap();
+    old_server.join().unwrap()?;
+
+    // Waits in the backlog until the new server accepts it
+    let mut queued = Codec::from_stream(TcpStream::connect(addr)?)?;
+    queued.write_request(Request::Get(Get {
+        key: "key1".to_owned(),
+    }))?;
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    let handle = server.shutdown_handle();
+    let new_server = thread::spawn(move || server.start_server_on(listener));
+    assert_eq!(queued.read_response()?, Some(Response::bulk("value1")));
+    // The connection to the old server was closed, the client reconnects
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(queued);
+    drop(client);
+
+    handle.shutdown();
+    new_server.join().unwrap()?;
+    // Unless a handler was set
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.start_server(&addr));
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    assert!(matches!(client.upgrade(), Err(Error::Server(_))));
+    handle.shutdown();
+    drop(client);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn ttl_jitter() -> Result<()> {
+    let addr = "127.0.0.1:4121";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_ttl_jitter(50);
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    let mut ttls = vec![];
+    for i in 0..20 {
+        let key = format!("key{}", i);
+        client.set(key.clone(), "value".to_owned())?;
+        assert!(client.expire(key.clone(), 1000)?);
+        ttls.push(client.ttl(key)?);
+    }
+    assert!(ttls.iter().all(|ttl| (1000..=1500).contains(ttl)));
+    assert!(ttls.iter().any(|&ttl| ttl != ttls[0]));
+    // Past the latest time that can be kept, the database is still usable after it
+    assert!(matches!(
+        client.expire("key0".to_owned(), i64::MAX),
+        Err(Error::Server(_))
+    ));
+    assert!((1000..=1500).contains(&client.ttl("key0".to_owned())?));
+    assert_eq!(client.get("key1".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Concurrent writes are batched, every one of them is acknowledged and applied.
+#[test]
+fn batched_writes() -> Result<()> {
+    let addr = "127.0.0.1:4123";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_write_batch_window(Some(Duration::from_micros(500)));
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let writers: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+                for j in 0..20 {
+                    client.set(format!("key{}-{}", i, j), format!("value{}", j))?;
+                }
+                assert!(!client.set_nx(format!("key{}-0", i), "other".to_owned())?);
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    for i in 0..8 {
+        for j in 0..20 {
+            assert_eq!(
+                client.get(format!("key{}-{}", i, j))?,
+                Some(format!("value{}", j))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn oversized_requests_are_rejected() -> Result<()> {
+    let addr = "127.0.0.1:4118";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_frame_limits(FrameLimits {
+        max_frame_size: 1024,
+        max_array_len: 4,
+        max_bulk_len: 16,
+    });
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), "a".repeat(16))?;
+    assert!(matches!(
+        client.set("key1".to_owned(), "a".repeat(17)),
+        Err(Error::Server(msg)) if msg == "Protocol error: invalid bulk length"
+    ));
+
+    // Only the headers are sent, the request is rejected without waiting for the data
+    let rejected = |request: &[u8]| -> Result<String> {
+        let mut stream = std::net::TcpStream::connect(addr)?;
+        stream.write_all(request)?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        Ok(reply)
+    };
+    assert_eq!(
+        rejected(b"*1000000\r\n")?,
+        "-ERR Protocol error: invalid multibulk length\r\n"
+    );
+    assert_eq!(
+        rejected(b"*3\r\n$3\r\nset\r\n$4\r\nkey1\r\n$1000000\r\n")?,
+        "-ERR Protocol error: invalid bulk length\r\n"
+    );
+    assert_eq!(
+        rejected(b"*3\r\n$3\r\nset\r\n$4\r\nkey1\r\n$99999999999999999999999999999999")?,
+        "-ERR Protocol error: invalid length\r\n"
+    );
+
+    Ok(())
+}
+
+// A client that stops reading its responses gets disconnected.
+#[test]
+fn slow_clients_are_evicted() -> Result<()> {
+    let addr = "127.0.0.1:4119";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_slow_client_policy(SlowClientPolicy {
+        write_timeout: Duration::from_millis(100),
+        ..SlowClientPolicy::default()
+    });
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let value = "a".repeat(1024 * 1024);
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.set("key1".to_owned(), value.clone())?;
+
+    // Far more than the socket buffers hold
+    let requests = 64;
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    for _ in 0..requests {
+        stream.write_all(b"*2\r\n$3\r\nget\r\n$4\r\nkey1\r\n")?;
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    // The connection is closed before all the responses are written
+    let mut received = 0;
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => received += n,
+        }
+    }
+    assert!(received < requests * value.len());
+
+    // Other clients are unaffected
+    assert_eq!(client.get("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Connecting should keep retrying until the server comes up.
+#[test]
+fn client_retries_connect() -> Result<()> {
+    let addr = "127.0.0.1:4102";
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        flaky_server(addr, 0, b"+OK\r\n").join().unwrap();
+    });
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(20))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    Ok(())
+}
+
+#[test]
+fn client_gives_up_connecting() {
+    let result = KvsClient::connect_with("127.0.0.1:4103", fast_retry_policy(2));
+    assert!(matches!(result, Err(Error::IO(_))));
+}
+
+// GET is retried on a new connection when the server drops the current one.
+#[test]
+fn client_retries_idempotent_request() -> Result<()> {
+    let addr = "127.0.0.1:4104";
+    let server = flaky_server(addr, 2, b"$6\r\nvalue1\r\n");
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(3))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    server.join().unwrap();
+    Ok(())
+}
+
+// SET is not retried since it may have been applied, but the next request reconnects.
+#[test]
+fn client_reconnects_after_failed_write() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    let server = flaky_server(addr, 1, b"+OK\r\n");
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(3))?;
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(Error::IO(_))
+    ));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(client);
+    server.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn pool_reuses_connections() -> Result<()> {
+    let addr = "127.0.0.1:4106";
+    let _temp_dir = start_server(addr)?;
+
+    let pool = KvsClientPool::with_policy(addr, 2, fast_retry_policy(10))?;
+    for i in 0..10 {
+        let mut client = pool.checkout()?;
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(pool.open_connections(), 1);
+
+    let mut client = pool.checkout()?;
+    assert_eq!(client.get("key9".to_owned())?, Some("value9".to_owned()));
+    Ok(())
+}
+
+// Threads share at most `size` connections.
+#[test]
+fn pool_limits_connections() -> Result<()> {
+    let addr = "127.0.0.1:4107";
+    let _temp_dir = start_server(addr)?;
+
+    let pool = KvsClientPool::with_policy(addr, 2, fast_retry_policy(10))?;
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..20 {
+                    let mut client = pool.checkout()?;
+                    assert!(pool.open_connections() <= 2);
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("{}", i))?;
+                    assert_eq!(client.get(key)?, Some(format!("{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert!(pool.open_connections() <= 2);
+
+    Ok(())
+}
+
+// Idle connections closed by the server are replaced on checkout.
+#[test]
+fn pool_replaces_closed_connections() -> Result<()> {
+    let addr = "127.0.0.1:4108";
+    let listener = TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        // Answer a single request per connection
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0; 1024];
+            if stream.read(&mut buf).unwrap() > 0 {
+                stream.write_all(b"+OK\r\n").unwrap();
+            }
+        }
+    });
+
+    let pool = KvsClientPool::with_policy(addr, 1, fast_retry_policy(3))?;
+    pool.checkout()?
+        .set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(100));
+    // SET isn't retried, so it only succeeds if the pool didn't hand out the closed connection
+    pool.checkout()?
+        .set("key1".to_owned(), "value1".to_owned())?;
+    assert!(pool.open_connections() <= 1);
+
+    Ok(())
+}
+
+// Neither the passwords nor the tokens of AUTH requests and replies are logged.
+#[test]
+fn auth_not_logged() -> Result<()> {
+    let _ = log::set_logger(&CAPTURED_LOG);
+    log::set_max_level(log::LevelFilter::Debug);
+    let addr = "127.0.0.1:4154";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_auth_provider(StaticPassword::new("hunter2"));
+    thread::spawn(move || server.start_server(&addr).unwrap());
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.auth(None, "hunter2".to_owned())?;
+    let token = client.rotate_token()?;
+    client.auth(None, token.clone())?;
+    client.get("key1".to_owned())?;
+
+    let log = CAPTURED_LOG.0.lock().unwrap().clone();
+    assert!(log.contains("Response: Nil"));
+    assert!(!log.contains("hunter2"));
+    assert!(!log.contains(&token));
+
+    Ok(())
+}
+
+// The server keeps the command and first key of its last requests, without values or passwords.
+#[test]
+fn recent_requests() -> Result<()> {
+    let addr = "127.0.0.1:4153";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.set_auth_provider(StaticPassword::new("secret"));
+    let recent = RecentRequests::new(3);
+    server.keep_recent_requests(recent.clone());
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.start_server(&addr));
+
+    let mut client = KvsClient::connect_with(addr, fast_retry_policy(10))?;
+    client.auth(None, "secret".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key2".to_owned())?;
+    client.remove("key1".to_owned())?;
+    let requests: Vec<_> = recent
+        .requests()
+        .into_iter()
+        .map(|request| (request.command, request.key))
+        .collect();
+    assert_eq!(
+        requests,
+        [
+            ("set", Some("key1".to_owned())),
+            ("get", Some("key2".to_owned())),
+            ("remove", Some("key1".to_owned())),
+        ]
+    );
+    assert!(!format!("{:?}", recent.requests()).contains("value1"));
+
+    handle.shutdown();
+    drop(client);
+    server.join().unwrap()?;
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use kvs::{Codec, Error, FrameLimits, Get, Protocol, Request, Response, Result, Set};
+
+#[test]
+fn codec_round_trip() -> Result<()> {
+    let mut wire = vec![];
+    let mut client = Codec::new(&[][..], &mut wire);
+    client.write_request(Request::Set(Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+    }))?;
+    client.write_request(Request::Get(Get {
+        key: "key".to_owned(),
+    }))?;
+    drop(client);
+
+    let mut responses = vec![];
+    let mut server = Codec::new(&wire[..], &mut responses);
+    assert!(matches!(
+        server.read_request()?,
+        Some(Request::Set(Set { key, value })) if key == "key" && value == "value"
+    ));
+    assert!(matches!(server.read_request()?, Some(Request::Get(Get { key })) if key == "key"));
+    assert!(server.read_request()?.is_none());
+    server.write_response(Response::Ok)?;
+    server.write_response(Response::bulk("value"))?;
+    server.write_response(Response::error_with_code(
+        "BUSYKEY",
+        "Target key name already exists",
+    ))?;
+    drop(server);
+
+    let mut client = Codec::new(&responses[..], vec![]);
+    assert_eq!(client.read_response()?, Some(Response::Ok));
+    assert_eq!(client.read_response()?, Some(Response::bulk("value")));
+    assert_eq!(
+        client.read_response()?,
+        Some(Response::error_with_code(
+            "BUSYKEY",
+            "Target key name already exists"
+        ))
+    );
+    assert_eq!(client.read_response()?, None);
+
+    Ok(())
+}
+
+#[test]
+fn codec_encodes_responses_in_its_protocol() -> Result<()> {
+    let mut wire = vec![];
+    let mut server = Codec::new(&[][..], &mut wire);
+    let map = Response::Map(vec![(Response::bulk("a"), Response::Integer(1))]);
+    server.write_response(map.clone())?;
+    server.set_protocol(Protocol::Resp3);
+    server.write_response(map)?;
+    server.write_response(Response::Nil)?;
+    drop(server);
+
+    assert_eq!(wire, b"*2\r\n$1\r\na\r\n:1\r\n%1\r\n$1\r\na\r\n:1\r\n_\r\n");
+    Ok(())
+}
+
+#[test]
+fn codec_limits() {
+    let wire = b"*2\r\n$3\r\nget\r\n$100\r\n";
+    let mut server = Codec::new(&wire[..], vec![]);
+    server.set_limits(Some(FrameLimits {
+        max_bulk_len: 10,
+        ..FrameLimits::default()
+    }));
+    assert!(matches!(
+        server.read_frame(),
+        Err(Error::FrameRejected("invalid bulk length"))
+    ));
+
+    // Without limits, the frame is only found to be incomplete
+    let mut server = Codec::new(&wire[..], vec![]);
+    assert!(matches!(server.read_frame(), Err(Error::IO(_))));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_codec_round_trip() -> Result<()> {
+    use futures::executor::block_on;
+    use kvs::AsyncCodec;
+
+    block_on(async {
+        let mut wire = vec![];
+        let mut client = AsyncCodec::new(&[][..], &mut wire);
+        client
+            .write_request(Request::Get(Get {
+                key: "key".to_owned(),
+            }))
+            .await?;
+        drop(client);
+
+        let mut responses = vec![];
+        let mut server = AsyncCodec::new(&wire[..], &mut responses);
+        assert!(matches!(
+            server.read_request().await?,
+            Some(Request::Get(Get { key })) if key == "key"
+        ));
+        server.write_response(Response::Nil).await?;
+        drop(server);
+
+        let mut client = AsyncCodec::new(&responses[..], vec![]);
+        assert_eq!(client.read_response().await?, Some(Response::Nil));
+        Ok(())
+    })
+}
@@ -1,4 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    BatchOp, Compression, Error, IndexKind, KeyEvent, KeyEventKind, KvStore, KvStoreOptions,
+    KvsEngine, LocalClient, ManualClock, MeteredEngine, NotifyingEngine, ReadOnly, RecoveryReport,
+    Redb, Result, Scheduler, Scoped, SyncPolicy, Transaction, WriteBatch,
+};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::ops::Bound;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -44,83 +53,2115 @@ fn overwrite_value() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.contains_key("key1")?);
+    assert!(!store.contains_key("key2")?);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.contains_key("key1")?);
+    assert!(!store.contains_key("key2")?);
+
+    Ok(())
+}
+
+#[test]
+fn key_expiration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let past = SystemTime::now() - Duration::from_secs(1);
+    let future = SystemTime::now() + Duration::from_secs(3600);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.expiration("key1")?, Some(None));
+    assert!(store.set_expiration("key1".to_owned(), Some(past))?);
+    assert!(store.set_expiration("key2".to_owned(), Some(future))?);
+    assert!(!store.set_expiration("key3".to_owned(), Some(future))?);
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.contains_key("key1")?);
+    assert_eq!(store.expiration("key1")?, None);
+    assert_eq!(store.scan(None, 10)?, ["key2"]);
+    assert_eq!(store.len()?, 1);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let expires_at = store.expiration("key2")?.unwrap().unwrap();
+    assert!(expires_at.duration_since(future).unwrap_or_default() < Duration::from_millis(1));
+
+    // Setting a key makes it persistent
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.expiration("key2")?, Some(None));
+
+    Ok(())
+}
+
+// Expired keys count as missing for SETNX and GETSET
+#[test]
+fn set_nx_and_get_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let past = SystemTime::now() - Duration::from_secs(1);
+    let future = SystemTime::now() + Duration::from_secs(3600);
+
+    assert!(store.set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set_expiration("key1".to_owned(), Some(past))?;
+    assert!(store.set_nx("key1".to_owned(), "value3".to_owned())?);
+
+    assert_eq!(store.get_set("key2".to_owned(), "value1".to_owned())?, None);
+    store.set_expiration("key2".to_owned(), Some(future))?;
+    assert_eq!(
+        store.get_set("key2".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.expiration("key2")?, Some(None));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Expired keys count as missing, on both engines and through shared handles.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+    let mut redb = Redb::open(temp_dir.path().join("redb"))?;
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+    store.set_clock(Arc::new(clock.clone()));
+    redb.set_clock(Arc::new(clock.clone()));
+
+    fn check(engine: &mut impl KvsEngine, clock: &ManualClock) -> Result<()> {
+        let value = |v: &str| Some(v.to_owned());
+        assert!(engine.compare_and_swap("key1".to_owned(), None, value("value1"))?);
+        assert!(!engine.compare_and_swap("key1".to_owned(), None, value("value2"))?);
+        assert!(!engine.compare_and_swap("key1".to_owned(), value("value2"), None)?);
+        assert_eq!(engine.get("key1".to_owned())?, value("value1"));
+
+        engine.set_expiration(
+            "key1".to_owned(),
+            Some(clock.now() + Duration::from_secs(1)),
+        )?;
+        assert!(engine.compare_and_swap("key1".to_owned(), value("value1"), value("value2"))?);
+        assert_eq!(engine.expiration("key1")?, Some(None));
+        assert!(engine.compare_and_swap("key1".to_owned(), value("value2"), None)?);
+        assert!(!engine.contains_key("key1")?);
+        assert!(engine.compare_and_swap("key1".to_owned(), None, None)?);
+
+        engine.set("key2".to_owned(), "value1".to_owned())?;
+        engine.set_expiration("key2".to_owned(), Some(clock.now()))?;
+        assert!(!engine.compare_and_swap("key2".to_owned(), value("value1"), None)?);
+        assert!(engine.compare_and_swap("key2".to_owned(), None, value("value2"))?);
+        assert_eq!(engine.get("key2".to_owned())?, value("value2"));
+        Ok(())
+    }
+    check(&mut store, &clock)?;
+    check(&mut redb, &clock)?;
+
+    // Increments retried until their swap succeeds are all counted
+    let store = store.into_shared();
+    let counters: Vec<_> = (0..4)
+        .map(|_| {
+            let mut handle = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let count = handle.get("counter".to_owned())?;
+                        let next: u32 = count.as_deref().map_or(0, |n| n.parse().unwrap()) + 1;
+                        let next = Some(next.to_string());
+                        if handle.compare_and_swap("counter".to_owned(), count, next)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for counter in counters {
+        counter.join().unwrap()?;
+    }
+    assert_eq!(
+        store.lock().get("counter".to_owned())?,
+        Some("200".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn update_with_closure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.into_shared();
+
+    let mut handle = store.clone();
+    assert_eq!(handle.update("key1".to_owned(), |_| None)?, None);
+    assert!(!handle.contains_key("key1")?);
+    handle.set("key1".to_owned(), "value".to_owned())?;
+    let upper = handle.update("key1".to_owned(), |value| value.map(|v| v.to_uppercase()))?;
+    assert_eq!(upper, Some("VALUE".to_owned()));
+    assert_eq!(handle.update("key1".to_owned(), |_| None)?, None);
+    assert!(!handle.contains_key("key1")?);
+
+    let increment = |count: Option<String>| {
+        let count: u32 = count.map_or(0, |count| count.parse().unwrap());
+        Some((count + 1).to_string())
+    };
+    let counters: Vec<_> = (0..4)
+        .map(|_| {
+            let mut handle = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    handle.update("counter".to_owned(), increment)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for counter in counters {
+        counter.join().unwrap()?;
+    }
+    assert_eq!(
+        store.lock().get("counter".to_owned())?,
+        Some("200".to_owned())
+    );
+
+    Ok(())
+}
+
+// Values come in the order of the keys, whatever the files their records are in
+#[test]
+fn multi_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+    let keys: Vec<String> = ["key3", "missing", "key1", "expired", "key2", "key1"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    let expected = [
+        Some("value3".to_owned()),
+        None,
+        Some("value1".to_owned()),
+        None,
+        Some("value2".to_owned()),
+        Some("value1".to_owned()),
+    ];
+
+    fn fill(engine: &mut impl KvsEngine, clock: &ManualClock, key: &str) -> Result<()> {
+        engine.set(key.to_owned(), key.replace("key", "value"))?;
+        engine.set("expired".to_owned(), "value".to_owned())?;
+        engine.set_expiration("expired".to_owned(), Some(clock.now()))?;
+        Ok(())
+    }
+
+    // Each open starts a data file, so the keys are spread over several
+    for key in ["key1", "key2", "key3"] {
+        let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+        store.set_clock(Arc::new(clock.clone()));
+        fill(&mut store, &clock, key)?;
+    }
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+    store.set_clock(Arc::new(clock.clone()));
+    assert_eq!(store.multi_get(&keys)?, expected);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let mut redb = Redb::open(temp_dir.path().join("redb"))?;
+    redb.set_clock(Arc::new(clock.clone()));
+    for key in ["key1", "key2", "key3"] {
+        fill(&mut redb, &clock, key)?;
+    }
+    assert_eq!(redb.multi_get(&keys)?, expected);
+    assert_eq!(redb.multi_get(&[])?, []);
+    Ok(())
+}
+
+// Appending creates missing or expired keys and keeps the expiration time
+#[test]
+fn append_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let past = SystemTime::now() - Duration::from_secs(1);
+    let future = SystemTime::now() + Duration::from_secs(3600);
+
+    assert_eq!(store.append("key1".to_owned(), "Hello".to_owned())?, 5);
+    store.set_expiration("key1".to_owned(), Some(future))?;
+    assert_eq!(store.append("key1".to_owned(), " World".to_owned())?, 11);
+    assert!(store.expiration("key1")?.unwrap().is_some());
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_expiration("key2".to_owned(), Some(past))?;
+    assert_eq!(store.append("key2".to_owned(), "new".to_owned())?, 3);
+    assert_eq!(store.expiration("key2")?, Some(None));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("Hello World".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// A rename is one record, which compaction rewrites as a set of the new key.
+#[test]
+fn rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let future = SystemTime::now() + Duration::from_secs(3600);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_expiration("key1".to_owned(), Some(future))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(!store.rename("key1".to_owned(), "key2".to_owned(), false)?);
+    assert!(store.rename("key1".to_owned(), "key2".to_owned(), true)?);
+    assert!(matches!(
+        store.rename("key1".to_owned(), "key3".to_owned(), true),
+        Err(Error::KeyNotFound)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(store.expiration("key2")?.unwrap().is_some());
+    // Written again after the rename, it must survive the rename record
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(store.expiration("key2")?.unwrap().is_some());
+    assert_eq!(store.len()?, 3);
+
+    Ok(())
+}
+
+// The directory of a store being written is locked, other processes get the owner's PID.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lock_path = temp_dir.path().join("LOCK");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        std::fs::read_to_string(&lock_path)?,
+        std::process::id().to_string()
+    );
+    drop(store);
+
+    // The lock is per open file, so another process holding it looks the same
+    let mut other = std::fs::File::create(&lock_path)?;
+    write!(other, "12345")?;
+    other.try_lock().unwrap();
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::AlreadyLocked {
+            pid: Some(12345),
+            ..
+        })
+    ));
+    // Reading doesn't take the lock
+    let mut view = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            read_only: true,
+            ..KvStoreOptions::default()
+        },
+    )?;
+    assert_eq!(view.get("key".to_owned())?, Some("value".to_owned()));
+    drop(view);
+
+    drop(other);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// A store is open once per process, components share it through handles.
+#[test]
+fn shared_handles() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.into_shared();
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::AlreadyOpen(_))
+    ));
+
+    let writers: Vec<_> = (0..4)
+        .map(|i| {
+            let mut handle = store.clone();
+            std::thread::spawn(move || handle.set(format!("key{}", i), format!("value{}", i)))
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    assert_eq!(store.len()?, 4);
+    assert_eq!(store.lock().keys().count(), 4);
+
+    // The store can be opened again once every handle is dropped
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Every operation through a MeteredEngine is counted, failed ones as errors too.
+#[test]
+fn metered_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = MeteredEngine::<KvStore>::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    assert!(store.remove("key3".to_owned()).is_err());
+    store.inner_mut().get("key2".to_owned())?;
+
+    let stats = store.op_stats();
+    assert_eq!(
+        stats.keys().copied().collect::<Vec<_>>(),
+        ["get", "remove", "set"]
+    );
+    assert_eq!((stats["set"].calls, stats["set"].errors), (2, 0));
+    assert_eq!((stats["get"].calls, stats["get"].errors), (1, 0));
+    assert_eq!((stats["remove"].calls, stats["remove"].errors), (1, 1));
+    assert!(stats["set"].max_time >= stats["set"].mean_time());
+
+    store.reset_op_stats();
+    assert!(store.op_stats().is_empty());
+
+    Ok(())
+}
+
+// Writes that change a key are reported, expired keys once purged.
+#[test]
+fn notifying_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = NotifyingEngine::<KvStore>::open(temp_dir.path())?;
+    let (sender, receiver) = mpsc::channel();
+    store.add_listener(move |event| sender.send((event.key.clone(), event.kind)).unwrap());
+    let past = SystemTime::now() - Duration::from_secs(1);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!store.set_nx("key1".to_owned(), "value2".to_owned())?);
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key2".to_owned());
+    batch.remove("key3".to_owned());
+    store.write_batch(batch)?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.set_expiration("key4".to_owned(), Some(past))?;
+    assert_eq!(store.purge_expired()?, ["key4"]);
+    assert_eq!(store.purge_expired()?, Vec::<String>::new());
+
+    let events: Vec<_> = receiver.try_iter().collect();
+    assert_eq!(
+        events,
+        [
+            ("key1".to_owned(), KeyEventKind::Set),
+            ("key1".to_owned(), KeyEventKind::Del),
+            ("key2".to_owned(), KeyEventKind::Set),
+            ("key2".to_owned(), KeyEventKind::Del),
+            ("key4".to_owned(), KeyEventKind::Set),
+            ("key4".to_owned(), KeyEventKind::Expired),
+        ]
+    );
+
+    // Purged keys stay removed
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.expiration("key4")?, None);
+    assert!(store.is_empty()?);
+
+    Ok(())
+}
+
+// Keys expiring in an embedded engine are reported once swept, whatever the engine.
+#[test]
+fn expire_sweep_notifies_listeners() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (sender, receiver) = mpsc::channel();
+    let mut kv_store = NotifyingEngine::<KvStore>::open(temp_dir.path().join("kvs"))?;
+    let mut redb = NotifyingEngine::<Redb>::open(temp_dir.path().join("redb"))?;
+    let listener = |name| {
+        let sender = sender.clone();
+        move |event: &KeyEvent| sender.send((name, event.key.clone(), event.kind)).unwrap()
+    };
+    kv_store.add_listener(listener("kvs"));
+    redb.add_listener(listener("redb"));
+    let event = |name, key: &str, kind| (name, key.to_owned(), kind);
+    let soon = SystemTime::now() + Duration::from_millis(50);
+    let past = SystemTime::now() - Duration::from_secs(1);
+
+    let kv_store = LocalClient::new(kv_store);
+    kv_store
+        .engine()
+        .set("key1".to_owned(), "value1".to_owned())?;
+    kv_store
+        .engine()
+        .set_expiration("key1".to_owned(), Some(soon))?;
+    let scheduler = Scheduler::new();
+    kv_store.add_expire_sweep(&scheduler, Duration::from_millis(10));
+    scheduler.start();
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        receiver.recv_timeout(timeout).unwrap(),
+        event("kvs", "key1", KeyEventKind::Set)
+    );
+    assert_eq!(
+        receiver.recv_timeout(timeout).unwrap(),
+        event("kvs", "key1", KeyEventKind::Expired)
+    );
+    scheduler.shutdown();
+
+    redb.set("key1".to_owned(), "value1".to_owned())?;
+    redb.set_expiration("key1".to_owned(), Some(past))?;
+    redb.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(redb.purge_expired()?, ["key1"]);
+    assert_eq!(redb.purge_expired()?, Vec::<String>::new());
+    assert_eq!(redb.get("key2".to_owned())?, Some("value2".to_owned()));
+    let events: Vec<_> = receiver.try_iter().collect();
+    assert_eq!(
+        events,
+        [
+            event("redb", "key1", KeyEventKind::Set),
+            event("redb", "key2", KeyEventKind::Set),
+            event("redb", "key1", KeyEventKind::Expired),
+        ]
+    );
+
+    Ok(())
+}
+
+// Keys expire on the engine's clock, wrappers and transactions read the same time.
+#[test]
+fn manual_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = MeteredEngine::new(KvStore::open(temp_dir.path())?);
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+    store.set_clock(Arc::new(clock.clone()));
+    assert_eq!(store.now(), clock.now());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let expires_at = clock.now() + Duration::from_secs(60);
+    store.set_expiration("key1".to_owned(), Some(expires_at))?;
+    clock.advance(Duration::from_secs(59));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.len()?, 2);
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    {
+        let mut transaction = Transaction::new(&mut store);
+        let expires_at = clock.now() + Duration::from_secs(1);
+        let mut batch = WriteBatch::new();
+        batch.push(BatchOp::Set {
+            key: b"key3".to_vec(),
+            value: b"value3".to_vec(),
+            expires_at: Some(expires_at),
+        });
+        transaction.write_batch(batch)?;
+        assert!(transaction.contains_key("key3")?);
+        clock.advance(Duration::from_secs(1));
+        assert!(!transaction.contains_key("key3")?);
+        transaction.commit()?;
+    }
+    let mut purged = store.purge_expired()?;
+    purged.sort();
+    assert_eq!(purged, ["key1", "key3"]);
+    assert_eq!(store.len()?, 1);
+
+    Ok(())
+}
+
+// Range over more keys than a page, with expired keys left out, on both engines.
+#[test]
+fn range_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+    let mut redb = Redb::open(temp_dir.path().join("redb"))?;
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+    store.set_clock(Arc::new(clock.clone()));
+    redb.set_clock(Arc::new(clock.clone()));
+
+    fn keys(
+        engine: &mut impl KvsEngine,
+        range: (Bound<String>, Bound<String>),
+    ) -> Result<Vec<String>> {
+        engine
+            .range(range)
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect()
+    }
+    fn check(engine: &mut impl KvsEngine, clock: &ManualClock) -> Result<()> {
+        for i in 0..250 {
+            engine.set(format!("key{:03}", i), format!("value{}", i))?;
+        }
+        engine.set_expiration("key101".to_owned(), Some(clock.now()))?;
+
+        let all = engine.range(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(all.len(), 249);
+        assert_eq!(all[0], ("key000".to_owned(), "value0".to_owned()));
+        assert_eq!(all[248], ("key249".to_owned(), "value249".to_owned()));
+
+        let from = "key099".to_owned();
+        let to = "key103".to_owned();
+        assert_eq!(
+            keys(
+                engine,
+                (Bound::Included(from.clone()), Bound::Excluded(to.clone()))
+            )?,
+            ["key099", "key100", "key102"]
+        );
+        assert_eq!(
+            keys(engine, (Bound::Excluded(from), Bound::Included(to)))?,
+            ["key100", "key102", "key103"]
+        );
+        assert_eq!(
+            keys(
+                engine,
+                (
+                    Bound::Included("key101".to_owned()),
+                    Bound::Included("key101".to_owned())
+                )
+            )?,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            keys(
+                engine,
+                (Bound::Included("key2495".to_owned()), Bound::Unbounded)
+            )?,
+            Vec::<String>::new()
+        );
+        Ok(())
+    }
+    check(&mut store, &clock)?;
+    check(&mut redb, &clock)?;
+
+    Ok(())
+}
+
+// Keys of an ordered index come in order, also once rebuilt from the data files.
+#[test]
+fn ordered_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index: IndexKind::Ordered,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.index_kind(), IndexKind::Ordered);
+    assert_eq!(store.first_key(), None);
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+    store.set_clock(Arc::new(clock.clone()));
+    for i in [5, 3, 9, 1, 7] {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set_expiration("key1".to_owned(), Some(clock.now()))?;
+    store.remove("key9".to_owned())?;
+
+    assert!(store.keys().eq([b"key3", b"key5", b"key7"]));
+    assert_eq!(store.first_key(), Some(&b"key3"[..]));
+    assert_eq!(store.last_key(), Some(&b"key7"[..]));
+    assert_eq!(store.scan(Some("key3"), 1)?, ["key5"]);
+    assert_eq!(store.scan(Some("key4"), 10)?, ["key5", "key7"]);
+    drop(store);
+
+    // key1 expired back in 1970 as far as the system clock is concerned
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert!(store.keys().eq([b"key3", b"key5", b"key7"]));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.index_kind(), IndexKind::Hash);
+    assert_eq!(store.first_key(), Some(&b"key3"[..]));
+    assert_eq!(store.last_key(), Some(&b"key7"[..]));
+    assert_eq!(store.scan(Some("key3"), 1)?, ["key5"]);
+
+    Ok(())
+}
+
+// Data files go to the data directories in turn and are found in all of them on open.
+#[test]
+fn data_dirs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs: Vec<_> = ["a", "b", "c"]
+        .iter()
+        .map(|name| temp_dir.path().join(name))
+        .collect();
+    let options = KvStoreOptions {
+        data_dirs: dirs[1..].to_vec(),
+        ..KvStoreOptions::default()
+    };
+    let log_files = |dir: &std::path::Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension().is_some_and(|ext| ext == "log")
+            })
+            .count()
+    };
+
+    for i in 0..3 {
+        let mut store = KvStore::open_with(&dirs[0], options.clone())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for dir in &dirs {
+        assert_eq!(log_files(dir), 1);
+    }
+    let mut store = KvStore::open_with(&dirs[0], options.clone())?;
+    for i in 0..3 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Compaction removes stale data files from every directory
+    store.set("key0".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    assert_eq!(dirs.iter().map(|dir| log_files(dir)).sum::<usize>(), 2);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // A data file in two directories is ambiguous
+    let log = walkdir::WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .unwrap();
+    let copy = ["a", "b"]
+        .iter()
+        .map(|name| temp_dir.path().join(name).join(log.file_name().unwrap()))
+        .find(|copy| *copy != log)
+        .unwrap();
+    std::fs::copy(&log, copy)?;
+    assert!(matches!(
+        KvStore::open_with(&dirs[0], options),
+        Err(Error::DuplicateDataFile(_))
+    ));
+
+    Ok(())
+}
+
+// Bytes that aren't UTF-8 survive a reopen and sort by their bytes, strings are bytes too.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let binary_key = vec![b'k', 0xc3, 0x28];
+    let binary_value: Vec<u8> = (0..=255).collect();
+    // A string that looked like tagged bytes once is read back the same
+    let tricky = "\0bin:AAAA".to_owned();
+
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+    let mut redb = Redb::open(temp_dir.path().join("redb"))?;
+    store.set_bytes(binary_key.clone(), binary_value.clone())?;
+    redb.set_bytes(binary_key.clone(), binary_value.clone())?;
+    store.set("text".to_owned(), tricky.clone())?;
+    redb.set("text".to_owned(), tricky.clone())?;
+    store.set_bytes(b"plain".to_vec(), b"value".to_vec())?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        store.get(String::from_utf8_lossy(&binary_key).into_owned()),
+        Ok(None)
+    ));
+    drop(store);
+    drop(redb);
+    // Bytes are stored as they are, not encoded into something longer
+    let data_len: u64 = std::fs::read_dir(temp_dir.path().join("kvs"))?
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".log"))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert!(data_len < 400, "{} bytes on disk", data_len);
+
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+    let mut redb = Redb::open(temp_dir.path().join("redb"))?;
+    assert_eq!(
+        store.get_bytes(binary_key.clone())?,
+        Some(binary_value.clone())
+    );
+    assert_eq!(
+        redb.get_bytes(binary_key.clone())?,
+        Some(binary_value.clone())
+    );
+    assert_eq!(
+        store.get_bytes(b"text".to_vec())?,
+        Some(tricky.clone().into_bytes())
+    );
+    assert_eq!(
+        redb.get_bytes(b"text".to_vec())?,
+        Some(tricky.clone().into_bytes())
+    );
+    assert_eq!(store.get("text".to_owned())?, Some(tricky.clone()));
+    assert_eq!(redb.get("text".to_owned())?, Some(tricky));
+    store.set_bytes(b"utf8".to_vec(), binary_value.clone())?;
+    redb.set_bytes(b"utf8".to_vec(), binary_value.clone())?;
+    assert!(matches!(store.get("utf8".to_owned()), Err(Error::NotUtf8)));
+    assert!(matches!(redb.get("utf8".to_owned()), Err(Error::NotUtf8)));
+    store.remove_bytes(binary_key.clone())?;
+    redb.remove_bytes(binary_key.clone())?;
+    assert_eq!(store.get_bytes(binary_key.clone())?, None);
+    assert_eq!(redb.get_bytes(binary_key)?, None);
+    assert_eq!(store.len()?, 3);
+
+    // Keys sort by their bytes, string scans skip those that aren't UTF-8
+    fn check_order(engine: &mut impl KvsEngine) -> Result<()> {
+        let keys: [&[u8]; 5] = [b"a", b"b", &[0x80], "\u{e9}".as_bytes(), &[0xff, 0x00]];
+        for key in keys.iter().rev() {
+            engine.set_bytes(key.to_vec(), b"value".to_vec())?;
+        }
+        assert_eq!(engine.scan_bytes(Some(b"a"), 10)?, keys[1..]);
+        assert_eq!(engine.scan(Some("b"), 1)?, ["\u{e9}"]);
+        Ok(())
+    }
+    check_order(&mut KvStore::open(temp_dir.path().join("hash"))?)?;
+    check_order(
+        &mut KvStore::builder()
+            .index(IndexKind::Ordered)
+            .open(temp_dir.path().join("ordered"))?,
+    )?;
+    check_order(&mut Redb::open(temp_dir.path().join("order.redb"))?)?;
+
+    Ok(())
+}
+
+// An intact record at the offset of another one goes unnoticed, unless its read is verified.
+#[test]
+fn read_verification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.set_config("read-verify-percent", "101").is_err());
+
+    // Same length, so both records still pass their checksum
+    let log = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .unwrap();
+    let mut data = std::fs::read(&log)?;
+    let len = data.len();
+    data.rotate_left(len / 2);
+    std::fs::write(&log, data)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    store.set_config("read-verify-percent", "100")?;
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(Error::CorruptRecord(key)) if key == "key1"
+    ));
+    assert!(matches!(
+        store.get("key2".to_owned()),
+        Err(Error::CorruptRecord(key)) if key == "key2"
+    ));
+    let stats = store.stats()?;
+    assert!(stats.contains(&("corrupt_reads", "2".to_owned())));
+
+    Ok(())
+}
+
+// Gets from sealed data files go through memory maps, which follow compaction.
+#[test]
+fn mmap_sealed_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mapped = |store: &KvStore| {
+        let stats = store.stats().unwrap();
+        let (_, mapped) = stats
+            .into_iter()
+            .find(|(name, _)| *name == "mapped_segments")
+            .unwrap();
+        mapped.parse::<usize>().unwrap()
+    };
+    let mut store = KvStore::builder()
+        .segment_size(64)
+        .compaction_ratio(1.0)
+        .mmap(true)
+        .open(temp_dir.path())?;
+    for n in 0..20 {
+        store.set(format!("key{}", n % 10), format!("value{}", n))?;
+    }
+    if cfg!(unix) {
+        assert!(mapped(&store) > 1);
+    }
+    for n in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", n))?,
+            Some(format!("value{}", n + 10))
+        );
+    }
+
+    store.compact()?;
+    store.set("key0".to_owned(), "value20".to_owned())?;
+    assert_eq!(mapped(&store), if cfg!(unix) { 1 } else { 0 });
+    assert_eq!(store.get("key0".to_owned())?, Some("value20".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value15".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::builder().mmap(false).open(temp_dir.path())?;
+    assert_eq!(mapped(&store), 0);
+    assert_eq!(store.get("key5".to_owned())?, Some("value15".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn verify_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Each open seals the data file of the previous one
+    for n in 1..=3 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", n), format!("value{}", n))?;
+        store.set(format!("other{}", n), format!("value{}", n))?;
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+    let sealed: u64 = (1..=3)
+        .map(|n| {
+            std::fs::metadata(temp_dir.path().join(format!("{}.log", n)))
+                .unwrap()
+                .len()
+        })
+        .sum();
+
+    assert_eq!(store.verify_data(u64::MAX)?, sealed);
+    // It continues where it stopped, a record at a time
+    let record = store.verify_data(1)?;
+    assert!(record > 0 && record < sealed);
+    assert_eq!(store.verify_data(u64::MAX)?, sealed);
+    assert!(store
+        .stats()?
+        .contains(&("corrupt_records", "0".to_owned())));
+
+    // A record failing its checksum, the rest of the file can't be trusted either
+    for n in 1..=2 {
+        let log = temp_dir.path().join(format!("{}.log", n));
+        let mut data = std::fs::read(&log)?;
+        data[10] ^= 1;
+        std::fs::write(&log, data)?;
+    }
+
+    assert_eq!(store.verify_data(u64::MAX)?, sealed);
+    assert_eq!(store.verify_data(u64::MAX)?, sealed);
+    let stats = store.stats()?;
+    assert!(stats.contains(&("corrupt_records", "2".to_owned())));
+    assert!(stats.contains(&("verified_bytes", (sealed * 4 + record).to_string())));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn typed_values() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Session {
+        user: String,
+        roles: Vec<String>,
+        expires_in: Option<u64>,
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let session = Session {
+        user: "alice".to_owned(),
+        roles: vec!["admin".to_owned()],
+        expires_in: None,
+    };
+    store.set_typed("session:1".to_owned(), &session)?;
+    store.set_typed("count".to_owned(), &42u64)?;
+    assert_eq!(store.get_typed("session:1".to_owned())?, Some(session));
+    assert_eq!(store.get_typed::<u64>("count".to_owned())?, Some(42));
+    assert_eq!(store.get("count".to_owned())?, Some("42".to_owned()));
+    assert_eq!(store.get_typed::<u64>("missing".to_owned())?, None);
+
+    store.set("plain".to_owned(), "not json".to_owned())?;
+    assert!(matches!(
+        store.get_typed::<Session>("plain".to_owned()),
+        Err(Error::SerdeJson(_))
+    ));
+
+    Ok(())
+}
+
+// Reads go through a ReadOnly view, every write fails.
+#[test]
+fn read_only_view() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?.into_shared();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut view = ReadOnly::new(store.clone());
+    assert_eq!(view.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(view.scan(None, 10)?, ["key1"]);
+    assert_eq!(view.len()?, 1);
+    assert!(matches!(
+        view.set("key1".to_owned(), "value2".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        view.append("key1".to_owned(), "value2".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        view.remove("key1".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        view.set_expiration("key1".to_owned(), None),
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(view.clear(), Err(Error::ReadOnly)));
+    assert!(matches!(
+        view.set_config("compaction-ratio", "1"),
+        Err(Error::ReadOnly)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Options set through the builder apply to the store, a read-only one leaves the files alone.
+#[test]
+fn store_builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStore::builder()
+            .compaction_ratio(2.0)
+            .open(temp_dir.path()),
+        Err(Error::InvalidConfig(..))
+    ));
+    assert!(KvStore::builder()
+        .read_only(true)
+        .open(temp_dir.path())
+        .is_err());
+
+    let mut store = KvStore::builder()
+        .segment_size(1)
+        .compaction_ratio(1.0)
+        .sync_policy(SyncPolicy::Always)
+        .open(temp_dir.path())?;
+    let config = store.config();
+    assert!(config.contains(&("segment-size", "1".to_owned())));
+    assert!(config.contains(&("compaction-ratio", "1".to_owned())));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let file_count = || WalkDir::new(temp_dir.path()).into_iter().count();
+    let files = file_count();
+    let mut store = KvStore::builder().read_only(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.scan(None, 10)?.len(), 2);
+    assert!(matches!(
+        store.set("key1".to_owned(), "value2".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(Error::ReadOnly)));
+    drop(store);
+    assert_eq!(file_count(), files);
+
+    // Wrappers pass the options on to the engine they open
+    let mut view = ReadOnly::<KvStore>::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            read_only: true,
+            ..KvStoreOptions::default()
+        },
+    )?;
+    assert_eq!(view.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A Scoped view only sees and changes the keys under its prefix.
+#[test]
+fn scoped_view() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?.into_shared();
+    store.set("a".to_owned(), "outside".to_owned())?;
+    store.set("jobs".to_owned(), "outside".to_owned())?;
+    store.set("jobs;".to_owned(), "outside".to_owned())?;
+
+    let mut jobs = Scoped::new(store.clone(), "jobs:");
+    assert!(jobs.is_empty()?);
+    assert_eq!(jobs.get("a".to_owned())?, None);
+    jobs.set("".to_owned(), "all".to_owned())?;
+    for id in ["3", "1", "2"] {
+        jobs.set(id.to_owned(), format!("job{}", id))?;
+    }
+    assert_eq!(store.get("jobs:1".to_owned())?, Some("job1".to_owned()));
+    assert!(jobs.contains_key("2")?);
+    assert!(!jobs.contains_key("a")?);
+    assert_eq!(jobs.len()?, 4);
+    assert_eq!(jobs.scan(None, 2)?, ["", "1"]);
+    assert_eq!(jobs.scan(Some("1"), 10)?, ["2", "3"]);
+
+    jobs.remove("1".to_owned())?;
+    assert!(matches!(
+        jobs.remove("a".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+    jobs.clear()?;
+    assert!(jobs.is_empty()?);
+    assert_eq!(store.scan(None, 10)?, ["a", "jobs", "jobs;"]);
+
+    Ok(())
+}
+
 // Should get `None` when getting a non-existent key
 #[test]
-fn get_non_existent_value() -> Result<()> {
+fn get_non_existent_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn remove_non_existent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let mut store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Sealed data files are compacted one at a time, keeping the removes older ones still need.
+#[test]
+fn segment_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    // Every write goes to a data file of its own
+    store.set_config("segment-size", "1")?;
+    store.set_config("compaction-ratio", "1")?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key0".to_owned(), "x".repeat(200));
+    batch.set("key1".to_owned(), "value1".to_owned());
+    store.write_batch(batch)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.rename("key2".to_owned(), "key3".to_owned(), true)?;
+
+    store.set_config("compaction-ratio", "0.5")?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    // Data file 1 is mostly live, 2 had only key2, 3 only the remove of key1
+    assert!(temp_dir.path().join("1.log").exists());
+    assert!(!temp_dir.path().join("2.log").exists());
+    assert!(store.io_stats().compaction_bytes_written > 0);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery_report().is_none());
+    assert_eq!(store.get("key0".to_owned())?, Some("x".repeat(200)));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Compaction can be run by hand, engines that don't need it accept the call.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.remove("other".to_owned())?;
+    let disk_bytes = |store: &KvStore| -> Result<u64> {
+        let stats = store.stats()?;
+        let (_, bytes) = stats
+            .iter()
+            .find(|(name, _)| *name == "disk_bytes")
+            .unwrap();
+        Ok(bytes.parse().unwrap())
+    };
+    let before = disk_bytes(&store)?;
+
+    store.compact()?;
+    assert!(disk_bytes(&store)? < before / 10);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, None);
+
+    let mut redb = Redb::open(temp_dir.path().join("redb"))?;
+    redb.set("key".to_owned(), "value".to_owned())?;
+    redb.compact()?;
+    assert_eq!(redb.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// The data file of a compaction a crash interrupted is discarded on open.
+#[test]
+fn interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    // Scrubbing compacts the live data
+    store.scrub("no-such-key")?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let leftovers = |dir: &std::path::Path| {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(".compact.tmp")
+            })
+            .count()
+    };
+    assert_eq!(leftovers(temp_dir.path()), 0);
+    drop(store);
+
+    // Cut short, as if the process crashed while writing it
+    std::fs::write(
+        temp_dir.path().join("9.compact.tmp"),
+        r#"{"Set":{"key":"key1","va"#,
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report(), None);
+    assert_eq!(leftovers(temp_dir.path()), 0);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Clearing removes every key and frees the disk space of the data files.
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".repeat(100))?;
+    }
+    assert_eq!(store.len()?, 100);
+    store.clear()?;
+    assert!(store.is_empty()?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.scan(None, 10)?, Vec::<String>::new());
+
+    let dir_size: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(dir_size < 100 * 500);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A clear the process died in the middle of stays done: the data files it didn't get to
+// delete are skipped on open.
+#[test]
+fn clear_interrupted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let stale_file = temp_dir.path().join("1.log");
+    let stale = std::fs::read(&stale_file)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.clear()?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+    // As if the process died before deleting it, and before saving the index
+    std::fs::write(&stale_file, stale)?;
+    std::fs::remove_file(temp_dir.path().join("index.json"))?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.len()?, 1);
+    assert!(!stale_file.exists());
+
+    Ok(())
+}
+
+// A damaged record is reported with its location when read, and skipped by the next open.
+#[test]
+fn record_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // Flip a bit in the value of key2
+    let data_file = temp_dir.path().join("1.log");
+    let mut data = std::fs::read(&data_file)?;
+    let len = data.len();
+    let offset = len / 2;
+    data[len - 5] ^= 1;
+    std::fs::write(&data_file, data)?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.get("key2".to_owned()),
+        Err(Error::Corruption { file, offset: o }) if file == data_file && o == offset as u64
+    ));
+    assert!(store.stats()?.contains(&("corrupt_reads", "1".to_owned())));
+    drop(store);
+    std::fs::remove_file(temp_dir.path().join("index.json"))?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let report = store.recovery_report().unwrap();
+    assert_eq!(report.skipped_tails.len(), 1);
+    assert_eq!(report.skipped_tails[0].offset, offset as u64);
+    assert!(report.skipped_tails[0].error.contains("Corrupt record"));
+    // Not a torn write, the damaged record is left for inspection
+    assert!(!report.skipped_tails[0].truncated);
+    assert_eq!(std::fs::metadata(&data_file)?.len(), len as u64);
+
+    Ok(())
+}
+
+// Data files of concatenated JSON records are upgraded to framed records on open.
+#[test]
+fn upgrade_legacy_data_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_file = temp_dir.path().join("1.log");
+    let data = [
+        r#"{"Set":{"key":"key1","value":"value1","expires_at":null}}"#,
+        r#"{"Set":{"key":"key2","value":"value2","expires_at":null}}"#,
+        r#"{"Remove":{"key":"key1"}}"#,
+        // A torn write
+        r#"{"Set":{"key":"key3","val"#,
+    ]
+    .concat();
+    std::fs::write(&data_file, &data)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    let report = store.recovery_report().unwrap();
+    assert_eq!(report.skipped_tails.len(), 1);
+    assert_eq!(report.skipped_tails[0].bytes, 25);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    assert!(!std::fs::read(&data_file)?.starts_with(b"{"));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Stores record their format version, older ones are migrated and newer ones refused.
+#[test]
+fn format_version() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let format_file = temp_dir.path().join("FORMAT");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(
+        std::fs::read(&format_file)?,
+        [&b"kvstore\n"[..], &7u32.to_le_bytes()].concat()
+    );
+
+    // A store of version 6, its records are read as they are
+    let first_file = temp_dir.path().join("1.log");
+    let first = std::fs::read(&first_file)?;
+    std::fs::write(
+        &format_file,
+        [&b"kvstore\n"[..], &6u32.to_le_bytes()].concat(),
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(std::fs::read(&first_file)?, first);
+    assert_eq!(
+        std::fs::read(&format_file)?,
+        [&b"kvstore\n"[..], &7u32.to_le_bytes()].concat()
+    );
+
+    // A store of version 5, framed JSON records and no format file
+    std::fs::remove_file(&format_file)?;
+    let json = r#"{"Set":{"key":"key2","value":"value2"}}"#;
+    let data = [
+        &(json.len() as u32).to_le_bytes()[..],
+        &crc32c(json.as_bytes()).to_le_bytes(),
+        json.as_bytes(),
+    ]
+    .concat();
+    let data_file = temp_dir.path().join("2.log");
+    std::fs::write(&data_file, data)?;
     let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert!(format_file.exists());
+    let data = std::fs::read(&data_file)?;
+    assert!(!data.windows(6).any(|w| w == br#"{"Set""#));
+
+    // A store written by a newer version
+    std::fs::write(
+        &format_file,
+        [&b"kvstore\n"[..], &8u32.to_le_bytes()].concat(),
+    )?;
+    for result in [
+        KvStore::open(temp_dir.path()),
+        KvStore::builder().read_only(true).open(temp_dir.path()),
+    ] {
+        match result {
+            Err(Error::UnsupportedFormat {
+                version, supported, ..
+            }) => assert_eq!((version, supported), (8, 7)),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+    assert_eq!(std::fs::read(&data_file)?, data);
+
+    std::fs::write(&format_file, b"garbage")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::Corruption { .. })
+    ));
+
+    Ok(())
+}
 
+// An unreadable end of a data file is skipped and reported, instead of failing to open.
+#[test]
+fn recovery_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let report_path = temp_dir.path().join("recovery.json");
+    let mut store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report(), None);
+    assert!(!report_path.exists());
+    drop(store);
+
+    // A torn write at the end of the first data file
+    let data_file = temp_dir.path().join("1.log");
+    let mut data = std::fs::read(&data_file)?;
+    let len = data.len();
+    data.truncate(len - 5);
+    std::fs::write(&data_file, data)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, None);
+    let report = store.recovery_report().unwrap().clone();
+    assert_eq!(report.skipped_tails.len(), 1);
+    assert_eq!(report.skipped_tails[0].file_id, 1);
+    assert_eq!(
+        report.skipped_tails[0].offset + report.skipped_tails[0].bytes,
+        len as u64 - 5
+    );
+    assert_eq!(report.orphan_removes, 0);
 
-    // Open from disk again and check persistent data
+    let persisted: RecoveryReport = serde_json::from_slice(&std::fs::read(&report_path)?)?;
+    assert_eq!(persisted, report);
+
+    Ok(())
+}
+
+// A write torn by a crash is truncated away, so it isn't reported again.
+#[test]
+fn torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
+
+    let data_file = temp_dir.path().join("1.log");
+    let mut data = std::fs::read(&data_file)?;
+    let len = data.len();
+    data.truncate(len - 5);
+    std::fs::write(&data_file, data)?;
+
     let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, None);
+    let report = store.recovery_report().unwrap();
+    assert!(report.skipped_tails[0].truncated);
+    assert_eq!(report.skipped_tails[0].offset, len as u64 / 2);
+    assert_eq!(std::fs::metadata(&data_file)?.len(), len as u64 / 2);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report(), None);
 
     Ok(())
 }
 
 #[test]
-fn remove_non_existent_key() -> Result<()> {
+fn index_snapshot() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_path = temp_dir.path().join("index.json");
+    let opened_from_snapshot = |store: &KvStore| -> Result<bool> {
+        Ok(store
+            .stats()?
+            .contains(&("opened_from_snapshot", "1".to_owned())))
+    };
+
     let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(!opened_from_snapshot(&store)?);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.checkpoint()?;
+    let checkpoint = std::fs::read(&snapshot_path)?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    // Written on drop, it covers every record
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(opened_from_snapshot(&store)?);
+    assert!(store.keys().eq([b"key2", b"key3"]) || store.keys().eq([b"key3", b"key2"]));
+    drop(store);
+
+    // The records written after the checkpoint are replayed
+    std::fs::write(&snapshot_path, &checkpoint)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(opened_from_snapshot(&store)?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Compaction deletes the data files it covers
+    store.clear()?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    std::fs::write(&snapshot_path, &checkpoint)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!opened_from_snapshot(&store)?);
+    assert!(store.keys().eq([b"key4"]));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
     Ok(())
 }
 
+// A batch is applied as a whole, or not at all if it was cut short.
 #[test]
-fn remove_key() -> Result<()> {
+fn write_batch() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    batch.remove("key3".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
     assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report(), None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // A torn write of the last record of the batch
+    let data_file = temp_dir.path().join("1.log");
+    let mut data = std::fs::read(&data_file)?;
+    let len = data.len();
+    data.truncate(len - 5);
+    std::fs::write(&data_file, data)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    let report = store.recovery_report().unwrap();
+    // The record of key2 is whole, but its batch isn't
+    assert_eq!(report.skipped_tails.len(), 1);
+    assert_eq!(report.skipped_tails[0].file_id, 1);
+
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Writes through a transaction are only seen by the transaction until it is committed.
 #[test]
-fn compaction() -> Result<()> {
+fn transaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
+    for key in ["a", "b", "c"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+    let mut transaction = Transaction::new(&mut store);
+    transaction.remove("b".to_owned())?;
+    transaction.set("bb".to_owned(), "value".to_owned())?;
+    assert_eq!(transaction.append("c".to_owned(), "2".to_owned())?, 6);
+    assert!(matches!(
+        transaction.remove("b".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+    assert_eq!(transaction.scan(None, 2)?, ["a", "bb"]);
+    assert_eq!(transaction.scan(Some("a"), 10)?, ["bb", "c"]);
+    assert_eq!(transaction.len()?, 3);
+    drop(transaction);
+    assert_eq!(store.scan(None, 10)?, ["a", "b", "c"]);
+
+    let mut transaction = Transaction::new(&mut store);
+    transaction.remove("b".to_owned())?;
+    transaction.set("bb".to_owned(), "value".to_owned())?;
+    transaction.commit()?;
+    assert_eq!(store.scan(None, 10)?, ["a", "bb", "c"]);
+
+    let mut transaction = Transaction::new(&mut store);
+    transaction.clear()?;
+    assert!(transaction.is_empty()?);
+    transaction.commit()?;
+    assert!(store.is_empty()?);
+
+    Ok(())
+}
+
+// Data files after a compaction and a restart get newer file_ids, or their writes would be
+// replayed before older ones.
+#[test]
+fn file_ids_keep_increasing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_ids = || -> Vec<u64> {
+        let mut ids: Vec<u64> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .map(|path| path.file_stem().unwrap().to_str().unwrap().parse().unwrap())
+            .collect();
+        ids.sort_unstable();
+        ids
     };
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+    let mut newest = 0;
+    for i in 0..3 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert!(*file_ids().last().unwrap() > newest);
+        store.set("key".to_owned(), format!("value{}", i))?;
+        store.compact()?;
+        newest = *file_ids().last().unwrap();
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered
+    Ok(())
+}
 
-        drop(store);
-        // reopen and check content
+// Gets mostly served from older data files trigger a compaction, even without dead data.
+#[test]
+fn compaction_on_read_dispersion() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    // Every open starts a new data file
+    for batch in 0..3 {
         let mut store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        for key_id in 0..10 {
+            store.set(format!("key{}-{}", batch, key_id), "value".to_owned())?;
         }
-        return Ok(());
     }
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(log_files(), 4);
+
+    for i in 0..999 {
+        store.get(format!("key{}-{}", i % 3, i % 10))?;
+    }
+    assert_eq!(store.io_stats().compaction_bytes_written, 0);
+    store.get("key0-0".to_owned())?;
+    assert!(store.io_stats().compaction_bytes_written > 0);
+    assert_eq!(log_files(), 2);
 
-    panic!("No compaction detected");
+    // Reads of the compacted data file alone don't compact again
+    for _ in 0..1000 {
+        assert_eq!(store.get("key2-9".to_owned())?, Some("value".to_owned()));
+    }
+    assert_eq!(log_files(), 2);
+
+    Ok(())
+}
+
+// Scrubbed keys should be gone, including every historical value on disk.
+#[test]
+fn scrub_erases_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("user:1".to_owned(), "secret-v1".to_owned())?;
+    store.set("user:1".to_owned(), "secret-v2".to_owned())?;
+    store.set("user:2".to_owned(), "secret-v3".to_owned())?;
+    store.remove("user:2".to_owned())?;
+    store.set("other".to_owned(), "kept".to_owned())?;
+
+    assert_eq!(store.scrub("user:*")?, 1);
+    assert_eq!(store.get("user:1".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, Some("kept".to_owned()));
+
+    let on_disk: String = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.expect("fail to walk directory"))
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| std::fs::read(entry.path()).expect("fail to read data file"))
+        .map(|data| String::from_utf8_lossy(&data).into_owned())
+        .collect();
+    assert!(!on_disk.contains("secret"));
+    assert!(!on_disk.contains("user:"));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("user:1".to_owned())?, None);
+    assert_eq!(store.get("user:2".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, Some("kept".to_owned()));
+
+    Ok(())
+}
+
+// Keys scrubbed by a scrub the process died in the middle of don't come back.
+#[test]
+fn scrub_interrupted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "secret".to_owned())?;
+    store.set("other".to_owned(), "kept".to_owned())?;
+    drop(store);
+    let stale_file = temp_dir.path().join("1.log");
+    let stale = std::fs::read(&stale_file)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scrub("user:*")?, 1);
+    drop(store);
+    // As if the process died before deleting it, and before saving the index
+    std::fs::write(&stale_file, stale)?;
+    std::fs::remove_file(temp_dir.path().join("index.json"))?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("user:1".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, Some("kept".to_owned()));
+    let on_disk: String = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.expect("fail to walk directory"))
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| std::fs::read(entry.path()).expect("fail to read data file"))
+        .map(|data| String::from_utf8_lossy(&data).into_owned())
+        .collect();
+    assert!(!on_disk.contains("secret"));
+
+    Ok(())
+}
+
+// Bytes written by users and by compaction are accounted separately.
+#[test]
+fn io_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.io_stats().write_amplification(), 1.0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let stats = store.io_stats();
+    assert!(stats.user_bytes_written > 0);
+    assert_eq!(stats.compaction_bytes_written, 0);
+    assert_eq!(stats.fsyncs, 0);
+    assert_eq!(stats.write_amplification(), 1.0);
+
+    // Scrubbing rewrites the live data
+    store.scrub("no-such-key")?;
+    let after = store.io_stats();
+    assert_eq!(after.user_bytes_written, stats.user_bytes_written);
+    assert!(after.compaction_bytes_written > 0);
+    assert_eq!(after.fsyncs, 1);
+    assert!(after.write_amplification() > 1.0);
+
+    store.sync()?;
+    assert_eq!(store.io_stats().fsyncs, 2);
+
+    Ok(())
+}
+
+// Writes are fsynced on every write, in the background, or never, as the sync policy says.
+#[test]
+fn sync_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |sync| {
+        let options = KvStoreOptions {
+            sync,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with(temp_dir.path(), options)
+    };
+    assert_eq!("always".parse(), Ok(SyncPolicy::Always));
+    assert_eq!("never".parse(), Ok(SyncPolicy::Never));
+    assert_eq!("100".parse(), Ok(SyncPolicy::EveryNMillis(100)));
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+
+    let mut store = open(SyncPolicy::Never)?;
+    assert_eq!(store.sync_policy(), SyncPolicy::Never);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.io_stats().fsyncs, 0);
+    drop(store);
+
+    let mut store = open(SyncPolicy::Always)?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.io_stats().fsyncs, 2);
+    drop(store);
+
+    // Writes are fsynced together once the interval is over
+    let mut store = open(SyncPolicy::EveryNMillis(50))?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.io_stats().fsyncs, 1);
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.io_stats().fsyncs, 1);
+    store.set("key2".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    let mut store = open(SyncPolicy::Never)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// Threads writing to a shared store that fsyncs every write share fsyncs.
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync: SyncPolicy::Always,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?.into_shared();
+
+    let writers: Vec<_> = (0..8)
+        .map(|i| {
+            let mut handle = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for j in 0..50 {
+                    handle.set(format!("key{}-{}", i, j), j.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    let fsyncs = store.lock().io_stats().fsyncs;
+    assert!(fsyncs > 0 && fsyncs <= 400, "{} fsyncs", fsyncs);
+
+    // Every write is fsynced before it returns, also without other writers
+    let mut handle = store.clone();
+    handle.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.lock().io_stats().fsyncs, fsyncs + 1);
+    drop((store, handle));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 401);
+
+    Ok(())
+}
+
+// Compressed records should be smaller on disk and readable with any compression setting.
+#[test]
+fn compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = |dir: &std::path::Path| -> u64 {
+        WalkDir::new(dir)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let value = |i: u32| {
+        let sessions: Vec<String> = (0..8)
+            .map(|s| format!(r#"{{"session":{},"device":"phone","active":true}}"#, s))
+            .collect();
+        format!(r#"{{"id":{},"sessions":[{}]}}"#, i, sessions.join(","))
+    };
+    let write = |dir: &std::path::Path, compression: Compression| -> Result<()> {
+        let mut store = KvStore::builder().compression(compression).open(dir)?;
+        for i in 0..100 {
+            store.set(format!("user:{}", i), value(i))?;
+        }
+        store.set("small".to_owned(), "1".to_owned())
+    };
+    let plain_dir = temp_dir.path().join("plain");
+    let lz4_dir = temp_dir.path().join("lz4");
+    write(&plain_dir, Compression::None)?;
+    write(&lz4_dir, Compression::Lz4)?;
+    assert!(log_size(&lz4_dir) < log_size(&plain_dir) / 2);
+
+    let mut store = KvStore::open(&lz4_dir)?;
+    assert_eq!(store.config()[3], ("compression", "none".to_owned()));
+    assert_eq!(store.get("user:42".to_owned())?, Some(value(42)));
+    assert_eq!(store.get("small".to_owned())?, Some("1".to_owned()));
+    store.set_config("compression", "lz4")?;
+    store.set_config("compression-min-size", "0")?;
+    assert!(matches!(
+        store.set_config("compression", "zip"),
+        Err(Error::InvalidConfig(..))
+    ));
+    store.set("user:42".to_owned(), value(0))?;
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open(&lz4_dir)?;
+    assert_eq!(store.get("user:42".to_owned())?, Some(value(0)));
+    assert_eq!(store.get("user:99".to_owned())?, Some(value(99)));
+    assert_eq!(store.len()?, 101);
+
+    Ok(())
+}
+
+// A damaged compressed record isn't mistaken for a torn write: the records after it are kept.
+#[test]
+fn damaged_compressed_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |i: u32| format!("{}", i).repeat(100);
+    let mut store = KvStore::builder()
+        .compression(Compression::Lz4)
+        .open(temp_dir.path())?;
+    for i in 1..=3 {
+        store.set(format!("key{}", i), value(i))?;
+    }
+    drop(store);
+
+    let data_file = temp_dir.path().join("1.log");
+    let data = std::fs::read(&data_file)?;
+    let first_len = u32::from_le_bytes(data[..4].try_into().unwrap());
+    assert_ne!(first_len & (1 << 31), 0);
+    // In the payload of the second record
+    let second = 8 + (first_len & !(1 << 31)) as usize;
+    let mut damaged = data.clone();
+    damaged[second + 10] ^= 1;
+    std::fs::write(&data_file, &damaged)?;
+    std::fs::remove_file(temp_dir.path().join("index.json"))?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value(1)));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    let report = store.recovery_report().unwrap();
+    assert_eq!(report.skipped_tails[0].offset, second as u64);
+    assert!(!report.skipped_tails[0].truncated);
+    drop(store);
+    assert_eq!(std::fs::metadata(&data_file)?.len(), data.len() as u64);
+
+    // Once repaired, the records after it are read again
+    std::fs::write(&data_file, &data)?;
+    std::fs::remove_file(temp_dir.path().join("index.json"))?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some(value(2)));
+    assert_eq!(store.get("key3".to_owned())?, Some(value(3)));
+
+    Ok(())
+}
+
+// A data file written by following the format spec should be readable by the store.
+#[test]
+fn format_spec() -> Result<()> {
+    let spec = KvStore::format_spec();
+    assert_eq!(spec.version, 7);
+    assert_eq!(spec.header.file_name, "FORMAT");
+    assert_eq!(spec.header.magic, "kvstore\\n");
+    let kinds: Vec<_> = spec.records.iter().map(|r| r.kind).collect();
+    assert_eq!(kinds, ["Set", "Remove", "Batch", "Rename"]);
+    let set_fields: Vec<_> = spec.records[0].fields.iter().map(|f| f.name).collect();
+    assert_eq!(set_fields, ["key", "value", "expires_at"]);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let record = |kind: &str, key: &str, value: &str| {
+        let spec = spec.records.iter().find(|r| r.kind == kind).unwrap();
+        let mut payload = vec![spec.tag];
+        for field in &spec.fields {
+            match (field.name, field.ty) {
+                ("key", "bytes") => payload.extend([&[key.len() as u8], key.as_bytes()].concat()),
+                ("value", "bytes") => {
+                    payload.extend([&[value.len() as u8], value.as_bytes()].concat())
+                }
+                ("expires_at", "optional varint") => payload.push(0),
+                field => panic!("unexpected field {:?}", field),
+            }
+        }
+        [
+            &(payload.len() as u32).to_le_bytes()[..],
+            &crc32c(&payload).to_le_bytes(),
+            &payload,
+        ]
+        .concat()
+    };
+    let data = [
+        record("Set", "key1", "value1"),
+        record("Set", "key2", "value2"),
+        record("Remove", "key1", ""),
+    ]
+    .concat();
+    let file_name = spec.segment.file_name.replace("{file_id}", "1");
+    std::fs::write(temp_dir.path().join(file_name), data).expect("fail to write data file");
+    // Records written before version 6 are still read
+    let json = r#"{"Set":{"key":"key3","value":"value3"}}"#;
+    let data = [
+        &(json.len() as u32).to_le_bytes()[..],
+        &crc32c(json.as_bytes()).to_le_bytes(),
+        json.as_bytes(),
+    ]
+    .concat();
+    std::fs::write(temp_dir.path().join("2.log"), data).expect("fail to write data file");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    // Remove, the length of "key", "key"
+    assert_eq!(spec.records[1].example, "01036b6579");
+
+    Ok(())
+}
+
+// CRC-32C of `bytes`, as the format spec requires
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F6_3B78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
 }
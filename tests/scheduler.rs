@@ -0,0 +1,196 @@
+use kvs::{Error, JobConfig, KvStore, KvsClient, KvsEngine, KvsServer, Scheduler};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn counting_job(scheduler: &Scheduler, name: &str, config: JobConfig) -> Arc<AtomicUsize> {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&runs);
+    scheduler.add_job(name, config, move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    });
+    runs
+}
+
+#[test]
+fn jobs_run_periodically() {
+    let scheduler = Scheduler::new();
+    let fast = counting_job(
+        &scheduler,
+        "fast",
+        JobConfig::every(Duration::from_millis(10)),
+    );
+    let slow = counting_job(
+        &scheduler,
+        "slow",
+        JobConfig::every(Duration::from_secs(60)),
+    );
+    scheduler.start();
+
+    thread::sleep(Duration::from_millis(300));
+    scheduler.shutdown();
+
+    assert!(fast.load(Ordering::SeqCst) >= 5);
+    assert_eq!(slow.load(Ordering::SeqCst), 0);
+}
+
+// A failing job is logged and keeps being scheduled.
+#[test]
+fn failing_job_keeps_running() {
+    let scheduler = Scheduler::new();
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&runs);
+    scheduler.add_job(
+        "failing",
+        JobConfig::every(Duration::from_millis(10)),
+        move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err(Error::KeyNotFound)
+        },
+    );
+    scheduler.start();
+
+    thread::sleep(Duration::from_millis(200));
+    scheduler.shutdown();
+
+    assert!(runs.load(Ordering::SeqCst) >= 2);
+}
+
+#[test]
+fn enable_disable_and_reschedule_jobs() {
+    let scheduler = Scheduler::new();
+    let config = JobConfig {
+        enabled: false,
+        ..JobConfig::every(Duration::from_millis(10))
+    };
+    let runs = counting_job(&scheduler, "job", config);
+    scheduler.start();
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(runs.load(Ordering::SeqCst), 0);
+
+    scheduler.set_enabled("job", true).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(runs.load(Ordering::SeqCst) > 0);
+
+    scheduler
+        .set_interval("job", Duration::from_secs(60))
+        .unwrap();
+    thread::sleep(Duration::from_millis(50));
+    let count = runs.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(runs.load(Ordering::SeqCst), count);
+    assert_eq!(
+        scheduler.job_config("job").unwrap().interval,
+        Duration::from_secs(60)
+    );
+
+    assert!(matches!(
+        scheduler.set_enabled("missing", true),
+        Err(Error::UnknownJob(_))
+    ));
+    scheduler.shutdown();
+}
+
+#[test]
+fn job_status() {
+    let scheduler = Scheduler::new();
+    scheduler.add_job("ok", JobConfig::every(Duration::from_millis(10)), || Ok(()));
+    scheduler.add_job(
+        "failing",
+        JobConfig::every(Duration::from_millis(10)),
+        || Err(Error::KeyNotFound),
+    );
+    let never = JobConfig {
+        enabled: false,
+        ..JobConfig::every(Duration::from_millis(10))
+    };
+    scheduler.add_job("disabled", never, || Ok(()));
+    scheduler.start();
+    thread::sleep(Duration::from_millis(200));
+    scheduler.shutdown();
+
+    let jobs = scheduler.jobs();
+    let names: Vec<_> = jobs.iter().map(|job| job.name.as_str()).collect();
+    assert_eq!(names, ["ok", "failing", "disabled"]);
+
+    assert!(jobs[0].runs > 0);
+    assert!(jobs[0].last_run.is_some());
+    assert!(jobs[0].last_duration.is_some());
+    assert_eq!(jobs[0].last_error, None);
+    assert!(jobs[0].next_run.is_some());
+
+    assert_eq!(jobs[1].last_error.as_deref(), Some("Key not found"));
+
+    assert_eq!(jobs[2].runs, 0);
+    assert!(jobs[2].last_run.is_none());
+    assert!(jobs[2].next_run.is_none());
+}
+
+// The TASKS command reports the server's engine jobs.
+#[test]
+fn tasks_command() -> kvs::Result<()> {
+    let addr = "127.0.0.1:4201";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.add_engine_job(
+        "touch",
+        JobConfig::every(Duration::from_millis(10)),
+        |engine: &mut KvStore| engine.set("touched".to_owned(), "yes".to_owned()),
+    );
+    thread::spawn(move || server.start_server(&addr).unwrap());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect(addr)?;
+    let tasks = client.tasks()?;
+    assert!(tasks.starts_with("# touch\n"));
+    assert!(tasks.contains("enabled:true"));
+    assert!(tasks.contains("last_error:-"));
+    assert!(!tasks.contains("runs:0"));
+    assert_eq!(client.get("touched".to_owned())?, Some("yes".to_owned()));
+
+    Ok(())
+}
+
+// CONFIG GET and CONFIG SET show and change the server's jobs.
+#[test]
+fn config_jobs() -> kvs::Result<()> {
+    let addr = "127.0.0.1:4202";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    server.add_expire_sweep(Duration::from_secs(1));
+    let scheduler = server.scheduler().clone();
+    thread::spawn(move || server.start_server(&addr).unwrap());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(
+        client.config_get("job-*".to_owned())?,
+        [
+            ("job-expire-sweep-enabled".to_owned(), "yes".to_owned()),
+            ("job-expire-sweep-interval".to_owned(), "1000".to_owned()),
+        ]
+    );
+
+    client.config_set("job-expire-sweep-enabled".to_owned(), "no".to_owned())?;
+    client.config_set("JOB-EXPIRE-SWEEP-INTERVAL".to_owned(), "250".to_owned())?;
+    let config = scheduler.job_config("expire-sweep").unwrap();
+    assert!(!config.enabled);
+    assert_eq!(config.interval, Duration::from_millis(250));
+
+    for (name, value) in [
+        ("job-expire-sweep-interval", "0"),
+        ("job-expire-sweep-enabled", "maybe"),
+        ("job-no-such-job-enabled", "yes"),
+    ] {
+        assert!(matches!(
+            client.config_set(name.to_owned(), value.to_owned()),
+            Err(Error::Server(_))
+        ));
+    }
+
+    Ok(())
+}